@@ -5,10 +5,7 @@ use std::env;
 async fn main() {
     let api_key = env::var("OPENAI_API_KEY").unwrap();
     let adapter = OpenaiAdapter::new(api_key, "gpt-4o-mini");
-    let messages = vec![Message {
-        role: Role::User,
-        content: "世界上最长的河流是什么？".to_string(),
-    }];
+    let messages = vec![Message::new(Role::User, "世界上最长的河流是什么？")];
     let response = adapter.complete(&messages).await.unwrap();
     println!("response: {}", response);
 }
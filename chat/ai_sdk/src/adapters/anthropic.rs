@@ -0,0 +1,614 @@
+use super::{LineBuffer, add_headers};
+use crate::{
+    AiAdapter, AiService, ApiError, Completion, CompletionOptions, Message, ResponseFormat, Role,
+    Tool, ToolCall, Usage,
+};
+use anyhow::anyhow;
+use reqwest::{Client, Response};
+use serde::{Deserialize, Serialize};
+
+const API_VERSION: &str = "2023-06-01";
+// the Messages API requires a limit for every request
+const DEFAULT_MAX_TOKENS: u32 = 1024;
+
+pub struct AnthropicAdapter {
+    host: String,
+    api_key: String,
+    model: String,
+    max_tokens: u32,
+    headers: Vec<(String, String)>,
+    client: Client,
+}
+
+#[derive(Serialize)]
+pub struct AnthropicMessagesRequest {
+    pub model: String,
+    pub max_tokens: u32,
+    // system messages don't go with the turns but in a field of their own
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system: Option<String>,
+    pub messages: Vec<AnthropicMessage>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tools: Vec<AnthropicTool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub stop_sequences: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AnthropicMessage {
+    pub role: String,
+    pub content: Vec<AnthropicContent>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AnthropicContent {
+    Text {
+        text: String,
+    },
+    ToolUse {
+        id: String,
+        name: String,
+        input: serde_json::Value,
+    },
+    ToolResult {
+        tool_use_id: String,
+        content: String,
+    },
+    // e.g. thinking blocks, not used here
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Serialize)]
+pub struct AnthropicTool {
+    pub name: String,
+    pub description: String,
+    pub input_schema: serde_json::Value,
+}
+
+#[derive(Deserialize)]
+pub struct AnthropicMessagesResponse {
+    pub id: String,
+    pub model: String,
+    pub content: Vec<AnthropicContent>,
+    pub stop_reason: Option<String>,
+    pub usage: AnthropicUsage,
+}
+
+/// Usage of a response, a stream reports the input in `message_start` and
+/// the output in `message_delta`
+#[derive(Deserialize, Default)]
+pub struct AnthropicUsage {
+    #[serde(default)]
+    pub input_tokens: u32,
+    #[serde(default)]
+    pub output_tokens: u32,
+}
+
+/// One `data:` event of a streamed response, the ones not listed are ignored
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AnthropicStreamEvent {
+    MessageStart {
+        message: AnthropicStreamMessage,
+    },
+    ContentBlockDelta {
+        delta: AnthropicDelta,
+    },
+    MessageDelta {
+        usage: AnthropicUsage,
+    },
+    Error {
+        error: AnthropicError,
+    },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize)]
+pub struct AnthropicStreamMessage {
+    pub usage: AnthropicUsage,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AnthropicDelta {
+    TextDelta {
+        text: String,
+    },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize)]
+pub struct AnthropicError {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub message: String,
+}
+
+impl AnthropicAdapter {
+    pub fn new(api_key: impl Into<String>, model: impl Into<String>) -> Self {
+        let client = Client::new();
+        Self {
+            host: "https://api.anthropic.com/v1".to_string(),
+            api_key: api_key.into(),
+            model: model.into(),
+            max_tokens: DEFAULT_MAX_TOKENS,
+            headers: vec![],
+            client,
+        }
+    }
+
+    /// send the requests to another host, e.g. a proxy with the same API
+    pub fn with_host(mut self, host: impl Into<String>) -> Self {
+        self.host = host.into().trim_end_matches('/').to_string();
+        self
+    }
+
+    pub fn with_headers(mut self, headers: impl IntoIterator<Item = (String, String)>) -> Self {
+        self.headers.extend(headers);
+        self
+    }
+
+    pub fn with_max_tokens(mut self, max_tokens: u32) -> Self {
+        self.max_tokens = max_tokens;
+        self
+    }
+}
+
+impl AnthropicAdapter {
+    fn request(
+        &self,
+        messages: &[Message],
+        tools: &[Tool],
+        options: &CompletionOptions,
+    ) -> AnthropicMessagesRequest {
+        let mut system: Vec<String> = messages
+            .iter()
+            .filter(|m| matches!(m.role, Role::System))
+            .map(|m| m.content.clone())
+            .collect();
+        // the API has no JSON mode, the model is asked for it
+        match &options.response_format {
+            ResponseFormat::Text => {}
+            ResponseFormat::Json => {
+                system.push("Answer with a single JSON object and nothing else.".to_string())
+            }
+            ResponseFormat::JsonSchema { schema, .. } => system.push(format!(
+                "Answer with a single JSON object matching this JSON schema and nothing else:\n{}",
+                schema
+            )),
+        }
+        AnthropicMessagesRequest {
+            model: self.model.clone(),
+            max_tokens: options.max_tokens.unwrap_or(self.max_tokens),
+            system: (!system.is_empty()).then(|| system.join("\n\n")),
+            messages: to_turns(messages),
+            tools: tools.iter().map(|t| t.into()).collect(),
+            stream: None,
+            temperature: options.temperature,
+            top_p: options.top_p,
+            stop_sequences: options.stop.clone(),
+        }
+    }
+
+    async fn send(&self, request: &AnthropicMessagesRequest) -> anyhow::Result<Response> {
+        let url = format!("{}/messages", self.host);
+        let response = add_headers(self.client.post(url).json(request), &self.headers)
+            .header("x-api-key", &self.api_key)
+            .header("anthropic-version", API_VERSION)
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let error_text = response.text().await?;
+            return Err(ApiError {
+                status: status.as_u16(),
+                message: error_text,
+            }
+            .into());
+        }
+        Ok(response)
+    }
+}
+
+impl AiService for AnthropicAdapter {
+    async fn complete_with_options(
+        &self,
+        messages: &[Message],
+        tools: &[Tool],
+        options: &CompletionOptions,
+    ) -> anyhow::Result<Completion> {
+        let request = self.request(messages, tools, options);
+        let response = self.send(&request).await?;
+        let data: AnthropicMessagesResponse = response.json().await?;
+        Ok(data.into())
+    }
+
+    async fn complete_stream_with_options(
+        &self,
+        messages: &[Message],
+        options: &CompletionOptions,
+        on_delta: &mut (dyn FnMut(&str) + Send),
+    ) -> anyhow::Result<Completion> {
+        let request = AnthropicMessagesRequest {
+            stream: Some(true),
+            ..self.request(messages, &[], options)
+        };
+        let mut response = self.send(&request).await?;
+        let mut lines = LineBuffer::default();
+        let mut completion = Completion::default();
+        while let Some(chunk) = response.chunk().await? {
+            for line in lines.push(&chunk) {
+                apply_stream_line(&line, &mut completion, on_delta)?;
+            }
+        }
+        if let Some(line) = lines.finish() {
+            apply_stream_line(&line, &mut completion, on_delta)?;
+        }
+        Ok(completion)
+    }
+}
+
+/// The turns of the conversation: tool results are sent by the user, and
+/// consecutive messages of the same role are merged into one turn
+fn to_turns(messages: &[Message]) -> Vec<AnthropicMessage> {
+    let mut turns: Vec<AnthropicMessage> = Vec::new();
+    for message in messages {
+        let role = match message.role {
+            Role::System => continue,
+            Role::User | Role::Tool => "user",
+            Role::Assistant => "assistant",
+        };
+        let content = content_blocks(message);
+        if content.is_empty() {
+            continue;
+        }
+        match turns.last_mut() {
+            Some(turn) if turn.role == role => turn.content.extend(content),
+            _ => turns.push(AnthropicMessage {
+                role: role.to_string(),
+                content,
+            }),
+        }
+    }
+    turns
+}
+
+fn content_blocks(message: &Message) -> Vec<AnthropicContent> {
+    if let Some(id) = &message.tool_call_id {
+        return vec![AnthropicContent::ToolResult {
+            tool_use_id: id.clone(),
+            content: message.content.clone(),
+        }];
+    }
+    let mut blocks = Vec::new();
+    // empty text blocks are rejected
+    if !message.content.is_empty() {
+        blocks.push(AnthropicContent::Text {
+            text: message.content.clone(),
+        });
+    }
+    blocks.extend(message.tool_calls.iter().map(|c| c.into()));
+    blocks
+}
+
+/// Parse a line of the event stream, it's None for anything but an event
+fn parse_stream_line(line: &str) -> anyhow::Result<Option<AnthropicStreamEvent>> {
+    let Some(data) = line.strip_prefix("data:").map(str::trim) else {
+        return Ok(None);
+    };
+    Ok(Some(serde_json::from_str(data)?))
+}
+
+fn apply_stream_line(
+    line: &str,
+    completion: &mut Completion,
+    on_delta: &mut (dyn FnMut(&str) + Send),
+) -> anyhow::Result<()> {
+    let Some(event) = parse_stream_line(line)? else {
+        return Ok(());
+    };
+    match event {
+        AnthropicStreamEvent::MessageStart { message } => {
+            completion.usage = Some(message.usage.into());
+        }
+        AnthropicStreamEvent::ContentBlockDelta {
+            delta: AnthropicDelta::TextDelta { text },
+        } if !text.is_empty() => {
+            on_delta(&text);
+            completion.content.push_str(&text);
+        }
+        AnthropicStreamEvent::MessageDelta { usage } => {
+            let total = completion.usage.get_or_insert_default();
+            total.completion_tokens = usage.output_tokens;
+        }
+        AnthropicStreamEvent::Error { error } => {
+            return Err(anyhow!("stream failed: {}: {}", error.kind, error.message));
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+impl From<AnthropicUsage> for Usage {
+    fn from(usage: AnthropicUsage) -> Self {
+        Usage {
+            prompt_tokens: usage.input_tokens,
+            completion_tokens: usage.output_tokens,
+        }
+    }
+}
+
+impl From<AnthropicMessagesResponse> for Completion {
+    fn from(data: AnthropicMessagesResponse) -> Self {
+        let mut completion = Completion {
+            usage: Some(data.usage.into()),
+            ..Default::default()
+        };
+        for block in data.content {
+            match block {
+                AnthropicContent::Text { text } => completion.content.push_str(&text),
+                AnthropicContent::ToolUse { id, name, input } => {
+                    completion.tool_calls.push(ToolCall {
+                        id,
+                        name,
+                        arguments: input,
+                    })
+                }
+                _ => {}
+            }
+        }
+        completion
+    }
+}
+
+impl From<&ToolCall> for AnthropicContent {
+    fn from(call: &ToolCall) -> Self {
+        AnthropicContent::ToolUse {
+            id: call.id.clone(),
+            name: call.name.clone(),
+            input: call.arguments.clone(),
+        }
+    }
+}
+
+impl From<&Tool> for AnthropicTool {
+    fn from(tool: &Tool) -> Self {
+        AnthropicTool {
+            name: tool.name.clone(),
+            description: tool.description.clone(),
+            input_schema: tool.parameters.clone(),
+        }
+    }
+}
+
+impl From<AnthropicAdapter> for AiAdapter {
+    fn from(adapter: AnthropicAdapter) -> Self {
+        AiAdapter::Anthropic(adapter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::adapters::test_server::mock_server;
+    use serde_json::{Value, json};
+
+    fn conversation() -> Vec<Message> {
+        vec![
+            Message::system("You are a helpful assistant."),
+            Message::system("Chat: general"),
+            Message::user("Who is Tyr?"),
+        ]
+    }
+
+    #[tokio::test]
+    async fn complete_should_send_system_prompt_and_report_usage() -> anyhow::Result<()> {
+        let body = json!({
+            "id": "msg_1",
+            "type": "message",
+            "role": "assistant",
+            "model": "claude-3-5-haiku-latest",
+            "content": [{"type": "text", "text": "Tyr is "}, {"type": "text", "text": "a user."}],
+            "stop_reason": "end_turn",
+            "stop_sequence": null,
+            "usage": {"input_tokens": 21, "output_tokens": 5}
+        });
+        let (host, server) = mock_server(200, "application/json", body.to_string()).await?;
+        let adapter = AnthropicAdapter::new("sk-test", "claude-3-5-haiku-latest").with_host(host);
+
+        let completion = adapter.complete_with_usage(&conversation()).await?;
+        assert_eq!(completion.content, "Tyr is a user.");
+        assert_eq!(
+            completion.usage,
+            Some(Usage {
+                prompt_tokens: 21,
+                completion_tokens: 5
+            })
+        );
+
+        let (head, request) = server.await??;
+        assert!(head.starts_with("post /v1/messages "));
+        assert!(head.contains("x-api-key: sk-test"));
+        assert!(head.contains("anthropic-version: 2023-06-01"));
+        assert_eq!(request["max_tokens"], DEFAULT_MAX_TOKENS);
+        assert_eq!(
+            request["system"],
+            "You are a helpful assistant.\n\nChat: general"
+        );
+        assert_eq!(
+            request["messages"],
+            json!([{"role": "user", "content": [{"type": "text", "text": "Who is Tyr?"}]}])
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tool_calls_should_round_trip_as_content_blocks() -> anyhow::Result<()> {
+        let body = json!({
+            "id": "msg_2",
+            "type": "message",
+            "role": "assistant",
+            "model": "claude-3-5-haiku-latest",
+            "content": [
+                {"type": "text", "text": "Let me look."},
+                {"type": "tool_use", "id": "toolu_1", "name": "lookup_user", "input": {"query": "Tyr"}}
+            ],
+            "stop_reason": "tool_use",
+            "usage": {"input_tokens": 30, "output_tokens": 12}
+        });
+        let (host, server) = mock_server(200, "application/json", body.to_string()).await?;
+        let adapter = AnthropicAdapter::new("sk-test", "claude-3-5-haiku-latest").with_host(host);
+        let tool = Tool::new("lookup_user", "find a user", json!({"type": "object"}));
+
+        let completion = adapter
+            .complete_with_tools(&conversation(), &[tool])
+            .await?;
+        assert_eq!(completion.content, "Let me look.");
+        assert_eq!(completion.tool_calls.len(), 1);
+        assert_eq!(completion.tool_calls[0].id, "toolu_1");
+        assert_eq!(completion.tool_calls[0].arguments, json!({"query": "Tyr"}));
+        let (_, request) = server.await??;
+        assert_eq!(request["tools"][0]["name"], "lookup_user");
+        assert_eq!(
+            request["tools"][0]["input_schema"],
+            json!({"type": "object"})
+        );
+
+        // the results of two calls go back in a single user turn
+        let mut calls = completion.tool_calls.clone();
+        calls.push(ToolCall {
+            id: "toolu_2".to_string(),
+            name: "list_files".to_string(),
+            arguments: json!({}),
+        });
+        let mut messages = conversation();
+        messages.extend([
+            Message::tool_calls(calls),
+            Message::tool("toolu_1", "[]"),
+            Message::tool("toolu_2", "[]"),
+        ]);
+        let turns = serde_json::to_value(
+            adapter
+                .request(&messages, &[], &CompletionOptions::default())
+                .messages,
+        )?;
+        assert_eq!(turns.as_array().map(Vec::len), Some(3));
+        assert_eq!(turns[1]["role"], "assistant");
+        assert_eq!(turns[1]["content"][0]["type"], "tool_use");
+        assert_eq!(turns[1]["content"][1]["input"], json!({}));
+        assert_eq!(turns[2]["role"], "user");
+        assert_eq!(
+            turns[2]["content"],
+            json!([
+                {"type": "tool_result", "tool_use_id": "toolu_1", "content": "[]"},
+                {"type": "tool_result", "tool_use_id": "toolu_2", "content": "[]"}
+            ])
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn complete_stream_should_send_text_deltas() -> anyhow::Result<()> {
+        let events = [
+            r#"{"type":"message_start","message":{"id":"msg_3","type":"message","role":"assistant","content":[],"model":"claude-3-5-haiku-latest","usage":{"input_tokens":9,"output_tokens":1}}}"#,
+            r#"{"type":"content_block_start","index":0,"content_block":{"type":"text","text":""}}"#,
+            r#"{"type":"ping"}"#,
+            r#"{"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":"Hello"}}"#,
+            r#"{"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":" world"}}"#,
+            r#"{"type":"content_block_stop","index":0}"#,
+            r#"{"type":"message_delta","delta":{"stop_reason":"end_turn"},"usage":{"output_tokens":2}}"#,
+            r#"{"type":"message_stop"}"#,
+        ];
+        let body: String = events
+            .iter()
+            .map(|data| {
+                let event: Value = serde_json::from_str(data).unwrap_or_default();
+                format!(
+                    "event: {}\ndata: {}\n\n",
+                    event["type"].as_str().unwrap_or_default(),
+                    data
+                )
+            })
+            .collect();
+        let (host, server) = mock_server(200, "text/event-stream", body).await?;
+        let adapter = AnthropicAdapter::new("sk-test", "claude-3-5-haiku-latest").with_host(host);
+
+        let mut deltas = Vec::new();
+        let completion = adapter
+            .complete_stream(&conversation(), &mut |d| deltas.push(d.to_string()))
+            .await?;
+        assert_eq!(deltas, vec!["Hello", " world"]);
+        assert_eq!(completion.content, "Hello world");
+        assert_eq!(completion.usage.map(|u| u.total_tokens()), Some(11));
+        let (_, request) = server.await??;
+        assert_eq!(request["stream"], true);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn error_status_should_be_api_error() -> anyhow::Result<()> {
+        let body = json!({
+            "type": "error",
+            "error": {"type": "overloaded_error", "message": "Overloaded"}
+        });
+        let (host, server) = mock_server(529, "application/json", body.to_string()).await?;
+        let adapter = AnthropicAdapter::new("sk-test", "claude-3-5-haiku-latest").with_host(host);
+
+        let e = adapter
+            .complete(&conversation())
+            .await
+            .expect_err("overloaded should fail");
+        assert_eq!(e.downcast_ref::<ApiError>().map(|e| e.status), Some(529));
+        assert!(crate::is_transient(&e));
+        server.await??;
+        Ok(())
+    }
+
+    #[test]
+    fn options_should_be_sent_with_request() -> anyhow::Result<()> {
+        let adapter = AnthropicAdapter::new("sk-test", "claude-3-5-haiku-latest");
+        let options = CompletionOptions {
+            temperature: Some(0.5),
+            top_p: Some(0.25),
+            max_tokens: Some(64),
+            stop: vec!["END".to_string()],
+            seed: Some(7),
+            response_format: ResponseFormat::JsonSchema {
+                name: "answer".to_string(),
+                schema: json!({"type": "object"}),
+            },
+        };
+        let request = serde_json::to_value(adapter.request(&conversation(), &[], &options))?;
+        assert_eq!(request["temperature"], 0.5);
+        assert_eq!(request["max_tokens"], 64);
+        assert_eq!(request["stop_sequences"], json!(["END"]));
+        // there is no seed in the Messages API
+        assert!(request.get("seed").is_none());
+        let system = request["system"].as_str().unwrap_or_default();
+        assert!(system.starts_with("You are a helpful assistant.\n\nChat: general\n\n"));
+        assert!(system.ends_with(
+            r#"matching this JSON schema and nothing else:
+{"type":"object"}"#
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn stream_error_event_should_fail() {
+        let line =
+            r#"data: {"type":"error","error":{"type":"overloaded_error","message":"Overloaded"}}"#;
+        let mut completion = Completion::default();
+        let ret = apply_stream_line(line, &mut completion, &mut |_| {});
+        assert!(ret.is_err());
+    }
+}
@@ -0,0 +1,417 @@
+use crate::{
+    AiAdapter, AiService, ApiError, Completion, CompletionOptions, Message, Role, Tool, ToolCall,
+    Usage,
+};
+use regex::Regex;
+use serde::Deserialize;
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+// size of the vectors of `embed`
+const EMBEDDING_SIZE: usize = 8;
+
+/// An adapter answering from a script instead of a model, for tests. Queued
+/// responses come first, then the first rule matching the last user message,
+/// else the default which echoes that message. Clones share the queue and the
+/// recorded requests.
+#[derive(Debug, Clone, Default)]
+pub struct MockAdapter {
+    responses: Arc<Mutex<VecDeque<MockResponse>>>,
+    rules: Vec<(Regex, MockResponse)>,
+    default: Option<MockResponse>,
+    latency: Duration,
+    requests: Arc<Mutex<Vec<MockRequest>>>,
+}
+
+/// What the mock answers, texts of rules may refer to the groups of the
+/// pattern as `$1` or `${name}`
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MockResponse {
+    Text(String),
+    ToolCalls(Vec<MockToolCall>),
+    // fails like the provider answering with the status
+    Error { status: u16, message: String },
+    // the connection drops after the first word of the text was streamed
+    Interrupted(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct MockToolCall {
+    pub name: String,
+    #[serde(default)]
+    pub arguments: serde_json::Value,
+}
+
+/// A script of the mock as JSON, e.g. in the args of an agent
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct MockScript {
+    pub responses: Vec<MockResponse>,
+    pub rules: Vec<MockRule>,
+    pub default: Option<MockResponse>,
+    pub latency_ms: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct MockRule {
+    pub pattern: String,
+    pub response: MockResponse,
+}
+
+/// A request the mock got
+#[derive(Debug, Clone)]
+pub struct MockRequest {
+    pub messages: Vec<Message>,
+    pub tools: Vec<Tool>,
+    pub options: CompletionOptions,
+}
+
+impl MockAdapter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn try_from_script(script: MockScript) -> anyhow::Result<Self> {
+        let mut adapter = Self::new().with_latency(Duration::from_millis(script.latency_ms));
+        for response in script.responses {
+            adapter = adapter.with_response(response);
+        }
+        for rule in script.rules {
+            adapter = adapter.with_rule(&rule.pattern, rule.response)?;
+        }
+        adapter.default = script.default;
+        Ok(adapter)
+    }
+
+    /// answer the next request with the response, before any rule
+    pub fn with_response(self, response: impl Into<MockResponse>) -> Self {
+        self.responses
+            .lock()
+            .expect("mock responses poisoned")
+            .push_back(response.into());
+        self
+    }
+
+    pub fn with_rule(
+        mut self,
+        pattern: &str,
+        response: impl Into<MockResponse>,
+    ) -> anyhow::Result<Self> {
+        self.rules.push((Regex::new(pattern)?, response.into()));
+        Ok(self)
+    }
+
+    /// the response when nothing else matches, instead of the echo
+    pub fn with_default(mut self, response: impl Into<MockResponse>) -> Self {
+        self.default = Some(response.into());
+        self
+    }
+
+    /// wait before every answer, e.g. to run into timeouts
+    pub fn with_latency(mut self, latency: Duration) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    /// the requests so far, oldest first
+    pub fn requests(&self) -> Vec<MockRequest> {
+        self.requests
+            .lock()
+            .expect("mock requests poisoned")
+            .clone()
+    }
+
+    async fn answer(
+        &self,
+        messages: &[Message],
+        tools: &[Tool],
+        options: &CompletionOptions,
+    ) -> anyhow::Result<Completion> {
+        match self.next_response(messages, tools, options).await {
+            MockResponse::Interrupted(_) => Err(interrupted()),
+            response => completion(messages, response),
+        }
+    }
+
+    /// record the request and pick the response to it
+    async fn next_response(
+        &self,
+        messages: &[Message],
+        tools: &[Tool],
+        options: &CompletionOptions,
+    ) -> MockResponse {
+        self.requests
+            .lock()
+            .expect("mock requests poisoned")
+            .push(MockRequest {
+                messages: messages.to_vec(),
+                tools: tools.to_vec(),
+                options: options.clone(),
+            });
+        if !self.latency.is_zero() {
+            tokio::time::sleep(self.latency).await;
+        }
+
+        let input = messages
+            .iter()
+            .rev()
+            .find(|m| matches!(m.role, Role::User))
+            .map(|m| m.content.as_str())
+            .unwrap_or_default();
+        let queued = self
+            .responses
+            .lock()
+            .expect("mock responses poisoned")
+            .pop_front();
+        match queued {
+            Some(response) => response,
+            None => self.rule_response(input),
+        }
+    }
+
+    fn rule_response(&self, input: &str) -> MockResponse {
+        for (pattern, response) in &self.rules {
+            let Some(captures) = pattern.captures(input) else {
+                continue;
+            };
+            return match response {
+                MockResponse::Text(text) => {
+                    let mut content = String::new();
+                    captures.expand(text, &mut content);
+                    MockResponse::Text(content)
+                }
+                response => response.clone(),
+            };
+        }
+        self.default
+            .clone()
+            .unwrap_or_else(|| MockResponse::Text(input.to_string()))
+    }
+}
+
+fn completion(messages: &[Message], response: MockResponse) -> anyhow::Result<Completion> {
+    let usage = |content: &str| Usage {
+        prompt_tokens: messages.iter().map(|m| count_words(&m.content)).sum(),
+        completion_tokens: count_words(content),
+    };
+    match response {
+        MockResponse::Text(content) => Ok(Completion {
+            usage: Some(usage(&content)),
+            content,
+            tool_calls: vec![],
+        }),
+        MockResponse::ToolCalls(calls) => Ok(Completion {
+            content: String::new(),
+            usage: Some(usage("")),
+            tool_calls: calls
+                .into_iter()
+                .enumerate()
+                .map(|(i, call)| ToolCall {
+                    id: format!("call_{}", i),
+                    name: call.name,
+                    arguments: call.arguments,
+                })
+                .collect(),
+        }),
+        MockResponse::Error { status, message } => Err(ApiError { status, message }.into()),
+        MockResponse::Interrupted(_) => Err(interrupted()),
+    }
+}
+
+fn interrupted() -> anyhow::Error {
+    ApiError {
+        status: 502,
+        message: "stream interrupted".to_string(),
+    }
+    .into()
+}
+
+impl AiService for MockAdapter {
+    async fn complete_with_options(
+        &self,
+        messages: &[Message],
+        tools: &[Tool],
+        options: &CompletionOptions,
+    ) -> anyhow::Result<Completion> {
+        self.answer(messages, tools, options).await
+    }
+
+    /// the content is streamed word by word
+    async fn complete_stream_with_options(
+        &self,
+        messages: &[Message],
+        options: &CompletionOptions,
+        on_delta: &mut (dyn FnMut(&str) + Send),
+    ) -> anyhow::Result<Completion> {
+        let response = self.next_response(messages, &[], options).await;
+        if let MockResponse::Interrupted(text) = &response {
+            if let Some(word) = text.split_inclusive(' ').next() {
+                on_delta(word);
+            }
+            return Err(interrupted());
+        }
+        let completion = completion(messages, response)?;
+        for word in completion.content.split_inclusive(' ') {
+            on_delta(word);
+        }
+        Ok(completion)
+    }
+
+    /// the same text always gets the same vector
+    async fn embed(&self, input: &[String]) -> anyhow::Result<Vec<Vec<f32>>> {
+        let embeddings = input
+            .iter()
+            .map(|text| {
+                let mut vector = vec![0.0f32; EMBEDDING_SIZE];
+                for (i, b) in text.bytes().enumerate() {
+                    vector[i % EMBEDDING_SIZE] += b as f32;
+                }
+                let norm = vector.iter().map(|v| v * v).sum::<f32>().sqrt();
+                if norm > 0.0 {
+                    vector.iter_mut().for_each(|v| *v /= norm);
+                }
+                vector
+            })
+            .collect();
+        Ok(embeddings)
+    }
+}
+
+fn count_words(text: &str) -> u32 {
+    text.split_whitespace().count() as u32
+}
+
+impl From<&str> for MockResponse {
+    fn from(text: &str) -> Self {
+        MockResponse::Text(text.to_string())
+    }
+}
+
+impl From<String> for MockResponse {
+    fn from(text: String) -> Self {
+        MockResponse::Text(text)
+    }
+}
+
+impl From<MockAdapter> for AiAdapter {
+    fn from(adapter: MockAdapter) -> Self {
+        AiAdapter::Mock(adapter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::is_transient;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn mock_should_answer_queued_then_rules_then_echo() -> anyhow::Result<()> {
+        let adapter = MockAdapter::new()
+            .with_response("first")
+            .with_rule(r"^translate (?<text>.+)$", "bonjour ${text}")?
+            .with_rule(
+                "weather",
+                MockResponse::Error {
+                    status: 503,
+                    message: "overloaded".to_string(),
+                },
+            )?;
+        let messages = [Message::system("be nice"), Message::user("translate hi")];
+        assert_eq!(adapter.complete(&messages).await?, "first");
+        let completion = adapter.complete_with_usage(&messages).await?;
+        assert_eq!(completion.content, "bonjour hi");
+        assert_eq!(
+            completion.usage,
+            Some(Usage {
+                prompt_tokens: 4,
+                completion_tokens: 2
+            })
+        );
+        assert_eq!(adapter.complete(&[Message::user("hello")]).await?, "hello");
+        let error = adapter
+            .complete(&[Message::user("the weather?")])
+            .await
+            .expect_err("weather should fail");
+        assert!(is_transient(&error));
+
+        let adapter = adapter.with_default("no idea");
+        assert_eq!(
+            adapter.complete(&[Message::user("hello")]).await?,
+            "no idea"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn mock_should_record_requests_and_stream_words() -> anyhow::Result<()> {
+        let adapter = MockAdapter::new();
+        let handle = adapter.clone();
+        let options = CompletionOptions {
+            temperature: Some(0.5),
+            ..Default::default()
+        };
+        let mut deltas = vec![];
+        let mut on_delta = |delta: &str| deltas.push(delta.to_string());
+        let completion = adapter
+            .complete_stream_with_options(
+                &[Message::user("hello big world")],
+                &options,
+                &mut on_delta,
+            )
+            .await?;
+        assert_eq!(completion.content, "hello big world");
+        assert_eq!(deltas, vec!["hello ", "big ", "world"]);
+
+        let requests = handle.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].messages[0].content, "hello big world");
+        assert_eq!(requests[0].options, options);
+
+        let adapter = adapter.with_response(MockResponse::Interrupted("bye big world".into()));
+        let mut deltas = vec![];
+        let mut on_delta = |delta: &str| deltas.push(delta.to_string());
+        let error = adapter
+            .complete_stream_with_options(&[Message::user("hi")], &options, &mut on_delta)
+            .await
+            .expect_err("stream should break");
+        assert!(is_transient(&error));
+        assert_eq!(deltas, vec!["bye "]);
+
+        let embeddings = adapter.embed(&["a".to_string(), "a".to_string()]).await?;
+        assert_eq!(embeddings[0], embeddings[1]);
+        assert_eq!(embeddings[0].len(), EMBEDDING_SIZE);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn script_should_build_mock() -> anyhow::Result<()> {
+        let script: MockScript = serde_json::from_value(json!({
+            "responses": [{"tool_calls": [{"name": "lookup_user", "arguments": {"query": "Tyr"}}]}],
+            "rules": [{"pattern": "^(.*)$", "response": {"text": "Rewrite: $1"}}],
+            "latency_ms": 1
+        }))?;
+        let adapter = MockAdapter::try_from_script(script)?;
+        let completion = adapter.complete_with_usage(&[Message::user("hi")]).await?;
+        assert_eq!(completion.tool_calls[0].name, "lookup_user");
+        assert_eq!(completion.tool_calls[0].arguments, json!({"query": "Tyr"}));
+        assert_eq!(
+            adapter.complete(&[Message::user("hi")]).await?,
+            "Rewrite: hi"
+        );
+
+        let script = MockScript {
+            rules: vec![MockRule {
+                pattern: "(".to_string(),
+                response: "oops".into(),
+            }],
+            ..Default::default()
+        };
+        assert!(MockAdapter::try_from_script(script).is_err());
+        Ok(())
+    }
+}
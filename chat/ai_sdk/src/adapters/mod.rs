@@ -1,4 +1,63 @@
+mod anthropic;
+mod mock;
 mod ollama;
 mod openai;
+#[cfg(test)]
+mod test_server;
+pub use anthropic::*;
+pub use mock::*;
 pub use ollama::*;
 pub use openai::*;
+
+use reqwest::RequestBuilder;
+
+/// add the extra headers of an endpoint, e.g. of a gateway in front of the model
+pub(crate) fn add_headers(request: RequestBuilder, headers: &[(String, String)]) -> RequestBuilder {
+    headers.iter().fold(request, |request, (name, value)| {
+        request.header(name, value)
+    })
+}
+
+/// Splits a streamed response body into lines, chunks may end anywhere
+#[derive(Debug, Default)]
+pub(crate) struct LineBuffer {
+    buf: Vec<u8>,
+}
+
+impl LineBuffer {
+    /// add a chunk and take the lines it completed, blank lines are skipped
+    pub(crate) fn push(&mut self, chunk: &[u8]) -> Vec<String> {
+        self.buf.extend_from_slice(chunk);
+        let mut lines = Vec::new();
+        while let Some(pos) = self.buf.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.buf.drain(..=pos).collect();
+            let line = String::from_utf8_lossy(&line).trim().to_string();
+            if !line.is_empty() {
+                lines.push(line);
+            }
+        }
+        lines
+    }
+
+    /// the last line if the body didn't end with a newline
+    pub(crate) fn finish(self) -> Option<String> {
+        let line = String::from_utf8_lossy(&self.buf).trim().to_string();
+        (!line.is_empty()).then_some(line)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn line_buffer_should_split_chunks_into_lines() {
+        let mut lines = LineBuffer::default();
+        let text = "data: 你好\n\ndata: [DONE]";
+        let bytes = text.as_bytes();
+        // split inside the multi-byte character
+        assert!(lines.push(&bytes[..8]).is_empty());
+        assert_eq!(lines.push(&bytes[8..]), vec!["data: 你好"]);
+        assert_eq!(lines.finish(), Some("data: [DONE]".to_string()));
+    }
+}
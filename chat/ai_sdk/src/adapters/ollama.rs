@@ -1,10 +1,17 @@
-use crate::{AiAdapter, AiService, Message};
+use super::{LineBuffer, OpenAITool, add_headers};
+use crate::{
+    AiAdapter, AiService, Completion, CompletionOptions, Message, ResponseFormat, Tool, ToolCall,
+    Usage,
+};
+use anyhow::anyhow;
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
 
 pub struct OllamaAdapter {
     pub host: String,
     pub model: String,
+    // e.g. the credentials of a proxy in front of ollama
+    pub headers: Vec<(String, String)>,
     pub client: Client,
 }
 
@@ -13,12 +20,72 @@ pub struct OllamaChatCompletionRequest {
     pub model: String,
     pub messages: Vec<OllamaMessage>,
     pub stream: bool,
+    // ollama takes tools in the format of openai
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tools: Vec<OpenAITool>,
+    // "json" or a JSON schema
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub options: Option<OllamaOptions>,
+}
+
+/// Sampling parameters, sent only if any is set
+#[derive(Serialize, Default, PartialEq)]
+pub struct OllamaOptions {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub num_predict: Option<u32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub stop: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct OllamaEmbedRequest {
+    pub model: String,
+    pub input: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct OllamaEmbedResponse {
+    pub embeddings: Vec<Vec<f32>>,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct OllamaMessage {
     pub role: String,
+    #[serde(default)]
     pub content: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<OllamaToolCall>,
+}
+
+/// Ollama calls have no id, results are matched to calls by their order
+#[derive(Serialize, Deserialize)]
+pub struct OllamaToolCall {
+    pub function: OllamaFunctionCall,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct OllamaFunctionCall {
+    pub name: String,
+    pub arguments: serde_json::Value,
+}
+
+/// One line of a streamed chat, counts are only in the last one
+#[derive(Deserialize)]
+pub struct OllamaChatCompletionChunk {
+    pub message: Option<OllamaMessage>,
+    pub done: bool,
+    #[serde(default)]
+    pub prompt_eval_count: u32,
+    #[serde(default)]
+    pub eval_count: u32,
 }
 
 #[derive(Deserialize)]
@@ -41,8 +108,9 @@ impl OllamaAdapter {
         let model = model.into();
         let client = Client::new();
         Self {
-            host,
+            host: host.trim_end_matches('/').to_string(),
             model,
+            headers: vec![],
             client,
         }
     }
@@ -53,45 +121,190 @@ impl OllamaAdapter {
         Self {
             host: "http://localhost:11434".to_string(),
             model,
+            headers: vec![],
             client,
         }
     }
 }
 
+impl OllamaAdapter {
+    pub fn with_headers(mut self, headers: impl IntoIterator<Item = (String, String)>) -> Self {
+        self.headers.extend(headers);
+        self
+    }
+}
+
 impl Default for OllamaAdapter {
     fn default() -> Self {
         Self::new_local("llama3.2")
     }
 }
 
-impl AiService for OllamaAdapter {
-    async fn complete(&self, messages: &[Message]) -> anyhow::Result<String> {
-        let request = OllamaChatCompletionRequest {
+impl OllamaAdapter {
+    fn request(
+        &self,
+        messages: &[Message],
+        tools: &[Tool],
+        options: &CompletionOptions,
+        stream: bool,
+    ) -> OllamaChatCompletionRequest {
+        let format = match &options.response_format {
+            ResponseFormat::Text => None,
+            ResponseFormat::Json => Some(serde_json::Value::from("json")),
+            ResponseFormat::JsonSchema { schema, .. } => Some(schema.clone()),
+        };
+        let sampling = OllamaOptions {
+            temperature: options.temperature,
+            top_p: options.top_p,
+            num_predict: options.max_tokens,
+            stop: options.stop.clone(),
+            seed: options.seed,
+        };
+        OllamaChatCompletionRequest {
             model: self.model.clone(),
             messages: messages.iter().map(|m| m.into()).collect(),
-            stream: false,
-        };
+            stream,
+            tools: tools.iter().map(|t| t.into()).collect(),
+            format,
+            options: (sampling != OllamaOptions::default()).then_some(sampling),
+        }
+    }
+}
+
+impl AiService for OllamaAdapter {
+    async fn complete_with_options(
+        &self,
+        messages: &[Message],
+        tools: &[Tool],
+        options: &CompletionOptions,
+    ) -> anyhow::Result<Completion> {
+        let request = self.request(messages, tools, options, false);
         let url = format!("{}/api/chat", self.host);
-        let response = self.client.post(url).json(&request).send().await?;
+        let response = add_headers(self.client.post(url).json(&request), &self.headers)
+            .send()
+            .await?
+            .error_for_status()?;
         let response: OllamaChatCompletionResponse = response.json().await?;
-        Ok(response.message.content)
+        Ok(response.into())
+    }
+
+    async fn complete_stream_with_options(
+        &self,
+        messages: &[Message],
+        options: &CompletionOptions,
+        on_delta: &mut (dyn FnMut(&str) + Send),
+    ) -> anyhow::Result<Completion> {
+        let request = self.request(messages, &[], options, true);
+        let url = format!("{}/api/chat", self.host);
+        let mut response = add_headers(self.client.post(url).json(&request), &self.headers)
+            .send()
+            .await?
+            .error_for_status()?;
+        let mut lines = LineBuffer::default();
+        let mut completion = Completion::default();
+        while let Some(chunk) = response.chunk().await? {
+            for line in lines.push(&chunk) {
+                apply_stream_line(&line, &mut completion, on_delta)?;
+            }
+        }
+        if let Some(line) = lines.finish() {
+            apply_stream_line(&line, &mut completion, on_delta)?;
+        }
+        Ok(completion)
+    }
+
+    async fn embed(&self, input: &[String]) -> anyhow::Result<Vec<Vec<f32>>> {
+        if input.is_empty() {
+            return Ok(vec![]);
+        }
+        let request = OllamaEmbedRequest {
+            model: self.model.clone(),
+            input: input.to_vec(),
+        };
+        let url = format!("{}/api/embed", self.host);
+        let response = add_headers(self.client.post(url).json(&request), &self.headers)
+            .send()
+            .await?
+            .error_for_status()?;
+        let response: OllamaEmbedResponse = response.json().await?;
+        if response.embeddings.len() != input.len() {
+            return Err(anyhow!(
+                "got {} embeddings for {} texts",
+                response.embeddings.len(),
+                input.len()
+            ));
+        }
+        Ok(response.embeddings)
+    }
+}
+
+fn apply_stream_line(
+    line: &str,
+    completion: &mut Completion,
+    on_delta: &mut (dyn FnMut(&str) + Send),
+) -> anyhow::Result<()> {
+    let chunk: OllamaChatCompletionChunk = serde_json::from_str(line)?;
+    if let Some(message) = chunk.message
+        && !message.content.is_empty()
+    {
+        on_delta(&message.content);
+        completion.content.push_str(&message.content);
+    }
+    if chunk.done {
+        completion.usage = Some(Usage {
+            prompt_tokens: chunk.prompt_eval_count,
+            completion_tokens: chunk.eval_count,
+        });
+    }
+    Ok(())
+}
+
+impl From<OllamaChatCompletionResponse> for Completion {
+    fn from(response: OllamaChatCompletionResponse) -> Self {
+        let tool_calls = response
+            .message
+            .tool_calls
+            .into_iter()
+            .enumerate()
+            .map(|(i, call)| ToolCall {
+                id: format!("call_{}", i),
+                name: call.function.name,
+                arguments: call.function.arguments,
+            })
+            .collect();
+        Completion {
+            content: response.message.content,
+            usage: Some(Usage {
+                prompt_tokens: response.prompt_eval_count,
+                completion_tokens: response.eval_count,
+            }),
+            tool_calls,
+        }
     }
 }
 
 impl From<Message> for OllamaMessage {
     fn from(message: Message) -> Self {
-        OllamaMessage {
-            role: message.role.to_string(),
-            content: message.content,
-        }
+        (&message).into()
     }
 }
 
 impl From<&Message> for OllamaMessage {
     fn from(message: &Message) -> Self {
+        let tool_calls = message
+            .tool_calls
+            .iter()
+            .map(|call| OllamaToolCall {
+                function: OllamaFunctionCall {
+                    name: call.name.clone(),
+                    arguments: call.arguments.clone(),
+                },
+            })
+            .collect();
         OllamaMessage {
             role: message.role.to_string(),
             content: message.content.clone(),
+            tool_calls,
         }
     }
 }
@@ -105,16 +318,119 @@ impl From<OllamaAdapter> for AiAdapter {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::Role;
+    use crate::{Role, adapters::test_server::mock_server};
+    use serde_json::json;
+
+    #[test]
+    fn stream_lines_should_build_completion() -> anyhow::Result<()> {
+        let lines = [
+            r#"{"model":"llama3.2","message":{"role":"assistant","content":"Hello"},"done":false}"#,
+            r#"{"model":"llama3.2","message":{"role":"assistant","content":" world"},"done":false}"#,
+            r#"{"model":"llama3.2","message":{"role":"assistant","content":""},"done":true,"prompt_eval_count":9,"eval_count":2}"#,
+        ];
+        let mut completion = Completion::default();
+        let mut deltas = Vec::new();
+        for line in lines {
+            apply_stream_line(line, &mut completion, &mut |d| deltas.push(d.to_string()))?;
+        }
+        assert_eq!(deltas, vec!["Hello", " world"]);
+        assert_eq!(completion.content, "Hello world");
+        assert_eq!(completion.usage.map(|u| u.total_tokens()), Some(11));
+        Ok(())
+    }
+
+    #[test]
+    fn tool_calls_should_be_parsed() -> anyhow::Result<()> {
+        let response: OllamaChatCompletionResponse = serde_json::from_value(json!({
+            "model": "llama3.2",
+            "created_at": "2025-08-24T05:22:37Z",
+            "message": {
+                "role": "assistant",
+                "content": "",
+                "tool_calls": [{"function": {"name": "list_files", "arguments": {}}}]
+            },
+            "done": true,
+            "total_duration": 1,
+            "load_duration": 1,
+            "prompt_eval_count": 20,
+            "prompt_eval_duration": 1,
+            "eval_count": 5,
+            "eval_duration": 1
+        }))?;
+        let completion: Completion = response.into();
+        assert_eq!(completion.tool_calls.len(), 1);
+        assert_eq!(completion.tool_calls[0].id, "call_0");
+        assert_eq!(completion.tool_calls[0].name, "list_files");
+
+        let message: OllamaMessage = (&Message::tool_calls(completion.tool_calls)).into();
+        let message = serde_json::to_value(&message)?;
+        assert_eq!(message["tool_calls"][0]["function"]["arguments"], json!({}));
+        Ok(())
+    }
+
+    #[test]
+    fn options_should_be_sent_as_ollama_options() -> anyhow::Result<()> {
+        let adapter = OllamaAdapter::new_local("llama3.2");
+        let options = CompletionOptions {
+            temperature: Some(0.5),
+            top_p: Some(0.25),
+            max_tokens: Some(64),
+            stop: vec!["END".to_string()],
+            seed: Some(7),
+            response_format: ResponseFormat::JsonSchema {
+                name: "answer".to_string(),
+                schema: json!({"type": "object"}),
+            },
+        };
+        let request = adapter.request(&[Message::user("hello")], &[], &options, true);
+        let request = serde_json::to_value(&request)?;
+        assert_eq!(
+            request["options"],
+            json!({"temperature": 0.5, "top_p": 0.25, "num_predict": 64, "stop": ["END"], "seed": 7})
+        );
+        assert_eq!(request["format"], json!({"type": "object"}));
+
+        let options = CompletionOptions {
+            response_format: ResponseFormat::Json,
+            ..Default::default()
+        };
+        let request = adapter.request(&[], &[], &options, false);
+        let request = serde_json::to_value(&request)?;
+        assert_eq!(request["format"], "json");
+        assert!(request.get("options").is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn embed_should_call_embed_api() -> anyhow::Result<()> {
+        let body = json!({
+            "model": "all-minilm",
+            "embeddings": [[0.1, 0.2], [0.3, 0.4]]
+        });
+        let (host, server) = mock_server(200, "application/json", body.to_string()).await?;
+        let adapter = OllamaAdapter::new(host, "all-minilm");
+        let input = ["hello".to_string(), "world".to_string()];
+        let embeddings = adapter.embed(&input).await?;
+        assert_eq!(embeddings, vec![vec![0.1, 0.2], vec![0.3, 0.4]]);
+        let (head, request) = server.await??;
+        assert!(head.starts_with("post /v1/api/embed "));
+        assert_eq!(
+            request,
+            json!({"model": "all-minilm", "input": ["hello", "world"]})
+        );
+
+        // a vector missing for a text is an error
+        let (host, _) = mock_server(200, "application/json", body.to_string()).await?;
+        let adapter = OllamaAdapter::new(host, "all-minilm");
+        assert!(adapter.embed(&input[..1]).await.is_err());
+        Ok(())
+    }
 
     #[ignore]
     #[tokio::test]
     async fn ollama_complete_should_work() {
         let adapter = OllamaAdapter::new_local("llama3.2");
-        let messages = vec![Message {
-            role: Role::User,
-            content: "Hello".to_string(),
-        }];
+        let messages = vec![Message::new(Role::User, "Hello")];
         let response = adapter.complete(&messages).await.unwrap();
         println!("response: {}", response);
     }
@@ -1,25 +1,110 @@
-use crate::{AiAdapter, AiService, Message};
+use super::{LineBuffer, add_headers};
+use crate::{
+    AiAdapter, AiService, ApiError, Completion, CompletionOptions, Message, ResponseFormat, Tool,
+    ToolCall, Usage,
+};
 use anyhow::anyhow;
-use reqwest::Client;
+use reqwest::{Client, Response};
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 
 pub struct OpenaiAdapter {
     host: String,
     api_key: String,
     model: String,
+    organization: Option<String>,
+    // gateways like Azure take the key in a header of their own instead of a bearer token
+    api_key_header: Option<String>,
+    headers: Vec<(String, String)>,
     client: Client,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Default)]
 pub struct OpenAIChatCompletionRequest {
     pub model: String,
     pub messages: Vec<OpenAIMessage>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream_options: Option<OpenAIStreamOptions>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tools: Vec<OpenAITool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    // compatible servers like vLLM don't know `max_completion_tokens`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub stop: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_format: Option<serde_json::Value>,
+}
+
+#[derive(Serialize)]
+pub struct OpenAIEmbeddingRequest {
+    pub model: String,
+    pub input: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct OpenAIEmbeddingResponse {
+    pub data: Vec<OpenAIEmbedding>,
+}
+
+#[derive(Deserialize)]
+pub struct OpenAIEmbedding {
+    pub index: usize,
+    pub embedding: Vec<f32>,
+}
+
+#[derive(Serialize)]
+pub struct OpenAIStreamOptions {
+    pub include_usage: bool,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct OpenAIMessage {
     pub role: String,
-    pub content: String,
+    // null when the assistant only calls tools
+    #[serde(default)]
+    pub content: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<OpenAIToolCall>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct OpenAITool {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub function: OpenAIFunction,
+}
+
+#[derive(Serialize)]
+pub struct OpenAIFunction {
+    pub name: String,
+    pub description: String,
+    pub parameters: serde_json::Value,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct OpenAIToolCall {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub function: OpenAIFunctionCall,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct OpenAIFunctionCall {
+    pub name: String,
+    // arguments are a JSON encoded string
+    pub arguments: String,
 }
 #[derive(Deserialize)]
 pub struct OpenAIChatCompletionResponse {
@@ -53,6 +138,24 @@ pub struct OpenAICompletionTokensDetails {
     pub reasoning_tokens: u32,
 }
 
+/// One `data:` event of a streamed completion
+#[derive(Deserialize)]
+pub struct OpenAIChatCompletionChunk {
+    pub choices: Vec<OpenAIChunkChoice>,
+    // only in the last chunk when usage is requested
+    pub usage: Option<OpenAIUsage>,
+}
+
+#[derive(Deserialize)]
+pub struct OpenAIChunkChoice {
+    pub delta: OpenAIDelta,
+}
+
+#[derive(Deserialize)]
+pub struct OpenAIDelta {
+    pub content: Option<String>,
+}
+
 impl OpenaiAdapter {
     pub fn new(api_key: impl Into<String>, model: impl Into<String>) -> Self {
         let client = Client::new();
@@ -60,61 +163,271 @@ impl OpenaiAdapter {
             host: "https://api.openai.com/v1".to_string(),
             api_key: api_key.into(),
             model: model.into(),
+            organization: None,
+            api_key_header: None,
+            headers: vec![],
             client,
         }
     }
-}
 
-impl AiService for OpenaiAdapter {
-    async fn complete(&self, messages: &[Message]) -> anyhow::Result<String> {
-        let request = OpenAIChatCompletionRequest {
-            model: self.model.clone(),
-            messages: messages.iter().map(|m| m.into()).collect(),
-        };
+    /// send the requests to another OpenAI compatible API, e.g. vLLM or LiteLLM
+    pub fn with_host(mut self, host: impl Into<String>) -> Self {
+        self.host = host.into().trim_end_matches('/').to_string();
+        self
+    }
+
+    pub fn with_organization(mut self, organization: impl Into<String>) -> Self {
+        self.organization = Some(organization.into());
+        self
+    }
+
+    pub fn with_api_key_header(mut self, name: impl Into<String>) -> Self {
+        self.api_key_header = Some(name.into());
+        self
+    }
+
+    pub fn with_headers(mut self, headers: impl IntoIterator<Item = (String, String)>) -> Self {
+        self.headers.extend(headers);
+        self
+    }
+}
 
-        let url = format!("{}/chat/completions", self.host);
-        let response = self
-            .client
-            .post(url)
-            .json(&request)
-            .header("Authorization", format!("Bearer {}", self.api_key))
-            .send()
-            .await?;
+impl OpenaiAdapter {
+    async fn send(&self, path: &str, request: &impl Serialize) -> anyhow::Result<Response> {
+        let url = format!("{}/{}", self.host, path);
+        let mut builder = add_headers(self.client.post(url).json(request), &self.headers);
+        // self-hosted servers may not need a key
+        if !self.api_key.is_empty() {
+            builder = match &self.api_key_header {
+                Some(name) => builder.header(name, &self.api_key),
+                None => builder.header("Authorization", format!("Bearer {}", self.api_key)),
+            };
+        }
+        if let Some(organization) = &self.organization {
+            builder = builder.header("OpenAI-Organization", organization);
+        }
+        let response = builder.send().await?;
         // 检查响应状态
         if !response.status().is_success() {
             let status = response.status();
             let error_text = response.text().await?;
+            return Err(ApiError {
+                status: status.as_u16(),
+                message: error_text,
+            }
+            .into());
+        }
+        Ok(response)
+    }
+}
+
+impl OpenaiAdapter {
+    fn request(
+        &self,
+        messages: &[Message],
+        tools: &[Tool],
+        options: &CompletionOptions,
+    ) -> OpenAIChatCompletionRequest {
+        OpenAIChatCompletionRequest {
+            model: self.model.clone(),
+            messages: messages.iter().map(|m| m.into()).collect(),
+            tools: tools.iter().map(|t| t.into()).collect(),
+            temperature: options.temperature,
+            top_p: options.top_p,
+            max_tokens: options.max_tokens,
+            stop: options.stop.clone(),
+            seed: options.seed,
+            response_format: response_format(&options.response_format),
+            ..Default::default()
+        }
+    }
+}
+
+impl AiService for OpenaiAdapter {
+    async fn complete_with_options(
+        &self,
+        messages: &[Message],
+        tools: &[Tool],
+        options: &CompletionOptions,
+    ) -> anyhow::Result<Completion> {
+        let request = self.request(messages, tools, options);
+        let response = self.send("chat/completions", &request).await?;
+        let data: OpenAIChatCompletionResponse = response.json().await?;
+        data.try_into()
+    }
+
+    async fn embed(&self, input: &[String]) -> anyhow::Result<Vec<Vec<f32>>> {
+        if input.is_empty() {
+            return Ok(vec![]);
+        }
+        let request = OpenAIEmbeddingRequest {
+            model: self.model.clone(),
+            input: input.to_vec(),
+        };
+        let response = self.send("embeddings", &request).await?;
+        let mut data: OpenAIEmbeddingResponse = response.json().await?;
+        if data.data.len() != input.len() {
             return Err(anyhow!(
-                "API request failed with status {}: {}",
-                status,
-                error_text
+                "got {} embeddings for {} texts",
+                data.data.len(),
+                input.len()
             ));
         }
-        let mut data: OpenAIChatCompletionResponse = response.json().await?;
-        let content = data
-            .choices
-            .pop()
-            .ok_or(anyhow!("No response"))?
-            .message
-            .content;
-        Ok(content)
+        data.data.sort_by_key(|e| e.index);
+        Ok(data.data.into_iter().map(|e| e.embedding).collect())
+    }
+
+    async fn complete_stream_with_options(
+        &self,
+        messages: &[Message],
+        options: &CompletionOptions,
+        on_delta: &mut (dyn FnMut(&str) + Send),
+    ) -> anyhow::Result<Completion> {
+        let request = OpenAIChatCompletionRequest {
+            stream: Some(true),
+            stream_options: Some(OpenAIStreamOptions {
+                include_usage: true,
+            }),
+            ..self.request(messages, &[], options)
+        };
+        let mut response = self.send("chat/completions", &request).await?;
+        let mut lines = LineBuffer::default();
+        let mut completion = Completion::default();
+        while let Some(chunk) = response.chunk().await? {
+            for line in lines.push(&chunk) {
+                apply_stream_line(&line, &mut completion, on_delta)?;
+            }
+        }
+        if let Some(line) = lines.finish() {
+            apply_stream_line(&line, &mut completion, on_delta)?;
+        }
+        Ok(completion)
+    }
+}
+
+fn response_format(format: &ResponseFormat) -> Option<serde_json::Value> {
+    match format {
+        ResponseFormat::Text => None,
+        ResponseFormat::Json => Some(json!({"type": "json_object"})),
+        ResponseFormat::JsonSchema { name, schema } => Some(json!({
+            "type": "json_schema",
+            "json_schema": {"name": name, "schema": schema},
+        })),
+    }
+}
+
+/// Parse a line of the event stream, it's None for anything but a chunk
+pub fn parse_stream_line(line: &str) -> anyhow::Result<Option<OpenAIChatCompletionChunk>> {
+    let Some(data) = line.strip_prefix("data:").map(str::trim) else {
+        return Ok(None);
+    };
+    if data == "[DONE]" {
+        return Ok(None);
+    }
+    Ok(Some(serde_json::from_str(data)?))
+}
+
+fn apply_stream_line(
+    line: &str,
+    completion: &mut Completion,
+    on_delta: &mut (dyn FnMut(&str) + Send),
+) -> anyhow::Result<()> {
+    let Some(chunk) = parse_stream_line(line)? else {
+        return Ok(());
+    };
+    for choice in chunk.choices {
+        if let Some(delta) = choice.delta.content
+            && !delta.is_empty()
+        {
+            on_delta(&delta);
+            completion.content.push_str(&delta);
+        }
+    }
+    if let Some(usage) = chunk.usage {
+        completion.usage = Some(usage.into());
+    }
+    Ok(())
+}
+
+impl From<OpenAIUsage> for Usage {
+    fn from(usage: OpenAIUsage) -> Self {
+        Usage {
+            prompt_tokens: usage.prompt_tokens,
+            completion_tokens: usage.completion_tokens,
+        }
+    }
+}
+
+impl TryFrom<OpenAIChatCompletionResponse> for Completion {
+    type Error = anyhow::Error;
+
+    fn try_from(mut data: OpenAIChatCompletionResponse) -> Result<Self, Self::Error> {
+        let message = data.choices.pop().ok_or(anyhow!("No response"))?.message;
+        Ok(Completion {
+            content: message.content.unwrap_or_default(),
+            usage: Some(data.usage.into()),
+            tool_calls: message.tool_calls.into_iter().map(|c| c.into()).collect(),
+        })
+    }
+}
+
+impl From<OpenAIToolCall> for ToolCall {
+    fn from(call: OpenAIToolCall) -> Self {
+        // a malformed argument string is kept, the tool reports it back to the model
+        let arguments = serde_json::from_str(&call.function.arguments)
+            .unwrap_or(serde_json::Value::String(call.function.arguments));
+        ToolCall {
+            id: call.id,
+            name: call.function.name,
+            arguments,
+        }
+    }
+}
+
+impl From<&ToolCall> for OpenAIToolCall {
+    fn from(call: &ToolCall) -> Self {
+        OpenAIToolCall {
+            id: call.id.clone(),
+            kind: "function".to_string(),
+            function: OpenAIFunctionCall {
+                name: call.name.clone(),
+                arguments: call.arguments.to_string(),
+            },
+        }
+    }
+}
+
+impl From<&Tool> for OpenAITool {
+    fn from(tool: &Tool) -> Self {
+        OpenAITool {
+            kind: "function".to_string(),
+            function: OpenAIFunction {
+                name: tool.name.clone(),
+                description: tool.description.clone(),
+                parameters: tool.parameters.clone(),
+            },
+        }
     }
 }
 
 impl From<Message> for OpenAIMessage {
     fn from(message: Message) -> Self {
-        OpenAIMessage {
-            role: message.role.to_string(),
-            content: message.content,
-        }
+        (&message).into()
     }
 }
 
 impl From<&Message> for OpenAIMessage {
     fn from(message: &Message) -> Self {
+        let content = if message.content.is_empty() && !message.tool_calls.is_empty() {
+            None
+        } else {
+            Some(message.content.clone())
+        };
         OpenAIMessage {
             role: message.role.to_string(),
-            content: message.content.clone(),
+            content,
+            tool_calls: message.tool_calls.iter().map(|c| c.into()).collect(),
+            tool_call_id: message.tool_call_id.clone(),
         }
     }
 }
@@ -129,17 +442,185 @@ impl From<OpenaiAdapter> for AiAdapter {
 mod tests {
     use super::*;
     use crate::Role;
+    use crate::adapters::test_server::mock_server;
+    use serde_json::json;
     use std::env;
 
+    #[test]
+    fn stream_lines_should_build_completion() -> anyhow::Result<()> {
+        let lines = [
+            r#"data: {"choices":[{"index":0,"delta":{"role":"assistant","content":""}}],"usage":null}"#,
+            r#"data: {"choices":[{"index":0,"delta":{"content":"Hello"}}],"usage":null}"#,
+            r#"data: {"choices":[{"index":0,"delta":{"content":" world"}}],"usage":null}"#,
+            r#"data: {"choices":[],"usage":{"prompt_tokens":9,"completion_tokens":2,"total_tokens":11}}"#,
+            "data: [DONE]",
+        ];
+        let mut completion = Completion::default();
+        let mut deltas = Vec::new();
+        for line in lines {
+            apply_stream_line(line, &mut completion, &mut |d| deltas.push(d.to_string()))?;
+        }
+        assert_eq!(deltas, vec!["Hello", " world"]);
+        assert_eq!(completion.content, "Hello world");
+        assert_eq!(completion.usage.map(|u| u.total_tokens()), Some(11));
+        Ok(())
+    }
+
+    #[test]
+    fn tool_calls_should_round_trip() -> anyhow::Result<()> {
+        let data: OpenAIChatCompletionResponse = serde_json::from_value(json!({
+            "id": "chatcmpl-1",
+            "object": "chat.completion",
+            "created": 1724000000,
+            "model": "gpt-4o-mini",
+            "system_fingerprint": "fp_1",
+            "choices": [{
+                "index": 0,
+                "message": {
+                    "role": "assistant",
+                    "content": null,
+                    "tool_calls": [{
+                        "id": "call_1",
+                        "type": "function",
+                        "function": {"name": "lookup_user", "arguments": "{\"query\":\"Tyr\"}"}
+                    }]
+                },
+                "logprobs": null,
+                "finish_reason": "tool_calls"
+            }],
+            "usage": {"prompt_tokens": 20, "completion_tokens": 5, "total_tokens": 25}
+        }))?;
+        let completion: Completion = data.try_into()?;
+        assert_eq!(completion.content, "");
+        assert_eq!(completion.tool_calls.len(), 1);
+        assert_eq!(completion.tool_calls[0].name, "lookup_user");
+        assert_eq!(completion.tool_calls[0].arguments, json!({"query": "Tyr"}));
+
+        let tool = Tool::new("lookup_user", "find a user", json!({"type": "object"}));
+        let request = OpenAIChatCompletionRequest {
+            model: "gpt-4o-mini".to_string(),
+            messages: vec![
+                (&Message::tool_calls(completion.tool_calls)).into(),
+                (&Message::tool("call_1", "[]")).into(),
+            ],
+            tools: vec![(&tool).into()],
+            ..Default::default()
+        };
+        let request = serde_json::to_value(&request)?;
+        assert_eq!(request["tools"][0]["function"]["name"], "lookup_user");
+        let messages = &request["messages"];
+        assert_eq!(messages[0]["content"], serde_json::Value::Null);
+        assert_eq!(
+            messages[0]["tool_calls"][0]["function"]["arguments"],
+            r#"{"query":"Tyr"}"#
+        );
+        assert_eq!(messages[1]["role"], "tool");
+        assert_eq!(messages[1]["tool_call_id"], "call_1");
+        Ok(())
+    }
+
+    #[test]
+    fn options_should_be_sent_with_request() -> anyhow::Result<()> {
+        let adapter = OpenaiAdapter::new("sk-test", "gpt-4o-mini");
+        let options = CompletionOptions {
+            temperature: Some(0.5),
+            top_p: Some(0.25),
+            max_tokens: Some(64),
+            stop: vec!["END".to_string()],
+            seed: Some(7),
+            response_format: ResponseFormat::JsonSchema {
+                name: "answer".to_string(),
+                schema: json!({"type": "object"}),
+            },
+        };
+        let request = adapter.request(&[Message::user("hello")], &[], &options);
+        let request = serde_json::to_value(&request)?;
+        assert_eq!(request["temperature"], 0.5);
+        assert_eq!(request["max_tokens"], 64);
+        assert_eq!(request["stop"], json!(["END"]));
+        assert_eq!(request["seed"], 7);
+        assert_eq!(request["response_format"]["type"], "json_schema");
+        assert_eq!(request["response_format"]["json_schema"]["name"], "answer");
+
+        // unset options are left to the model
+        let request = adapter.request(&[], &[], &CompletionOptions::default());
+        let request = serde_json::to_value(&request)?;
+        assert_eq!(request.as_object().map(|r| r.len()), Some(2));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn compatible_endpoint_should_get_its_headers() -> anyhow::Result<()> {
+        let body = json!({
+            "id": "chatcmpl-2",
+            "object": "chat.completion",
+            "created": 1724000000,
+            "model": "qwen2.5",
+            "system_fingerprint": "fp_2",
+            "choices": [{
+                "index": 0,
+                "message": {"role": "assistant", "content": "hi"},
+                "logprobs": null,
+                "finish_reason": "stop"
+            }],
+            "usage": {"prompt_tokens": 3, "completion_tokens": 1, "total_tokens": 4}
+        });
+        let (host, server) = mock_server(200, "application/json", body.to_string()).await?;
+        let adapter = OpenaiAdapter::new("gw-key", "qwen2.5")
+            .with_host(format!("{}/", host))
+            .with_organization("org-1")
+            .with_api_key_header("api-key")
+            .with_headers([("X-Team".to_string(), "chat".to_string())]);
+        assert_eq!(adapter.complete(&[Message::user("hello")]).await?, "hi");
+        let (head, request) = server.await??;
+        assert!(head.starts_with("post /v1/chat/completions "));
+        assert!(head.contains("api-key: gw-key"));
+        assert!(!head.contains("authorization:"));
+        assert!(head.contains("openai-organization: org-1"));
+        assert!(head.contains("x-team: chat"));
+        assert_eq!(request["model"], "qwen2.5");
+
+        // a self-hosted server without a key gets no credentials
+        let (host, server) = mock_server(200, "application/json", body.to_string()).await?;
+        let adapter = OpenaiAdapter::new("", "qwen2.5").with_host(host);
+        adapter.complete(&[Message::user("hello")]).await?;
+        let (head, _) = server.await??;
+        assert!(!head.contains("authorization:"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn embed_should_return_vectors_in_order_of_input() -> anyhow::Result<()> {
+        let body = json!({
+            "object": "list",
+            "data": [
+                {"object": "embedding", "index": 1, "embedding": [0.5, 0.25]},
+                {"object": "embedding", "index": 0, "embedding": [1.0, 0.0]}
+            ],
+            "model": "text-embedding-3-small",
+            "usage": {"prompt_tokens": 4, "total_tokens": 4}
+        });
+        let (host, server) = mock_server(200, "application/json", body.to_string()).await?;
+        let adapter = OpenaiAdapter::new("sk-test", "text-embedding-3-small").with_host(host);
+        let input = ["hello".to_string(), "world".to_string()];
+        let embeddings = adapter.embed(&input).await?;
+        assert_eq!(embeddings, vec![vec![1.0, 0.0], vec![0.5, 0.25]]);
+        let (head, request) = server.await??;
+        assert!(head.starts_with("post /v1/embeddings "));
+        assert_eq!(request["model"], "text-embedding-3-small");
+        assert_eq!(request["input"], json!(["hello", "world"]));
+
+        // nothing to embed needs no request
+        assert!(adapter.embed(&[]).await?.is_empty());
+        Ok(())
+    }
+
     #[ignore]
     #[tokio::test]
     async fn openai_complete_should_work() {
         let api_key = env::var("OPENAI_API_KEY").unwrap();
         let adapter = OpenaiAdapter::new(api_key, "gpt-4o-mini");
-        let messages = vec![Message {
-            role: Role::User,
-            content: "Hello".to_string(),
-        }];
+        let messages = vec![Message::new(Role::User, "Hello")];
         let response = adapter.complete(&messages).await.unwrap();
         assert!(response.len() > 0);
     }
@@ -0,0 +1,55 @@
+//! A local HTTP server standing in for the providers in tests
+
+use serde_json::Value;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+    task::JoinHandle,
+};
+
+/// Serve one request with the given status and body, the task returns
+/// the request head and its JSON body
+pub(crate) async fn mock_server(
+    status: u16,
+    content_type: &'static str,
+    body: String,
+) -> anyhow::Result<(String, JoinHandle<anyhow::Result<(String, Value)>>)> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let host = format!("http://{}/v1", listener.local_addr()?);
+    let handle = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await?;
+        let mut buf = Vec::new();
+        let mut chunk = [0u8; 4096];
+        let (head, body_start) = loop {
+            let n = stream.read(&mut chunk).await?;
+            anyhow::ensure!(n > 0, "connection closed before the request ended");
+            buf.extend_from_slice(&chunk[..n]);
+            if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                break (String::from_utf8_lossy(&buf[..pos]).to_lowercase(), pos + 4);
+            }
+        };
+        let len: usize = head
+            .lines()
+            .find_map(|l| l.strip_prefix("content-length:"))
+            .map(|v| v.trim().parse())
+            .transpose()?
+            .unwrap_or_default();
+        while buf.len() < body_start + len {
+            let n = stream.read(&mut chunk).await?;
+            anyhow::ensure!(n > 0, "connection closed before the body ended");
+            buf.extend_from_slice(&chunk[..n]);
+        }
+        let request = serde_json::from_slice(&buf[body_start..body_start + len])?;
+        let response = format!(
+            "HTTP/1.1 {} Mock\r\ncontent-type: {}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+            status,
+            content_type,
+            body.len(),
+            body
+        );
+        stream.write_all(response.as_bytes()).await?;
+        stream.shutdown().await?;
+        Ok((head, request))
+    });
+    Ok((host, handle))
+}
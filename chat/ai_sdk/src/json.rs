@@ -0,0 +1,163 @@
+use crate::ResponseFormat;
+use schemars::JsonSchema;
+use serde::de::DeserializeOwned;
+
+/// The response format asking for JSON matching the schema of `T`
+pub fn json_format<T: JsonSchema>() -> ResponseFormat {
+    let mut schema = schemars::schema_for!(T).to_value();
+    if let Some(schema) = schema.as_object_mut() {
+        schema.remove("$schema");
+    }
+    ResponseFormat::JsonSchema {
+        name: schema_name(&T::schema_name()),
+        schema,
+    }
+}
+
+/// Parse the answer of a model, repairing what models often get wrong: code
+/// fences, text around the JSON and trailing commas
+pub fn parse_json<T: DeserializeOwned>(content: &str) -> Result<T, serde_json::Error> {
+    serde_json::from_str(content.trim()).or_else(|_| serde_json::from_str(&repair_json(content)))
+}
+
+// the APIs take names of letters, digits, `_` and `-` up to 64 long
+fn schema_name(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .take(64)
+        .collect()
+}
+
+fn repair_json(content: &str) -> String {
+    // from the first opening to the last closing bracket
+    let json = match (content.find(['{', '[']), content.rfind(['}', ']'])) {
+        (Some(start), Some(end)) if start < end => &content[start..=end],
+        _ => content.trim(),
+    };
+    let mut repaired = String::with_capacity(json.len());
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in json.char_indices() {
+        if in_string {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                in_string = false;
+            }
+        } else if c == '"' {
+            in_string = true;
+        } else if c == ',' && json[i + 1..].trim_start().starts_with(['}', ']']) {
+            continue;
+        }
+        repaired.push(c);
+    }
+    repaired
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AiService, Completion, CompletionOptions, Message, Role, Tool};
+    use serde::Deserialize;
+    use std::sync::Mutex;
+
+    #[derive(Debug, PartialEq, Deserialize, JsonSchema)]
+    struct Labels {
+        labels: Vec<String>,
+        urgent: bool,
+    }
+
+    // answers with the given contents in turn and keeps the requests
+    struct Scripted {
+        answers: Mutex<Vec<&'static str>>,
+        requests: Mutex<Vec<(Vec<Message>, CompletionOptions)>>,
+    }
+
+    impl AiService for Scripted {
+        async fn complete_with_options(
+            &self,
+            messages: &[Message],
+            _tools: &[Tool],
+            options: &CompletionOptions,
+        ) -> anyhow::Result<Completion> {
+            self.requests
+                .lock()
+                .unwrap()
+                .push((messages.to_vec(), options.clone()));
+            let content = self.answers.lock().unwrap().remove(0);
+            Ok(Completion {
+                content: content.to_string(),
+                ..Default::default()
+            })
+        }
+    }
+
+    #[test]
+    fn json_format_should_have_schema_of_type() {
+        let ResponseFormat::JsonSchema { name, schema } = json_format::<Labels>() else {
+            panic!("expected a json schema");
+        };
+        assert_eq!(name, "Labels");
+        assert_eq!(schema["type"], "object");
+        assert_eq!(schema["properties"]["labels"]["type"], "array");
+        assert!(schema.get("$schema").is_none());
+        assert_eq!(schema_name("Array_of<Label>"), "Array_of_Label_");
+    }
+
+    #[test]
+    fn almost_json_should_be_repaired() -> anyhow::Result<()> {
+        let expected = Labels {
+            labels: vec!["bug, ]".to_string()],
+            urgent: true,
+        };
+        let answers = [
+            r#"{"labels": ["bug, ]"], "urgent": true}"#,
+            "```json\n{\"labels\": [\"bug, ]\"], \"urgent\": true}\n```",
+            r#"Sure! Here it is: {"labels": ["bug, ]",], "urgent": true,} Hope it helps."#,
+        ];
+        for answer in answers {
+            assert_eq!(parse_json::<Labels>(answer)?, expected);
+        }
+        assert!(parse_json::<Labels>("no idea").is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn invalid_answer_should_be_sent_back_once() -> anyhow::Result<()> {
+        let service = Scripted {
+            answers: Mutex::new(vec![
+                r#"{"labels": "bug"}"#,
+                r#"{"labels": ["bug"], "urgent": false}"#,
+            ]),
+            requests: Mutex::default(),
+        };
+        let labels: Labels = service.complete_json(&[Message::user("label it")]).await?;
+        assert_eq!(labels.labels, vec!["bug"]);
+
+        let requests = std::mem::take(&mut *service.requests.lock().unwrap());
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].1.response_format, json_format::<Labels>());
+        let (retry, _) = &requests[1];
+        assert_eq!(retry.len(), 3);
+        assert!(matches!(retry[1].role, Role::Assistant));
+        assert!(retry[2].content.contains("invalid type"));
+
+        let service = Scripted {
+            answers: Mutex::new(vec!["no", "still no"]),
+            requests: Mutex::default(),
+        };
+        let labels = service
+            .complete_json::<Labels>(&[Message::user("label it")])
+            .await;
+        assert!(labels.is_err());
+        Ok(())
+    }
+}
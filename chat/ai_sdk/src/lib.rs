@@ -1,10 +1,17 @@
 mod adapters;
+mod json;
 
 pub use adapters::*;
+pub use json::{json_format, parse_json};
+pub use schemars::{self, JsonSchema};
 
+use anyhow::{Context, anyhow};
+use serde::de::DeserializeOwned;
 use std::fmt;
 
 pub enum AiAdapter {
+    Anthropic(AnthropicAdapter),
+    Mock(MockAdapter),
     Ollama(OllamaAdapter),
     OpenAI(OpenaiAdapter),
 }
@@ -14,28 +21,285 @@ pub enum Role {
     User,
     Assistant,
     System,
+    Tool,
 }
 
 #[derive(Debug, Clone)]
 pub struct Message {
     pub role: Role,
     pub content: String,
+    // calls the assistant asked for, answered by tool messages
+    pub tool_calls: Vec<ToolCall>,
+    // the call a tool message answers
+    pub tool_call_id: Option<String>,
+}
+
+/// A function the model may call, `parameters` is a JSON schema of its arguments
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tool {
+    pub name: String,
+    pub description: String,
+    pub parameters: serde_json::Value,
+}
+
+/// A call of a tool the model asked for
+#[derive(Debug, Clone, PartialEq)]
+pub struct ToolCall {
+    pub id: String,
+    pub name: String,
+    pub arguments: serde_json::Value,
+}
+
+/// Tokens a completion took, as reported by the model provider
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Usage {
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Completion {
+    pub content: String,
+    pub usage: Option<Usage>,
+    // if not empty the model wants the results of these calls before it answers
+    pub tool_calls: Vec<ToolCall>,
+}
+
+/// How the model should generate, unset values are left to the model. Adapters
+/// ignore the options their API doesn't have, e.g. a seed for Anthropic.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CompletionOptions {
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    // tokens of the completion
+    pub max_tokens: Option<u32>,
+    pub stop: Vec<String>,
+    pub seed: Option<u64>,
+    pub response_format: ResponseFormat,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum ResponseFormat {
+    #[default]
+    Text,
+    // any JSON object
+    Json,
+    // JSON matching the schema
+    JsonSchema {
+        name: String,
+        schema: serde_json::Value,
+    },
+}
+
+/// The provider answered a request with an error status
+#[derive(Debug)]
+pub struct ApiError {
+    pub status: u16,
+    pub message: String,
 }
 
 #[allow(async_fn_in_trait)]
 pub trait AiService {
-    async fn complete(&self, messages: &[Message]) -> anyhow::Result<String>;
+    async fn complete(&self, messages: &[Message]) -> anyhow::Result<String> {
+        Ok(self.complete_with_usage(messages).await?.content)
+    }
+
+    async fn complete_with_usage(&self, messages: &[Message]) -> anyhow::Result<Completion> {
+        self.complete_with_options(messages, &[], &CompletionOptions::default())
+            .await
+    }
+
+    /// Complete with tools the model may call instead of answering
+    async fn complete_with_tools(
+        &self,
+        messages: &[Message],
+        tools: &[Tool],
+    ) -> anyhow::Result<Completion> {
+        self.complete_with_options(messages, tools, &CompletionOptions::default())
+            .await
+    }
+
+    /// Complete with the options and tools the model may call, adapters which
+    /// don't support tools answer right away
+    async fn complete_with_options(
+        &self,
+        messages: &[Message],
+        tools: &[Tool],
+        options: &CompletionOptions,
+    ) -> anyhow::Result<Completion>;
+
+    /// Complete and hand the content to `on_delta` while it is generated
+    async fn complete_stream(
+        &self,
+        messages: &[Message],
+        on_delta: &mut (dyn FnMut(&str) + Send),
+    ) -> anyhow::Result<Completion> {
+        self.complete_stream_with_options(messages, &CompletionOptions::default(), on_delta)
+            .await
+    }
+
+    /// Stream a completion with the options, adapters which can't stream
+    /// deliver it in one piece
+    async fn complete_stream_with_options(
+        &self,
+        messages: &[Message],
+        options: &CompletionOptions,
+        on_delta: &mut (dyn FnMut(&str) + Send),
+    ) -> anyhow::Result<Completion> {
+        let completion = self.complete_with_options(messages, &[], options).await?;
+        on_delta(&completion.content);
+        Ok(completion)
+    }
+
+    /// Complete into a `T`, asking for JSON of its schema. Adapters without a
+    /// schema mode get the schema as an instruction, answers which don't parse
+    /// even when repaired are sent back to the model once with the error.
+    async fn complete_json<T: JsonSchema + DeserializeOwned>(
+        &self,
+        messages: &[Message],
+    ) -> anyhow::Result<T> {
+        self.complete_json_with_options(messages, &CompletionOptions::default())
+            .await
+    }
+
+    /// Complete into a `T` with the options, the response format is replaced
+    async fn complete_json_with_options<T: JsonSchema + DeserializeOwned>(
+        &self,
+        messages: &[Message],
+        options: &CompletionOptions,
+    ) -> anyhow::Result<T> {
+        let options = CompletionOptions {
+            response_format: json_format::<T>(),
+            ..options.clone()
+        };
+        let completion = self.complete_with_options(messages, &[], &options).await?;
+        let error = match parse_json(&completion.content) {
+            Ok(value) => return Ok(value),
+            Err(e) => e,
+        };
+
+        let mut messages = messages.to_vec();
+        messages.push(Message::assistant(completion.content));
+        messages.push(Message::user(format!(
+            "Your answer is not valid JSON for the schema: {}. Answer again with the JSON only.",
+            error
+        )));
+        let completion = self.complete_with_options(&messages, &[], &options).await?;
+        parse_json(&completion.content).context("model answered with invalid JSON")
+    }
+
+    /// Embed the texts with the model of the adapter, one vector per text in
+    /// their order
+    async fn embed(&self, _input: &[String]) -> anyhow::Result<Vec<Vec<f32>>> {
+        Err(anyhow!("the adapter has no embeddings"))
+    }
     // other common functions
 }
 
 // TODO: in future, use enum_dispatch crate to dispatch to the correct adapter
 impl AiService for AiAdapter {
-    async fn complete(&self, messages: &[Message]) -> anyhow::Result<String> {
+    async fn complete_with_options(
+        &self,
+        messages: &[Message],
+        tools: &[Tool],
+        options: &CompletionOptions,
+    ) -> anyhow::Result<Completion> {
         match self {
-            AiAdapter::Ollama(adapter) => adapter.complete(messages).await,
-            AiAdapter::OpenAI(adapter) => adapter.complete(messages).await,
+            AiAdapter::Anthropic(adapter) => {
+                adapter
+                    .complete_with_options(messages, tools, options)
+                    .await
+            }
+            AiAdapter::Mock(adapter) => {
+                adapter
+                    .complete_with_options(messages, tools, options)
+                    .await
+            }
+            AiAdapter::Ollama(adapter) => {
+                adapter
+                    .complete_with_options(messages, tools, options)
+                    .await
+            }
+            AiAdapter::OpenAI(adapter) => {
+                adapter
+                    .complete_with_options(messages, tools, options)
+                    .await
+            }
         }
     }
+
+    async fn complete_stream_with_options(
+        &self,
+        messages: &[Message],
+        options: &CompletionOptions,
+        on_delta: &mut (dyn FnMut(&str) + Send),
+    ) -> anyhow::Result<Completion> {
+        match self {
+            AiAdapter::Anthropic(adapter) => {
+                adapter
+                    .complete_stream_with_options(messages, options, on_delta)
+                    .await
+            }
+            AiAdapter::Mock(adapter) => {
+                adapter
+                    .complete_stream_with_options(messages, options, on_delta)
+                    .await
+            }
+            AiAdapter::Ollama(adapter) => {
+                adapter
+                    .complete_stream_with_options(messages, options, on_delta)
+                    .await
+            }
+            AiAdapter::OpenAI(adapter) => {
+                adapter
+                    .complete_stream_with_options(messages, options, on_delta)
+                    .await
+            }
+        }
+    }
+
+    async fn embed(&self, input: &[String]) -> anyhow::Result<Vec<Vec<f32>>> {
+        match self {
+            AiAdapter::Anthropic(adapter) => adapter.embed(input).await,
+            AiAdapter::Mock(adapter) => adapter.embed(input).await,
+            AiAdapter::Ollama(adapter) => adapter.embed(input).await,
+            AiAdapter::OpenAI(adapter) => adapter.embed(input).await,
+        }
+    }
+}
+
+/// Whether a failed completion may succeed if retried: connection problems,
+/// timeouts, rate limits and server errors
+pub fn is_transient(e: &anyhow::Error) -> bool {
+    let transient_status = |status: u16| status == 429 || status >= 500;
+    if let Some(e) = e.downcast_ref::<ApiError>() {
+        return transient_status(e.status);
+    }
+    if let Some(e) = e.downcast_ref::<reqwest::Error>() {
+        return e.is_timeout()
+            || e.is_connect()
+            || e.is_body()
+            || e.status().is_some_and(|s| transient_status(s.as_u16()));
+    }
+    false
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "API request failed with status {}: {}",
+            self.status, self.message
+        )
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+impl Usage {
+    pub fn total_tokens(&self) -> u32 {
+        self.prompt_tokens + self.completion_tokens
+    }
 }
 
 impl fmt::Display for Role {
@@ -44,6 +308,7 @@ impl fmt::Display for Role {
             Role::User => write!(f, "user"),
             Role::Assistant => write!(f, "assistant"),
             Role::System => write!(f, "system"),
+            Role::Tool => write!(f, "tool"),
         }
     }
 }
@@ -53,6 +318,8 @@ impl Message {
         Self {
             role,
             content: content.into(),
+            tool_calls: vec![],
+            tool_call_id: None,
         }
     }
 
@@ -67,4 +334,53 @@ impl Message {
     pub fn system(content: impl Into<String>) -> Self {
         Self::new(Role::System, content)
     }
+
+    /// the assistant turn asking for tool calls, to keep them in the conversation
+    pub fn tool_calls(calls: Vec<ToolCall>) -> Self {
+        Self {
+            tool_calls: calls,
+            ..Self::new(Role::Assistant, "")
+        }
+    }
+
+    /// the result of a tool call
+    pub fn tool(call_id: impl Into<String>, content: impl Into<String>) -> Self {
+        Self {
+            tool_call_id: Some(call_id.into()),
+            ..Self::new(Role::Tool, content)
+        }
+    }
+}
+
+impl Tool {
+    pub fn new(
+        name: impl Into<String>,
+        description: impl Into<String>,
+        parameters: serde_json::Value,
+    ) -> Self {
+        Self {
+            name: name.into(),
+            description: description.into(),
+            parameters,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_limits_and_server_errors_should_be_transient() {
+        let error = |status| {
+            anyhow::Error::new(ApiError {
+                status,
+                message: "oops".to_string(),
+            })
+        };
+        assert!(is_transient(&error(429)));
+        assert!(is_transient(&error(503)));
+        assert!(!is_transient(&error(401)));
+        assert!(!is_transient(&anyhow::anyhow!("No response")));
+    }
 }
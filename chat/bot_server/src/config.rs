@@ -1,10 +1,16 @@
 use anyhow::{Result, bail};
+use chat_core::{AdapterType, EndpointConfig};
 use serde::{Deserialize, Serialize};
 use std::{env, fs::File};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AppConfig {
     pub server: ServerConfig,
+    #[serde(default)]
+    pub embedding: EmbeddingConfig,
+    // keys the endpoints of workspaces may use, as in chat_server
+    #[serde(default)]
+    pub endpoints: EndpointConfig,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -13,6 +19,29 @@ pub struct ServerConfig {
     pub db_url: String,
 }
 
+/// Embedding model of the indexed chunks, it has to be the `knowledge` model
+/// of chat_server to search them from reply agents
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EmbeddingConfig {
+    pub adapter: AdapterType,
+    pub model: String,
+    // e.g. an ollama on another host, or an OpenAI compatible server
+    pub host: Option<String>,
+    pub vector_size: i32,
+}
+
+impl Default for EmbeddingConfig {
+    fn default() -> Self {
+        Self {
+            adapter: AdapterType::Ollama,
+            model: "all-minilm".to_string(),
+            host: None,
+            vector_size: 384,
+        }
+    }
+}
+
 impl AppConfig {
     pub fn load() -> Result<Self> {
         // read from  ./bot_server/bot.yml, ./bot.yml, or /etc/config/bot.yml, or from env BOT_CONFIG
@@ -0,0 +1,119 @@
+use std::{env, fmt, sync::Arc};
+
+use ai_sdk::{AiAdapter, AiService, OllamaAdapter, OpenaiAdapter, is_transient};
+use anyhow::{Result, anyhow, bail};
+use async_trait::async_trait;
+use chat_core::{AdapterType, ModelEndpoint, default_endpoint};
+use sqlx::PgPool;
+use swiftide::{Embeddings, chat_completion::errors::LanguageModelError, traits::EmbeddingModel};
+
+use crate::{AppConfig, EmbeddingConfig};
+
+/// The embeddings of ai_sdk as a swiftide embedding model, so the bot indexes
+/// and searches with the same models as the knowledge of chat_server
+#[derive(Clone)]
+pub struct AiEmbed {
+    adapter: Arc<AiAdapter>,
+    model: String,
+}
+
+impl AiEmbed {
+    /// The embedding model of the config, called at the default endpoint of
+    /// its adapter in the workspace if there is one, like the knowledge of
+    /// chat_server
+    pub async fn for_workspace(pool: &PgPool, config: &AppConfig, ws_id: i64) -> Result<Self> {
+        let embedding = &config.embedding;
+        let endpoint = default_endpoint(pool, &config.endpoints, ws_id, &embedding.adapter).await?;
+        Self::try_new(embedding, endpoint.as_ref())
+    }
+
+    pub fn try_new(config: &EmbeddingConfig, endpoint: Option<&ModelEndpoint>) -> Result<Self> {
+        let adapter = match endpoint {
+            Some(endpoint) => endpoint_adapter(endpoint, &config.model)?,
+            None => config_adapter(config)?,
+        };
+        Ok(Self {
+            adapter: Arc::new(adapter),
+            model: config.model.clone(),
+        })
+    }
+}
+
+fn config_adapter(config: &EmbeddingConfig) -> Result<AiAdapter> {
+    let adapter = match config.adapter {
+        AdapterType::Openai => {
+            let api_key =
+                env::var("OPENAI_API_KEY").map_err(|_| anyhow!("OPENAI_API_KEY not set"))?;
+            let adapter = OpenaiAdapter::new(api_key, &config.model);
+            match &config.host {
+                Some(host) => adapter.with_host(host).into(),
+                None => adapter.into(),
+            }
+        }
+        AdapterType::Ollama => match &config.host {
+            Some(host) => OllamaAdapter::new(host, &config.model).into(),
+            None => OllamaAdapter::new_local(&config.model).into(),
+        },
+        AdapterType::Anthropic | AdapterType::Test => {
+            bail!("{:?} adapter has no embedding model", config.adapter)
+        }
+    };
+    Ok(adapter)
+}
+
+fn endpoint_adapter(endpoint: &ModelEndpoint, model: &str) -> Result<AiAdapter> {
+    let api_key = match &endpoint.api_key_env {
+        Some(name) => env::var(name).map_err(|_| anyhow!("{} not set", name))?,
+        None => String::new(),
+    };
+    let headers = endpoint.headers.0.clone();
+    let adapter = match endpoint.adapter {
+        AdapterType::Openai => {
+            let mut adapter = OpenaiAdapter::new(api_key, model)
+                .with_host(&endpoint.base_url)
+                .with_headers(headers);
+            if let Some(organization) = &endpoint.organization {
+                adapter = adapter.with_organization(organization);
+            }
+            if let Some(name) = &endpoint.api_key_header {
+                adapter = adapter.with_api_key_header(name);
+            }
+            adapter.into()
+        }
+        AdapterType::Ollama => {
+            let key = match (&endpoint.api_key_header, api_key.is_empty()) {
+                (_, true) => None,
+                (Some(name), false) => Some((name.clone(), api_key)),
+                (None, false) => Some(("Authorization".to_string(), format!("Bearer {}", api_key))),
+            };
+            OllamaAdapter::new(&endpoint.base_url, model)
+                .with_headers(headers.into_iter().chain(key))
+                .into()
+        }
+        AdapterType::Anthropic | AdapterType::Test => {
+            bail!("{:?} adapter has no embedding model", endpoint.adapter)
+        }
+    };
+    Ok(adapter)
+}
+
+#[async_trait]
+impl EmbeddingModel for AiEmbed {
+    async fn embed(&self, input: Vec<String>) -> Result<Embeddings, LanguageModelError> {
+        self.adapter.embed(&input).await.map_err(|e| {
+            if is_transient(&e) {
+                LanguageModelError::transient(e)
+            } else {
+                LanguageModelError::permanent(e)
+            }
+        })
+    }
+}
+
+impl fmt::Debug for AiEmbed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AiEmbed")
+            .field("model", &self.model)
+            .finish()
+    }
+}
@@ -1,5 +1,6 @@
-use anyhow::Result;
-use bot_server::{AppConfig, VECTOR_SIZE};
+use anyhow::{Result, bail};
+use bot_server::{AiEmbed, AppConfig};
+use chat_core::knowledge_table;
 use sqlx::postgres::PgPoolOptions;
 use swiftide::{
     indexing::{
@@ -8,8 +9,9 @@ use swiftide::{
         transformers::{ChunkCode, Embed, MetadataQACode},
     },
     integrations,
+    traits::Persist,
 };
-use swiftide_pgvector::PgVector;
+use swiftide_pgvector::{PgVector, PgVectorBuilder};
 use tracing::level_filters::LevelFilter;
 use tracing_subscriber::{Layer as _, fmt::Layer, layer::SubscriberExt, util::SubscriberInitExt};
 
@@ -22,11 +24,32 @@ async fn main() -> Result<()> {
     let db_url = &config.server.db_url;
 
     let pool = PgPoolOptions::new().connect(db_url).await?;
-    let fastembed = integrations::fastembed::FastEmbed::try_default()?;
+    let vector_size = config.embedding.vector_size;
     let client = integrations::ollama::Ollama::default()
         .with_default_prompt_model("llama3.2")
         .to_owned();
-    let store = PgVector::try_new(pool, VECTOR_SIZE as _).await?;
+    // `indexer <ws_id> <collection>` indexes into a knowledge collection of a
+    // workspace for its reply agents, else into the collection of the bot
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let (store, embed) = match args.as_slice() {
+        [] => {
+            let embed = AiEmbed::try_new(&config.embedding, None)?;
+            (PgVector::try_new(pool, vector_size as _).await?, embed)
+        }
+        [ws_id, collection] => {
+            // embedded by the provider chat_server searches the collection with
+            let ws_id = ws_id.parse()?;
+            let embed = AiEmbed::for_workspace(&pool, &config, ws_id).await?;
+            let store = PgVectorBuilder::default()
+                .pool(pool)
+                .table_name(knowledge_table(ws_id, collection))
+                .vector_size(vector_size as _)
+                .build()?;
+            store.setup().await?;
+            (store, embed)
+        }
+        _ => bail!("Usage: indexer [<ws_id> <collection>]"),
+    };
 
     indexing::Pipeline::from_loader(FileLoader::new(".").with_extensions(&["rs"]))
         .then(MetadataQACode::new(client.clone()))
@@ -34,7 +57,7 @@ async fn main() -> Result<()> {
             "rust",
             10..2048,
         )?)
-        .then_in_batch(Embed::new(fastembed).with_batch_size(10))
+        .then_in_batch(Embed::new(embed).with_batch_size(10))
         .then_store_with(store)
         .run()
         .await?;
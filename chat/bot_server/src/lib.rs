@@ -1,7 +1,7 @@
 mod config;
+mod embed;
 mod notif;
 
-pub use config::AppConfig;
+pub use config::{AppConfig, EmbeddingConfig};
+pub use embed::AiEmbed;
 pub use notif::setup_pg_listener;
-
-pub const VECTOR_SIZE: usize = 384;
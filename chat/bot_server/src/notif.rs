@@ -1,7 +1,7 @@
-use std::collections::HashSet;
+use std::{collections::HashSet, time::Duration};
 
-use crate::{AppConfig, VECTOR_SIZE};
-use chat_core::Message;
+use crate::{AiEmbed, AppConfig};
+use chat_core::{EventOutbox, Message, OutboxEvent};
 use serde::{Deserialize, Serialize};
 use sqlx::{
     PgPool,
@@ -9,12 +9,15 @@ use sqlx::{
 };
 use swiftide::{
     integrations,
-    query::{self, answers, query_transformers, response_transformers},
+    query::{
+        self, answers, query_transformers, response_transformers,
+        search_strategies::SimilaritySingleEmbedding,
+    },
     traits::{EmbeddingModel, SimplePrompt},
 };
 use swiftide_pgvector::PgVectorBuilder;
 use tokio_stream::StreamExt;
-use tracing::info;
+use tracing::{info, warn};
 
 #[allow(dead_code)]
 #[derive(Debug)]
@@ -23,13 +26,20 @@ struct Notification {
     event: Message,
 }
 
-// pg_notify('chat_message_created', row_to_json(NEW)::text);
+// payload of the `chat_message_created` outbox event
 #[derive(Debug, Serialize, Deserialize)]
 struct ChatMessageCreated {
     message: Message,
     members: HashSet<i64>,
 }
 
+const CONSUMER: &str = "bot_server";
+const REPLAY_LIMIT: i64 = 100;
+// notify_server prunes consumers which stop showing they are alive
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(60);
+// chunks retrieved to answer a message
+const TOP_K: u64 = 5;
+
 pub async fn setup_pg_listener(config: &AppConfig) -> anyhow::Result<()> {
     let db_url = &config.server.db_url;
     let mut listener = PgListener::connect(db_url).await?;
@@ -38,32 +48,97 @@ pub async fn setup_pg_listener(config: &AppConfig) -> anyhow::Result<()> {
 
     let pool = PgPoolOptions::new().connect(db_url).await?;
     let bots = get_bots(&pool).await?;
+    let outbox = EventOutbox::new(pool.clone(), CONSUMER);
+    outbox.register().await?;
+    let heartbeat = outbox.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(HEARTBEAT_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = heartbeat.heartbeat().await {
+                warn!("Failed to keep the outbox consumer alive: {}", e);
+            }
+        }
+    });
 
-    let fastembed = integrations::fastembed::FastEmbed::try_default()?;
+    // the collection of the bot isn't one of a workspace, it is searched with
+    // the model of the config it was indexed with
+    let embed = AiEmbed::try_new(&config.embedding, None)?;
+    let vector_size = config.embedding.vector_size;
     let client = integrations::ollama::Ollama::default()
         .with_default_prompt_model("llama3.2")
         .to_owned();
 
-    let mut stream = listener.into_stream();
+    // replay messages received while the bot was down. The listener is already
+    // up, replayed events it gets notified of again are skipped
+    let mut replayed = HashSet::new();
+    let mut last_id = 0;
+    loop {
+        let events = outbox.pending_after(last_id, REPLAY_LIMIT).await?;
+        let Some(last) = events.last() else {
+            break;
+        };
+        last_id = last.id;
+        info!("Replaying {} pending events", events.len());
+        for event in events {
+            replayed.insert(event.id);
+            handle(event, &bots, &outbox, &pool, &client, &embed, vector_size).await?;
+        }
+    }
 
+    let mut stream = listener.into_stream();
     while let Some(Ok(notif)) = stream.next().await {
         info!("Received notification: {:?}", notif);
-        if let Some(notification) = Notification::load(notif.channel(), notif.payload(), &bots) {
+        let Ok(id) = notif.payload().parse::<i64>() else {
+            continue;
+        };
+        if replayed.remove(&id) {
+            continue;
+        }
+        let event = match outbox.fetch(id).await? {
+            Some(event) => event,
+            None => continue,
+        };
+        handle(event, &bots, &outbox, &pool, &client, &embed, vector_size).await?;
+    }
+
+    Ok(())
+}
+
+async fn handle(
+    event: OutboxEvent,
+    bots: &HashSet<i64>,
+    outbox: &EventOutbox,
+    pool: &PgPool,
+    client: &integrations::ollama::Ollama,
+    embed: &AiEmbed,
+    vector_size: i32,
+) -> anyhow::Result<()> {
+    match Notification::load(&event, bots) {
+        Some(notification) => {
+            let outbox = outbox.clone();
             let pool = pool.clone();
             let client = client.clone();
-            let fastembed = fastembed.clone();
-            tokio::spawn(async move { notification.process(&pool, client, fastembed).await });
+            let embed = embed.clone();
+            tokio::spawn(async move {
+                // only acknowledge answered messages so failed ones are replayed
+                notification
+                    .process(&pool, client, embed, vector_size)
+                    .await?;
+                outbox.ack(event.id).await?;
+                Ok::<_, anyhow::Error>(())
+            });
         }
+        None => outbox.ack(event.id).await?,
     }
-
     Ok(())
 }
 
 impl Notification {
-    fn load(r#type: &str, payload: &str, bots: &HashSet<i64>) -> Option<Self> {
-        match r#type {
+    fn load(event: &OutboxEvent, bots: &HashSet<i64>) -> Option<Self> {
+        match event.channel.as_str() {
             "chat_message_created" => {
-                let payload: ChatMessageCreated = serde_json::from_str(payload).ok()?;
+                let payload = ChatMessageCreated::deserialize(&*event.payload).ok()?;
                 let mut members = payload.members;
                 members.remove(&payload.message.sender_id);
 
@@ -87,17 +162,21 @@ impl Notification {
         self,
         pool: &PgPool,
         client: impl SimplePrompt + Clone + 'static,
-        fastembed: impl EmbeddingModel + Clone + 'static,
+        embed: impl EmbeddingModel + Clone + 'static,
+        vector_size: i32,
     ) -> anyhow::Result<()> {
         let store = PgVectorBuilder::default()
             .pool(pool.clone())
-            .vector_size(VECTOR_SIZE as _)
+            .vector_size(vector_size as _)
             .build()?;
-        let pipeline = query::Pipeline::default()
+        let strategy: SimilaritySingleEmbedding = SimilaritySingleEmbedding::default()
+            .with_top_k(TOP_K)
+            .to_owned();
+        let pipeline = query::Pipeline::from_search_strategy(strategy)
             .then_transform_query(query_transformers::GenerateSubquestions::from_client(
                 client.clone(),
             ))
-            .then_transform_query(query_transformers::Embed::from_client(fastembed.clone()))
+            .then_transform_query(query_transformers::Embed::from_client(embed.clone()))
             .then_retrieve(store)
             .then_transform_response(response_transformers::Summary::from_client(client.clone()))
             .then_answer(answers::Simple::from_client(client.clone()));
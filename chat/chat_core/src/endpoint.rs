@@ -0,0 +1,87 @@
+use std::collections::BTreeMap;
+
+use axum::http::Uri;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use tracing::warn;
+
+use crate::{AdapterType, ModelEndpoint};
+
+/// Environment variables the endpoints of workspaces may take their API key
+/// from, each with the hosts it may be sent to. An endpoint can't send any
+/// other variable, nor a listed one to another host.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EndpointConfig {
+    pub api_keys: BTreeMap<String, Vec<String>>,
+}
+
+impl Default for EndpointConfig {
+    fn default() -> Self {
+        Self {
+            api_keys: BTreeMap::from([
+                (
+                    "OPENAI_API_KEY".to_string(),
+                    vec!["api.openai.com".to_string()],
+                ),
+                (
+                    "ANTHROPIC_API_KEY".to_string(),
+                    vec!["api.anthropic.com".to_string()],
+                ),
+            ]),
+        }
+    }
+}
+
+impl EndpointConfig {
+    pub fn allows_key(&self, env: &str, host: &str) -> bool {
+        self.api_keys
+            .get(env)
+            .is_some_and(|hosts| hosts.iter().any(|h| h.eq_ignore_ascii_case(host)))
+    }
+
+    /// The key of an endpoint is only sent while the config still allows it
+    /// for the host, e.g. after a variable was removed from the config
+    pub fn allowed_key(&self, mut endpoint: ModelEndpoint) -> ModelEndpoint {
+        if let Some(env) = &endpoint.api_key_env {
+            let host = base_url_host(&endpoint.base_url).unwrap_or_default();
+            if !self.allows_key(env, &host) {
+                warn!("endpoint {} may not send {} to {}", endpoint.id, env, host);
+                endpoint.api_key_env = None;
+            }
+        }
+        endpoint
+    }
+}
+
+/// The endpoint of the adapter marked default in the workspace. Embeddings of
+/// a workspace are computed there, by chat_server searching its knowledge as
+/// well as by bot_server indexing it.
+pub async fn default_endpoint(
+    pool: &PgPool,
+    config: &EndpointConfig,
+    ws_id: i64,
+    adapter: &AdapterType,
+) -> Result<Option<ModelEndpoint>, sqlx::Error> {
+    let endpoint = sqlx::query_as(
+        r#"
+        SELECT * FROM model_endpoints
+        WHERE ws_id = $1 AND adapter = $2 AND is_default
+        "#,
+    )
+    .bind(ws_id)
+    .bind(adapter)
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(endpoint.map(|e| config.allowed_key(e)))
+}
+
+/// host of an http(s) base url
+pub fn base_url_host(base_url: &str) -> Option<String> {
+    let uri: Uri = base_url.parse().ok()?;
+    match uri.scheme_str() {
+        Some("http" | "https") => uri.host().map(|h| h.to_string()),
+        _ => None,
+    }
+}
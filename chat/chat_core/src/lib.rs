@@ -1,7 +1,11 @@
+mod endpoint;
 pub mod middlewares;
+mod outbox;
 mod utils;
 use chrono::{DateTime, Utc};
+pub use endpoint::*;
 pub use middlewares::*;
+pub use outbox::*;
 use serde::{Deserialize, Serialize};
 #[allow(unused_imports)]
 use serde_json::json;
@@ -12,16 +16,47 @@ use utoipa::ToSchema;
 
 #[allow(async_fn_in_trait)]
 pub trait Agent {
-    async fn process(&self, msg: &str, ctx: &AgentContext) -> Result<AgentDecision, AgentError>;
+    async fn process(&self, msg: &str, ctx: &AgentContext) -> Result<AgentDecision, AgentError> {
+        Ok(self.process_with_usage(msg, ctx).await?.0)
+    }
+
+    /// Process the message and report the tokens the model used, if it tells
+    async fn process_with_usage(
+        &self,
+        msg: &str,
+        ctx: &AgentContext,
+    ) -> Result<(AgentDecision, Option<TokenUsage>), AgentError>;
+}
+
+#[derive(Debug, Clone, Copy, Default, ToSchema, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct TokenUsage {
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
 }
 
+/// What an agent knows about the conversation a message belongs to
 #[derive(Debug, Default, Clone)]
-pub struct AgentContext {}
+pub struct AgentContext {
+    pub chat: Option<Chat>,
+    pub sender: Option<ChatUser>,
+    // previous messages of the chat, oldest first
+    pub history: Vec<ContextMessage>,
+}
+
+#[derive(Debug, Clone, FromRow, PartialEq)]
+pub struct ContextMessage {
+    pub id: i64,
+    pub sender_id: i64,
+    pub sender_name: String,
+    pub content: String,
+}
 
 #[derive(Debug, Clone)]
 pub enum AgentDecision {
     Modify(String),
-    Reply(String),
+    // the reply and the sources it cites
+    Reply(String, Vec<Citation>),
     Delete,
     None,
 }
@@ -30,6 +65,9 @@ pub enum AgentError {
     #[error("Network error: {0}")]
     Network(String),
 
+    #[error("Missing credentials: {0}")]
+    MissingCredentials(String),
+
     #[error("{0}")]
     AnyError(#[from] anyhow::Error),
 }
@@ -60,6 +98,8 @@ pub enum AdapterType {
     Openai,
     #[serde(alias = "ollama", alias = "Ollama")]
     Ollama,
+    #[serde(alias = "anthropic", alias = "Anthropic")]
+    Anthropic,
     #[serde(alias = "test", alias = "Test")]
     Test,
 }
@@ -75,14 +115,391 @@ pub struct ChatAgent {
     pub adapter: AdapterType,
     pub model: String,
     pub prompt: String,
+    #[serde(default)]
+    pub priority: i32,
+    // disabled agents are skipped by the message pipeline
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    // timeouts, retries and fallback when the model fails
+    #[serde(default)]
+    #[schema(value_type = AgentPolicy)]
+    pub policy: sqlx::types::Json<AgentPolicy>,
+    // how the model generates, e.g. its temperature
+    #[serde(default)]
+    #[schema(value_type = ModelOptions)]
+    pub options: sqlx::types::Json<ModelOptions>,
+    // validated against the args of the agent type, see `AgentArgs`
     #[schema(value_type = Object, example = json!({"key": "value"}))]
-    pub args: sqlx::types::Json<serde_json::Value>, // TODO: change to custom type
+    pub args: sqlx::types::Json<serde_json::Value>,
+    // bot user the agent replies as outside single chats, created with its first reply
+    #[serde(default, alias = "botId")]
+    pub bot_id: Option<i64>,
+    // endpoint of the workspace the model is called at, unset uses the default one
+    #[serde(default, alias = "endpointId")]
+    pub endpoint_id: Option<i64>,
     #[serde(alias = "createdAt")]
     pub created_at: DateTime<Utc>,
     #[serde(alias = "updatedAt")]
     pub updated_at: DateTime<Utc>,
 }
 
+/// Where the agents of a workspace call the models of an adapter, e.g. a vLLM
+/// server, a LiteLLM or Azure gateway or a self-hosted proxy. Only the name of
+/// the environment variable holding the API key is stored, never the key.
+#[derive(Debug, Clone, FromRow, ToSchema, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct ModelEndpoint {
+    pub id: i64,
+    #[serde(alias = "wsId")]
+    pub ws_id: i64,
+    pub name: String,
+    pub adapter: AdapterType,
+    #[serde(alias = "baseUrl")]
+    pub base_url: String,
+    #[serde(default, alias = "apiKeyEnv")]
+    pub api_key_env: Option<String>,
+    // header the key is sent in instead of a bearer token, e.g. `api-key`
+    #[serde(default, alias = "apiKeyHeader")]
+    pub api_key_header: Option<String>,
+    #[serde(default)]
+    pub organization: Option<String>,
+    // sent with every request
+    #[serde(default)]
+    #[schema(value_type = Object, example = json!({"x-team": "chat"}))]
+    pub headers: sqlx::types::Json<std::collections::BTreeMap<String, String>>,
+    // used by the agents of the adapter which don't name an endpoint
+    #[serde(default, alias = "isDefault")]
+    pub is_default: bool,
+    #[serde(alias = "createdAt")]
+    pub created_at: DateTime<Utc>,
+}
+
+/// Generation parameters of the model of an agent, unset ones are left to the
+/// model. Options the API of the adapter doesn't have are ignored.
+#[derive(Debug, Clone, Default, ToSchema, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct ModelOptions {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(default, alias = "topP", skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    // tokens of the completion
+    #[serde(default, alias = "maxTokens", skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u32>,
+    // sequences which end the completion
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stop: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    #[serde(default, alias = "responseFormat")]
+    pub response_format: ResponseFormat,
+}
+
+/// What the model answers with, e.g. `{"type": "json_schema", "name": "answer",
+/// "schema": {...}}`
+#[derive(Debug, Clone, Default, ToSchema, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseFormat {
+    #[default]
+    Text,
+    // any JSON object
+    Json,
+    JsonSchema {
+        name: String,
+        #[schema(value_type = Object)]
+        schema: serde_json::Value,
+    },
+}
+
+/// How an agent copes with a slow or failing model and what it may spend,
+/// unset values come from the `agent` and `limits` sections of the server config
+#[derive(Debug, Clone, Default, ToSchema, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct AgentPolicy {
+    #[serde(
+        default,
+        alias = "timeoutSecs",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub timeout_secs: Option<u64>,
+    // retries of network errors and timeouts
+    #[serde(default, alias = "maxRetries", skip_serializing_if = "Option::is_none")]
+    pub max_retries: Option<u32>,
+    #[serde(default, alias = "onFailure")]
+    pub on_failure: FailurePolicy,
+    // used once the agent failed on its own model
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fallback: Option<AgentFallback>,
+    // limits of the agent, 0 means unlimited
+    #[serde(
+        default,
+        alias = "requestsPerMinute",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub requests_per_minute: Option<u32>,
+    #[serde(
+        default,
+        alias = "tokensPerDay",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub tokens_per_day: Option<u64>,
+}
+
+/// What happens to a message when a proxy agent fails on it
+#[derive(Debug, Clone, Copy, Default, ToSchema, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub enum FailurePolicy {
+    // post the content as the agent didn't exist
+    #[serde(alias = "passthrough", alias = "Passthrough")]
+    #[default]
+    Passthrough,
+    #[serde(alias = "reject", alias = "Reject")]
+    Reject,
+}
+
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize, PartialEq)]
+pub struct AgentFallback {
+    pub adapter: AdapterType,
+    pub model: String,
+}
+
+/// Typed args of an agent. Keys without a meaning for the agent type are
+/// variables of the prompt template, available as `args.<key>`. `tools` lists
+/// the built-in tools the agent may call, `knowledge` the collection a reply
+/// agent answers from.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum AgentArgs {
+    Proxy(PromptArgs),
+    Reply(ReplyArgs),
+    Tap(TapArgs),
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PromptArgs {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tools: Vec<String>,
+    #[serde(flatten)]
+    pub vars: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ReplyArgs {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tools: Vec<String>,
+    // documents the reply is grounded in
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub knowledge: Option<KnowledgeArgs>,
+    #[serde(flatten)]
+    pub vars: serde_json::Map<String, serde_json::Value>,
+}
+
+/// A collection of embedded chunks in pgvector, searched for the message
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct KnowledgeArgs {
+    // name of the collection in the workspace of the agent
+    pub collection: String,
+    #[serde(default = "default_top_k")]
+    pub top_k: u64,
+}
+
+/// The table of a knowledge collection, every workspace has its own collections
+pub fn knowledge_table(ws_id: i64, collection: &str) -> String {
+    format!("ws{}_{}", ws_id, collection)
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TapArgs {
+    // outputs are listed by kind, e.g. "summary"
+    #[serde(default = "default_tap_kind")]
+    pub kind: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tools: Vec<String>,
+    #[serde(flatten)]
+    pub vars: serde_json::Map<String, serde_json::Value>,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_tap_kind() -> String {
+    "output".to_string()
+}
+
+fn default_top_k() -> u64 {
+    5
+}
+
+impl ChatAgent {
+    pub fn typed_args(&self) -> Result<AgentArgs, serde_json::Error> {
+        AgentArgs::parse(&self.r#type, &self.args)
+    }
+}
+
+impl AgentArgs {
+    /// Args of the agent type without any prompt variables
+    pub fn new(r#type: &AgentType) -> Self {
+        match r#type {
+            AgentType::Proxy => Self::Proxy(Default::default()),
+            AgentType::Reply => Self::Reply(Default::default()),
+            AgentType::Tap => Self::Tap(Default::default()),
+        }
+    }
+
+    /// names of the tools the agent may call
+    pub fn tools(&self) -> &[String] {
+        match self {
+            Self::Proxy(args) => &args.tools,
+            Self::Reply(args) => &args.tools,
+            Self::Tap(args) => &args.tools,
+        }
+    }
+
+    /// the collection a reply agent answers from
+    pub fn knowledge(&self) -> Option<&KnowledgeArgs> {
+        match self {
+            Self::Reply(args) => args.knowledge.as_ref(),
+            Self::Proxy(_) | Self::Tap(_) => None,
+        }
+    }
+
+    pub fn parse(r#type: &AgentType, args: &serde_json::Value) -> Result<Self, serde_json::Error> {
+        let args = args.clone();
+        match r#type {
+            AgentType::Proxy => Ok(Self::Proxy(serde_json::from_value(args)?)),
+            AgentType::Reply => Ok(Self::Reply(serde_json::from_value(args)?)),
+            AgentType::Tap => {
+                let args: TapArgs = serde_json::from_value(args)?;
+                if args.kind.is_empty() {
+                    return Err(serde::de::Error::custom("tap kind must not be empty"));
+                }
+                Ok(Self::Tap(args))
+            }
+        }
+    }
+}
+
+impl Default for TapArgs {
+    fn default() -> Self {
+        Self {
+            kind: default_tap_kind(),
+            tools: vec![],
+            vars: Default::default(),
+        }
+    }
+}
+
+#[derive(
+    Debug, Clone, Default, ToSchema, Serialize, Deserialize, PartialEq, PartialOrd, sqlx::Type,
+)]
+#[sqlx(type_name = "agent_outcome_status", rename_all = "snake_case")]
+#[serde(rename_all(serialize = "camelCase"))]
+pub enum AgentOutcomeStatus {
+    #[serde(alias = "modified", alias = "Modified")]
+    Modified,
+    #[serde(alias = "replied", alias = "Replied")]
+    Replied,
+    #[serde(alias = "deleted", alias = "Deleted")]
+    Deleted,
+    #[serde(alias = "observed", alias = "Observed")]
+    Observed,
+    #[serde(alias = "skipped", alias = "Skipped")]
+    #[default]
+    Skipped,
+    #[serde(alias = "failed", alias = "Failed")]
+    Failed,
+}
+
+/// What made an agent run, recorded with each invocation
+#[derive(Debug, Clone, Copy, Default, ToSchema, Serialize, Deserialize, PartialEq, sqlx::Type)]
+#[sqlx(type_name = "agent_invocation_source", rename_all = "snake_case")]
+#[serde(rename_all(serialize = "camelCase"))]
+pub enum AgentInvocationSource {
+    #[serde(alias = "message", alias = "Message")]
+    #[default]
+    Message,
+    #[serde(alias = "job", alias = "Job")]
+    Job,
+    #[serde(alias = "preview", alias = "Preview")]
+    Preview,
+}
+
+/// What an agent did with a message when it went through the pipeline of the chat
+#[derive(Debug, Clone, FromRow, ToSchema, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct AgentOutcome {
+    pub id: i64,
+    #[serde(alias = "chatId")]
+    pub chat_id: i64,
+    #[serde(alias = "messageId")]
+    pub message_id: Option<i64>,
+    #[serde(alias = "agentId")]
+    pub agent_id: i64,
+    pub status: AgentOutcomeStatus,
+    pub output: Option<String>,
+    pub error: Option<String>,
+    #[serde(alias = "latencyMs")]
+    pub latency_ms: i32,
+    #[serde(alias = "createdAt")]
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(
+    Debug, Clone, Default, ToSchema, Serialize, Deserialize, PartialEq, PartialOrd, sqlx::Type,
+)]
+#[sqlx(type_name = "job_status", rename_all = "snake_case")]
+#[serde(rename_all(serialize = "camelCase"))]
+pub enum JobStatus {
+    #[serde(alias = "pending", alias = "Pending")]
+    #[default]
+    Pending,
+    #[serde(alias = "running", alias = "Running")]
+    Running,
+    #[serde(alias = "done", alias = "Done")]
+    Done,
+    #[serde(alias = "failed", alias = "Failed")]
+    Failed,
+}
+
+/// A tap or reply agent run queued for a message
+#[derive(Debug, Clone, FromRow, ToSchema, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct AgentJob {
+    pub id: i64,
+    #[serde(alias = "agentId")]
+    pub agent_id: i64,
+    // the type of the agent when the job was queued
+    pub kind: AgentType,
+    #[serde(alias = "chatId")]
+    pub chat_id: i64,
+    #[serde(alias = "messageId")]
+    pub message_id: i64,
+    pub status: JobStatus,
+    pub attempts: i32,
+    #[serde(alias = "lastError")]
+    pub last_error: Option<String>,
+    #[serde(alias = "runAt")]
+    pub run_at: DateTime<Utc>,
+}
+
+/// Output of a tap agent for a message, `kind` comes from the agent args
+#[derive(Debug, Clone, FromRow, ToSchema, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct TapOutput {
+    pub id: i64,
+    #[serde(alias = "agentId")]
+    pub agent_id: i64,
+    #[serde(alias = "chatId")]
+    pub chat_id: i64,
+    #[serde(alias = "messageId")]
+    pub message_id: i64,
+    pub kind: String,
+    pub output: String,
+    #[serde(alias = "createdAt")]
+    pub created_at: DateTime<Utc>,
+}
+
 #[derive(Debug, Clone, FromRow, ToSchema, Serialize, Deserialize, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct User {
@@ -134,7 +551,7 @@ pub enum ChatType {
     PublicChannel,
 }
 
-#[derive(Debug, FromRow, ToSchema, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Clone, FromRow, ToSchema, Serialize, Deserialize, PartialEq)]
 #[serde(rename_all(serialize = "camelCase"))]
 pub struct Chat {
     pub id: i64,
@@ -148,6 +565,31 @@ pub struct Chat {
     pub created_at: DateTime<Utc>,
 }
 
+#[derive(
+    Debug, Clone, Default, Serialize, Deserialize, ToSchema, PartialEq, PartialOrd, sqlx::Type,
+)]
+#[sqlx(type_name = "notification_level", rename_all = "snake_case")]
+#[serde(rename_all(serialize = "camelCase"))]
+pub enum NotificationLevel {
+    #[serde(alias = "all", alias = "All")]
+    #[default]
+    All,
+    #[serde(alias = "mentions", alias = "Mentions")]
+    Mentions,
+}
+
+#[derive(Debug, Clone, FromRow, ToSchema, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct NotificationSettings {
+    #[serde(alias = "chatId")]
+    pub chat_id: i64,
+    #[serde(alias = "userId")]
+    pub user_id: i64,
+    pub level: NotificationLevel,
+    #[serde(alias = "mutedUntil")]
+    pub muted_until: Option<DateTime<Utc>>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, ToSchema, FromRow, PartialEq)]
 #[serde(rename_all(serialize = "camelCase"))]
 pub struct Message {
@@ -157,12 +599,63 @@ pub struct Message {
     #[serde(alias = "senderId")]
     pub sender_id: i64,
     pub content: String,
+    #[serde(alias = "modifiedContent")]
     pub modified_content: Option<String>,
     pub files: Vec<String>, // store file paths
+    // agent replies are streamed into the message before it is sent
+    #[serde(default)]
+    pub status: MessageStatus,
+    // sources of an agent reply
+    #[serde(default)]
+    #[schema(value_type = Vec<Citation>)]
+    pub citations: sqlx::types::Json<Vec<Citation>>,
     #[serde(alias = "createdAt")]
     pub created_at: DateTime<Utc>,
 }
 
+/// A retrieved chunk an agent reply refers to as `[index]`
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, PartialEq)]
+pub struct Citation {
+    pub index: u32,
+    // where the chunk was indexed from, e.g. a file path
+    pub source: String,
+    pub excerpt: String,
+}
+
+/// A piece of an agent reply while it is streamed into the message
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, PartialEq)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct MessageDelta {
+    #[serde(alias = "chatId")]
+    pub chat_id: i64,
+    #[serde(alias = "messageId")]
+    pub message_id: i64,
+    // position in the stream, starting at 0
+    pub seq: i32,
+    pub delta: String,
+    // the last event of the stream, `status` tells whether the reply is complete
+    pub done: bool,
+    pub status: MessageStatus,
+    // sources of the reply, sent with the last event
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub citations: Vec<Citation>,
+}
+
+#[derive(
+    Debug, Clone, Copy, Default, ToSchema, Serialize, Deserialize, PartialEq, PartialOrd, sqlx::Type,
+)]
+#[sqlx(type_name = "message_status", rename_all = "snake_case")]
+#[serde(rename_all(serialize = "camelCase"))]
+pub enum MessageStatus {
+    #[serde(alias = "sent", alias = "Sent")]
+    #[default]
+    Sent,
+    #[serde(alias = "streaming", alias = "Streaming")]
+    Streaming,
+    #[serde(alias = "failed", alias = "Failed")]
+    Failed,
+}
+
 impl User {
     pub fn new(id: i64, fullname: &str, email: &str) -> Self {
         use chrono::Utc;
@@ -179,3 +672,158 @@ impl User {
         }
     }
 }
+
+impl AgentContext {
+    pub fn new(chat: Chat, sender: ChatUser, history: Vec<ContextMessage>) -> Self {
+        Self {
+            chat: Some(chat),
+            sender: Some(sender),
+            history,
+        }
+    }
+
+    /// Keep the most recent messages whose estimated tokens fit in the budget
+    pub fn with_token_budget(mut self, budget: usize) -> Self {
+        let mut used = 0;
+        let keep = self
+            .history
+            .iter()
+            .rev()
+            .take_while(|m| {
+                used += estimate_tokens(&m.content);
+                used <= budget
+            })
+            .count();
+        self.history.drain(..self.history.len() - keep);
+        self
+    }
+}
+
+/// rough token count of a text, about 4 characters per token
+pub fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(4)
+}
+
+impl NotificationSettings {
+    pub fn is_muted(&self, now: DateTime<Utc>) -> bool {
+        self.muted_until.is_some_and(|until| until > now)
+    }
+
+    /// whether the user should get the message without being alerted
+    pub fn is_silent(&self, content: &str, fullname: &str, now: DateTime<Utc>) -> bool {
+        self.is_muted(now)
+            || (self.level == NotificationLevel::Mentions && !is_mentioned(content, fullname))
+    }
+}
+
+/// check if the content mentions the user as `@fullname`, the mention has to
+/// end with the name: `@askbot` or `@ask.me` don't mention `ask`
+pub fn is_mentioned(content: &str, fullname: &str) -> bool {
+    let mention = format!("@{}", fullname.to_lowercase());
+    let content = content.to_lowercase();
+    let is_name_char = |c: char| c.is_alphanumeric() || c == '_' || c == '-';
+    content.match_indices(&mention).any(|(i, _)| {
+        let mut rest = content[i + mention.len()..].chars();
+        match rest.next() {
+            None => true,
+            // a trailing dot ends a sentence, not a name
+            Some('.') => !rest.next().is_some_and(is_name_char),
+            Some(c) => !is_name_char(c),
+        }
+    })
+}
+
+/// What a message asks an agent of a group chat or channel: the text after a
+/// leading `/name` command, or the whole message if it mentions `@name`
+pub fn agent_request<'a>(content: &'a str, name: &str) -> Option<&'a str> {
+    let command = content.trim_start().strip_prefix('/').and_then(|rest| {
+        let (cmd, args) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+        cmd.eq_ignore_ascii_case(name).then_some(args.trim())
+    });
+    match command {
+        Some(args) => Some(args),
+        None if is_mentioned(content, name) => Some(content),
+        None => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(id: i64, content: &str) -> ContextMessage {
+        ContextMessage {
+            id,
+            sender_id: 1,
+            sender_name: "Tyr Chen".to_string(),
+            content: content.to_string(),
+        }
+    }
+
+    #[test]
+    fn token_budget_should_keep_recent_messages() {
+        let ctx = AgentContext {
+            history: vec![
+                message(1, &"a".repeat(40)),
+                message(2, &"b".repeat(16)),
+                message(3, &"c".repeat(16)),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(ctx.clone().with_token_budget(100).history.len(), 3);
+
+        let ids: Vec<_> = ctx
+            .clone()
+            .with_token_budget(9)
+            .history
+            .iter()
+            .map(|m| m.id)
+            .collect();
+        assert_eq!(ids, vec![2, 3]);
+        assert!(ctx.with_token_budget(3).history.is_empty());
+    }
+
+    #[test]
+    fn agent_args_should_be_typed_by_agent_type() -> Result<(), serde_json::Error> {
+        let args = AgentArgs::parse(&AgentType::Tap, &json!({"language": "French"}))?;
+        let AgentArgs::Tap(tap) = &args else {
+            panic!("expect tap args");
+        };
+        assert_eq!(tap.kind, "output");
+        assert_eq!(
+            serde_json::to_value(&args)?,
+            json!({"kind": "output", "language": "French"})
+        );
+
+        let args = AgentArgs::parse(&AgentType::Proxy, &json!({"language": "French"}))?;
+        assert_eq!(serde_json::to_value(&args)?, json!({"language": "French"}));
+
+        let args = AgentArgs::parse(&AgentType::Reply, &json!({"tools": ["list_files"]}))?;
+        assert_eq!(args.tools(), ["list_files"]);
+
+        assert!(AgentArgs::parse(&AgentType::Tap, &json!({"kind": 1})).is_err());
+        assert!(AgentArgs::parse(&AgentType::Tap, &json!({"kind": ""})).is_err());
+        assert!(AgentArgs::parse(&AgentType::Reply, &json!(["a"])).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn agent_request_should_need_a_command_or_mention() {
+        assert_eq!(agent_request("/ask what is up", "ask"), Some("what is up"));
+        assert_eq!(agent_request("  /Ask", "ask"), Some(""));
+        assert_eq!(
+            agent_request("hey @Ask, what is up", "ask"),
+            Some("hey @Ask, what is up")
+        );
+        assert_eq!(agent_request("/asked what is up", "ask"), None);
+        assert_eq!(agent_request("ask what is up", "ask"), None);
+        assert_eq!(agent_request("thanks @ask.", "ask"), Some("thanks @ask."));
+        assert_eq!(agent_request("@askbot what is up", "ask"), None);
+        assert_eq!(agent_request("mail @ask.me", "ask"), None);
+        assert_eq!(agent_request("@ask_v2 hi", "ask"), None);
+        assert_eq!(
+            agent_request("@askbot and @ask", "ask"),
+            Some("@askbot and @ask")
+        );
+    }
+}
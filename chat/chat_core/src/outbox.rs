@@ -0,0 +1,195 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgPool};
+use std::time::Duration;
+
+/// A row of the `events` outbox. Triggers write the event in the same
+/// transaction as the change and only `pg_notify` its id.
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct OutboxEvent {
+    pub id: i64,
+    pub channel: String,
+    pub payload: sqlx::types::Json<serde_json::Value>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Reads and acknowledges outbox events on behalf of a named consumer.
+#[derive(Debug, Clone)]
+pub struct EventOutbox {
+    pool: PgPool,
+    consumer: String,
+}
+
+impl EventOutbox {
+    pub fn new(pool: PgPool, consumer: impl Into<String>) -> Self {
+        Self {
+            pool,
+            consumer: consumer.into(),
+        }
+    }
+
+    pub fn consumer(&self) -> &str {
+        &self.consumer
+    }
+
+    /// Register the consumer. A new consumer starts after the latest event, an
+    /// existing one keeps its unacknowledged events for replay.
+    pub async fn register(&self) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"
+            INSERT INTO event_consumers (name, start_id)
+            VALUES ($1, (SELECT COALESCE(MAX(id), 0) FROM events))
+            ON CONFLICT (name) DO UPDATE SET last_seen_at = NOW()
+            "#,
+        )
+        .bind(&self.consumer)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Keep the consumer alive, consumers not seen for a while are pruned with
+    /// their acknowledgements
+    pub async fn heartbeat(&self) -> Result<(), sqlx::Error> {
+        sqlx::query("UPDATE event_consumers SET last_seen_at = NOW() WHERE name = $1")
+            .bind(&self.consumer)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Delete the consumers not seen within `consumer_ttl`, then the events
+    /// all remaining consumers acknowledged and the ones older than
+    /// `retention`. Returns the number of deleted events.
+    pub async fn prune(
+        &self,
+        retention: Duration,
+        consumer_ttl: Duration,
+    ) -> Result<u64, sqlx::Error> {
+        sqlx::query(
+            r#"
+            DELETE FROM event_consumers
+            WHERE last_seen_at < NOW() - make_interval(secs => $1)
+            "#,
+        )
+        .bind(consumer_ttl.as_secs_f64())
+        .execute(&self.pool)
+        .await?;
+        let ret = sqlx::query(
+            r#"
+            DELETE FROM events e
+            WHERE e.created_at < NOW() - make_interval(secs => $1)
+            OR NOT EXISTS (
+                SELECT 1 FROM event_consumers c
+                WHERE c.start_id < e.id
+                AND NOT EXISTS (
+                    SELECT 1 FROM event_acks a WHERE a.consumer = c.name AND a.event_id = e.id
+                )
+            )
+            "#,
+        )
+        .bind(retention.as_secs_f64())
+        .execute(&self.pool)
+        .await?;
+        Ok(ret.rows_affected())
+    }
+
+    /// fetch an event by the id received from `pg_notify`, unless the consumer
+    /// already acknowledged it, e.g. when it was replayed after the listener
+    /// was up
+    pub async fn fetch(&self, id: i64) -> Result<Option<OutboxEvent>, sqlx::Error> {
+        let event = sqlx::query_as(
+            r#"
+            SELECT e.id, e.channel, e.payload, e.created_at
+            FROM events e
+            JOIN event_consumers c ON c.name = $2
+            WHERE e.id = $1 AND e.id > c.start_id
+            AND NOT EXISTS (
+                SELECT 1 FROM event_acks a WHERE a.consumer = $2 AND a.event_id = e.id
+            )
+            "#,
+        )
+        .bind(id)
+        .bind(&self.consumer)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(event)
+    }
+
+    /// events not yet acknowledged by the consumer, oldest first
+    pub async fn pending(&self, limit: i64) -> Result<Vec<OutboxEvent>, sqlx::Error> {
+        self.pending_after(0, limit).await
+    }
+
+    /// events after `after` not yet acknowledged by the consumer, oldest first.
+    /// Pages through the backlog while earlier events are still being processed
+    pub async fn pending_after(
+        &self,
+        after: i64,
+        limit: i64,
+    ) -> Result<Vec<OutboxEvent>, sqlx::Error> {
+        let events = sqlx::query_as(
+            r#"
+            SELECT e.id, e.channel, e.payload, e.created_at
+            FROM events e
+            JOIN event_consumers c ON c.name = $1
+            WHERE e.id > GREATEST(c.start_id, $2)
+            AND NOT EXISTS (
+                SELECT 1 FROM event_acks a WHERE a.consumer = $1 AND a.event_id = e.id
+            )
+            ORDER BY e.id ASC
+            LIMIT $3
+            "#,
+        )
+        .bind(&self.consumer)
+        .bind(after)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(events)
+    }
+
+    pub async fn ack(&self, id: i64) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"
+            INSERT INTO event_acks (consumer, event_id)
+            VALUES ($1, $2)
+            ON CONFLICT DO NOTHING
+            "#,
+        )
+        .bind(&self.consumer)
+        .bind(id)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Give up a claimed event, e.g. after a transient failure, it is pending
+    /// again for the processes sharing the consumer name
+    pub async fn release(&self, id: i64) -> Result<(), sqlx::Error> {
+        sqlx::query("DELETE FROM event_acks WHERE consumer = $1 AND event_id = $2")
+            .bind(&self.consumer)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Acknowledge the event before processing it. Returns false if another
+    /// process sharing the consumer name already claimed it, so every event is
+    /// processed at most once.
+    pub async fn claim(&self, id: i64) -> Result<bool, sqlx::Error> {
+        let ret = sqlx::query(
+            r#"
+            INSERT INTO event_acks (consumer, event_id)
+            VALUES ($1, $2)
+            ON CONFLICT DO NOTHING
+            "#,
+        )
+        .bind(&self.consumer)
+        .bind(id)
+        .execute(&self.pool)
+        .await?;
+        Ok(ret.rows_affected() == 1)
+    }
+}
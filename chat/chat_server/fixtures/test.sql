@@ -24,6 +24,7 @@ VALUES (1, 'single', '{1, 2}'),
 -- insert agent to chat
 INSERT INTO chat_agents(chat_id, name, type, adapter, model, prompt, args)
 VALUES (1, 'translation', 'proxy', 'test', 'gpt-4o-mini', 'If language is Chinese, translate to English, if language is English, translate to Chinese. Please reply with the translated content directly. No explanation is needed. Here is the content: ', '{}');
+UPDATE chats SET agents = '{1}' WHERE id = 1;
 
 INSERT INTO messages (chat_id, sender_id, content)
 Values (1, 1, 'Hello, world!'),
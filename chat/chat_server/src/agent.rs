@@ -1,123 +1,601 @@
-use ai_sdk::{AiAdapter, AiService, OllamaAdapter, OpenaiAdapter};
+use ai_sdk::{
+    AiAdapter, AiService, AnthropicAdapter, Completion, CompletionOptions, MockAdapter, MockScript,
+    OllamaAdapter, OpenaiAdapter,
+};
+use anyhow::anyhow;
 use chat_core::{
-    AdapterType, Agent, AgentContext, AgentDecision, AgentError, AgentType, ChatAgent,
+    AdapterType, Agent, AgentArgs, AgentContext, AgentDecision, AgentError, AgentType, ChatAgent,
+    ChatType, ModelEndpoint, ModelOptions, ResponseFormat, TokenUsage,
+};
+use std::{env, future::Future};
+use tracing::warn;
+
+use crate::{
+    AppState, CallMeter, ChatTools, Knowledge, Source, cited_sources, render_prompt, sources_prompt,
 };
-use std::env;
+
+// rounds of tool calls before the model has to answer
+const MAX_TOOL_ROUNDS: usize = 5;
 
 pub enum AgentVariant {
     Proxy(ProxyAgent),
     Reply(ReplyAgent),
     Tap(TapAgent),
-    Test(TestAgent),
 }
 
 #[allow(unused)]
 pub struct ProxyAgent {
     pub name: String,
     pub adapter: AiAdapter,
+    pub options: CompletionOptions,
     pub prompt: String,
-    pub args: serde_json::Value,
+    pub args: AgentArgs,
+    pub tools: Option<ChatTools>,
+    pub(crate) meter: Option<CallMeter>,
 }
 
 #[allow(unused)]
 pub struct ReplyAgent {
     pub name: String,
     pub adapter: AiAdapter,
+    pub options: CompletionOptions,
     pub prompt: String,
-    pub args: serde_json::Value,
+    pub args: AgentArgs,
+    pub tools: Option<ChatTools>,
+    pub knowledge: Option<Knowledge>,
+    pub(crate) meter: Option<CallMeter>,
 }
 
 #[allow(unused)]
 pub struct TapAgent {
     pub name: String,
     pub adapter: AiAdapter,
+    pub options: CompletionOptions,
     pub prompt: String,
-    pub args: serde_json::Value,
+    pub args: AgentArgs,
+    pub tools: Option<ChatTools>,
+    pub(crate) meter: Option<CallMeter>,
 }
 
-#[allow(unused)]
-pub struct TestAgent;
+impl Agent for ProxyAgent {
+    async fn process_with_usage(
+        &self,
+        msg: &str,
+        ctx: &AgentContext,
+    ) -> Result<(AgentDecision, Option<TokenUsage>), AgentError> {
+        let messages = prompt_messages(&self.prompt, &self.args, msg, ctx)?;
+        let res = complete(
+            &self.adapter,
+            messages,
+            &self.options,
+            self.tools.as_ref(),
+            self.meter.as_ref(),
+        )
+        .await?;
+        Ok((
+            AgentDecision::Modify(res.content),
+            res.usage.map(token_usage),
+        ))
+    }
+}
 
-impl Agent for TestAgent {
-    async fn process(&self, _msg: &str, _ctx: &AgentContext) -> Result<AgentDecision, AgentError> {
-        Ok(AgentDecision::None)
+impl Agent for ReplyAgent {
+    async fn process_with_usage(
+        &self,
+        msg: &str,
+        ctx: &AgentContext,
+    ) -> Result<(AgentDecision, Option<TokenUsage>), AgentError> {
+        let (messages, sources) = self.messages(msg, ctx).await?;
+        let res = complete(
+            &self.adapter,
+            messages,
+            &self.options,
+            self.tools.as_ref(),
+            self.meter.as_ref(),
+        )
+        .await?;
+        let citations = cited_sources(&res.content, &sources);
+        Ok((
+            AgentDecision::Reply(res.content, citations),
+            res.usage.map(token_usage),
+        ))
     }
 }
 
-impl Agent for ProxyAgent {
-    async fn process(&self, msg: &str, _ctx: &AgentContext) -> Result<AgentDecision, AgentError> {
-        // If we need it to be flexible: prompt is a jinja2 template, and args is a json
-        let prompt = format!("{} {}", self.prompt, msg);
-        let messages = vec![ai_sdk::Message::user(prompt)];
-        let res = self.adapter.complete(&messages).await?;
-        Ok(AgentDecision::Modify(res))
+// runs from the job queue, the output is stored as a tap output instead of changing the message
+impl Agent for TapAgent {
+    async fn process_with_usage(
+        &self,
+        msg: &str,
+        ctx: &AgentContext,
+    ) -> Result<(AgentDecision, Option<TokenUsage>), AgentError> {
+        let messages = prompt_messages(&self.prompt, &self.args, msg, ctx)?;
+        let res = complete(
+            &self.adapter,
+            messages,
+            &self.options,
+            self.tools.as_ref(),
+            self.meter.as_ref(),
+        )
+        .await?;
+        Ok((
+            AgentDecision::Modify(res.content),
+            res.usage.map(token_usage),
+        ))
     }
 }
 
-impl Agent for ReplyAgent {
-    async fn process(&self, msg: &str, _ctx: &AgentContext) -> Result<AgentDecision, AgentError> {
-        // 1. create embedding for the message
-        // 2. search related docs via vector db with embedding
-        // 3. query llm with prompt and related docs as context
-        let prompt = format!("{} {}", self.prompt, msg);
-        let messages = vec![ai_sdk::Message::user(prompt)];
-        let res = self.adapter.complete(&messages).await?;
-        Ok(AgentDecision::Reply(res))
+impl ReplyAgent {
+    pub async fn process_stream(
+        &self,
+        msg: &str,
+        ctx: &AgentContext,
+        on_delta: &mut (dyn FnMut(&str) + Send),
+    ) -> Result<(AgentDecision, Option<TokenUsage>), AgentError> {
+        let (messages, sources) = self.messages(msg, ctx).await?;
+        // the answer is only known after the tool calls, it's delivered in one piece
+        let res = match &self.tools {
+            Some(tools) => {
+                let meter = self.meter.as_ref();
+                let res =
+                    complete(&self.adapter, messages, &self.options, Some(tools), meter).await?;
+                on_delta(&res.content);
+                res
+            }
+            None => {
+                let call =
+                    self.adapter
+                        .complete_stream_with_options(&messages, &self.options, on_delta);
+                metered(self.meter.as_ref(), call).await?
+            }
+        };
+        let citations = cited_sources(&res.content, &sources);
+        Ok((
+            AgentDecision::Reply(res.content, citations),
+            res.usage.map(token_usage),
+        ))
+    }
+
+    /// The conversation for the model. With a knowledge collection the chunks
+    /// found for the message follow the system messages, as sources to cite.
+    async fn messages(
+        &self,
+        msg: &str,
+        ctx: &AgentContext,
+    ) -> Result<(Vec<ai_sdk::Message>, Vec<Source>), AgentError> {
+        let mut messages = prompt_messages(&self.prompt, &self.args, msg, ctx)?;
+        let Some(knowledge) = &self.knowledge else {
+            return Ok((messages, vec![]));
+        };
+        let sources = knowledge.search(msg).await?;
+        if let Some(prompt) = sources_prompt(&sources) {
+            let at = messages
+                .iter()
+                .take_while(|m| matches!(m.role, ai_sdk::Role::System))
+                .count();
+            messages.insert(at, ai_sdk::Message::system(prompt));
+        }
+        Ok((messages, sources))
     }
 }
 
-// in future, we should push messages into a queue and process in a delayed manner
-impl Agent for TapAgent {
-    async fn process(&self, _msg: &str, _ctx: &AgentContext) -> Result<AgentDecision, AgentError> {
-        Ok(AgentDecision::Modify("test".to_string()))
+/// Complete the conversation, running the tools the model calls until it
+/// answers. Every round is a model call reserved against the quotas.
+async fn complete(
+    adapter: &AiAdapter,
+    mut messages: Vec<ai_sdk::Message>,
+    options: &CompletionOptions,
+    tools: Option<&ChatTools>,
+    meter: Option<&CallMeter>,
+) -> Result<Completion, AgentError> {
+    let Some(tools) = tools else {
+        return metered(
+            meter,
+            adapter.complete_with_options(&messages, &[], options),
+        )
+        .await;
+    };
+    let definitions = tools.definitions();
+    let mut usage = None;
+    for _ in 0..MAX_TOOL_ROUNDS {
+        let call = adapter.complete_with_options(&messages, &definitions, options);
+        let res = metered(meter, call).await?;
+        usage = add_usage(usage, res.usage);
+        if res.tool_calls.is_empty() {
+            return Ok(Completion { usage, ..res });
+        }
+        let mut results = Vec::with_capacity(res.tool_calls.len());
+        for call in &res.tool_calls {
+            results.push(ai_sdk::Message::tool(&call.id, tools.call(call).await));
+        }
+        messages.push(ai_sdk::Message::tool_calls(res.tool_calls));
+        messages.extend(results);
     }
+    // the model keeps calling tools, make it answer with what it has got
+    let res = metered(
+        meter,
+        adapter.complete_with_options(&messages, &[], options),
+    )
+    .await?;
+    Ok(Completion {
+        usage: add_usage(usage, res.usage),
+        ..res
+    })
+}
+
+/// Make a model call, reserved first and its tokens counted once it's done.
+/// A refused reservation fails the agent before the model is called.
+async fn metered(
+    meter: Option<&CallMeter>,
+    call: impl Future<Output = anyhow::Result<Completion>>,
+) -> Result<Completion, AgentError> {
+    let Some(meter) = meter else {
+        return call.await.map_err(ai_error);
+    };
+    let id = meter.reserve().await?;
+    let ret = call.await;
+    let usage = ret.as_ref().ok().and_then(|res| res.usage).map(token_usage);
+    meter.spend(id, usage).await;
+    ret.map_err(ai_error)
+}
+
+/// errors worth a retry are network errors of the agent
+fn ai_error(e: anyhow::Error) -> AgentError {
+    if ai_sdk::is_transient(&e) {
+        AgentError::Network(e.to_string())
+    } else {
+        AgentError::AnyError(e)
+    }
+}
+
+fn add_usage(total: Option<ai_sdk::Usage>, usage: Option<ai_sdk::Usage>) -> Option<ai_sdk::Usage> {
+    match (total, usage) {
+        (Some(a), Some(b)) => Some(ai_sdk::Usage {
+            prompt_tokens: a.prompt_tokens + b.prompt_tokens,
+            completion_tokens: a.completion_tokens + b.completion_tokens,
+        }),
+        (a, b) => a.or(b),
+    }
+}
+
+fn token_usage(usage: ai_sdk::Usage) -> TokenUsage {
+    TokenUsage {
+        prompt_tokens: usage.prompt_tokens,
+        completion_tokens: usage.completion_tokens,
+    }
+}
+
+/// Render the prompt template of an agent and build the conversation sent to the model
+fn prompt_messages(
+    template: &str,
+    args: &AgentArgs,
+    msg: &str,
+    ctx: &AgentContext,
+) -> Result<Vec<ai_sdk::Message>, AgentError> {
+    let prompt = render_prompt(template, msg, ctx, args).map_err(anyhow::Error::from)?;
+    if prompt.has_message {
+        Ok(build_messages("", &prompt.text, ctx))
+    } else {
+        Ok(build_messages(&prompt.text, msg, ctx))
+    }
+}
+
+/// The agent prompt and what we know about the chat as system messages, then
+/// the conversation so far and the message to process as the last user message.
+/// In a single chat the other member speaks as the assistant, in other chats
+/// messages of other members are prefixed with their name.
+pub fn build_messages(prompt: &str, msg: &str, ctx: &AgentContext) -> Vec<ai_sdk::Message> {
+    let mut messages = Vec::with_capacity(ctx.history.len() + 3);
+    if !prompt.trim().is_empty() {
+        messages.push(ai_sdk::Message::system(prompt));
+    }
+    if let (Some(chat), Some(sender)) = (&ctx.chat, &ctx.sender) {
+        let name = chat.name.as_deref().unwrap_or("unnamed");
+        messages.push(ai_sdk::Message::system(format!(
+            "This is a {:?} chat \"{}\" with {} members. The last message is from {}.",
+            chat.r#type,
+            name,
+            chat.members.len(),
+            sender.fullname
+        )));
+    }
+
+    let single = ctx
+        .chat
+        .as_ref()
+        .is_some_and(|chat| chat.r#type == ChatType::Single);
+    let sender_id = ctx.sender.as_ref().map(|u| u.id);
+    for m in &ctx.history {
+        let message = if Some(m.sender_id) == sender_id {
+            ai_sdk::Message::user(&m.content)
+        } else if single {
+            ai_sdk::Message::assistant(&m.content)
+        } else {
+            ai_sdk::Message::user(format!("{}: {}", m.sender_name, m.content))
+        };
+        messages.push(message);
+    }
+    messages.push(ai_sdk::Message::user(msg));
+    messages
 }
 
 impl Agent for AgentVariant {
-    async fn process(&self, msg: &str, ctx: &AgentContext) -> Result<AgentDecision, AgentError> {
+    async fn process_with_usage(
+        &self,
+        msg: &str,
+        ctx: &AgentContext,
+    ) -> Result<(AgentDecision, Option<TokenUsage>), AgentError> {
         match self {
-            AgentVariant::Reply(agent) => agent.process(msg, ctx).await,
-            AgentVariant::Proxy(agent) => agent.process(msg, ctx).await,
-            AgentVariant::Tap(agent) => agent.process(msg, ctx).await,
-            AgentVariant::Test(agent) => agent.process(msg, ctx).await,
+            AgentVariant::Reply(agent) => agent.process_with_usage(msg, ctx).await,
+            AgentVariant::Proxy(agent) => agent.process_with_usage(msg, ctx).await,
+            AgentVariant::Tap(agent) => agent.process_with_usage(msg, ctx).await,
         }
     }
 }
 
-impl From<ChatAgent> for AgentVariant {
-    fn from(mut agent: ChatAgent) -> Self {
-        let adapter: AiAdapter = match agent.adapter {
-            AdapterType::Openai => {
-                let api_key = env::var("OPENAI_API_KEY").expect("OPENAI_API_KEY not set");
-                OpenaiAdapter::new(api_key, agent.model).into()
+impl AgentVariant {
+    /// Give the agent the tools listed in its args, they run as the sender of the message
+    pub fn attach_tools(&mut self, state: &AppState, ctx: &AgentContext) {
+        let (Some(chat), Some(sender)) = (&ctx.chat, &ctx.sender) else {
+            return;
+        };
+        let (args, tools) = match self {
+            AgentVariant::Proxy(agent) => (&agent.args, &mut agent.tools),
+            AgentVariant::Reply(agent) => (&agent.args, &mut agent.tools),
+            AgentVariant::Tap(agent) => (&agent.args, &mut agent.tools),
+        };
+        if !args.tools().is_empty() {
+            *tools = Some(ChatTools::new(
+                state.clone(),
+                chat.id,
+                sender.id,
+                args.tools().to_vec(),
+            ));
+        }
+    }
+
+    /// Count the model calls of the agent against the quotas
+    pub(crate) fn attach_meter(&mut self, meter: CallMeter) {
+        let slot = match self {
+            AgentVariant::Proxy(agent) => &mut agent.meter,
+            AgentVariant::Reply(agent) => &mut agent.meter,
+            AgentVariant::Tap(agent) => &mut agent.meter,
+        };
+        *slot = Some(meter);
+    }
+
+    /// Connect a reply agent to the knowledge collection in its args, it's
+    /// searched with the embedding model at the default endpoint of the workspace
+    pub async fn attach_knowledge(
+        &mut self,
+        state: &AppState,
+        ctx: &AgentContext,
+    ) -> Result<(), AgentError> {
+        let AgentVariant::Reply(agent) = self else {
+            return Ok(());
+        };
+        let Some(args) = agent.args.knowledge() else {
+            return Ok(());
+        };
+        // collections belong to the workspace of the chat
+        let Some(chat) = &ctx.chat else {
+            return Err(anyhow!("Knowledge needs the chat of the message").into());
+        };
+        let config = &state.config.knowledge;
+        let endpoint = state
+            .default_endpoint(chat.ws_id, &config.adapter)
+            .await
+            .map_err(anyhow::Error::from)?;
+        let knowledge = Knowledge::try_new(
+            state.pool.clone(),
+            config,
+            chat.ws_id,
+            args,
+            endpoint.as_ref(),
+        )?;
+        agent.knowledge = Some(knowledge);
+        Ok(())
+    }
+
+    /// Process the message, replies are handed to `on_delta` while the model writes them
+    pub async fn process_stream(
+        &self,
+        msg: &str,
+        ctx: &AgentContext,
+        on_delta: &mut (dyn FnMut(&str) + Send),
+    ) -> Result<(AgentDecision, Option<TokenUsage>), AgentError> {
+        match self {
+            AgentVariant::Reply(agent) => agent.process_stream(msg, ctx, on_delta).await,
+            AgentVariant::Proxy(_) | AgentVariant::Tap(_) => {
+                self.process_with_usage(msg, ctx).await
             }
-            AdapterType::Ollama => OllamaAdapter::new_local(agent.model).into(),
-            AdapterType::Test => return AgentVariant::Test(TestAgent),
+        }
+    }
+}
+
+impl TryFrom<ChatAgent> for AgentVariant {
+    type Error = AgentError;
+
+    fn try_from(agent: ChatAgent) -> Result<Self, Self::Error> {
+        Self::try_new(agent, None)
+    }
+}
+
+impl AgentVariant {
+    /// Build the agent to call its model at the endpoint, or at the public API
+    /// of its adapter with the key from the environment. Agents of the `test`
+    /// adapter call a mock scripted by their args
+    pub fn try_new(agent: ChatAgent, endpoint: Option<&ModelEndpoint>) -> Result<Self, AgentError> {
+        let adapter: AiAdapter = match (&agent.adapter, endpoint) {
+            (AdapterType::Test, _) => mock_adapter(&agent.args)
+                .map_err(|e| anyhow!(e))?
+                .unwrap_or_default()
+                .into(),
+            (adapter, endpoint) => model_adapter(adapter, agent.model.clone(), endpoint)?,
         };
+        Ok(Self::with_adapter(agent, adapter))
+    }
+
+    /// Build the agent to call its model with the adapter
+    pub fn with_adapter(agent: ChatAgent, adapter: AiAdapter) -> Self {
+        // args are validated when the agent is saved, only rows from before fall back
+        let args = agent.typed_args().unwrap_or_else(|e| {
+            warn!("Invalid args of agent {}: {}", agent.id, e);
+            AgentArgs::new(&agent.r#type)
+        });
+        let options = completion_options(&agent.options);
 
         match agent.r#type {
             AgentType::Reply => AgentVariant::Reply(ReplyAgent {
                 name: agent.name,
                 adapter,
+                options,
                 prompt: agent.prompt,
-                args: agent.args.take(),
+                args,
+                tools: None,
+                knowledge: None,
+                meter: None,
             }),
             AgentType::Proxy => AgentVariant::Proxy(ProxyAgent {
                 name: agent.name,
                 adapter,
+                options,
                 prompt: agent.prompt,
-                args: agent.args.take(),
+                args,
+                tools: None,
+                meter: None,
             }),
             AgentType::Tap => AgentVariant::Tap(TapAgent {
                 name: agent.name,
                 adapter,
+                options,
                 prompt: agent.prompt,
-                args: agent.args.take(),
+                args,
+                tools: None,
+                meter: None,
             }),
         }
     }
 }
 
+impl AppState {
+    /// The mock model of a `test` agent, shared by all its runs until its
+    /// script changes
+    pub(crate) fn agent_mock(&self, agent: &ChatAgent) -> Result<MockAdapter, AgentError> {
+        let mut mocks = self.mocks.lock().expect("agent mocks poisoned");
+        if let Some((args, mock)) = mocks.get(&agent.id)
+            && *args == agent.args.0
+        {
+            return Ok(mock.clone());
+        }
+        let mock = mock_adapter(&agent.args)
+            .map_err(|e| anyhow!(e))?
+            .unwrap_or_default();
+        mocks.insert(agent.id, (agent.args.0.clone(), mock.clone()));
+        Ok(mock)
+    }
+}
+
+fn completion_options(options: &ModelOptions) -> CompletionOptions {
+    let response_format = match &options.response_format {
+        ResponseFormat::Text => ai_sdk::ResponseFormat::Text,
+        ResponseFormat::Json => ai_sdk::ResponseFormat::Json,
+        ResponseFormat::JsonSchema { name, schema } => ai_sdk::ResponseFormat::JsonSchema {
+            name: name.clone(),
+            schema: schema.clone(),
+        },
+    };
+    CompletionOptions {
+        temperature: options.temperature,
+        top_p: options.top_p,
+        max_tokens: options.max_tokens,
+        stop: options.stop.clone(),
+        seed: options.seed,
+        response_format,
+    }
+}
+
+/// An adapter calling the model at the endpoint, or at the public API of the
+/// adapter with the key from the environment
+pub(crate) fn model_adapter(
+    adapter: &AdapterType,
+    model: String,
+    endpoint: Option<&ModelEndpoint>,
+) -> Result<AiAdapter, AgentError> {
+    let adapter = match (adapter, endpoint) {
+        (_, Some(endpoint)) => endpoint_adapter(endpoint, model)?,
+        (AdapterType::Openai, None) => {
+            let api_key = env::var("OPENAI_API_KEY").map_err(|_| {
+                AgentError::MissingCredentials("OPENAI_API_KEY not set".to_string())
+            })?;
+            OpenaiAdapter::new(api_key, model).into()
+        }
+        (AdapterType::Ollama, None) => OllamaAdapter::new_local(model).into(),
+        (AdapterType::Anthropic, None) => {
+            let api_key = env::var("ANTHROPIC_API_KEY").map_err(|_| {
+                AgentError::MissingCredentials("ANTHROPIC_API_KEY not set".to_string())
+            })?;
+            AnthropicAdapter::new(api_key, model).into()
+        }
+        (AdapterType::Test, None) => return Err(anyhow!("test adapter has no model").into()),
+    };
+    Ok(adapter)
+}
+
+/// The mock model scripted by `mock` in the args of a test agent, if any
+pub(crate) fn mock_adapter(args: &serde_json::Value) -> Result<Option<MockAdapter>, String> {
+    let Some(script) = args.get("mock") else {
+        return Ok(None);
+    };
+    let script: MockScript =
+        serde_json::from_value(script.clone()).map_err(|e| format!("Invalid mock: {}", e))?;
+    MockAdapter::try_from_script(script)
+        .map(Some)
+        .map_err(|e| format!("Invalid mock: {}", e))
+}
+
+/// An adapter calling the endpoint, its key is read from the environment
+fn endpoint_adapter(endpoint: &ModelEndpoint, model: String) -> Result<AiAdapter, AgentError> {
+    let api_key = match &endpoint.api_key_env {
+        Some(name) => env::var(name)
+            .map_err(|_| AgentError::MissingCredentials(format!("{} not set", name)))?,
+        // e.g. a vLLM server in the private network
+        None => String::new(),
+    };
+    let headers = endpoint.headers.0.clone();
+    let adapter = match endpoint.adapter {
+        AdapterType::Openai => {
+            let mut adapter = OpenaiAdapter::new(api_key, model)
+                .with_host(&endpoint.base_url)
+                .with_headers(headers);
+            if let Some(organization) = &endpoint.organization {
+                adapter = adapter.with_organization(organization);
+            }
+            if let Some(name) = &endpoint.api_key_header {
+                adapter = adapter.with_api_key_header(name);
+            }
+            adapter.into()
+        }
+        AdapterType::Ollama => {
+            // ollama has no keys, a proxy in front of it may
+            let key = match (&endpoint.api_key_header, api_key.is_empty()) {
+                (_, true) => None,
+                (Some(name), false) => Some((name.clone(), api_key)),
+                (None, false) => Some(("Authorization".to_string(), format!("Bearer {}", api_key))),
+            };
+            OllamaAdapter::new(&endpoint.base_url, model)
+                .with_headers(headers.into_iter().chain(key))
+                .into()
+        }
+        AdapterType::Anthropic => AnthropicAdapter::new(api_key, model)
+            .with_host(&endpoint.base_url)
+            .with_headers(headers)
+            .into(),
+        AdapterType::Test => return Err(anyhow!("test adapter has no endpoints").into()),
+    };
+    Ok(adapter)
+}
+
 impl From<ProxyAgent> for AgentVariant {
     fn from(agent: ProxyAgent) -> Self {
         AgentVariant::Proxy(agent)
@@ -140,7 +618,127 @@ impl From<TapAgent> for AgentVariant {
 mod tests {
     use super::*;
     use crate::AppState;
+    use ai_sdk::Role;
     use anyhow::Result;
+    use chat_core::{Chat, ChatUser, ContextMessage};
+    use chrono::Utc;
+    use serde_json::json;
+
+    fn context(r#type: ChatType) -> AgentContext {
+        let chat = Chat {
+            id: 1,
+            ws_id: 1,
+            name: Some("general".to_string()),
+            r#type,
+            members: vec![1, 2],
+            agents: vec![],
+            created_at: Utc::now(),
+        };
+        let sender = ChatUser {
+            id: 1,
+            fullname: "Tyr Chen".to_string(),
+            email: "tchen@acme.org".to_string(),
+        };
+        let history = vec![
+            ContextMessage {
+                id: 1,
+                sender_id: 1,
+                sender_name: "Tyr Chen".to_string(),
+                content: "hi".to_string(),
+            },
+            ContextMessage {
+                id: 2,
+                sender_id: 2,
+                sender_name: "John Doe".to_string(),
+                content: "hello".to_string(),
+            },
+        ];
+        AgentContext::new(chat, sender, history)
+    }
+
+    #[test]
+    fn build_messages_should_include_prompt_and_history() {
+        let messages = build_messages("be nice", "how are you?", &context(ChatType::Single));
+        assert_eq!(messages.len(), 5);
+        assert!(matches!(messages[0].role, Role::System));
+        assert_eq!(messages[0].content, "be nice");
+        assert!(matches!(messages[1].role, Role::System));
+        assert!(messages[1].content.contains("Tyr Chen"));
+        assert!(matches!(messages[2].role, Role::User));
+        assert!(matches!(messages[3].role, Role::Assistant));
+        assert!(matches!(messages[4].role, Role::User));
+        assert_eq!(messages[4].content, "how are you?");
+
+        let messages = build_messages("", "how are you?", &context(ChatType::Group));
+        assert_eq!(messages.len(), 4);
+        assert!(matches!(messages[2].role, Role::User));
+        assert_eq!(messages[2].content, "John Doe: hello");
+
+        let messages = build_messages("", "how are you?", &AgentContext::default());
+        assert_eq!(messages.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn proxy_agent_should_send_prompt_and_options_to_model() -> Result<()> {
+        let mock = MockAdapter::new().with_rule("^(.*)$", "Fixed: $1")?;
+        let agent = ProxyAgent {
+            name: "grammar".to_string(),
+            adapter: mock.clone().into(),
+            options: CompletionOptions {
+                temperature: Some(0.2),
+                ..Default::default()
+            },
+            prompt: "Fix the grammar, answer in {{ args.language }}".to_string(),
+            args: AgentArgs::parse(&AgentType::Proxy, &json!({"language": "English"}))?,
+            tools: None,
+            meter: None,
+        };
+        let (decision, usage) = agent
+            .process_with_usage("me go home", &context(ChatType::Single))
+            .await?;
+        assert!(
+            matches!(decision, AgentDecision::Modify(content) if content == "Fixed: me go home")
+        );
+        assert_eq!(usage.map(|u| u.completion_tokens), Some(4));
+
+        let requests = mock.requests();
+        assert_eq!(requests.len(), 1);
+        let messages = &requests[0].messages;
+        assert_eq!(messages[0].content, "Fix the grammar, answer in English");
+        assert_eq!(
+            messages.last().map(|m| m.content.as_str()),
+            Some("me go home")
+        );
+        assert_eq!(requests[0].options.temperature, Some(0.2));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_agent_should_be_agent_of_its_type_calling_a_mock() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let mut agent = state.list_agents(1).await?[0].clone();
+        agent.r#type = AgentType::Reply;
+        agent.adapter = AdapterType::Test;
+        agent.args.0 =
+            json!({"mock": {"responses": [{"text": "first"}], "default": {"text": "hi"}}});
+        let variant = AgentVariant::try_from(agent.clone())?;
+        assert!(matches!(variant, AgentVariant::Reply(_)));
+        assert!(mock_adapter(&json!({"mock": {"rules": [{"pattern": "("}]}})).is_err());
+
+        // runs of the agent share the queued responses
+        let ctx = AgentContext::default();
+        let mock = state.agent_mock(&agent)?;
+        assert_eq!(mock.complete(&[ai_sdk::Message::user("a")]).await?, "first");
+        let mock = state.agent_mock(&agent)?;
+        assert_eq!(mock.complete(&[ai_sdk::Message::user("a")]).await?, "hi");
+
+        // without a script the mock echoes, a new script starts over
+        agent.args.0 = json!({});
+        let variant = AgentVariant::with_adapter(agent.clone(), state.agent_mock(&agent)?.into());
+        let decision = variant.process("hello", &ctx).await?;
+        assert!(matches!(decision, AgentDecision::Reply(content, _) if content == "hello"));
+        Ok(())
+    }
 
     #[ignore]
     #[tokio::test]
@@ -148,7 +746,7 @@ mod tests {
         let (_tdb, state) = AppState::new_for_test().await?;
         let agents = state.list_agents(1).await.expect("list agents failed");
         let agent = agents[0].clone();
-        let agent: AgentVariant = agent.into();
+        let agent: AgentVariant = agent.try_into()?;
         let decision = agent.process("hello", &AgentContext::default()).await?;
         // test if it is modify
         if let AgentDecision::Modify(_content) = decision {
@@ -1,4 +1,6 @@
 use anyhow::{Result, bail};
+use chat_core::AdapterType;
+pub use chat_core::EndpointConfig;
 use serde::{Deserialize, Serialize};
 use std::{fs::File, path::PathBuf};
 
@@ -6,6 +8,18 @@ use std::{fs::File, path::PathBuf};
 pub struct AppConfig {
     pub server: ServerConfig,
     pub auth: AuthConfig,
+    #[serde(default)]
+    pub jobs: JobConfig,
+    #[serde(default)]
+    pub agent: AgentConfig,
+    #[serde(default)]
+    pub models: ModelConfig,
+    #[serde(default)]
+    pub limits: LimitConfig,
+    #[serde(default)]
+    pub knowledge: KnowledgeConfig,
+    #[serde(default)]
+    pub endpoints: EndpointConfig,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -21,6 +35,144 @@ pub struct AuthConfig {
     pub pk: String,
 }
 
+/// Workers running tap agents off the request path
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobConfig {
+    pub workers: usize,
+    pub max_attempts: i32,
+    // delay before the first retry, doubled on every attempt
+    pub backoff_secs: u64,
+    // how often idle workers look for new jobs
+    pub poll_interval_ms: u64,
+}
+
+impl Default for JobConfig {
+    fn default() -> Self {
+        Self {
+            workers: 2,
+            max_attempts: 5,
+            backoff_secs: 5,
+            poll_interval_ms: 1000,
+        }
+    }
+}
+
+/// How much of the conversation agents get to see, and how long they may take.
+/// Timeouts and retries are the defaults of the `policy` of agents.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AgentConfig {
+    // number of previous messages loaded into the context
+    pub history_limit: i64,
+    // estimated tokens the history may take
+    pub token_budget: usize,
+    // of a single attempt
+    pub timeout_secs: u64,
+    pub max_retries: u32,
+    // delay before the first retry, doubled on every attempt
+    pub retry_backoff_ms: u64,
+}
+
+impl Default for AgentConfig {
+    fn default() -> Self {
+        Self {
+            history_limit: 20,
+            token_budget: 2000,
+            timeout_secs: 30,
+            max_retries: 2,
+            retry_backoff_ms: 500,
+        }
+    }
+}
+
+/// What agents may spend, 0 means no limit. Agent limits are the defaults of
+/// the `policy` of agents, workspace limits are for all agents of a workspace.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LimitConfig {
+    pub agent_requests_per_minute: u32,
+    pub agent_tokens_per_day: u64,
+    pub workspace_requests_per_minute: u32,
+    pub workspace_tokens_per_day: u64,
+}
+
+/// Embedding model of the knowledge collections of reply agents, it has to be
+/// the one the collections were indexed with
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct KnowledgeConfig {
+    pub adapter: AdapterType,
+    pub model: String,
+    pub vector_size: i32,
+}
+
+impl Default for KnowledgeConfig {
+    fn default() -> Self {
+        Self {
+            adapter: AdapterType::Ollama,
+            model: "all-minilm".to_string(),
+            vector_size: 384,
+        }
+    }
+}
+
+// in the models of an adapter, agents may use any model
+pub const ANY_MODEL: &str = "*";
+
+/// Models agents may use per adapter. `"*"` accepts any model, an empty list
+/// disables the adapter
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ModelConfig {
+    pub openai: Vec<String>,
+    pub ollama: Vec<String>,
+    pub anthropic: Vec<String>,
+    pub test: Vec<String>,
+}
+
+impl Default for ModelConfig {
+    fn default() -> Self {
+        let openai = [
+            "gpt-4o",
+            "gpt-4o-mini",
+            "gpt-4.1",
+            "gpt-4.1-mini",
+            "gpt-4.1-nano",
+        ];
+        let anthropic = [
+            "claude-3-5-haiku-latest",
+            "claude-3-7-sonnet-latest",
+            "claude-sonnet-4-0",
+            "claude-opus-4-0",
+        ];
+        Self {
+            openai: openai.into_iter().map(String::from).collect(),
+            // whatever is pulled into the local ollama
+            ollama: vec![ANY_MODEL.to_string()],
+            anthropic: anthropic.into_iter().map(String::from).collect(),
+            // the mock costs nothing whatever the model is called
+            test: vec![ANY_MODEL.to_string()],
+        }
+    }
+}
+
+impl ModelConfig {
+    pub fn models(&self, adapter: &AdapterType) -> &[String] {
+        match adapter {
+            AdapterType::Openai => &self.openai,
+            AdapterType::Ollama => &self.ollama,
+            AdapterType::Anthropic => &self.anthropic,
+            AdapterType::Test => &self.test,
+        }
+    }
+
+    pub fn is_supported(&self, adapter: &AdapterType, model: &str) -> bool {
+        self.models(adapter)
+            .iter()
+            .any(|m| m == ANY_MODEL || m == model)
+    }
+}
+
 impl AppConfig {
     pub fn load() -> Result<Self> {
         // read from ./app.yml, or /etc/config/app.yml, or from env CHAT_CONFIG
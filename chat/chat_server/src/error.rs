@@ -14,6 +14,9 @@ pub enum AppError {
     #[error("user already exists: {0}")]
     UserAlreadyExists(String),
 
+    #[error("create user error: {0}")]
+    CreateUserError(String),
+
     #[error("sql error: {0}")]
     SqlxError(#[from] sqlx::Error),
 
@@ -47,11 +50,20 @@ pub enum AppError {
     #[error("update agent error: {0}")]
     UpdateAgentError(String),
 
+    #[error("endpoint error: {0}")]
+    EndpointError(String),
+
     #[error("user {user_id} is not member of chat {chat_id}")]
     NotChatMemberError { user_id: u64, chat_id: u64 },
 
+    #[error("user {user_id} is not the owner of workspace {ws_id}")]
+    NotWorkspaceOwnerError { user_id: u64, ws_id: u64 },
+
     #[error("ai agent error: {0}")]
     AiAgentError(#[from] AgentError),
+
+    #[error("quota exceeded: {0}")]
+    QuotaExceeded(String),
 }
 
 impl ErrorOutput {
@@ -70,6 +82,7 @@ impl IntoResponse for AppError {
             AppError::JwtError(_) => axum::http::StatusCode::FORBIDDEN,
             AppError::HttpHeaderError(_) => axum::http::StatusCode::UNPROCESSABLE_ENTITY,
             AppError::UserAlreadyExists(_) => axum::http::StatusCode::CONFLICT,
+            AppError::CreateUserError(_) => axum::http::StatusCode::BAD_REQUEST,
             AppError::CreateChatError(_) => axum::http::StatusCode::BAD_REQUEST,
             AppError::NotFound(_) => axum::http::StatusCode::NOT_FOUND,
             AppError::IoError(_) => axum::http::StatusCode::INTERNAL_SERVER_ERROR,
@@ -77,8 +90,11 @@ impl IntoResponse for AppError {
             AppError::ChatFileError(_) => axum::http::StatusCode::INTERNAL_SERVER_ERROR,
             AppError::CreateAgentError(_) => axum::http::StatusCode::BAD_REQUEST,
             AppError::UpdateAgentError(_) => axum::http::StatusCode::BAD_REQUEST,
+            AppError::EndpointError(_) => axum::http::StatusCode::BAD_REQUEST,
             AppError::NotChatMemberError { .. } => axum::http::StatusCode::FORBIDDEN,
+            AppError::NotWorkspaceOwnerError { .. } => axum::http::StatusCode::FORBIDDEN,
             AppError::AiAgentError(_) => axum::http::StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::QuotaExceeded(_) => axum::http::StatusCode::TOO_MANY_REQUESTS,
         };
         (status, Json(ErrorOutput::new(self.to_string()))).into_response()
     }
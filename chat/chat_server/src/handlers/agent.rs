@@ -1,11 +1,14 @@
-use crate::{AppError, AppState, CreateAgent, ErrorOutput, UpdateAgent};
+use crate::{
+    AgentPreview, AgentQuota, AgentUsage, AppError, AppState, CreateAgent, ErrorOutput,
+    ListAgentUsage, ListTapOutputs, PreviewAgent, UpdateAgent,
+};
 use axum::{
-    Json,
-    extract::{Path, State},
+    Extension, Json,
+    extract::{Path, Query, State},
     http::StatusCode,
     response::IntoResponse,
 };
-use chat_core::{Chat, ChatAgent};
+use chat_core::{AgentOutcome, ChatAgent, TapOutput, User};
 
 /// List all agents in the chat.
 #[utoipa::path(
@@ -52,7 +55,36 @@ pub(crate) async fn create_agent_handler(
     Ok((StatusCode::CREATED, Json(agent)))
 }
 
-/// Update the agent by id.
+/// Get the agent by id.
+#[utoipa::path(
+    get,
+    path = "/api/chats/{id}/agents/{agent_id}",
+    params(
+        ("id" = u64, Path, description = "Chat id"),
+        ("agent_id" = u64, Path, description = "Agent id")
+    ),
+    responses(
+        (status = 200, description = "Agent found", body = ChatAgent),
+        (status = 404, description = "Agent not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn get_agent_handler(
+    Path((id, agent_id)): Path<(u64, u64)>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    match state.get_agent_by_id(id, agent_id).await? {
+        Some(agent) => Ok(Json(agent)),
+        None => Err(AppError::NotFound(format!(
+            "Agent {} not found in chat {}",
+            agent_id, id
+        ))),
+    }
+}
+
+/// Update the agent by id, omitted fields are kept.
 #[utoipa::path(
     patch,
     path = "/api/chats/{id}/agents/{agent_id}",
@@ -60,19 +92,173 @@ pub(crate) async fn create_agent_handler(
         ("id" = u64, Path, description = "Chat id"),
         ("agent_id" = u64, Path, description = "Agent id")
     ),
+    request_body = UpdateAgent,
     responses(
-        (status = 200, description = "Chat found", body = Chat),
-        (status = 404, description = "Chat not found", body = ErrorOutput),
+        (status = 200, description = "Agent updated", body = ChatAgent),
+        (status = 400, description = "Invalid input", body = ErrorOutput),
     ),
     security(
         ("token" = [])
     )
 )]
 pub(crate) async fn update_agent_handler(
-    Path(id): Path<u64>,
+    Path((id, agent_id)): Path<(u64, u64)>,
     State(state): State<AppState>,
-    Json(input): Json<UpdateAgent>,
+    Json(mut input): Json<UpdateAgent>,
 ) -> Result<impl IntoResponse, AppError> {
-    let agent = state.update_agent(input, id as _).await?;
+    input.id = agent_id;
+    let agent = state.update_agent(input, id).await?;
     Ok((StatusCode::OK, Json(agent)))
 }
+
+/// Delete the agent by id.
+#[utoipa::path(
+    delete,
+    path = "/api/chats/{id}/agents/{agent_id}",
+    params(
+        ("id" = u64, Path, description = "Chat id"),
+        ("agent_id" = u64, Path, description = "Agent id")
+    ),
+    responses(
+        (status = 204, description = "Agent deleted"),
+        (status = 404, description = "Agent not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn delete_agent_handler(
+    Path((id, agent_id)): Path<(u64, u64)>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    state.delete_agent(id, agent_id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Run the agent on a message without posting it, only its usage is recorded.
+#[utoipa::path(
+    post,
+    path = "/api/chats/{id}/agents/{agent_id}/preview",
+    params(
+        ("id" = u64, Path, description = "Chat id"),
+        ("agent_id" = u64, Path, description = "Agent id")
+    ),
+    request_body = PreviewAgent,
+    responses(
+        (status = 200, description = "What the agent would do", body = AgentPreview),
+        (status = 404, description = "Agent not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn preview_agent_handler(
+    Extension(user): Extension<User>,
+    Path((id, agent_id)): Path<(u64, u64)>,
+    State(state): State<AppState>,
+    Json(input): Json<PreviewAgent>,
+) -> Result<impl IntoResponse, AppError> {
+    let preview = state
+        .preview_agent(input, id, agent_id, user.id as _)
+        .await?;
+    Ok((StatusCode::OK, Json(preview)))
+}
+
+/// List what each agent of the chat did with a message.
+#[utoipa::path(
+    get,
+    path = "/api/chats/{id}/messages/{message_id}/outcomes",
+    params(
+        ("id" = u64, Path, description = "Chat id"),
+        ("message_id" = u64, Path, description = "Message id")
+    ),
+    responses(
+        (status = 200, description = "Agent outcomes in execution order", body = Vec<AgentOutcome>),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn list_agent_outcome_handler(
+    Path((id, message_id)): Path<(u64, u64)>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let outcomes = state.list_agent_outcomes(id, message_id).await?;
+    Ok((StatusCode::OK, Json(outcomes)))
+}
+
+/// List outputs of the tap agents in the chat, newest first.
+#[utoipa::path(
+    get,
+    path = "/api/chats/{id}/tap-outputs",
+    params(
+        ("id" = u64, Path, description = "Chat id"),
+        ListTapOutputs
+    ),
+    responses(
+        (status = 200, description = "List of tap outputs", body = Vec<TapOutput>),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn list_tap_output_handler(
+    Path(id): Path<u64>,
+    State(state): State<AppState>,
+    Query(input): Query<ListTapOutputs>,
+) -> Result<impl IntoResponse, AppError> {
+    let outputs = state.list_tap_outputs(input, id).await?;
+    Ok((StatusCode::OK, Json(outputs)))
+}
+
+/// Summarize how often each agent of the chat ran and the tokens it used.
+#[utoipa::path(
+    get,
+    path = "/api/chats/{id}/agent-usage",
+    params(
+        ("id" = u64, Path, description = "Chat id"),
+        ListAgentUsage
+    ),
+    responses(
+        (status = 200, description = "Usage per agent", body = Vec<AgentUsage>),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn chat_agent_usage_handler(
+    Extension(user): Extension<User>,
+    Path(id): Path<u64>,
+    State(state): State<AppState>,
+    Query(input): Query<ListAgentUsage>,
+) -> Result<impl IntoResponse, AppError> {
+    let usage = state
+        .list_agent_usage(input, user.ws_id as _, Some(id))
+        .await?;
+    Ok((StatusCode::OK, Json(usage)))
+}
+
+/// Get the remaining quota of the agent and of its workspace.
+#[utoipa::path(
+    get,
+    path = "/api/chats/{id}/agents/{agent_id}/quota",
+    params(
+        ("id" = u64, Path, description = "Chat id"),
+        ("agent_id" = u64, Path, description = "Agent id")
+    ),
+    responses(
+        (status = 200, description = "Quota of the agent", body = AgentQuota),
+        (status = 404, description = "Agent not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn get_agent_quota_handler(
+    Extension(user): Extension<User>,
+    Path((id, agent_id)): Path<(u64, u64)>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let quota = state.get_agent_quota(id, agent_id, user.ws_id as _).await?;
+    Ok((StatusCode::OK, Json(quota)))
+}
@@ -2,12 +2,14 @@ mod agent;
 mod auth;
 mod chat;
 mod messages;
+mod notification;
 mod workspace;
 pub(crate) use agent::*;
 pub(crate) use auth::*;
 use axum::response::IntoResponse;
 pub(crate) use chat::*;
 pub(crate) use messages::*;
+pub(crate) use notification::*;
 pub(crate) use workspace::*;
 pub(crate) async fn index_handler() -> impl IntoResponse {
     "Welcome to the chat application!"
@@ -0,0 +1,84 @@
+use axum::{
+    Extension, Json,
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+};
+
+use crate::{
+    AppError, AppState, ErrorOutput, UpdateNotificationSettings, UpdateWorkspaceNotification,
+};
+use chat_core::{NotificationLevel, NotificationSettings, User};
+
+/// Get the notification settings of the user in the chat.
+#[utoipa::path(
+    get,
+    path = "/api/chats/{id}/notification",
+    params(
+        ("id" = u64, Path, description = "Chat id")
+    ),
+    responses(
+        (status = 200, description = "Notification settings", body = NotificationSettings),
+        (status = 404, description = "Chat not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn get_notification_handler(
+    Extension(user): Extension<User>,
+    Path(id): Path<u64>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let settings = state.get_notification_settings(id, user.id as _).await?;
+    Ok((StatusCode::OK, Json(settings)))
+}
+
+/// Update the notification settings of the user in the chat.
+#[utoipa::path(
+    put,
+    path = "/api/chats/{id}/notification",
+    params(
+        ("id" = u64, Path, description = "Chat id")
+    ),
+    responses(
+        (status = 200, description = "Notification settings updated", body = NotificationSettings),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn update_notification_handler(
+    Extension(user): Extension<User>,
+    Path(id): Path<u64>,
+    State(state): State<AppState>,
+    Json(input): Json<UpdateNotificationSettings>,
+) -> Result<impl IntoResponse, AppError> {
+    let settings = state
+        .update_notification_settings(input, id, user.id as _)
+        .await?;
+    Ok((StatusCode::OK, Json(settings)))
+}
+
+/// Update the default notification level of the workspace. Only the owner can do it.
+#[utoipa::path(
+    put,
+    path = "/api/workspace/notification",
+    responses(
+        (status = 200, description = "Default notification level updated", body = NotificationLevel),
+        (status = 404, description = "Not the workspace owner", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn update_workspace_notification_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<UpdateWorkspaceNotification>,
+) -> Result<impl IntoResponse, AppError> {
+    let level = state
+        .update_workspace_notification(input, user.ws_id as _, user.id as _)
+        .await?;
+    Ok((StatusCode::OK, Json(level)))
+}
@@ -1,7 +1,15 @@
-use axum::{Extension, Json, extract::State, response::IntoResponse};
+use axum::{
+    Extension, Json,
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+};
 
-use crate::{AppError, AppState};
-use chat_core::{ChatUser, User};
+use crate::{
+    AgentUsage, AppError, AppState, CreateEndpoint, ErrorOutput, ListAgentUsage, Quota,
+    UpdateEndpoint,
+};
+use chat_core::{ChatUser, ModelEndpoint, User};
 
 #[utoipa::path(
     get,
@@ -21,3 +29,147 @@ pub(crate) async fn list_chat_users_handler(
     let users = state.fetch_chat_users(user.ws_id as _).await?;
     Ok(Json(users))
 }
+
+#[utoipa::path(
+    get,
+    path = "/api/workspace/agent-usage",
+    params(
+        ListAgentUsage
+    ),
+    responses(
+        (status = 200, description = "Usage per chat and agent", body = Vec<AgentUsage>),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+/// Summarize how often the agents of the workspace ran and the tokens they used.
+pub(crate) async fn workspace_agent_usage_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Query(input): Query<ListAgentUsage>,
+) -> Result<impl IntoResponse, AppError> {
+    let usage = state.list_agent_usage(input, user.ws_id as _, None).await?;
+    Ok(Json(usage))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/workspace/agent-quota",
+    responses(
+        (status = 200, description = "Quota of the agents of the workspace", body = Quota),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+/// Get the remaining quota of all agents of the workspace together.
+pub(crate) async fn workspace_agent_quota_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let quota = state.get_workspace_quota(user.ws_id as _).await?;
+    Ok(Json(quota))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/workspace/endpoints",
+    responses(
+        (status = 200, description = "Model endpoints of the workspace", body = Vec<ModelEndpoint>),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+/// List the model endpoints of the workspace, header values are only shown to the owner.
+pub(crate) async fn list_endpoint_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let endpoints = state.list_endpoints(user.ws_id as _, user.id as _).await?;
+    Ok(Json(endpoints))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/workspace/endpoints",
+    request_body = CreateEndpoint,
+    responses(
+        (status = 201, description = "Endpoint created", body = ModelEndpoint),
+        (status = 400, description = "Invalid input", body = ErrorOutput),
+        (status = 403, description = "Not the owner of the workspace", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+/// Add a model endpoint agents of the workspace can call, e.g. a vLLM server or a gateway.
+/// Only the owner of the workspace can manage its endpoints.
+pub(crate) async fn create_endpoint_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<CreateEndpoint>,
+) -> Result<impl IntoResponse, AppError> {
+    let endpoint = state
+        .create_endpoint(input, user.ws_id as _, user.id as _)
+        .await?;
+    Ok((StatusCode::CREATED, Json(endpoint)))
+}
+
+#[utoipa::path(
+    patch,
+    path = "/api/workspace/endpoints/{id}",
+    params(
+        ("id" = u64, Path, description = "Endpoint id")
+    ),
+    request_body = UpdateEndpoint,
+    responses(
+        (status = 200, description = "Endpoint updated", body = ModelEndpoint),
+        (status = 400, description = "Invalid input", body = ErrorOutput),
+        (status = 404, description = "Endpoint not found", body = ErrorOutput),
+        (status = 403, description = "Not the owner of the workspace", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+/// Update a model endpoint of the workspace.
+pub(crate) async fn update_endpoint_handler(
+    Extension(user): Extension<User>,
+    Path(id): Path<u64>,
+    State(state): State<AppState>,
+    Json(input): Json<UpdateEndpoint>,
+) -> Result<impl IntoResponse, AppError> {
+    let endpoint = state
+        .update_endpoint(input, user.ws_id as _, id, user.id as _)
+        .await?;
+    Ok(Json(endpoint))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/workspace/endpoints/{id}",
+    params(
+        ("id" = u64, Path, description = "Endpoint id")
+    ),
+    responses(
+        (status = 204, description = "Endpoint deleted"),
+        (status = 404, description = "Endpoint not found", body = ErrorOutput),
+        (status = 403, description = "Not the owner of the workspace", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+/// Delete a model endpoint, its agents go back to the default endpoint.
+pub(crate) async fn delete_endpoint_handler(
+    Extension(user): Extension<User>,
+    Path(id): Path<u64>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    state
+        .delete_endpoint(user.ws_id as _, id, user.id as _)
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
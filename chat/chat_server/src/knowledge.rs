@@ -0,0 +1,175 @@
+use std::sync::Arc;
+
+use ai_sdk::{AiAdapter, AiService};
+use anyhow::anyhow;
+use chat_core::{AdapterType, AgentError, Citation, KnowledgeArgs, ModelEndpoint, knowledge_table};
+use sqlx::PgPool;
+use swiftide::{
+    query::{Query, search_strategies::SimilaritySingleEmbedding},
+    traits::Retrieve,
+};
+use swiftide_pgvector::{PgVector, PgVectorBuilder};
+
+use crate::{KnowledgeConfig, agent::model_adapter};
+
+// characters of a chunk kept in its citation
+const MAX_EXCERPT_CHARS: usize = 200;
+
+/// The knowledge collection of a reply agent: chunks indexed into a pgvector
+/// table by swiftide, searched by the embedding of the message
+pub struct Knowledge {
+    store: PgVector,
+    embed: Arc<AiAdapter>,
+    top_k: u64,
+}
+
+/// A chunk found for a message, the model cites it by its position from 1
+#[derive(Debug, Clone, PartialEq)]
+pub struct Source {
+    pub path: String,
+    pub chunk: String,
+}
+
+impl Knowledge {
+    /// Search the collection of the workspace with the embedding model of the
+    /// config, called at the endpoint if the workspace has one for its adapter
+    pub fn try_new(
+        pool: PgPool,
+        config: &KnowledgeConfig,
+        ws_id: i64,
+        args: &KnowledgeArgs,
+        endpoint: Option<&ModelEndpoint>,
+    ) -> Result<Self, AgentError> {
+        if matches!(config.adapter, AdapterType::Anthropic | AdapterType::Test) {
+            return Err(anyhow!("{:?} adapter has no embedding model", config.adapter).into());
+        }
+        let embed = Arc::new(model_adapter(
+            &config.adapter,
+            config.model.clone(),
+            endpoint,
+        )?);
+        let store = PgVectorBuilder::default()
+            .pool(pool)
+            .table_name(knowledge_table(ws_id, &args.collection))
+            .vector_size(config.vector_size)
+            .build()
+            .map_err(anyhow::Error::from)?;
+        Ok(Self {
+            store,
+            embed,
+            top_k: args.top_k,
+        })
+    }
+
+    /// the chunks closest to the text, most similar first
+    pub async fn search(&self, text: &str) -> Result<Vec<Source>, AgentError> {
+        let embedding = self
+            .embed
+            .embed(&[text.to_string()])
+            .await
+            .map_err(|e| AgentError::Network(e.to_string()))?
+            .pop();
+        let mut query = Query::from(text);
+        query.embedding = embedding;
+        let strategy: SimilaritySingleEmbedding = SimilaritySingleEmbedding::default()
+            .with_top_k(self.top_k)
+            .to_owned();
+        let query = self.store.retrieve(&strategy, query).await?;
+        let sources = query
+            .documents()
+            .iter()
+            .map(|doc| Source {
+                path: doc
+                    .metadata()
+                    .get("path")
+                    .and_then(|v| v.as_str())
+                    .unwrap_or_default()
+                    .to_string(),
+                chunk: doc.content().to_string(),
+            })
+            .collect();
+        Ok(sources)
+    }
+}
+
+/// The sources as a system message, the model should only answer from them
+pub fn sources_prompt(sources: &[Source]) -> Option<String> {
+    if sources.is_empty() {
+        return None;
+    }
+    let mut prompt = String::from(
+        "Answer with the sources below and cite the ones you use as [n]. \
+         If they don't answer the question, say so.\n",
+    );
+    for (i, source) in sources.iter().enumerate() {
+        prompt.push_str(&format!(
+            "\n[{}] {}\n{}\n",
+            i + 1,
+            source.path,
+            source.chunk.trim()
+        ));
+    }
+    Some(prompt)
+}
+
+/// Citations of the sources the reply refers to as `[n]`
+pub fn cited_sources(reply: &str, sources: &[Source]) -> Vec<Citation> {
+    sources
+        .iter()
+        .enumerate()
+        .map(|(i, source)| (i as u32 + 1, source))
+        .filter(|(index, _)| reply.contains(&format!("[{}]", index)))
+        .map(|(index, source)| Citation {
+            index,
+            source: source.path.clone(),
+            excerpt: excerpt(&source.chunk),
+        })
+        .collect()
+}
+
+fn excerpt(chunk: &str) -> String {
+    let chunk = chunk.trim();
+    match chunk.char_indices().nth(MAX_EXCERPT_CHARS) {
+        Some((i, _)) => format!("{}…", &chunk[..i]),
+        None => chunk.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sources() -> Vec<Source> {
+        vec![
+            Source {
+                path: "docs/setup.md".to_string(),
+                chunk: "Run `make db` to create the database.".to_string(),
+            },
+            Source {
+                path: "docs/deploy.md".to_string(),
+                chunk: "x".repeat(300),
+            },
+        ]
+    }
+
+    #[test]
+    fn sources_should_be_numbered_in_prompt() {
+        assert_eq!(sources_prompt(&[]), None);
+        let prompt = sources_prompt(&sources()).expect("prompt should exist");
+        assert!(prompt.contains("[1] docs/setup.md\nRun `make db`"));
+        assert!(prompt.contains("[2] docs/deploy.md\n"));
+    }
+
+    #[test]
+    fn only_cited_sources_should_be_returned() {
+        let citations = cited_sources("Run `make db` [1].", &sources());
+        assert_eq!(citations.len(), 1);
+        assert_eq!(citations[0].index, 1);
+        assert_eq!(citations[0].source, "docs/setup.md");
+
+        let citations = cited_sources("See [2] and [3]", &sources());
+        assert_eq!(citations.len(), 1);
+        assert_eq!(citations[0].excerpt.chars().count(), MAX_EXCERPT_CHARS + 1);
+        assert!(cited_sources("no idea", &sources()).is_empty());
+    }
+}
@@ -2,28 +2,43 @@ mod agent;
 mod config;
 mod error;
 mod handlers;
+mod knowledge;
 mod middlewares;
 mod models;
 mod openapi;
+mod prompt;
+mod tools;
+use ai_sdk::MockAdapter;
 use anyhow::Context;
 use axum::{
     Router,
     http::Method,
     middleware::from_fn_with_state,
-    routing::{get, post},
+    routing::{get, patch, post, put},
 };
 use chat_core::{DecodingKey, EncodingKey, TokenVerify, User, set_layer, verify_token};
 use sqlx::PgPool;
-use std::{fmt, ops::Deref, sync::Arc};
+use std::{
+    collections::HashMap,
+    fmt,
+    ops::Deref,
+    sync::{Arc, Mutex},
+};
 use tokio::fs;
 use tower_http::cors::{Any, CorsLayer};
 
 use crate::{middlewares::verify_chat, openapi::OpenApiRouter};
 pub use agent::*;
-pub use config::AppConfig;
+pub use config::{
+    ANY_MODEL, AgentConfig, AppConfig, EndpointConfig, JobConfig, KnowledgeConfig, LimitConfig,
+    ModelConfig,
+};
 pub use error::{AppError, ErrorOutput};
 use handlers::*;
+pub use knowledge::{Knowledge, Source, cited_sources, sources_prompt};
 pub use models::*;
+pub use prompt::{PROMPT_VARS, Prompt, render_prompt, validate_agent};
+pub use tools::{ChatTools, TOOL_NAMES};
 #[derive(Clone)]
 pub struct AppState {
     inner: Arc<AppStateInner>,
@@ -34,6 +49,9 @@ pub struct AppStateInner {
     pub(crate) dk: DecodingKey,
     pub(crate) ek: EncodingKey,
     pub(crate) pool: PgPool,
+    // models of `test` agents by agent id, with the args scripting them. They
+    // are kept so queued responses carry over retries, fallbacks and messages
+    pub(crate) mocks: Mutex<HashMap<i64, (serde_json::Value, MockAdapter)>>,
 }
 
 pub async fn get_router(state: AppState) -> Result<Router, AppError> {
@@ -47,11 +65,33 @@ pub async fn get_router(state: AppState) -> Result<Router, AppError> {
         )
         .route(
             "/{id}/agents",
-            get(list_agent_handler)
-                .post(create_agent_handler)
-                .patch(update_agent_handler),
+            get(list_agent_handler).post(create_agent_handler),
+        )
+        .route(
+            "/{id}/agents/{agent_id}",
+            get(get_agent_handler)
+                .patch(update_agent_handler)
+                .delete(delete_agent_handler),
+        )
+        .route(
+            "/{id}/agents/{agent_id}/preview",
+            post(preview_agent_handler),
+        )
+        .route(
+            "/{id}/agents/{agent_id}/quota",
+            get(get_agent_quota_handler),
         )
         .route("/{id}/messages", get(list_message_handler))
+        .route(
+            "/{id}/messages/{message_id}/outcomes",
+            get(list_agent_outcome_handler),
+        )
+        .route("/{id}/tap-outputs", get(list_tap_output_handler))
+        .route("/{id}/agent-usage", get(chat_agent_usage_handler))
+        .route(
+            "/{id}/notification",
+            get(get_notification_handler).put(update_notification_handler),
+        )
         .layer(from_fn_with_state(state.clone(), verify_chat))
         .route("/", get(list_chat_handler).post(create_chat_handler));
     let cors = CorsLayer::new()
@@ -66,6 +106,20 @@ pub async fn get_router(state: AppState) -> Result<Router, AppError> {
         .allow_headers(Any);
     let api = Router::new()
         .route("/users", get(list_chat_users_handler))
+        .route(
+            "/workspace/notification",
+            put(update_workspace_notification_handler),
+        )
+        .route("/workspace/agent-usage", get(workspace_agent_usage_handler))
+        .route("/workspace/agent-quota", get(workspace_agent_quota_handler))
+        .route(
+            "/workspace/endpoints",
+            get(list_endpoint_handler).post(create_endpoint_handler),
+        )
+        .route(
+            "/workspace/endpoints/{id}",
+            patch(update_endpoint_handler).delete(delete_endpoint_handler),
+        )
         .nest("/chats", chat)
         .route("/upload", post(upload_handler))
         .route("/files/{ws_id}/{*path}", get(file_handler))
@@ -112,6 +166,7 @@ impl AppState {
                 ek,
                 dk,
                 pool,
+                mocks: Default::default(),
             }),
         })
     }
@@ -145,11 +200,117 @@ mod test_util {
                     ek,
                     dk,
                     pool,
+                    mocks: Default::default(),
                 }),
             };
             Ok((tdb, state))
         }
     }
+
+    /// Args of a `test` agent whose model answers with the text, `$1` is the message
+    #[cfg(test)]
+    pub fn mock_args(text: &str) -> serde_json::Value {
+        let rule = serde_json::json!({"pattern": "(?s)^(.*)$", "response": {"text": text}});
+        serde_json::json!({"mock": {"rules": [rule]}})
+    }
+
+    /// Args of a `test` agent whose model is down
+    #[cfg(test)]
+    pub fn mock_error_args() -> serde_json::Value {
+        let error = serde_json::json!({"status": 503, "message": "model is down"});
+        serde_json::json!({"mock": {"default": {"error": error}}})
+    }
+
+    /// A message without files
+    #[cfg(test)]
+    pub fn message(content: &str) -> CreateMessage {
+        CreateMessage {
+            content: content.to_string(),
+            files: vec![],
+        }
+    }
+
+    /// Send the message to chat 3 as user 1
+    #[cfg(test)]
+    pub async fn send(state: &AppState, content: &str) -> Result<chat_core::Message, AppError> {
+        state.create_message(message(content), 3, 1).await
+    }
+
+    /// Add a `test` agent to chat 3
+    #[cfg(test)]
+    pub async fn add_agent(
+        state: &AppState,
+        name: &str,
+        r#type: chat_core::AgentType,
+        priority: i32,
+        args: serde_json::Value,
+    ) -> Result<chat_core::ChatAgent, AppError> {
+        let input = CreateAgent::new(name, r#type, chat_core::AdapterType::Test, "test", "", args)
+            .with_priority(priority);
+        state.create_agent(input, 3).await
+    }
+
+    /// Add a `test` agent calling the model to chat 3, with a policy given as JSON
+    #[cfg(test)]
+    pub async fn add_agent_with_policy(
+        state: &AppState,
+        name: &str,
+        r#type: chat_core::AgentType,
+        model: &str,
+        args: serde_json::Value,
+        policy: serde_json::Value,
+    ) -> Result<chat_core::ChatAgent, AppError> {
+        let policy = serde_json::from_value(policy).expect("invalid policy");
+        let input = CreateAgent::new(name, r#type, chat_core::AdapterType::Test, model, "", args)
+            .with_policy(policy);
+        state.create_agent(input, 3).await
+    }
+
+    /// An OpenAI compatible server answering with what it got as JSON: the
+    /// last message, the `x-team` header and the request without the messages.
+    /// Returns its base url.
+    #[cfg(test)]
+    pub async fn mock_openai_server() -> Result<String, AppError> {
+        use axum::{Json, http::HeaderMap, routing::post};
+        use serde_json::{Value, json};
+        use tokio::net::TcpListener;
+
+        let app = Router::new().route(
+            "/v1/chat/completions",
+            post(
+                |headers: HeaderMap, Json(mut req): Json<Value>| async move {
+                    let messages = req.as_object_mut().and_then(|r| r.remove("messages"));
+                    let last = messages
+                        .as_ref()
+                        .and_then(|m| m.as_array()?.last().cloned());
+                    let content = json!({
+                        "message": last.map(|m| m["content"].clone()),
+                        "team": headers.get("x-team").and_then(|v| v.to_str().ok()),
+                        "request": req,
+                    });
+                    Json(json!({
+                        "id": "chatcmpl-1",
+                        "object": "chat.completion",
+                        "created": 1724000000,
+                        "model": req["model"],
+                        "system_fingerprint": "fp_1",
+                        "choices": [{
+                            "index": 0,
+                            "message": {"role": "assistant", "content": content.to_string()},
+                            "logprobs": null,
+                            "finish_reason": "stop"
+                        }],
+                        "usage": {"prompt_tokens": 5, "completion_tokens": 3, "total_tokens": 8}
+                    }))
+                },
+            ),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        Ok(format!("http://{}/v1", addr))
+    }
+
     pub async fn get_test_pool(url: Option<&str>) -> (TestPg, PgPool) {
         let url = match url {
             Some(url) => url.to_string(),
@@ -13,6 +13,7 @@ async fn main() -> Result<()> {
     let addr = format!("0.0.0.0:{}", config.server.port);
 
     let state = AppState::try_new(config).await?;
+    state.spawn_job_workers();
     let app = chat_server::get_router(state).await?;
 
     let listener = TcpListener::bind(&addr).await?;
@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+
 use axum::{
     extract::{FromRequestParts, Path, Request, State},
     middleware::Next,
@@ -10,9 +12,13 @@ use chat_core::User;
 pub async fn verify_chat(State(state): State<AppState>, req: Request, next: Next) -> Response {
     //verify if user_id is a member of the chat
     let (mut parts, body) = req.into_parts();
-    let Path(chat_id) = Path::<u64>::from_request_parts(&mut parts, &state)
+    // nested routes have more path params than the chat id
+    let Path(params) = Path::<HashMap<String, String>>::from_request_parts(&mut parts, &state)
         .await
         .unwrap();
+    let Some(chat_id) = params.get("id").and_then(|id| id.parse::<u64>().ok()) else {
+        return AppError::NotFound("Chat id is missing or invalid".to_string()).into_response();
+    };
     let user = parts.extensions.get::<User>().unwrap();
     if !state
         .is_chat_member(chat_id, user.id as _)
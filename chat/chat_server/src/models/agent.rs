@@ -1,9 +1,21 @@
-use crate::{AppError, AppState};
-use chat_core::{AdapterType, AgentType, ChatAgent};
+use super::pipeline::{AgentStep, bot_fullname};
+use crate::{AppError, AppState, agent::mock_adapter, validate_agent};
+use chat_core::{
+    AdapterType, AgentDecision, AgentInvocationSource, AgentOutcomeStatus, AgentPolicy, AgentType,
+    ChatAgent, Citation, ContextMessage, ModelOptions, ResponseFormat, TokenUsage,
+};
 use serde::{Deserialize, Serialize};
+use sqlx::types::Json;
 use tracing::info;
 use utoipa::ToSchema;
 
+// bounds of the policy of an agent, a message send waits for its agents
+const MAX_TIMEOUT_SECS: u64 = 120;
+const MAX_RETRIES: u32 = 5;
+// bounds of the model options of an agent
+const MAX_COMPLETION_TOKENS: u32 = 32_768;
+const MAX_STOP_SEQUENCES: usize = 4;
+
 #[derive(Debug, Clone, Default, ToSchema, Serialize, Deserialize)]
 #[serde(rename_all(serialize = "camelCase"))]
 pub struct CreateAgent {
@@ -14,6 +26,16 @@ pub struct CreateAgent {
     pub prompt: String,
     #[serde(default = "default_map")]
     pub args: serde_json::Value,
+    // agents run in ascending priority
+    #[serde(default)]
+    pub priority: i32,
+    #[serde(default)]
+    pub policy: AgentPolicy,
+    #[serde(default)]
+    pub options: ModelOptions,
+    // unset uses the default endpoint of the adapter in the workspace
+    #[serde(default, alias = "endpointId")]
+    pub endpoint_id: Option<i64>,
 }
 
 fn default_map() -> serde_json::Value {
@@ -23,11 +45,67 @@ fn default_map() -> serde_json::Value {
 #[derive(Debug, Clone, Default, ToSchema, Serialize, Deserialize)]
 #[serde(rename_all(serialize = "camelCase"))]
 pub struct UpdateAgent {
+    // taken from the path when updated via the API
+    #[serde(default)]
     pub id: u64,
     #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub r#type: Option<AgentType>,
+    #[serde(default)]
+    pub adapter: Option<AdapterType>,
+    #[serde(default)]
+    pub model: Option<String>,
+    #[serde(default)]
     pub prompt: String,
     #[serde(default)]
     pub args: serde_json::Value,
+    #[serde(default)]
+    pub priority: Option<i32>,
+    #[serde(default)]
+    pub enabled: Option<bool>,
+    #[serde(default)]
+    pub policy: Option<AgentPolicy>,
+    #[serde(default)]
+    pub options: Option<ModelOptions>,
+    // 0 goes back to the default endpoint
+    #[serde(default, alias = "endpointId")]
+    pub endpoint_id: Option<i64>,
+}
+
+/// A message to run an agent on without posting it
+#[derive(Debug, Clone, Default, ToSchema, Serialize, Deserialize)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct PreviewAgent {
+    pub content: String,
+    // previous messages to use instead of the latest messages of the chat
+    #[serde(default)]
+    pub history: Option<Vec<PreviewMessage>>,
+}
+
+#[derive(Debug, Clone, Default, ToSchema, Serialize, Deserialize)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct PreviewMessage {
+    #[serde(alias = "senderId")]
+    pub sender_id: i64,
+    #[serde(default, alias = "senderName")]
+    pub sender_name: String,
+    pub content: String,
+}
+
+/// What an agent would do with a message, nothing of it is stored
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct AgentPreview {
+    pub status: AgentOutcomeStatus,
+    pub output: Option<String>,
+    pub error: Option<String>,
+    #[serde(alias = "latencyMs")]
+    pub latency_ms: i32,
+    pub usage: Option<TokenUsage>,
+    // sources a reply cites
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub citations: Vec<Citation>,
 }
 
 impl CreateAgent {
@@ -46,8 +124,27 @@ impl CreateAgent {
             model: model.into(),
             prompt: prompt.into(),
             args: serde_json::to_value(args).unwrap(),
+            priority: 0,
+            policy: AgentPolicy::default(),
+            options: ModelOptions::default(),
+            endpoint_id: None,
         }
     }
+
+    pub fn with_priority(mut self, priority: i32) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    pub fn with_policy(mut self, policy: AgentPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    pub fn with_options(mut self, options: ModelOptions) -> Self {
+        self.options = options;
+        self
+    }
 }
 
 impl UpdateAgent {
@@ -56,6 +153,7 @@ impl UpdateAgent {
             id,
             prompt: prompt.into(),
             args: serde_json::to_value(args).unwrap(),
+            ..Default::default()
         }
     }
 }
@@ -77,12 +175,34 @@ impl AppState {
             )));
         }
 
-        //TODO: check if adapter and model are valid for the agent type
+        self.check_model(&input.adapter, &input.model)
+            .map_err(AppError::CreateAgentError)?;
+        self.check_policy(&input.policy)
+            .map_err(AppError::CreateAgentError)?;
+        check_options(&input.options).map_err(AppError::CreateAgentError)?;
+        if let Some(endpoint_id) = input.endpoint_id
+            && let Some(reason) = self
+                .check_agent_endpoint(chat_id, endpoint_id, &input.adapter)
+                .await?
+        {
+            return Err(AppError::CreateAgentError(reason));
+        }
+        validate_agent(&input.r#type, &input.prompt, &input.args)
+            .map_err(AppError::CreateAgentError)?;
+        check_mock(&input.adapter, &input.args).map_err(AppError::CreateAgentError)?;
+        // keep chats.agents in sync
         let agent = sqlx::query_as(
             r#"
-            INSERT INTO chat_agents (chat_id, name, type, adapter, model, prompt, args)
-            VALUES ($1, $2, $3, $4, $5, $6, $7)
-            RETURNING *
+            WITH agent AS (
+                INSERT INTO chat_agents (chat_id, name, type, adapter, model, prompt, args, priority,
+                    policy, endpoint_id, options)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
+                RETURNING *
+            ), chat AS (
+                UPDATE chats SET agents = array_append(agents, (SELECT id FROM agent))
+                WHERE id = $1
+            )
+            SELECT * FROM agent
             "#,
         )
         .bind(chat_id as i64)
@@ -92,12 +212,54 @@ impl AppState {
         .bind(input.model)
         .bind(input.prompt)
         .bind(input.args)
+        .bind(input.priority)
+        .bind(Json(input.policy))
+        .bind(input.endpoint_id)
+        .bind(Json(input.options))
         .fetch_one(&self.pool)
         .await?;
 
         Ok(agent)
     }
 
+    /// check the model is in the catalog of the adapter
+    pub(crate) fn check_model(&self, adapter: &AdapterType, model: &str) -> Result<(), String> {
+        if self.config.models.is_supported(adapter, model) {
+            return Ok(());
+        }
+        let models = self.config.models.models(adapter);
+        if models.is_empty() {
+            return Err(format!("Adapter {:?} is disabled", adapter));
+        }
+        Err(format!(
+            "Model {} is not supported by adapter {:?}, available models: {}",
+            model,
+            adapter,
+            models.join(", ")
+        ))
+    }
+
+    /// check timeouts and retries are sane and the fallback model is in the catalog
+    pub(crate) fn check_policy(&self, policy: &AgentPolicy) -> Result<(), String> {
+        if let Some(secs) = policy.timeout_secs
+            && !(1..=MAX_TIMEOUT_SECS).contains(&secs)
+        {
+            return Err(format!(
+                "Timeout must be between 1 and {} seconds",
+                MAX_TIMEOUT_SECS
+            ));
+        }
+        if let Some(retries) = policy.max_retries
+            && retries > MAX_RETRIES
+        {
+            return Err(format!("At most {} retries are allowed", MAX_RETRIES));
+        }
+        if let Some(fallback) = &policy.fallback {
+            self.check_model(&fallback.adapter, &fallback.model)?;
+        }
+        Ok(())
+    }
+
     /// check if an agent name exists in a chat
     pub async fn agent_name_exists(&self, chat_id: u64, name: &str) -> Result<bool, AppError> {
         let exists = sqlx::query_scalar(
@@ -128,11 +290,26 @@ impl AppState {
         Ok(exists)
     }
 
-    /// List all agents in a chat
+    /// List all agents in a chat in execution order
     pub async fn list_agents(&self, chat_id: u64) -> Result<Vec<ChatAgent>, AppError> {
         let agents = sqlx::query_as(
             r#"
-            SELECT * FROM chat_agents WHERE chat_id = $1 ORDER BY id ASC
+            SELECT * FROM chat_agents WHERE chat_id = $1 ORDER BY priority ASC, id ASC
+            "#,
+        )
+        .bind(chat_id as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(agents)
+    }
+
+    /// List the agents of a chat which run on new messages, in execution order
+    pub async fn list_enabled_agents(&self, chat_id: u64) -> Result<Vec<ChatAgent>, AppError> {
+        let agents = sqlx::query_as(
+            r#"
+            SELECT * FROM chat_agents WHERE chat_id = $1 AND enabled
+            ORDER BY priority ASC, id ASC
             "#,
         )
         .bind(chat_id as i64)
@@ -142,55 +319,260 @@ impl AppState {
         Ok(agents)
     }
 
-    /// update an agent in a chat
+    /// get an agent of a chat by id
+    pub async fn get_agent_by_id(
+        &self,
+        chat_id: u64,
+        agent_id: u64,
+    ) -> Result<Option<ChatAgent>, AppError> {
+        let agent = sqlx::query_as(
+            r#"
+            SELECT * FROM chat_agents WHERE chat_id = $1 AND id = $2
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(agent_id as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(agent)
+    }
+
+    /// update an agent in a chat, an empty prompt or missing args keep the current ones
     pub async fn update_agent(
         &self,
         input: UpdateAgent,
         chat_id: u64,
     ) -> Result<ChatAgent, AppError> {
         let agent_id = input.id;
-        let prompt = input.prompt;
-        let args = input.args;
 
         // check if agent exists
-        if !self.agent_id_exists(chat_id, agent_id).await? {
+        let Some(agent) = self.get_agent_by_id(chat_id, agent_id).await? else {
             info!("Agent {agent_id} does not exist in chat {chat_id}");
             return Err(AppError::UpdateAgentError(format!(
                 "Agent {} does not exist",
                 agent_id
             )));
-        }
+        };
 
-        let agent = match (prompt.as_str(), &args) {
-            ("", _) => {
-                sqlx::query_as(
-                    r#"
-                    UPDATE chat_agents SET args = $1 WHERE chat_id = $2 AND id = $3 RETURNING *
-                    "#,
-                )
-                .bind(args)
-                .bind(chat_id as i64)
-                .bind(agent_id as i64)
-                .fetch_one(&self.pool)
-                .await?
-            }
-            (_, _) => {
-                sqlx::query_as(
-                    r#"
-                    UPDATE chat_agents SET prompt = $1, args = $2 WHERE chat_id = $3 AND id = $4 RETURNING *
-                    "#,
-                )
-                .bind(prompt)
-                .bind(args)
-                .bind(chat_id as i64)
-                .bind(agent_id as i64)
-                .fetch_one(&self.pool)
-                .await?
-            }
+        if let Some(name) = &input.name
+            && *name != agent.name
+            && self.agent_name_exists(chat_id, name).await?
+        {
+            return Err(AppError::UpdateAgentError(format!(
+                "Agent {} already exists",
+                name
+            )));
+        }
+        let r#type = input.r#type.unwrap_or(agent.r#type);
+        let adapter = input.adapter.unwrap_or(agent.adapter);
+        let model = input.model.unwrap_or(agent.model);
+        let prompt = match input.prompt.as_str() {
+            "" => agent.prompt,
+            _ => input.prompt,
+        };
+        let args = match input.args {
+            serde_json::Value::Null => agent.args.0,
+            args => args,
+        };
+        let policy = input.policy.unwrap_or(agent.policy.0);
+        let options = input.options.unwrap_or(agent.options.0);
+        self.check_model(&adapter, &model)
+            .map_err(AppError::UpdateAgentError)?;
+        self.check_policy(&policy)
+            .map_err(AppError::UpdateAgentError)?;
+        check_options(&options).map_err(AppError::UpdateAgentError)?;
+        let endpoint_id = match input.endpoint_id {
+            Some(0) => None,
+            Some(id) => Some(id),
+            None => agent.endpoint_id,
         };
+        // the endpoint has to fit a changed adapter too
+        if let Some(endpoint_id) = endpoint_id
+            && let Some(reason) = self
+                .check_agent_endpoint(chat_id, endpoint_id, &adapter)
+                .await?
+        {
+            return Err(AppError::UpdateAgentError(reason));
+        }
+        validate_agent(&r#type, &prompt, &args).map_err(AppError::UpdateAgentError)?;
+        check_mock(&adapter, &args).map_err(AppError::UpdateAgentError)?;
+
+        let agent: ChatAgent = sqlx::query_as(
+            r#"
+            UPDATE chat_agents
+            SET name = $3, type = $4, adapter = $5, model = $6, prompt = $7, args = $8,
+                priority = COALESCE($9, priority), enabled = COALESCE($10, enabled),
+                policy = $11, endpoint_id = $12, options = $13, updated_at = NOW()
+            WHERE chat_id = $1 AND id = $2 RETURNING *
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(agent_id as i64)
+        .bind(input.name.unwrap_or(agent.name))
+        .bind(r#type)
+        .bind(adapter)
+        .bind(model)
+        .bind(prompt)
+        .bind(args)
+        .bind(input.priority)
+        .bind(input.enabled)
+        .bind(Json(policy))
+        .bind(endpoint_id)
+        .bind(Json(options))
+        .fetch_one(&self.pool)
+        .await?;
+
+        // the bot replies under the name of the agent
+        if let Some(bot_id) = agent.bot_id {
+            sqlx::query("UPDATE users SET fullname = $2 WHERE id = $1")
+                .bind(bot_id)
+                .bind(bot_fullname(&agent.name))
+                .execute(&self.pool)
+                .await?;
+        }
 
         Ok(agent)
     }
+
+    /// run an agent on a message as if it was sent by the user, without touching
+    /// the chat. The model call is billed like any other, so it is recorded in
+    /// the usage log `agent_invocations` and counts against the quota
+    pub async fn preview_agent(
+        &self,
+        input: PreviewAgent,
+        chat_id: u64,
+        agent_id: u64,
+        user_id: u64,
+    ) -> Result<AgentPreview, AppError> {
+        let Some(agent) = self.get_agent_by_id(chat_id, agent_id).await? else {
+            return Err(AppError::NotFound(format!(
+                "Agent {} not found in chat {}",
+                agent_id, chat_id
+            )));
+        };
+
+        let mut ctx = self.build_agent_context(chat_id, user_id, None).await?;
+        if let Some(history) = input.history {
+            ctx.history = history
+                .into_iter()
+                .map(|m| ContextMessage {
+                    id: 0,
+                    sender_id: m.sender_id,
+                    sender_name: m.sender_name,
+                    content: m.content,
+                })
+                .collect();
+            ctx = ctx.with_token_budget(self.config.agent.token_budget);
+        }
+
+        let (step, decision) = AgentStep::run(self, &agent, &input.content, &ctx).await;
+        if let Some(reason) = step.limited {
+            return Err(AppError::QuotaExceeded(reason));
+        }
+        self.save_agent_invocations(
+            chat_id as _,
+            None,
+            AgentInvocationSource::Preview,
+            std::slice::from_ref(&step),
+        )
+        .await?;
+        // taps only observe, as they do when run from the job queue
+        let status = match (&agent.r#type, step.status) {
+            (AgentType::Tap, AgentOutcomeStatus::Modified) => AgentOutcomeStatus::Observed,
+            (_, status) => status,
+        };
+        Ok(AgentPreview {
+            status,
+            output: step.output,
+            error: step.error,
+            latency_ms: step.latency_ms,
+            usage: step.usage,
+            citations: match decision {
+                Some(AgentDecision::Reply(_, citations)) => citations,
+                _ => vec![],
+            },
+        })
+    }
+
+    /// delete an agent of a chat, its outcomes, jobs and tap outputs go with it
+    pub async fn delete_agent(&self, chat_id: u64, agent_id: u64) -> Result<(), AppError> {
+        let deleted: Option<i64> = sqlx::query_scalar(
+            r#"
+            WITH agent AS (
+                DELETE FROM chat_agents WHERE chat_id = $1 AND id = $2 RETURNING id
+            ), chat AS (
+                UPDATE chats SET agents = array_remove(agents, $2)
+                WHERE id = $1 AND EXISTS (SELECT 1 FROM agent)
+            )
+            SELECT id FROM agent
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(agent_id as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        match deleted {
+            Some(id) => {
+                self.mocks.lock().expect("agent mocks poisoned").remove(&id);
+                Ok(())
+            }
+            None => Err(AppError::NotFound(format!(
+                "Agent {} not found in chat {}",
+                agent_id, chat_id
+            ))),
+        }
+    }
+}
+
+/// check the model options are within the ranges the APIs accept
+fn check_options(options: &ModelOptions) -> Result<(), String> {
+    if let Some(temperature) = options.temperature
+        && !(0.0..=2.0).contains(&temperature)
+    {
+        return Err("Temperature must be between 0 and 2".to_string());
+    }
+    if let Some(top_p) = options.top_p
+        && !(0.0..=1.0).contains(&top_p)
+    {
+        return Err("Top p must be between 0 and 1".to_string());
+    }
+    if let Some(max_tokens) = options.max_tokens
+        && !(1..=MAX_COMPLETION_TOKENS).contains(&max_tokens)
+    {
+        return Err(format!(
+            "Max tokens must be between 1 and {}",
+            MAX_COMPLETION_TOKENS
+        ));
+    }
+    if options.stop.len() > MAX_STOP_SEQUENCES || options.stop.iter().any(|s| s.is_empty()) {
+        return Err(format!(
+            "At most {} non-empty stop sequences are allowed",
+            MAX_STOP_SEQUENCES
+        ));
+    }
+    if let ResponseFormat::JsonSchema { name, schema } = &options.response_format {
+        let valid_name = (1..=64).contains(&name.len())
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+        if !valid_name {
+            return Err(format!("Invalid JSON schema name {}", name));
+        }
+        if !schema.is_object() {
+            return Err("JSON schema must be an object".to_string());
+        }
+    }
+    Ok(())
+}
+
+/// check the script of the mock model of a test agent
+fn check_mock(adapter: &AdapterType, args: &serde_json::Value) -> Result<(), String> {
+    match adapter {
+        AdapterType::Test => mock_adapter(args).map(|_| ()),
+        _ => Ok(()),
+    }
 }
 
 #[cfg(test)]
@@ -269,4 +651,242 @@ mod tests {
         assert_eq!(agent.args, sqlx::types::Json(serde_json::json!({})));
         Ok(())
     }
+
+    #[tokio::test]
+    async fn invalid_agent_template_or_args_should_fail() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = CreateAgent::new(
+            "test",
+            AgentType::Proxy,
+            AdapterType::Test,
+            "test",
+            "Translate to {{ language }}: {{ message }}",
+            HashMap::<String, String>::new(),
+        );
+        let err = state.create_agent(input, 1).await.unwrap_err();
+        assert!(err.to_string().contains("language"));
+
+        let input = CreateAgent::new(
+            "summary",
+            AgentType::Tap,
+            AdapterType::Test,
+            "test",
+            "Summarize in {{ args.language }}: {{ message }}",
+            serde_json::json!({"kind": "summary", "language": "French"}),
+        );
+        let agent = state.create_agent(input, 1).await?;
+
+        let input = UpdateAgent::new(agent.id as _, "", serde_json::json!({"kind": 1}));
+        assert!(state.update_agent(input, 1).await.is_err());
+        let input = UpdateAgent::new(agent.id as _, "{% if %}", serde_json::Value::Null);
+        assert!(state.update_agent(input, 1).await.is_err());
+
+        // missing args keep the current ones
+        let input = UpdateAgent::new(agent.id as _, "Summarize: {{ message }}", ());
+        let agent = state.update_agent(input, 1).await?;
+        assert_eq!(agent.prompt, "Summarize: {{ message }}");
+        assert_eq!(agent.args.0["kind"], "summary");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn create_agent_with_unknown_model_should_fail() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = CreateAgent::new(
+            "test",
+            AgentType::Proxy,
+            AdapterType::Openai,
+            "gpt-2",
+            "You are a helpful assistant",
+            HashMap::<String, String>::new(),
+        );
+        let err = state.create_agent(input, 1).await.unwrap_err();
+        assert!(matches!(err, AppError::CreateAgentError(_)));
+        assert!(err.to_string().contains("gpt-4o-mini"));
+
+        // ollama accepts any model pulled into it by default
+        let input = CreateAgent::new(
+            "test",
+            AgentType::Proxy,
+            AdapterType::Ollama,
+            "llama3.2",
+            "You are a helpful assistant",
+            HashMap::<String, String>::new(),
+        );
+        assert!(state.create_agent(input, 1).await.is_ok());
+
+        let input = CreateAgent::new(
+            "claude",
+            AgentType::Proxy,
+            AdapterType::Anthropic,
+            "claude-2",
+            "You are a helpful assistant",
+            HashMap::<String, String>::new(),
+        );
+        let err = state.create_agent(input, 1).await.unwrap_err();
+        assert!(err.to_string().contains("claude-3-5-haiku-latest"));
+
+        // any model is an explicit choice, no model disables the adapter
+        let mut models = crate::ModelConfig::default();
+        assert!(models.is_supported(&AdapterType::Anthropic, "claude-sonnet-4-0"));
+        assert!(!models.is_supported(&AdapterType::Anthropic, "claude-2"));
+        models.ollama.clear();
+        assert!(!models.is_supported(&AdapterType::Ollama, "llama3.2"));
+        models.anthropic = vec![crate::ANY_MODEL.to_string()];
+        assert!(models.is_supported(&AdapterType::Anthropic, "claude-2"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn chat_agents_should_be_kept_in_sync() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = CreateAgent::new(
+            "test",
+            AgentType::Reply,
+            AdapterType::Test,
+            "test",
+            "",
+            HashMap::<String, String>::new(),
+        );
+        let agent = state.create_agent(input, 1).await?;
+        let chat = state.get_chat_by_id(1).await?.expect("chat should exist");
+        assert_eq!(chat.agents, vec![1, agent.id]);
+
+        state.delete_agent(1, agent.id as _).await?;
+        assert!(state.get_agent_by_id(1, agent.id as _).await?.is_none());
+        let chat = state.get_chat_by_id(1).await?.expect("chat should exist");
+        assert_eq!(chat.agents, vec![1]);
+
+        let err = state.delete_agent(2, 1).await.unwrap_err();
+        assert!(matches!(err, AppError::NotFound(_)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn update_agent_should_change_type_model_and_name() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = UpdateAgent {
+            id: 1,
+            name: Some("summary".to_string()),
+            r#type: Some(AgentType::Tap),
+            adapter: Some(AdapterType::Openai),
+            model: Some("gpt-4o".to_string()),
+            enabled: Some(false),
+            ..Default::default()
+        };
+        let agent = state.update_agent(input, 1).await?;
+        assert_eq!(agent.name, "summary");
+        assert_eq!(agent.r#type, AgentType::Tap);
+        assert_eq!(agent.adapter, AdapterType::Openai);
+        assert_eq!(agent.model, "gpt-4o");
+        assert!(!agent.enabled);
+        assert!(state.list_enabled_agents(1).await?.is_empty());
+
+        let input = UpdateAgent {
+            id: 1,
+            model: Some("gpt-2".to_string()),
+            ..Default::default()
+        };
+        assert!(state.update_agent(input, 1).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn preview_agent_should_only_record_usage() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = CreateAgent::new(
+            "echo",
+            AgentType::Reply,
+            AdapterType::Test,
+            "test",
+            "",
+            crate::test_util::mock_args("you said ${1}"),
+        );
+        let agent = state.create_agent(input, 1).await?;
+        let last = state.build_agent_context(1, 1, None).await?.history;
+
+        let input = PreviewAgent {
+            content: "hello".to_string(),
+            history: Some(vec![]),
+        };
+        let preview = state.preview_agent(input, 1, agent.id as _, 1).await?;
+        assert_eq!(preview.status, AgentOutcomeStatus::Replied);
+        assert_eq!(preview.output.as_deref(), Some("you said hello"));
+        assert!(preview.error.is_none());
+        let usage = preview.usage.expect("test agent should report usage");
+        assert!(usage.prompt_tokens > 0);
+        assert_eq!(usage.completion_tokens, 3);
+
+        assert_eq!(state.build_agent_context(1, 1, None).await?.history, last);
+        for table in ["agent_outcomes", "agent_jobs", "tap_outputs"] {
+            let sql = format!("SELECT COUNT(*) FROM {}", table);
+            let count: i64 = sqlx::query_scalar(&sql).fetch_one(&state.pool).await?;
+            assert_eq!(count, 0, "{} should be empty", table);
+        }
+        let sources: Vec<AgentInvocationSource> =
+            sqlx::query_scalar("SELECT source FROM agent_invocations")
+                .fetch_all(&state.pool)
+                .await?;
+        assert_eq!(sources, [AgentInvocationSource::Preview]);
+
+        let input = PreviewAgent::default();
+        let err = state.preview_agent(input, 2, agent.id as _, 1).await;
+        assert!(matches!(err, Err(AppError::NotFound(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn agent_options_should_be_checked_and_sent_to_model() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        state.update_workspace_owner(1, 1).await?;
+        let base_url = crate::test_util::mock_openai_server().await?;
+        let input = crate::CreateEndpoint::new("vllm", AdapterType::Openai, base_url);
+        let endpoint = state.create_endpoint(input, 1, 1).await?;
+
+        let options = |v| serde_json::from_value::<ModelOptions>(v);
+        let mut input = CreateAgent::new(
+            "formatter",
+            AgentType::Proxy,
+            AdapterType::Openai,
+            "gpt-4o-mini",
+            "Format as JSON: {{ message }}",
+            serde_json::json!({}),
+        )
+        .with_options(options(serde_json::json!({"temperature": 3.0}))?);
+        input.endpoint_id = Some(endpoint.id);
+        let ret = state.create_agent(input.clone(), 3).await;
+        assert!(matches!(ret, Err(AppError::CreateAgentError(e)) if e.contains("Temperature")));
+
+        let input = input.with_options(options(serde_json::json!({
+            "temperature": 0.5,
+            "maxTokens": 64,
+            "stop": ["END"],
+            "seed": 7,
+            "responseFormat": {"type": "json"}
+        }))?);
+        let agent = state.create_agent(input, 3).await?;
+        assert_eq!(agent.options.max_tokens, Some(64));
+
+        let preview = PreviewAgent {
+            content: "hello".to_string(),
+            history: Some(vec![]),
+        };
+        let preview = state.preview_agent(preview, 3, agent.id as _, 1).await?;
+        let output: serde_json::Value =
+            serde_json::from_str(preview.output.as_deref().unwrap_or_default())?;
+        let request = &output["request"];
+        assert_eq!(request["temperature"], 0.5);
+        assert_eq!(request["max_tokens"], 64);
+        assert_eq!(request["stop"], serde_json::json!(["END"]));
+        assert_eq!(request["seed"], 7);
+        assert_eq!(request["response_format"]["type"], "json_object");
+
+        let input = UpdateAgent {
+            options: Some(options(serde_json::json!({"stop": [""]}))?),
+            ..UpdateAgent::new(agent.id as _, "", serde_json::Value::Null)
+        };
+        let ret = state.update_agent(input, 3).await;
+        assert!(matches!(ret, Err(AppError::UpdateAgentError(_))));
+        Ok(())
+    }
 }
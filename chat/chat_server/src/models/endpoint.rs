@@ -0,0 +1,561 @@
+use std::collections::BTreeMap;
+
+use axum::http::{HeaderName, HeaderValue};
+use chat_core::{AdapterType, ChatAgent, ModelEndpoint, base_url_host, default_endpoint};
+use serde::{Deserialize, Serialize};
+use sqlx::types::Json;
+use utoipa::ToSchema;
+
+use crate::{AppError, AppState, EndpointConfig};
+
+#[derive(Debug, Clone, Default, ToSchema, Serialize, Deserialize)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct CreateEndpoint {
+    pub name: String,
+    pub adapter: AdapterType,
+    #[serde(alias = "baseUrl")]
+    pub base_url: String,
+    // name of an environment variable listed in `endpoints.api_keys` for the
+    // host of the base url
+    #[serde(default, alias = "apiKeyEnv")]
+    pub api_key_env: Option<String>,
+    #[serde(default, alias = "apiKeyHeader")]
+    pub api_key_header: Option<String>,
+    #[serde(default)]
+    pub organization: Option<String>,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    #[serde(default, alias = "isDefault")]
+    pub is_default: bool,
+}
+
+/// Unset fields keep their value, an empty string clears an optional one
+#[derive(Debug, Clone, Default, ToSchema, Serialize, Deserialize)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct UpdateEndpoint {
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default, alias = "baseUrl")]
+    pub base_url: Option<String>,
+    #[serde(default, alias = "apiKeyEnv")]
+    pub api_key_env: Option<String>,
+    #[serde(default, alias = "apiKeyHeader")]
+    pub api_key_header: Option<String>,
+    #[serde(default)]
+    pub organization: Option<String>,
+    #[serde(default)]
+    pub headers: Option<BTreeMap<String, String>>,
+    #[serde(default, alias = "isDefault")]
+    pub is_default: Option<bool>,
+}
+
+impl CreateEndpoint {
+    pub fn new(name: impl Into<String>, adapter: AdapterType, base_url: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            adapter,
+            base_url: base_url.into(),
+            ..Default::default()
+        }
+    }
+}
+
+impl AppState {
+    /// List the endpoints of a workspace, the values of their headers may be
+    /// credentials and are only shown to the owner
+    pub async fn list_endpoints(
+        &self,
+        ws_id: u64,
+        user_id: u64,
+    ) -> Result<Vec<ModelEndpoint>, AppError> {
+        let mut endpoints: Vec<ModelEndpoint> = sqlx::query_as(
+            r#"
+            SELECT * FROM model_endpoints WHERE ws_id = $1 ORDER BY id
+            "#,
+        )
+        .bind(ws_id as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        if self.check_workspace_owner(ws_id, user_id).await.is_err() {
+            for endpoint in endpoints.iter_mut() {
+                endpoint
+                    .headers
+                    .values_mut()
+                    .for_each(|v| *v = "***".to_string());
+            }
+        }
+        Ok(endpoints)
+    }
+
+    pub async fn get_endpoint_by_id(
+        &self,
+        ws_id: u64,
+        id: u64,
+    ) -> Result<Option<ModelEndpoint>, AppError> {
+        let endpoint = sqlx::query_as(
+            r#"
+            SELECT * FROM model_endpoints WHERE ws_id = $1 AND id = $2
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(id as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(endpoint)
+    }
+
+    /// Create an endpoint, a new default replaces the default of its adapter.
+    /// Only the owner of the workspace manages its endpoints.
+    pub async fn create_endpoint(
+        &self,
+        input: CreateEndpoint,
+        ws_id: u64,
+        user_id: u64,
+    ) -> Result<ModelEndpoint, AppError> {
+        self.check_workspace_owner(ws_id, user_id).await?;
+        if input.adapter == AdapterType::Test {
+            return Err(AppError::EndpointError(
+                "The test adapter has no endpoints".to_string(),
+            ));
+        }
+        let endpoint = ModelEndpoint {
+            id: 0,
+            ws_id: ws_id as _,
+            name: input.name,
+            adapter: input.adapter,
+            base_url: input.base_url.trim_end_matches('/').to_string(),
+            api_key_env: input.api_key_env,
+            api_key_header: input.api_key_header,
+            organization: input.organization,
+            headers: Json(input.headers),
+            is_default: input.is_default,
+            created_at: Default::default(),
+        };
+        self.check_endpoint(&endpoint).await?;
+
+        let mut tx = self.pool.begin().await?;
+        if endpoint.is_default {
+            clear_default(&mut tx, ws_id as _, &endpoint.adapter).await?;
+        }
+        let endpoint = sqlx::query_as(
+            r#"
+            INSERT INTO model_endpoints (ws_id, name, adapter, base_url, api_key_env,
+                api_key_header, organization, headers, is_default)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+            RETURNING *
+            "#,
+        )
+        .bind(endpoint.ws_id)
+        .bind(endpoint.name)
+        .bind(endpoint.adapter)
+        .bind(endpoint.base_url)
+        .bind(endpoint.api_key_env)
+        .bind(endpoint.api_key_header)
+        .bind(endpoint.organization)
+        .bind(endpoint.headers)
+        .bind(endpoint.is_default)
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(endpoint)
+    }
+
+    /// Update an endpoint, the agents using it call the new one right away
+    pub async fn update_endpoint(
+        &self,
+        input: UpdateEndpoint,
+        ws_id: u64,
+        id: u64,
+        user_id: u64,
+    ) -> Result<ModelEndpoint, AppError> {
+        self.check_workspace_owner(ws_id, user_id).await?;
+        let Some(mut endpoint) = self.get_endpoint_by_id(ws_id, id).await? else {
+            return Err(AppError::NotFound(format!("Endpoint {} not found", id)));
+        };
+        // an empty string clears the value
+        let clearable = |value: Option<String>, current: Option<String>| match value {
+            Some(v) if v.is_empty() => None,
+            Some(v) => Some(v),
+            None => current,
+        };
+        endpoint.name = input.name.unwrap_or(endpoint.name);
+        if let Some(base_url) = input.base_url {
+            endpoint.base_url = base_url.trim_end_matches('/').to_string();
+        }
+        endpoint.api_key_env = clearable(input.api_key_env, endpoint.api_key_env);
+        endpoint.api_key_header = clearable(input.api_key_header, endpoint.api_key_header);
+        endpoint.organization = clearable(input.organization, endpoint.organization);
+        if let Some(headers) = input.headers {
+            endpoint.headers = Json(headers);
+        }
+        endpoint.is_default = input.is_default.unwrap_or(endpoint.is_default);
+        self.check_endpoint(&endpoint).await?;
+
+        let mut tx = self.pool.begin().await?;
+        if endpoint.is_default {
+            clear_default(&mut tx, endpoint.ws_id, &endpoint.adapter).await?;
+        }
+        let endpoint = sqlx::query_as(
+            r#"
+            UPDATE model_endpoints
+            SET name = $3, base_url = $4, api_key_env = $5, api_key_header = $6,
+                organization = $7, headers = $8, is_default = $9
+            WHERE ws_id = $1 AND id = $2
+            RETURNING *
+            "#,
+        )
+        .bind(endpoint.ws_id)
+        .bind(endpoint.id)
+        .bind(endpoint.name)
+        .bind(endpoint.base_url)
+        .bind(endpoint.api_key_env)
+        .bind(endpoint.api_key_header)
+        .bind(endpoint.organization)
+        .bind(endpoint.headers)
+        .bind(endpoint.is_default)
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(endpoint)
+    }
+
+    /// Delete an endpoint, its agents go back to the default of their adapter
+    pub async fn delete_endpoint(&self, ws_id: u64, id: u64, user_id: u64) -> Result<(), AppError> {
+        self.check_workspace_owner(ws_id, user_id).await?;
+        let ret = sqlx::query("DELETE FROM model_endpoints WHERE ws_id = $1 AND id = $2")
+            .bind(ws_id as i64)
+            .bind(id as i64)
+            .execute(&self.pool)
+            .await?;
+        match ret.rows_affected() {
+            0 => Err(AppError::NotFound(format!("Endpoint {} not found", id))),
+            _ => Ok(()),
+        }
+    }
+
+    /// The endpoint an agent calls its model at: the one it names if it is of
+    /// its adapter (a fallback may use another one), else the workspace default
+    pub(crate) async fn agent_endpoint(
+        &self,
+        agent: &ChatAgent,
+        ws_id: i64,
+    ) -> Result<Option<ModelEndpoint>, AppError> {
+        if agent.adapter == AdapterType::Test {
+            return Ok(None);
+        }
+        let endpoint = sqlx::query_as(
+            r#"
+            SELECT * FROM model_endpoints
+            WHERE ws_id = $1 AND adapter = $2 AND (id = $3 OR is_default)
+            ORDER BY COALESCE(id = $3, FALSE) DESC
+            LIMIT 1
+            "#,
+        )
+        .bind(ws_id)
+        .bind(&agent.adapter)
+        .bind(agent.endpoint_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(endpoint.map(|e| self.config.endpoints.allowed_key(e)))
+    }
+
+    /// The endpoint of the adapter marked default in the workspace
+    pub(crate) async fn default_endpoint(
+        &self,
+        ws_id: i64,
+        adapter: &AdapterType,
+    ) -> Result<Option<ModelEndpoint>, AppError> {
+        let config = &self.config.endpoints;
+        Ok(default_endpoint(&self.pool, config, ws_id, adapter).await?)
+    }
+
+    /// Why an agent of the chat can't use the endpoint with its adapter, if it can't
+    pub(crate) async fn check_agent_endpoint(
+        &self,
+        chat_id: u64,
+        endpoint_id: i64,
+        adapter: &AdapterType,
+    ) -> Result<Option<String>, AppError> {
+        let endpoint_adapter: Option<AdapterType> = sqlx::query_scalar(
+            r#"
+            SELECT e.adapter FROM model_endpoints e
+            JOIN chats c ON c.ws_id = e.ws_id
+            WHERE c.id = $1 AND e.id = $2
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(endpoint_id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(match endpoint_adapter {
+            None => Some(format!("Endpoint {} not found", endpoint_id)),
+            Some(a) if a != *adapter => Some(format!(
+                "Endpoint {} is for adapter {:?}, not {:?}",
+                endpoint_id, a, adapter
+            )),
+            Some(_) => None,
+        })
+    }
+
+    async fn check_workspace_owner(&self, ws_id: u64, user_id: u64) -> Result<(), AppError> {
+        match self.find_workspace_by_id(ws_id).await? {
+            Some(ws) if ws.owner_id == user_id as i64 => Ok(()),
+            _ => Err(AppError::NotWorkspaceOwnerError { user_id, ws_id }),
+        }
+    }
+
+    async fn check_endpoint(&self, endpoint: &ModelEndpoint) -> Result<(), AppError> {
+        validate_endpoint(endpoint, &self.config.endpoints).map_err(AppError::EndpointError)?;
+        let taken: bool = sqlx::query_scalar(
+            r#"
+            SELECT EXISTS (SELECT 1 FROM model_endpoints WHERE ws_id = $1 AND name = $2 AND id != $3)
+            "#,
+        )
+        .bind(endpoint.ws_id)
+        .bind(&endpoint.name)
+        .bind(endpoint.id)
+        .fetch_one(&self.pool)
+        .await?;
+        if taken {
+            return Err(AppError::EndpointError(format!(
+                "Endpoint {} already exists",
+                endpoint.name
+            )));
+        }
+        Ok(())
+    }
+}
+
+async fn clear_default(
+    tx: &mut sqlx::PgConnection,
+    ws_id: i64,
+    adapter: &AdapterType,
+) -> Result<(), AppError> {
+    sqlx::query("UPDATE model_endpoints SET is_default = FALSE WHERE ws_id = $1 AND adapter = $2")
+        .bind(ws_id)
+        .bind(adapter)
+        .execute(tx)
+        .await?;
+    Ok(())
+}
+
+/// check the endpoint can be called, and only sends a key listed for its host
+fn validate_endpoint(endpoint: &ModelEndpoint, config: &EndpointConfig) -> Result<(), String> {
+    if endpoint.name.is_empty() || endpoint.name.chars().count() > 64 {
+        return Err("Endpoint name must have 1 to 64 characters".to_string());
+    }
+    let host = base_url_host(&endpoint.base_url)
+        .ok_or_else(|| format!("Base url {} must be an http(s) url", endpoint.base_url))?;
+    if let Some(env) = &endpoint.api_key_env
+        && !config.allows_key(env, &host)
+    {
+        return Err(format!(
+            "API key variable {} is not allowed for host {}",
+            env, host
+        ));
+    }
+    let header_names = endpoint
+        .api_key_header
+        .iter()
+        .chain(endpoint.headers.keys());
+    for name in header_names {
+        HeaderName::from_bytes(name.as_bytes())
+            .map_err(|_| format!("Invalid header name {}", name))?;
+    }
+    for (name, value) in endpoint.headers.iter() {
+        HeaderValue::from_str(value).map_err(|_| format!("Invalid value of header {}", name))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CreateAgent, PreviewAgent, UpdateAgent};
+    use anyhow::Result;
+    use chat_core::{AgentOutcomeStatus, AgentType};
+    use serde_json::{Value, json};
+
+    #[tokio::test]
+    async fn endpoint_should_be_validated() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        state.update_workspace_owner(1, 1).await?;
+        let input = CreateEndpoint {
+            api_key_env: Some("DATABASE_URL".to_string()),
+            ..CreateEndpoint::new("vllm", AdapterType::Openai, "http://vllm:8000/v1")
+        };
+        let ret = state.create_endpoint(input, 1, 1).await;
+        assert!(matches!(ret, Err(AppError::EndpointError(e)) if e.contains("DATABASE_URL")));
+
+        let input = CreateEndpoint::new("vllm", AdapterType::Openai, "ftp://vllm/v1");
+        assert!(state.create_endpoint(input, 1, 1).await.is_err());
+
+        let input = CreateEndpoint {
+            headers: BTreeMap::from([("bad header".to_string(), "x".to_string())]),
+            ..CreateEndpoint::new("vllm", AdapterType::Openai, "http://vllm:8000/v1")
+        };
+        assert!(state.create_endpoint(input, 1, 1).await.is_err());
+
+        // the server key only goes to the host it is listed for
+        let input = CreateEndpoint {
+            api_key_env: Some("OPENAI_API_KEY".to_string()),
+            ..CreateEndpoint::new("vllm", AdapterType::Openai, "http://vllm:8000/v1/")
+        };
+        let ret = state.create_endpoint(input, 1, 1).await;
+        assert!(matches!(ret, Err(AppError::EndpointError(e)) if e.contains("vllm")));
+
+        let input = CreateEndpoint {
+            api_key_env: Some("OPENAI_API_KEY".to_string()),
+            ..CreateEndpoint::new("openai", AdapterType::Openai, "https://api.openai.com/v1/")
+        };
+        let endpoint = state.create_endpoint(input.clone(), 1, 1).await?;
+        assert_eq!(endpoint.base_url, "https://api.openai.com/v1");
+        assert!(state.create_endpoint(input, 1, 1).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn only_owner_should_manage_endpoints() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        state.update_workspace_owner(1, 1).await?;
+        let input = CreateEndpoint {
+            headers: BTreeMap::from([("x-api-key".to_string(), "secret".to_string())]),
+            ..CreateEndpoint::new("vllm", AdapterType::Openai, "http://vllm:8000/v1")
+        };
+        let ret = state.create_endpoint(input.clone(), 1, 2).await;
+        assert!(matches!(ret, Err(AppError::NotWorkspaceOwnerError { .. })));
+        let endpoint = state.create_endpoint(input, 1, 1).await?;
+
+        let input = UpdateEndpoint {
+            is_default: Some(true),
+            ..Default::default()
+        };
+        let ret = state.update_endpoint(input, 1, endpoint.id as _, 2).await;
+        assert!(matches!(ret, Err(AppError::NotWorkspaceOwnerError { .. })));
+        let ret = state.delete_endpoint(1, endpoint.id as _, 2).await;
+        assert!(matches!(ret, Err(AppError::NotWorkspaceOwnerError { .. })));
+
+        // members see which headers are sent, not their values
+        let endpoints = state.list_endpoints(1, 2).await?;
+        assert_eq!(endpoints[0].headers["x-api-key"], "***");
+        let endpoints = state.list_endpoints(1, 1).await?;
+        assert_eq!(endpoints[0].headers["x-api-key"], "secret");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn agent_should_use_its_endpoint_or_the_default() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        state.update_workspace_owner(1, 1).await?;
+        let input = CreateEndpoint {
+            is_default: true,
+            ..CreateEndpoint::new("gateway", AdapterType::Openai, "http://gateway/v1")
+        };
+        let gateway = state.create_endpoint(input, 1, 1).await?;
+        let input = CreateEndpoint::new("vllm", AdapterType::Openai, "http://vllm/v1");
+        let vllm = state.create_endpoint(input, 1, 1).await?;
+
+        let agents = state.list_agents(1).await?;
+        let mut agent = agents[0].clone();
+        agent.adapter = AdapterType::Openai;
+        let endpoint = state.agent_endpoint(&agent, 1).await?;
+        assert_eq!(endpoint.map(|e| e.id), Some(gateway.id));
+        agent.endpoint_id = Some(vllm.id);
+        let endpoint = state.agent_endpoint(&agent, 1).await?;
+        assert_eq!(endpoint.map(|e| e.id), Some(vllm.id));
+        // the fallback adapter has no endpoint
+        agent.adapter = AdapterType::Ollama;
+        assert_eq!(state.agent_endpoint(&agent, 1).await?, None);
+
+        // a new default replaces the old one
+        let input = UpdateEndpoint {
+            is_default: Some(true),
+            ..Default::default()
+        };
+        let vllm = state.update_endpoint(input, 1, vllm.id as _, 1).await?;
+        assert!(vllm.is_default);
+        let gateway = state.get_endpoint_by_id(1, gateway.id as _).await?;
+        assert!(!gateway.expect("gateway should exist").is_default);
+        let endpoint = state.default_endpoint(1, &AdapterType::Openai).await?;
+        assert_eq!(endpoint.map(|e| e.id), Some(vllm.id));
+        assert_eq!(state.default_endpoint(1, &AdapterType::Ollama).await?, None);
+
+        state.delete_endpoint(1, vllm.id as _, 1).await?;
+        agent.adapter = AdapterType::Openai;
+        assert_eq!(state.agent_endpoint(&agent, 1).await?, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn agent_endpoint_should_be_of_the_workspace_and_adapter() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        state.update_workspace_owner(1, 1).await?;
+        let input = CreateEndpoint::new("local", AdapterType::Ollama, "http://ollama:11434");
+        let endpoint = state.create_endpoint(input, 1, 1).await?;
+
+        let mut input = CreateAgent::new(
+            "writer",
+            AgentType::Proxy,
+            AdapterType::Openai,
+            "gpt-4o-mini",
+            "You are a writer",
+            json!({}),
+        );
+        input.endpoint_id = Some(endpoint.id);
+        let ret = state.create_agent(input.clone(), 1).await;
+        assert!(matches!(ret, Err(AppError::CreateAgentError(e)) if e.contains("Ollama")));
+
+        input.adapter = AdapterType::Ollama;
+        input.model = "llama3.2".to_string();
+        let agent = state.create_agent(input, 1).await?;
+        assert_eq!(agent.endpoint_id, Some(endpoint.id));
+
+        // 0 goes back to the default endpoint
+        let update = UpdateAgent {
+            endpoint_id: Some(0),
+            ..UpdateAgent::new(agent.id as _, "", Value::Null)
+        };
+        let agent = state.update_agent(update, 1).await?;
+        assert_eq!(agent.endpoint_id, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn agent_should_call_compatible_endpoint() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        state.update_workspace_owner(1, 1).await?;
+        // an OpenAI compatible server, e.g. vLLM, which wants its team header
+        let base_url = crate::test_util::mock_openai_server().await?;
+        let input = CreateEndpoint {
+            headers: BTreeMap::from([("x-team".to_string(), "chat".to_string())]),
+            ..CreateEndpoint::new("vllm", AdapterType::Openai, base_url)
+        };
+        let endpoint = state.create_endpoint(input, 1, 1).await?;
+        let mut input = CreateAgent::new(
+            "rewriter",
+            AgentType::Proxy,
+            AdapterType::Openai,
+            "gpt-4o-mini",
+            "Rewrite: {{ message }}",
+            json!({}),
+        );
+        input.endpoint_id = Some(endpoint.id);
+        let agent = state.create_agent(input, 3).await?;
+
+        let preview = PreviewAgent {
+            content: "hello".to_string(),
+            history: Some(vec![]),
+        };
+        let preview = state.preview_agent(preview, 3, agent.id as _, 1).await?;
+        assert_eq!(preview.status, AgentOutcomeStatus::Modified);
+        let output: Value = serde_json::from_str(preview.output.as_deref().unwrap_or_default())?;
+        assert_eq!(output["message"], "Rewrite: hello");
+        assert_eq!(output["team"], "chat");
+        Ok(())
+    }
+}
@@ -0,0 +1,487 @@
+use std::time::Duration;
+
+use chat_core::{
+    AgentArgs, AgentContext, AgentInvocationSource, AgentJob, AgentOutcomeStatus, AgentType,
+    ChatAgent, Message, TapArgs, TapOutput,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::PgConnection;
+use tracing::{info, warn};
+use utoipa::{IntoParams, ToSchema};
+
+use super::pipeline::AgentStep;
+use crate::{AppError, AppState};
+
+// a running job not kept alive by then belongs to a worker which went away
+const JOB_LOCK_TIMEOUT_SECS: u64 = 60;
+// workers refresh `locked_at` of their job, however long the agent takes
+const JOB_HEARTBEAT_SECS: u64 = 15;
+const MAX_JOB_BACKOFF_SECS: u64 = 3600;
+
+#[derive(Debug, Clone, IntoParams, ToSchema, Serialize, Deserialize)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct ListTapOutputs {
+    #[serde(default)]
+    pub kind: Option<String>,
+    #[serde(default, alias = "agentId")]
+    pub agent_id: Option<u64>,
+    #[serde(default, alias = "lastId")]
+    pub last_id: Option<u64>,
+    #[serde(default)]
+    pub limit: u64,
+}
+
+impl AppState {
+    /// Queue a job for every tap agent of the chat and every reply agent the
+    /// message asks, in the transaction storing the message
+    pub(crate) async fn enqueue_agent_jobs(
+        &self,
+        tx: &mut PgConnection,
+        agents: &[ChatAgent],
+        message: &Message,
+        ctx: &AgentContext,
+    ) -> Result<(), AppError> {
+        let asked = |agent: &ChatAgent| match &ctx.chat {
+            Some(chat) => self.reply_request(agent, message, chat).is_some(),
+            None => false,
+        };
+        let reply_ids: Vec<i64> = agents
+            .iter()
+            .filter(|a| a.r#type == AgentType::Reply && asked(a))
+            .map(|a| a.id)
+            .collect();
+        let tap_ids: Vec<i64> = agents
+            .iter()
+            .filter(|a| a.r#type == AgentType::Tap)
+            .map(|a| a.id)
+            .collect();
+        for (kind, ids) in [(AgentType::Reply, reply_ids), (AgentType::Tap, tap_ids)] {
+            if ids.is_empty() {
+                continue;
+            }
+            // in the order of the agents, replies run one after the other
+            sqlx::query(
+                r#"
+                INSERT INTO agent_jobs (agent_id, chat_id, message_id, kind)
+                SELECT agent_id, $2, $3, $4
+                FROM UNNEST($1::BIGINT[]) WITH ORDINALITY AS t(agent_id, n)
+                ORDER BY n
+                "#,
+            )
+            .bind(&ids)
+            .bind(message.chat_id)
+            .bind(message.id)
+            .bind(kind)
+            .execute(&mut *tx)
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Start the workers processing queued jobs
+    pub fn spawn_job_workers(&self) {
+        let poll_interval = Duration::from_millis(self.config.jobs.poll_interval_ms);
+        for _ in 0..self.config.jobs.workers {
+            let state = self.clone();
+            tokio::spawn(async move {
+                loop {
+                    match state.run_next_job().await {
+                        Ok(Some(_)) => continue,
+                        Ok(None) => {}
+                        Err(e) => warn!("Failed to run agent job: {}", e),
+                    }
+                    tokio::time::sleep(poll_interval).await;
+                }
+            });
+        }
+    }
+
+    /// Claim the next due job and run it, returns None if there is nothing to do
+    pub async fn run_next_job(&self) -> Result<Option<AgentJob>, AppError> {
+        let Some(job) = self.claim_job().await? else {
+            return Ok(None);
+        };
+        let id = job.id;
+        let ret = tokio::select! {
+            ret = self.run_job(job.clone()) => ret,
+            _ = self.keep_job_locked(id) => unreachable!("the heartbeat never ends"),
+        };
+        match ret {
+            Ok(job) => Ok(job),
+            Err(e) => {
+                // don't leave the job running until its lock times out
+                if let Err(e) = self.finish_job(job, Some(&e.to_string())).await {
+                    warn!("Failed to mark agent job {} as failed: {}", id, e);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    async fn run_job(&self, job: AgentJob) -> Result<Option<AgentJob>, AppError> {
+        let agent: Option<ChatAgent> = sqlx::query_as("SELECT * FROM chat_agents WHERE id = $1")
+            .bind(job.agent_id)
+            .fetch_optional(&self.pool)
+            .await?;
+        let message: Option<Message> = sqlx::query_as("SELECT * FROM messages WHERE id = $1")
+            .bind(job.message_id)
+            .fetch_optional(&self.pool)
+            .await?;
+        let (Some(agent), Some(message)) = (agent, message) else {
+            return self.finish_job(job, Some("agent or message is gone")).await;
+        };
+        if !agent.enabled {
+            return self.finish_job(job, Some("agent is disabled")).await;
+        }
+
+        let ctx = self
+            .build_agent_context(job.chat_id as _, message.sender_id as _, Some(message.id))
+            .await?;
+        if job.kind == AgentType::Reply {
+            return self.run_reply_job(job, &agent, &message, &ctx).await;
+        }
+
+        let content = message
+            .modified_content
+            .as_deref()
+            .unwrap_or(&message.content);
+        let (mut step, _) = AgentStep::run(self, &agent, content, &ctx).await;
+        if step.limited.is_some() {
+            let error = step.error.clone();
+            self.save_agent_outcomes(job.chat_id, Some(job.message_id), &[step])
+                .await?;
+            return self.finish_job(job, error.as_deref()).await;
+        }
+        // retries are recorded too, every run costs
+        self.save_agent_invocations(
+            job.chat_id,
+            Some(job.message_id),
+            AgentInvocationSource::Job,
+            std::slice::from_ref(&step),
+        )
+        .await?;
+        if step.status == AgentOutcomeStatus::Failed {
+            let error = step.error.clone().unwrap_or_default();
+            if job.attempts < self.config.jobs.max_attempts {
+                return self.retry_job(job, &error).await;
+            }
+            self.save_agent_outcomes(job.chat_id, Some(job.message_id), &[step])
+                .await?;
+            return self.finish_job(job, Some(&error)).await;
+        }
+
+        // taps never change the conversation, they only observe it
+        step.status = AgentOutcomeStatus::Observed;
+        if let Some(output) = &step.output {
+            let kind = match agent.typed_args() {
+                Ok(AgentArgs::Tap(args)) => args.kind,
+                _ => TapArgs::default().kind,
+            };
+            sqlx::query(
+                r#"
+                INSERT INTO tap_outputs (agent_id, chat_id, message_id, kind, output)
+                VALUES ($1, $2, $3, $4, $5)
+                "#,
+            )
+            .bind(agent.id)
+            .bind(job.chat_id)
+            .bind(job.message_id)
+            .bind(kind)
+            .bind(output)
+            .execute(&self.pool)
+            .await?;
+        }
+        self.save_agent_outcomes(job.chat_id, Some(job.message_id), &[step])
+            .await?;
+        self.finish_job(job, None).await
+    }
+
+    /// A reply is never retried by the queue, it may be partly posted already.
+    /// The agent retries within its policy.
+    async fn run_reply_job(
+        &self,
+        job: AgentJob,
+        agent: &ChatAgent,
+        message: &Message,
+        ctx: &AgentContext,
+    ) -> Result<Option<AgentJob>, AppError> {
+        let Some(step) = self.run_reply(agent, message, ctx).await? else {
+            return self.finish_job(job, None).await;
+        };
+        let error = match step.status {
+            AgentOutcomeStatus::Failed | AgentOutcomeStatus::Skipped => step.error.clone(),
+            _ => None,
+        };
+        self.record_agent_steps(job.chat_id, Some(job.message_id), &[step])
+            .await?;
+        self.finish_job(job, error.as_deref()).await
+    }
+
+    /// List outputs of tap agents in a chat, newest first
+    pub async fn list_tap_outputs(
+        &self,
+        input: ListTapOutputs,
+        chat_id: u64,
+    ) -> Result<Vec<TapOutput>, AppError> {
+        let last_id = input.last_id.unwrap_or(i64::MAX as _);
+        let limit = match input.limit {
+            0 => i64::MAX,
+            1..=100 => input.limit as _,
+            _ => 100,
+        };
+        let outputs = sqlx::query_as(
+            r#"
+            SELECT id, agent_id, chat_id, message_id, kind, output, created_at
+            FROM tap_outputs
+            WHERE chat_id = $1
+            AND id < $2
+            AND ($3::VARCHAR IS NULL OR kind = $3)
+            AND ($4::BIGINT IS NULL OR agent_id = $4)
+            ORDER BY id DESC
+            LIMIT $5
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(last_id as i64)
+        .bind(input.kind)
+        .bind(input.agent_id.map(|id| id as i64))
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(outputs)
+    }
+
+    /// Run the due jobs until there are none left
+    #[cfg(test)]
+    pub(crate) async fn run_jobs(&self) -> Result<Vec<AgentJob>, AppError> {
+        let mut jobs = Vec::new();
+        while let Some(job) = self.run_next_job().await? {
+            jobs.push(job);
+        }
+        Ok(jobs)
+    }
+
+    async fn claim_job(&self) -> Result<Option<AgentJob>, AppError> {
+        // jobs of workers which went away are reclaimed, unless they used up
+        // their attempts. A reply is never run twice, it may be partly posted
+        sqlx::query(
+            r#"
+            UPDATE agent_jobs
+            SET status = 'failed', last_error = 'worker went away', locked_at = NULL,
+                updated_at = NOW()
+            WHERE status = 'running' AND locked_at < NOW() - make_interval(secs => $1)
+            AND (kind = 'reply' OR attempts >= $2)
+            "#,
+        )
+        .bind(JOB_LOCK_TIMEOUT_SECS as f64)
+        .bind(self.config.jobs.max_attempts)
+        .execute(&self.pool)
+        .await?;
+        let job = sqlx::query_as(
+            r#"
+            UPDATE agent_jobs
+            SET status = 'running', attempts = attempts + 1, locked_at = NOW(), updated_at = NOW()
+            WHERE id = (
+                SELECT id FROM agent_jobs j
+                WHERE ((status = 'pending' AND run_at <= NOW())
+                    OR (status = 'running' AND locked_at < NOW() - make_interval(secs => $1)
+                        AND attempts < $2))
+                -- replies to a message wait for the replies before them
+                AND (kind != 'reply' OR NOT EXISTS (
+                    SELECT 1 FROM agent_jobs p
+                    WHERE p.message_id = j.message_id AND p.kind = 'reply' AND p.id < j.id
+                    AND p.status IN ('pending', 'running')
+                ))
+                ORDER BY run_at ASC, id ASC
+                FOR UPDATE SKIP LOCKED
+                LIMIT 1
+            )
+            RETURNING id, agent_id, kind, chat_id, message_id, status, attempts, last_error, run_at
+            "#,
+        )
+        .bind(JOB_LOCK_TIMEOUT_SECS as f64)
+        .bind(self.config.jobs.max_attempts)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(job)
+    }
+
+    /// refresh the lock of a running job until the future is dropped
+    async fn keep_job_locked(&self, id: i64) {
+        let mut interval = tokio::time::interval(Duration::from_secs(JOB_HEARTBEAT_SECS));
+        // the first tick completes at once, the job was just locked
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let ret = sqlx::query(
+                "UPDATE agent_jobs SET locked_at = NOW() WHERE id = $1 AND status = 'running'",
+            )
+            .bind(id)
+            .execute(&self.pool)
+            .await;
+            if let Err(e) = ret {
+                warn!("Failed to keep agent job {} locked: {}", id, e);
+            }
+        }
+    }
+
+    async fn retry_job(&self, job: AgentJob, error: &str) -> Result<Option<AgentJob>, AppError> {
+        let backoff = job_backoff(self.config.jobs.backoff_secs, job.attempts);
+        info!(
+            "Agent job {} failed (attempt {}), retry in {}s: {}",
+            job.id, job.attempts, backoff, error
+        );
+        let job = sqlx::query_as(
+            r#"
+            UPDATE agent_jobs
+            SET status = 'pending', last_error = $2, locked_at = NULL, updated_at = NOW(),
+                run_at = NOW() + make_interval(secs => $3)
+            WHERE id = $1
+            RETURNING id, agent_id, kind, chat_id, message_id, status, attempts, last_error, run_at
+            "#,
+        )
+        .bind(job.id)
+        .bind(error)
+        .bind(backoff as f64)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(Some(job))
+    }
+
+    async fn finish_job(
+        &self,
+        job: AgentJob,
+        error: Option<&str>,
+    ) -> Result<Option<AgentJob>, AppError> {
+        let job = sqlx::query_as(
+            r#"
+            UPDATE agent_jobs
+            SET status = CASE WHEN $2::TEXT IS NULL THEN 'done' ELSE 'failed' END::job_status,
+                last_error = COALESCE($2, last_error), locked_at = NULL, updated_at = NOW()
+            WHERE id = $1
+            RETURNING id, agent_id, kind, chat_id, message_id, status, attempts, last_error, run_at
+            "#,
+        )
+        .bind(job.id)
+        .bind(error)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(Some(job))
+    }
+}
+
+/// delay before the next attempt, doubled on every attempt up to an hour
+fn job_backoff(backoff_secs: u64, attempts: i32) -> u64 {
+    let exp = attempts.max(1) as u32 - 1;
+    2u64.checked_pow(exp)
+        .and_then(|factor| backoff_secs.checked_mul(factor))
+        .unwrap_or(MAX_JOB_BACKOFF_SECS)
+        .min(MAX_JOB_BACKOFF_SECS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{add_agent, mock_args, mock_error_args, send};
+    use anyhow::Result;
+    use chat_core::JobStatus;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn tap_job_should_store_output() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let tap = add_agent(
+            &state,
+            "summary",
+            AgentType::Tap,
+            0,
+            json!({"mock": mock_args("summary of ${1}")["mock"], "kind": "summary"}),
+        )
+        .await?;
+        let msg = send(&state, "hello").await?;
+
+        let job = state.run_next_job().await?.expect("job should be queued");
+        assert_eq!(job.status, JobStatus::Done);
+        assert_eq!(job.agent_id, tap.id);
+        assert!(state.run_next_job().await?.is_none());
+
+        let input = ListTapOutputs {
+            kind: Some("summary".to_string()),
+            agent_id: None,
+            last_id: None,
+            limit: 0,
+        };
+        let outputs = state.list_tap_outputs(input, 3).await?;
+        assert_eq!(outputs.len(), 1);
+        assert_eq!(outputs[0].message_id, msg.id);
+        assert_eq!(outputs[0].output, "summary of hello");
+
+        let outcomes = state.list_agent_outcomes(3, msg.id as _).await?;
+        assert_eq!(outcomes[0].status, AgentOutcomeStatus::Observed);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn failed_tap_job_should_retry_with_backoff() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        add_agent(&state, "broken", AgentType::Tap, 0, mock_error_args()).await?;
+        let msg = send(&state, "hello").await?;
+
+        let job = state.run_next_job().await?.expect("job should be queued");
+        assert_eq!(job.status, JobStatus::Pending);
+        assert_eq!(job.attempts, 1);
+        assert!(job.last_error.is_some());
+        // not due before the backoff
+        assert!(job.run_at > chrono::Utc::now());
+        assert!(state.run_next_job().await?.is_none());
+
+        // the last attempt fails the job for good
+        let max_attempts = state.config.jobs.max_attempts;
+        sqlx::query("UPDATE agent_jobs SET run_at = NOW(), attempts = $1 WHERE id = $2")
+            .bind(max_attempts - 1)
+            .bind(job.id)
+            .execute(&state.pool)
+            .await?;
+        let job = state.run_next_job().await?.expect("job should be due");
+        assert_eq!(job.status, JobStatus::Failed);
+        assert_eq!(job.attempts, max_attempts);
+
+        let outcomes = state.list_agent_outcomes(3, msg.id as _).await?;
+        assert_eq!(outcomes.len(), 1);
+        assert_eq!(outcomes[0].status, AgentOutcomeStatus::Failed);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stale_job_should_not_be_reclaimed_after_last_attempt() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        add_agent(&state, "summary", AgentType::Tap, 0, json!({})).await?;
+        send(&state, "hello").await?;
+
+        // the worker running the last attempt went away
+        sqlx::query(
+            r#"
+            UPDATE agent_jobs SET status = 'running', attempts = $1,
+                locked_at = NOW() - INTERVAL '1 hour'
+            "#,
+        )
+        .bind(state.config.jobs.max_attempts)
+        .execute(&state.pool)
+        .await?;
+        assert!(state.run_next_job().await?.is_none());
+        let status: JobStatus = sqlx::query_scalar("SELECT status FROM agent_jobs")
+            .fetch_one(&state.pool)
+            .await?;
+        assert_eq!(status, JobStatus::Failed);
+        Ok(())
+    }
+
+    #[test]
+    fn job_backoff_should_be_capped() {
+        assert_eq!(job_backoff(5, 1), 5);
+        assert_eq!(job_backoff(5, 3), 20);
+        assert_eq!(job_backoff(5, 40), MAX_JOB_BACKOFF_SECS);
+        assert_eq!(job_backoff(5, 100), MAX_JOB_BACKOFF_SECS);
+        assert_eq!(job_backoff(u64::MAX, 2), MAX_JOB_BACKOFF_SECS);
+    }
+}
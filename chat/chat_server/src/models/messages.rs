@@ -3,8 +3,8 @@ use std::str::FromStr;
 use serde::{Deserialize, Serialize};
 use utoipa::{IntoParams, ToSchema};
 
-use crate::{AppError, AppState, ChatFile, agent::AgentVariant};
-use chat_core::{Agent, AgentContext, AgentDecision, ChatType, Message};
+use crate::{AppError, AppState, ChatFile};
+use chat_core::{AgentContext, Message};
 use tracing::warn;
 
 #[derive(Debug, Clone, ToSchema, Serialize, Deserialize)]
@@ -48,28 +48,26 @@ impl AppState {
             }
         }
 
-        // if we have agent, apply it and get the result
-        let mut agents = self.list_agents(chat_id).await?;
-        let decision = if let Some(agent) = agents.pop() {
-            let agent: AgentVariant = agent.into();
-            match agent {
-                AgentVariant::Proxy(agent) => {
-                    agent
-                        .process(&input.content, &AgentContext::default())
-                        .await?
-                }
-                _ => AgentDecision::None,
-            }
+        // run the agents of the chat: proxies before the message is stored,
+        // replies and taps after it from the job queue
+        let agents = self.list_enabled_agents(chat_id).await?;
+        let ctx = if agents.is_empty() {
+            AgentContext::default()
         } else {
-            AgentDecision::None
-        };
-
-        let modified_content = match decision {
-            AgentDecision::Modify(ref s) => Some(s),
-            _ => None,
+            self.build_agent_context(chat_id, user_id, None).await?
         };
+        let proxies = self.run_proxies(&agents, &input.content, &ctx).await;
+        if let Some(name) = proxies.rejected_by {
+            self.record_agent_steps(chat_id as i64, None, &proxies.steps)
+                .await?;
+            return Err(AppError::CreateMessageError(format!(
+                "Message rejected by agent {}",
+                name
+            )));
+        }
 
-        // create message
+        // the message and its agent jobs are stored together
+        let mut tx = self.pool.begin().await?;
         let message: Message = sqlx::query_as(
             r#"
             INSERT INTO messages (chat_id, sender_id, content, modified_content, files)
@@ -80,39 +78,26 @@ impl AppState {
         .bind(chat_id as i64)
         .bind(user_id as i64)
         .bind(input.content)
-        .bind(modified_content)
+        .bind(proxies.modified_content)
         .bind(&input.files)
-        .fetch_one(&self.pool)
+        .fetch_one(&mut *tx)
         .await?;
+        self.enqueue_agent_jobs(&mut tx, &agents, &message, &ctx)
+            .await?;
+        tx.commit().await?;
 
-        // if decision is reply, create a new message
-        if let AgentDecision::Reply(reply) = decision {
-            let chat = self
-                .get_chat_by_id(chat_id)
-                .await?
-                .expect("chat should exist");
-            if chat.r#type != ChatType::Single {
-                warn!(
-                    "reply decision found in non single chat {}. reply: {}",
-                    chat_id, reply
-                );
+        // the message is sent, failing to record what the agents did mustn't
+        // fail the request, the client would send it again
+        for (agent, reply) in proxies.replies {
+            if let Err(e) = self.post_proxy_reply(&agent, &ctx, &message, reply).await {
+                warn!("Failed to post reply of agent {}: {}", agent.name, e);
             }
-            let other_user_id = chat
-                .members
-                .into_iter()
-                .find(|m| m != &(user_id as i64))
-                .expect("other user should exist");
-            sqlx::query(
-                r#"
-                INSERT INTO messages (chat_id, sender_id, content)
-                VALUES ($1, $2, $3)
-                "#,
-            )
-            .bind(chat_id as i64)
-            .bind(other_user_id)
-            .bind(reply)
-            .execute(&self.pool)
-            .await?;
+        }
+        if let Err(e) = self
+            .record_agent_steps(chat_id as i64, Some(message.id), &proxies.steps)
+            .await
+        {
+            warn!("Failed to record agents of message {}: {}", message.id, e);
         }
 
         Ok(message)
@@ -131,7 +116,7 @@ impl AppState {
         };
         let messages: Vec<Message> = sqlx::query_as(
             r#"
-            SELECT id, chat_id, sender_id, content, modified_content, files, created_at
+            SELECT id, chat_id, sender_id, content, modified_content, files, status, citations, created_at
             FROM messages
             WHERE chat_id = $1
             AND id < $2
@@ -153,6 +138,7 @@ mod tests {
 
     use super::*;
     use anyhow::Result;
+    use chat_core::EventOutbox;
 
     #[tokio::test]
     #[ignore] // Ignore this test if you don't have a test database set up
@@ -218,6 +204,33 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn long_message_should_go_through_outbox() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let outbox = EventOutbox::new(state.pool.clone(), "test");
+        outbox.register().await?;
+
+        // larger than the 8000 bytes pg_notify payload limit
+        let input = CreateMessage {
+            content: "a".repeat(10_000),
+            files: vec![],
+        };
+        let message = state.create_message(input, 1, 1).await?;
+
+        let events = outbox.pending(10).await?;
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].channel, "chat_message_created");
+        assert_eq!(events[0].payload["message"]["id"], message.id);
+        assert_eq!(
+            events[0].payload["members"],
+            serde_json::json!([1, 2, 3, 4, 5])
+        );
+
+        outbox.ack(events[0].id).await?;
+        assert!(outbox.pending(10).await?.is_empty());
+        Ok(())
+    }
+
     fn upload_dummy_file(state: &AppState) -> Result<String> {
         let file = ChatFile::new(1, "test.txt", b"hello world");
         let path = file.path(&state.config.server.base_dir);
@@ -1,14 +1,26 @@
 mod agent;
 mod chat;
+mod endpoint;
 mod file;
+mod job;
 mod messages;
+mod notification;
+mod pipeline;
+mod quota;
+mod usage;
 pub mod user;
 mod workspace;
 use serde::{Deserialize, Serialize};
 
 pub use agent::*;
 pub use chat::CreateChat;
+pub use endpoint::{CreateEndpoint, UpdateEndpoint};
+pub use job::ListTapOutputs;
 pub use messages::{CreateMessage, ListMessages};
+pub use notification::{UpdateNotificationSettings, UpdateWorkspaceNotification};
+pub(crate) use quota::CallMeter;
+pub use quota::{AgentQuota, Quota, QuotaUsage};
+pub use usage::{AgentUsage, ListAgentUsage};
 pub use user::{CreateUser, SigninUser};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
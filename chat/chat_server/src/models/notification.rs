@@ -0,0 +1,158 @@
+use chat_core::{NotificationLevel, NotificationSettings};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::{AppError, AppState};
+
+#[derive(Debug, Clone, Default, ToSchema, Serialize, Deserialize)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct UpdateNotificationSettings {
+    #[serde(default)]
+    pub level: NotificationLevel,
+    #[serde(default, alias = "mutedUntil")]
+    pub muted_until: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Default, ToSchema, Serialize, Deserialize)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct UpdateWorkspaceNotification {
+    pub level: NotificationLevel,
+}
+
+impl UpdateNotificationSettings {
+    pub fn new(level: NotificationLevel, muted_until: Option<DateTime<Utc>>) -> Self {
+        Self { level, muted_until }
+    }
+}
+
+impl AppState {
+    /// Get the notification settings of a user in a chat, falling back to the workspace default
+    pub async fn get_notification_settings(
+        &self,
+        chat_id: u64,
+        user_id: u64,
+    ) -> Result<NotificationSettings, AppError> {
+        let settings = sqlx::query_as(
+            r#"
+            SELECT c.id AS chat_id, u.id AS user_id,
+                COALESCE(s.level, w.default_notification_level) AS level, s.muted_until
+            FROM chats c
+            JOIN users u ON u.id = $2
+            JOIN workspaces w ON w.id = c.ws_id
+            LEFT JOIN chat_notification_settings s ON s.chat_id = c.id AND s.user_id = u.id
+            WHERE c.id = $1
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(user_id as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        settings.ok_or_else(|| AppError::NotFound(format!("Chat with id {} not found", chat_id)))
+    }
+
+    /// Create or replace the notification settings of a user in a chat
+    pub async fn update_notification_settings(
+        &self,
+        input: UpdateNotificationSettings,
+        chat_id: u64,
+        user_id: u64,
+    ) -> Result<NotificationSettings, AppError> {
+        let settings = sqlx::query_as(
+            r#"
+            INSERT INTO chat_notification_settings (chat_id, user_id, level, muted_until)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (chat_id, user_id)
+            DO UPDATE SET level = $3, muted_until = $4, updated_at = CURRENT_TIMESTAMP
+            RETURNING chat_id, user_id, level, muted_until
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(user_id as i64)
+        .bind(input.level)
+        .bind(input.muted_until)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(settings)
+    }
+
+    /// Set the default notification level of a workspace, only the owner can do it
+    pub async fn update_workspace_notification(
+        &self,
+        input: UpdateWorkspaceNotification,
+        ws_id: u64,
+        user_id: u64,
+    ) -> Result<NotificationLevel, AppError> {
+        let level: Option<NotificationLevel> = sqlx::query_scalar(
+            r#"
+            UPDATE workspaces
+            SET default_notification_level = $1
+            WHERE id = $2 AND owner_id = $3
+            RETURNING default_notification_level
+            "#,
+        )
+        .bind(input.level)
+        .bind(ws_id as i64)
+        .bind(user_id as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        level.ok_or_else(|| {
+            AppError::NotFound(format!(
+                "Workspace {} doesn't exist or you are not the owner",
+                ws_id
+            ))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use chrono::Duration;
+
+    #[tokio::test]
+    async fn notification_settings_should_default_to_workspace() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let settings = state.get_notification_settings(1, 1).await?;
+        assert_eq!(settings.level, NotificationLevel::All);
+        assert_eq!(settings.muted_until, None);
+
+        // user 1 doesn't own workspace 1 in fixtures
+        let input = UpdateWorkspaceNotification {
+            level: NotificationLevel::Mentions,
+        };
+        assert!(
+            state
+                .update_workspace_notification(input.clone(), 1, 1)
+                .await
+                .is_err()
+        );
+        state.update_workspace_owner(1, 1).await?;
+        let level = state.update_workspace_notification(input, 1, 1).await?;
+        assert_eq!(level, NotificationLevel::Mentions);
+
+        let settings = state.get_notification_settings(1, 2).await?;
+        assert_eq!(settings.level, NotificationLevel::Mentions);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn update_notification_settings_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let until = Utc::now() + Duration::hours(1);
+        let input = UpdateNotificationSettings::new(NotificationLevel::Mentions, Some(until));
+        let settings = state.update_notification_settings(input, 1, 2).await?;
+        assert_eq!(settings.level, NotificationLevel::Mentions);
+        assert!(settings.is_muted(Utc::now()));
+
+        let input = UpdateNotificationSettings::new(NotificationLevel::All, None);
+        state.update_notification_settings(input, 1, 2).await?;
+        let settings = state.get_notification_settings(1, 2).await?;
+        assert_eq!(settings.level, NotificationLevel::All);
+        assert!(!settings.is_muted(Utc::now()));
+        Ok(())
+    }
+}
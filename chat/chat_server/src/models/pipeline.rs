@@ -0,0 +1,1104 @@
+use std::time::{Duration, Instant};
+
+use chat_core::{
+    AdapterType, AgentContext, AgentDecision, AgentError, AgentInvocationSource, AgentOutcome,
+    AgentOutcomeStatus, AgentType, Chat, ChatAgent, ChatType, ChatUser, Citation, ContextMessage,
+    FailurePolicy, Message, MessageDelta, MessageStatus, TokenUsage, agent_request,
+};
+use sqlx::types::Json;
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+use super::user::BOT_EMAIL_DOMAIN;
+use crate::{AppError, AppState, CallMeter, agent::AgentVariant};
+
+// pg_notify payloads are limited to 8000 bytes
+const MAX_DELTA_BYTES: usize = 4000;
+
+/// What an agent did with a message, kept until the outcome can be stored.
+#[derive(Debug, Clone)]
+pub(crate) struct AgentStep {
+    pub agent_id: i64,
+    pub status: AgentOutcomeStatus,
+    pub output: Option<String>,
+    pub error: Option<String>,
+    pub latency_ms: i32,
+    pub usage: Option<TokenUsage>,
+    // size of the content the agent processed
+    pub input_chars: i32,
+    // why the agent was skipped without calling its model: it or its
+    // workspace ran out of quota
+    pub limited: Option<String>,
+}
+
+/// Result of the proxy agents of a chat, applied before the message is stored.
+#[derive(Debug, Default)]
+pub(crate) struct ProxyResult {
+    // content after all modifications, None if no proxy changed it
+    pub modified_content: Option<String>,
+    // replies proxies asked for and the agent asking, posted after the message
+    pub replies: Vec<(ChatAgent, String)>,
+    // name of the agent which deleted the message
+    pub rejected_by: Option<String>,
+    pub steps: Vec<AgentStep>,
+}
+
+impl AgentStep {
+    /// run an agent and record its decision, failures don't stop the pipeline
+    pub(crate) async fn run(
+        state: &AppState,
+        agent: &ChatAgent,
+        content: &str,
+        ctx: &AgentContext,
+    ) -> (Self, Option<AgentDecision>) {
+        Self::run_stream(state, agent, content, ctx, &mut |_| {}).await
+    }
+
+    /// same as `run`, a reply is handed to `on_delta` while it is generated
+    pub(crate) async fn run_stream(
+        state: &AppState,
+        agent: &ChatAgent,
+        content: &str,
+        ctx: &AgentContext,
+        on_delta: &mut (dyn FnMut(&str) + Send),
+    ) -> (Self, Option<AgentDecision>) {
+        let start = Instant::now();
+        // the first call is reserved up front, an agent out of quota is skipped
+        let ret = match Self::reserve_call(state, agent, ctx).await {
+            Ok(call) => Self::invoke(state, agent, content, ctx, call, on_delta).await,
+            Err(AppError::QuotaExceeded(reason)) => {
+                info!("Agent {} skipped: {}", agent.name, reason);
+                let step = Self {
+                    agent_id: agent.id,
+                    status: AgentOutcomeStatus::Skipped,
+                    output: None,
+                    error: Some(format!("quota exceeded: {}", reason)),
+                    latency_ms: 0,
+                    usage: None,
+                    input_chars: content.chars().count() as i32,
+                    limited: Some(reason),
+                };
+                return (step, Some(AgentDecision::None));
+            }
+            // an unknown quota fails the agent rather than letting it spend
+            Err(e) => Err(anyhow::Error::from(e).into()),
+        };
+        let latency_ms = start.elapsed().as_millis() as i32;
+        let (usage, ret) = match ret {
+            Ok((decision, usage)) => (usage, Ok(decision)),
+            Err(e) => (None, Err(e)),
+        };
+        let (status, output, error, decision) = match ret {
+            Ok(decision) => {
+                let (status, output) = match &decision {
+                    AgentDecision::Modify(s) => (AgentOutcomeStatus::Modified, Some(s.clone())),
+                    AgentDecision::Reply(s, _) => (AgentOutcomeStatus::Replied, Some(s.clone())),
+                    AgentDecision::Delete => (AgentOutcomeStatus::Deleted, None),
+                    AgentDecision::None => (AgentOutcomeStatus::Skipped, None),
+                };
+                (status, output, None, Some(decision))
+            }
+            Err(e) => {
+                warn!("Agent {} failed: {}", agent.name, e);
+                (AgentOutcomeStatus::Failed, None, Some(e.to_string()), None)
+            }
+        };
+        let step = Self {
+            agent_id: agent.id,
+            status,
+            output,
+            error,
+            latency_ms,
+            usage,
+            input_chars: content.chars().count() as i32,
+            limited: None,
+        };
+        (step, decision)
+    }
+
+    /// reserve a model call against the quotas of the chat, None outside a chat
+    async fn reserve_call(
+        state: &AppState,
+        agent: &ChatAgent,
+        ctx: &AgentContext,
+    ) -> Result<Option<i64>, AppError> {
+        match &ctx.chat {
+            Some(chat) => Ok(Some(state.reserve_agent_call(agent, chat.ws_id).await?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Run an agent within its policy: every attempt has a timeout, network
+    /// errors are retried with backoff and then the fallback model gets a try.
+    /// A reply already partly streamed is neither retried nor taken over.
+    /// Every model call of the attempts is counted against the quotas, `call`
+    /// is the one reserved for the first.
+    async fn invoke(
+        state: &AppState,
+        agent: &ChatAgent,
+        content: &str,
+        ctx: &AgentContext,
+        mut call: Option<i64>,
+        on_delta: &mut (dyn FnMut(&str) + Send),
+    ) -> Result<(AgentDecision, Option<TokenUsage>), AgentError> {
+        let config = &state.config.agent;
+        let policy = &agent.policy;
+        let timeout = Duration::from_secs(policy.timeout_secs.unwrap_or(config.timeout_secs));
+        let max_retries = policy.max_retries.unwrap_or(config.max_retries);
+        let mut delivered = false;
+        let mut attempts = 0;
+        let error = loop {
+            let mut on_attempt_delta = |delta: &str| {
+                delivered = true;
+                on_delta(delta);
+            };
+            let ret = Self::attempt(
+                state,
+                agent.clone(),
+                content,
+                ctx,
+                call.take(),
+                timeout,
+                &mut on_attempt_delta,
+            )
+            .await;
+            attempts += 1;
+            match ret {
+                Err(AgentError::Network(e)) if attempts <= max_retries && !delivered => {
+                    let backoff = config.retry_backoff_ms << (attempts - 1).min(10);
+                    warn!(
+                        "Agent {} failed: {}, retrying in {}ms",
+                        agent.name, e, backoff
+                    );
+                    tokio::time::sleep(Duration::from_millis(backoff)).await;
+                }
+                Err(AgentError::Network(e)) if attempts > 1 => {
+                    break AgentError::Network(format!("{} (after {} attempts)", e, attempts));
+                }
+                Err(e) => break e,
+                Ok(ret) => return Ok(ret),
+            }
+        };
+
+        match &policy.fallback {
+            Some(fallback) if !delivered => {
+                warn!(
+                    "Agent {} failed: {}, falling back to {}",
+                    agent.name, error, fallback.model
+                );
+                let mut agent = agent.clone();
+                agent.adapter = fallback.adapter.clone();
+                agent.model = fallback.model.clone();
+                Self::attempt(state, agent, content, ctx, None, timeout, on_delta).await
+            }
+            _ => Err(error),
+        }
+    }
+
+    async fn attempt(
+        state: &AppState,
+        agent: ChatAgent,
+        content: &str,
+        ctx: &AgentContext,
+        call: Option<i64>,
+        timeout: Duration,
+        on_delta: &mut (dyn FnMut(&str) + Send),
+    ) -> Result<(AgentDecision, Option<TokenUsage>), AgentError> {
+        let endpoint = match &ctx.chat {
+            Some(chat) => state
+                .agent_endpoint(&agent, chat.ws_id)
+                .await
+                .map_err(anyhow::Error::from)?,
+            None => None,
+        };
+        let meter = ctx
+            .chat
+            .as_ref()
+            .map(|chat| CallMeter::new(state.clone(), agent.clone(), chat.ws_id, call));
+        // e.g. missing credentials only fail this agent
+        let mut variant = match agent.adapter {
+            AdapterType::Test => {
+                let mock = state.agent_mock(&agent)?;
+                AgentVariant::with_adapter(agent, mock.into())
+            }
+            _ => AgentVariant::try_new(agent, endpoint.as_ref())?,
+        };
+        if let Some(meter) = meter {
+            variant.attach_meter(meter);
+        }
+        variant.attach_tools(state, ctx);
+        variant.attach_knowledge(state, ctx).await?;
+        tokio::time::timeout(timeout, variant.process_stream(content, ctx, on_delta))
+            .await
+            .map_err(|_| AgentError::Network(format!("timed out after {:?}", timeout)))?
+    }
+}
+
+// users.fullname holds 64 characters
+pub(crate) fn bot_fullname(name: &str) -> String {
+    name.chars().take(64).collect()
+}
+
+impl AppState {
+    /// Load the conversation before a message for the agents: the chat, the
+    /// sender and the latest messages fitting in the token budget.
+    pub(crate) async fn build_agent_context(
+        &self,
+        chat_id: u64,
+        sender_id: u64,
+        before_id: Option<i64>,
+    ) -> Result<AgentContext, AppError> {
+        let chat = self
+            .get_chat_by_id(chat_id)
+            .await?
+            .ok_or_else(|| AppError::NotFound(format!("Chat with id {} not found", chat_id)))?;
+        let sender: ChatUser =
+            sqlx::query_as("SELECT id, fullname, email FROM users WHERE id = $1")
+                .bind(sender_id as i64)
+                .fetch_one(&self.pool)
+                .await?;
+        let mut history: Vec<ContextMessage> = sqlx::query_as(
+            r#"
+            SELECT m.id, m.sender_id, u.fullname AS sender_name, m.content
+            FROM messages m
+            JOIN users u ON u.id = m.sender_id
+            WHERE m.chat_id = $1
+            AND m.id < $2
+            AND m.status = 'sent'
+            ORDER BY m.id DESC
+            LIMIT $3
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(before_id.unwrap_or(i64::MAX))
+        .bind(self.config.agent.history_limit)
+        .fetch_all(&self.pool)
+        .await?;
+        history.reverse();
+        Ok(AgentContext::new(chat, sender, history)
+            .with_token_budget(self.config.agent.token_budget))
+    }
+
+    /// Run proxy agents in order, each one gets the output of the previous one.
+    /// A proxy deleting the message stops the pipeline, so does a failing proxy
+    /// whose policy is to reject, other failing proxies leave the content as is.
+    pub(crate) async fn run_proxies(
+        &self,
+        agents: &[ChatAgent],
+        content: &str,
+        ctx: &AgentContext,
+    ) -> ProxyResult {
+        let mut ret = ProxyResult::default();
+        for agent in agents.iter().filter(|a| a.r#type == AgentType::Proxy) {
+            let current = ret.modified_content.as_deref().unwrap_or(content);
+            let (step, decision) = AgentStep::run(self, agent, current, ctx).await;
+            ret.steps.push(step);
+            match decision {
+                Some(AgentDecision::Modify(s)) => ret.modified_content = Some(s),
+                Some(AgentDecision::Reply(s, _)) => ret.replies.push((agent.clone(), s)),
+                Some(AgentDecision::Delete) => {
+                    ret.rejected_by = Some(agent.name.clone());
+                    break;
+                }
+                None if agent.policy.on_failure == FailurePolicy::Reject => {
+                    ret.rejected_by = Some(agent.name.clone());
+                    break;
+                }
+                Some(AgentDecision::None) | None => {}
+            }
+        }
+        ret
+    }
+
+    /// What a reply agent is asked about a message: the whole message in
+    /// single chats, elsewhere only a mention of the agent or its `/name`
+    /// command, None if the agent isn't asked
+    pub(crate) fn reply_request<'a>(
+        &self,
+        agent: &ChatAgent,
+        message: &'a Message,
+        chat: &Chat,
+    ) -> Option<&'a str> {
+        let content = message
+            .modified_content
+            .as_deref()
+            .unwrap_or(&message.content);
+        match chat.r#type {
+            ChatType::Single => Some(content),
+            _ => agent_request(content, &agent.name),
+        }
+    }
+
+    /// Run a reply agent against the stored message, its reply is streamed
+    /// into a message of the other member or of its bot. Returns None if the
+    /// message doesn't ask the agent.
+    pub(crate) async fn run_reply(
+        &self,
+        agent: &ChatAgent,
+        message: &Message,
+        ctx: &AgentContext,
+    ) -> Result<Option<AgentStep>, AppError> {
+        let chat = self.context_chat(ctx, message.chat_id)?;
+        let Some(content) = self.reply_request(agent, message, chat) else {
+            return Ok(None);
+        };
+        let sender_id = self.reply_sender_id(agent, chat, message.sender_id).await?;
+        let step = self
+            .stream_reply(agent, content, ctx, message.chat_id, sender_id)
+            .await?;
+        Ok(Some(step))
+    }
+
+    /// Post the reply a proxy asked for after the message
+    pub(crate) async fn post_proxy_reply(
+        &self,
+        agent: &ChatAgent,
+        ctx: &AgentContext,
+        message: &Message,
+        reply: String,
+    ) -> Result<(), AppError> {
+        let chat = self.context_chat(ctx, message.chat_id)?;
+        let sender_id = self.reply_sender_id(agent, chat, message.sender_id).await?;
+        self.post_reply(message.chat_id, sender_id, reply, vec![])
+            .await
+    }
+
+    /// Run a reply agent and stream its reply: the message is created as a placeholder
+    /// with the first delta, deltas go to the members through `chat_message_delta`,
+    /// and the message is finalized when the agent is done or marked failed
+    async fn stream_reply(
+        &self,
+        agent: &ChatAgent,
+        content: &str,
+        ctx: &AgentContext,
+        chat_id: i64,
+        sender_id: i64,
+    ) -> Result<AgentStep, AppError> {
+        let (tx, mut rx) = mpsc::unbounded_channel::<String>();
+        let run = async move {
+            let mut on_delta = |delta: &str| {
+                let _ = tx.send(delta.to_string());
+            };
+            AgentStep::run_stream(self, agent, content, ctx, &mut on_delta).await
+        };
+        let forward = async {
+            let mut placeholder = None;
+            let mut seq = 0;
+            let mut streamed = String::new();
+            while let Some(mut delta) = rx.recv().await {
+                // whatever piled up while the last delta was sent goes out together
+                while delta.len() < MAX_DELTA_BYTES
+                    && let Ok(more) = rx.try_recv()
+                {
+                    delta.push_str(&more);
+                }
+                let message_id = match placeholder {
+                    Some(id) => id,
+                    None => {
+                        let id = self.create_placeholder(chat_id, sender_id).await?;
+                        *placeholder.insert(id)
+                    }
+                };
+                streamed.push_str(&delta);
+                let delta = MessageDelta {
+                    chat_id,
+                    message_id,
+                    seq,
+                    delta,
+                    done: false,
+                    status: MessageStatus::Streaming,
+                    citations: vec![],
+                };
+                self.notify_delta(&delta).await?;
+                seq += 1;
+            }
+            Ok::<_, AppError>((placeholder, seq, streamed))
+        };
+        let ((step, decision), forwarded) = tokio::join!(run, forward);
+        let (placeholder, seq, streamed) = forwarded?;
+
+        let (reply, citations, status) = match decision {
+            Some(AgentDecision::Reply(reply, citations)) => {
+                (Some(reply), citations, MessageStatus::Sent)
+            }
+            _ => (None, vec![], MessageStatus::Failed),
+        };
+        match (placeholder, reply) {
+            // a failed reply keeps what was streamed until then
+            (Some(message_id), reply) => {
+                sqlx::query(
+                    "UPDATE messages SET content = $2, status = $3, citations = $4 WHERE id = $1",
+                )
+                .bind(message_id)
+                .bind(reply.unwrap_or(streamed))
+                .bind(status)
+                .bind(Json(&citations))
+                .execute(&self.pool)
+                .await?;
+                // the citations may not fit in a notification, notify_server
+                // loads them from the message
+                let delta = MessageDelta {
+                    chat_id,
+                    message_id,
+                    seq,
+                    delta: String::new(),
+                    done: true,
+                    status,
+                    citations: vec![],
+                };
+                self.notify_delta(&delta).await?;
+            }
+            // nothing was streamed
+            (None, Some(reply)) => {
+                self.post_reply(chat_id, sender_id, reply, citations)
+                    .await?;
+            }
+            (None, None) => {}
+        }
+        Ok(step)
+    }
+
+    async fn create_placeholder(&self, chat_id: i64, sender_id: i64) -> Result<i64, AppError> {
+        let id = sqlx::query_scalar(
+            r#"
+            INSERT INTO messages (chat_id, sender_id, content, status)
+            VALUES ($1, $2, '', 'streaming')
+            RETURNING id
+            "#,
+        )
+        .bind(chat_id)
+        .bind(sender_id)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(id)
+    }
+
+    /// deltas are not worth an outbox event, a client missing some reloads the
+    /// message. Payloads stay below the 8000 bytes of pg_notify: a delta is at
+    /// most about `MAX_DELTA_BYTES` and the last one goes without citations
+    async fn notify_delta(&self, delta: &MessageDelta) -> Result<(), AppError> {
+        let payload = serde_json::to_value(delta).expect("delta should serialize");
+        sqlx::query(
+            r#"
+            SELECT pg_notify('chat_message_delta', jsonb_set($2, '{members}', to_jsonb(members))::TEXT)
+            FROM chats WHERE id = $1
+            "#,
+        )
+        .bind(delta.chat_id)
+        .bind(payload)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// the chat the agents run in, loaded with their context
+    pub(crate) fn context_chat<'a>(
+        &self,
+        ctx: &'a AgentContext,
+        chat_id: i64,
+    ) -> Result<&'a Chat, AppError> {
+        ctx.chat
+            .as_ref()
+            .ok_or_else(|| AppError::NotFound(format!("Chat with id {} not found", chat_id)))
+    }
+
+    /// Agent replies are posted on behalf of the other member of a single chat,
+    /// in group chats and channels by the bot user of the agent
+    pub(crate) async fn reply_sender_id(
+        &self,
+        agent: &ChatAgent,
+        chat: &Chat,
+        user_id: i64,
+    ) -> Result<i64, AppError> {
+        if chat.r#type != ChatType::Single {
+            return self.agent_bot_id(agent, chat.ws_id).await;
+        }
+        let other_user_id = chat
+            .members
+            .iter()
+            .find(|&&m| m != user_id)
+            .copied()
+            .expect("other user should exist");
+        Ok(other_user_id)
+    }
+
+    /// The bot user of an agent, created in the workspace of the chat when the
+    /// agent first replies. The agent row is locked meanwhile, so agents
+    /// replying at the same time end up with the same bot. Its email is random
+    /// and it has no password, no one can sign up or in as it.
+    pub(crate) async fn agent_bot_id(
+        &self,
+        agent: &ChatAgent,
+        ws_id: i64,
+    ) -> Result<i64, AppError> {
+        if let Some(bot_id) = agent.bot_id {
+            return Ok(bot_id);
+        }
+        let mut tx = self.pool.begin().await?;
+        let bot_id: Option<i64> =
+            sqlx::query_scalar("SELECT bot_id FROM chat_agents WHERE id = $1 FOR UPDATE")
+                .bind(agent.id)
+                .fetch_one(&mut *tx)
+                .await?;
+        if let Some(bot_id) = bot_id {
+            return Ok(bot_id);
+        }
+        let bot_id = sqlx::query_scalar(
+            r#"
+            WITH bot AS (
+                INSERT INTO users (ws_id, fullname, email, password_hash, is_bot)
+                VALUES ($2, $3, 'agent-' || $1 || '-' || replace(gen_random_uuid()::text, '-', '') || $4, '', TRUE)
+                RETURNING id
+            )
+            UPDATE chat_agents SET bot_id = (SELECT id FROM bot)
+            WHERE id = $1
+            RETURNING bot_id
+            "#,
+        )
+        .bind(agent.id)
+        .bind(ws_id)
+        .bind(bot_fullname(&agent.name))
+        .bind(BOT_EMAIL_DOMAIN)
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(bot_id)
+    }
+
+    /// Post a reply to the chat on behalf of a member or the bot of an agent
+    pub(crate) async fn post_reply(
+        &self,
+        chat_id: i64,
+        sender_id: i64,
+        reply: String,
+        citations: Vec<Citation>,
+    ) -> Result<(), AppError> {
+        sqlx::query(
+            r#"
+            INSERT INTO messages (chat_id, sender_id, content, citations)
+            VALUES ($1, $2, $3, $4)
+            "#,
+        )
+        .bind(chat_id)
+        .bind(sender_id)
+        .bind(reply)
+        .bind(Json(citations))
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Store the outcomes of agents run for a message and their invocations
+    pub(crate) async fn record_agent_steps(
+        &self,
+        chat_id: i64,
+        message_id: Option<i64>,
+        steps: &[AgentStep],
+    ) -> Result<(), AppError> {
+        self.save_agent_outcomes(chat_id, message_id, steps).await?;
+        self.save_agent_invocations(chat_id, message_id, AgentInvocationSource::Message, steps)
+            .await
+    }
+
+    pub(crate) async fn save_agent_outcomes(
+        &self,
+        chat_id: i64,
+        message_id: Option<i64>,
+        steps: &[AgentStep],
+    ) -> Result<(), AppError> {
+        for step in steps {
+            sqlx::query(
+                r#"
+                INSERT INTO agent_outcomes (chat_id, message_id, agent_id, status, output, error, latency_ms)
+                VALUES ($1, $2, $3, $4, $5, $6, $7)
+                "#,
+            )
+            .bind(chat_id)
+            .bind(message_id)
+            .bind(step.agent_id)
+            .bind(&step.status)
+            .bind(&step.output)
+            .bind(&step.error)
+            .bind(step.latency_ms)
+            .execute(&self.pool)
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Record the runs of agents in the audit log, with the tokens they used.
+    /// Quotas are counted from the model calls reserved by the runs.
+    pub(crate) async fn save_agent_invocations(
+        &self,
+        chat_id: i64,
+        message_id: Option<i64>,
+        source: AgentInvocationSource,
+        steps: &[AgentStep],
+    ) -> Result<(), AppError> {
+        // agents out of quota didn't call their model
+        for step in steps.iter().filter(|s| s.limited.is_none()) {
+            let output_chars = step.output.as_ref().map_or(0, |s| s.chars().count()) as i32;
+            sqlx::query(
+                r#"
+                INSERT INTO agent_invocations (ws_id, chat_id, agent_id, message_id, source, status,
+                    input_chars, output_chars, prompt_tokens, completion_tokens, latency_ms, error)
+                SELECT ws_id, id, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11
+                FROM chats WHERE id = $1
+                "#,
+            )
+            .bind(chat_id)
+            .bind(step.agent_id)
+            .bind(message_id)
+            .bind(source)
+            .bind(&step.status)
+            .bind(step.input_chars)
+            .bind(output_chars)
+            .bind(step.usage.map(|u| u.prompt_tokens as i32))
+            .bind(step.usage.map(|u| u.completion_tokens as i32))
+            .bind(step.latency_ms)
+            .bind(&step.error)
+            .execute(&self.pool)
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// What each agent of the chat did with a message, in execution order
+    pub async fn list_agent_outcomes(
+        &self,
+        chat_id: u64,
+        message_id: u64,
+    ) -> Result<Vec<AgentOutcome>, AppError> {
+        let outcomes = sqlx::query_as(
+            r#"
+            SELECT id, chat_id, message_id, agent_id, status, output, error, latency_ms, created_at
+            FROM agent_outcomes
+            WHERE chat_id = $1 AND message_id = $2
+            ORDER BY id ASC
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(message_id as i64)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(outcomes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        CreateAgent, ListMessages, UpdateAgent,
+        test_util::{add_agent, add_agent_with_policy, message, mock_args, mock_error_args},
+    };
+    use anyhow::Result;
+    use chat_core::AdapterType;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn pipeline_should_run_agents_in_priority_order() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let tap = add_agent(&state, "tap", AgentType::Tap, 0, mock_args("seen ${1}")).await?;
+        let second = add_agent(&state, "second", AgentType::Proxy, 2, mock_args("B(${1})")).await?;
+        let first = add_agent(&state, "first", AgentType::Proxy, 1, mock_args("A(${1})")).await?;
+        let reply = add_agent(&state, "reply", AgentType::Reply, 0, mock_args("re: ${1}")).await?;
+
+        let msg = state.create_message(message("hi"), 3, 1).await?;
+        assert_eq!(msg.content, "hi");
+        assert_eq!(msg.modified_content.as_deref(), Some("B(A(hi))"));
+        // replies run off the request path, before taps
+        assert_eq!(last_message(&state, 3).await?.id, msg.id);
+        let job = state
+            .run_next_job()
+            .await?
+            .expect("reply job should be queued");
+        assert_eq!(job.kind, AgentType::Reply);
+
+        // the reply is posted by the other member after the message
+        let messages = state
+            .list_messages(
+                ListMessages {
+                    last_id: None,
+                    limit: 1,
+                },
+                3,
+            )
+            .await?;
+        assert_eq!(messages[0].sender_id, 2);
+        assert_eq!(messages[0].content, "re: B(A(hi))");
+
+        let outcomes = state.list_agent_outcomes(3, msg.id as _).await?;
+        let ids: Vec<_> = outcomes.iter().map(|o| o.agent_id).collect();
+        assert_eq!(ids, vec![first.id, second.id, reply.id]);
+        assert_eq!(outcomes[1].status, AgentOutcomeStatus::Modified);
+        assert_eq!(outcomes[2].status, AgentOutcomeStatus::Replied);
+
+        // taps observe the message off the request path
+        state
+            .run_next_job()
+            .await?
+            .expect("tap job should be queued");
+        let outcomes = state.list_agent_outcomes(3, msg.id as _).await?;
+        let observed = outcomes.last().expect("tap outcome should exist");
+        assert_eq!(observed.agent_id, tap.id);
+        assert_eq!(observed.status, AgentOutcomeStatus::Observed);
+        assert_eq!(observed.output.as_deref(), Some("seen B(A(hi))"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn mock_model_should_drive_proxy_and_reply_agents() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let rules = json!({"mock": {"rules": [
+            {"pattern": "^please (.*)$", "response": {"text": "$1"}},
+        ]}});
+        add_agent(&state, "polite", AgentType::Proxy, 1, rules).await?;
+        let reply = json!({"mock": {"default": {"text": "done right away"}}});
+        add_agent(&state, "reply", AgentType::Reply, 2, reply).await?;
+        let error = json!({"status": 401, "message": "invalid key"});
+        let failing = json!({"mock": {"default": {"error": error}}});
+        let failing = add_agent(&state, "failing", AgentType::Reply, 3, failing).await?;
+
+        let msg = state
+            .create_message(message("please ship it"), 3, 1)
+            .await?;
+        assert_eq!(msg.modified_content.as_deref(), Some("ship it"));
+        state.run_jobs().await?;
+        let messages = state
+            .list_messages(
+                ListMessages {
+                    last_id: None,
+                    limit: 1,
+                },
+                3,
+            )
+            .await?;
+        assert_eq!(messages[0].content, "done right away");
+        // rules which don't match leave the message as it is
+        let msg = state.create_message(message("ship it"), 3, 1).await?;
+        assert_eq!(msg.modified_content.as_deref(), Some("ship it"));
+        state.run_jobs().await?;
+
+        let outcomes = state.list_agent_outcomes(3, msg.id as _).await?;
+        let failed = outcomes
+            .iter()
+            .find(|o| o.agent_id == failing.id)
+            .expect("failing agent should have an outcome");
+        assert_eq!(failed.status, AgentOutcomeStatus::Failed);
+        assert!(
+            failed
+                .error
+                .as_deref()
+                .is_some_and(|e| e.contains("invalid key"))
+        );
+
+        let mock = json!({"mock": {"rules": [{"pattern": "(", "response": {"text": ""}}]}});
+        let ret = add_agent(&state, "broken", AgentType::Proxy, 4, mock).await;
+        assert!(ret.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn failed_agent_should_not_stop_pipeline() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let failed = add_agent(&state, "broken", AgentType::Proxy, 0, mock_error_args()).await?;
+        add_agent(&state, "proxy", AgentType::Proxy, 1, mock_args("${1}!")).await?;
+
+        let msg = state.create_message(message("hi"), 3, 1).await?;
+        assert_eq!(msg.modified_content.as_deref(), Some("hi!"));
+        let outcomes = state.list_agent_outcomes(3, msg.id as _).await?;
+        assert_eq!(outcomes[0].agent_id, failed.id);
+        assert_eq!(outcomes[0].status, AgentOutcomeStatus::Failed);
+        assert!(outcomes[0].error.is_some());
+        assert_eq!(outcomes[1].status, AgentOutcomeStatus::Modified);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejecting_proxy_should_stop_the_pipeline() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let policy = json!({"maxRetries": 0, "onFailure": "reject"});
+        let args = mock_error_args();
+        add_agent_with_policy(&state, "moderator", AgentType::Proxy, "test", args, policy).await?;
+        add_agent(&state, "proxy", AgentType::Proxy, 1, mock_args("${1}!")).await?;
+
+        let ret = state.create_message(message("spam"), 3, 1).await;
+        assert!(matches!(ret, Err(AppError::CreateMessageError(_))));
+
+        let statuses: Vec<AgentOutcomeStatus> = sqlx::query_scalar(
+            "SELECT status FROM agent_outcomes WHERE chat_id = 3 AND message_id IS NULL",
+        )
+        .fetch_all(&state.pool)
+        .await?;
+        // the proxies after it never ran
+        assert_eq!(statuses, vec![AgentOutcomeStatus::Failed]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn agent_context_should_hold_recent_history() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let ctx = state.build_agent_context(1, 1, Some(10)).await?;
+        assert_eq!(ctx.chat.as_ref().map(|c| c.id), Some(1));
+        assert_eq!(ctx.sender.as_ref().map(|u| u.id), Some(1));
+        // the current message is not part of its own history
+        let ids: Vec<_> = ctx.history.iter().map(|m| m.id).collect();
+        assert_eq!(ids, (1..10).collect::<Vec<_>>());
+        assert_eq!(ctx.history[1].sender_name, "John Doe");
+
+        let ctx = state.build_agent_context(1, 1, Some(3)).await?;
+        assert_eq!(ctx.history.len(), 2);
+        Ok(())
+    }
+
+    async fn last_message(state: &AppState, chat_id: u64) -> Result<Message> {
+        let input = ListMessages {
+            last_id: None,
+            limit: 1,
+        };
+        let mut messages = state.list_messages(input, chat_id).await?;
+        Ok(messages.remove(0))
+    }
+
+    #[tokio::test]
+    async fn reply_should_be_streamed_into_a_placeholder() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        add_agent(
+            &state,
+            "reply",
+            AgentType::Reply,
+            0,
+            mock_args("you said ${1}"),
+        )
+        .await?;
+        let mut listener = sqlx::postgres::PgListener::connect_with(&state.pool).await?;
+        listener.listen("chat_message_delta").await?;
+
+        state.create_message(message("hello"), 3, 1).await?;
+        state.run_jobs().await?;
+        let reply = last_message(&state, 3).await?;
+        assert_eq!(reply.sender_id, 2);
+        assert_eq!(reply.content, "you said hello");
+        assert_eq!(reply.status, MessageStatus::Sent);
+
+        let mut deltas = Vec::new();
+        loop {
+            let notif = listener.recv().await?;
+            let delta: MessageDelta = serde_json::from_str(notif.payload())?;
+            assert_eq!(delta.message_id, reply.id);
+            deltas.push(delta);
+            if deltas.last().is_some_and(|d| d.done) {
+                break;
+            }
+        }
+        let streamed: String = deltas.iter().map(|d| d.delta.as_str()).collect();
+        assert_eq!(streamed, "you said hello");
+        let seqs: Vec<_> = deltas.iter().map(|d| d.seq).collect();
+        assert_eq!(seqs, (0..deltas.len() as i32).collect::<Vec<_>>());
+        assert_eq!(deltas.last().map(|d| d.status), Some(MessageStatus::Sent));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn interrupted_reply_should_be_marked_failed() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        add_agent(
+            &state,
+            "reply",
+            AgentType::Reply,
+            0,
+            json!({"mock": {"default": {"interrupted": "you said hello"}}}),
+        )
+        .await?;
+
+        let msg = state.create_message(message("hello"), 3, 1).await?;
+        let jobs = state.run_jobs().await?;
+        // the queue doesn't retry a reply which was partly posted
+        assert_eq!(jobs.len(), 1);
+        assert_eq!(jobs[0].status, chat_core::JobStatus::Failed);
+        let reply = last_message(&state, 3).await?;
+        assert_eq!(reply.sender_id, 2);
+        assert_eq!(reply.content, "you ");
+        assert_eq!(reply.status, MessageStatus::Failed);
+
+        let outcomes = state.list_agent_outcomes(3, msg.id as _).await?;
+        assert_eq!(outcomes[0].status, AgentOutcomeStatus::Failed);
+
+        // failed replies are not part of the conversation agents see
+        let ctx = state.build_agent_context(3, 1, None).await?;
+        assert!(ctx.history.iter().all(|m| m.id != reply.id));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn failing_agent_should_retry_then_fall_back() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let proxy = add_agent_with_policy(
+            &state,
+            "proxy",
+            AgentType::Proxy,
+            "primary",
+            // both attempts of the primary model fail, the fallback answers
+            json!({"mock": {
+                "responses": [{"error": {"status": 503, "message": "down"}}, {"error": {"status": 503, "message": "down"}}],
+                "rules": [{"pattern": "^(.*)$", "response": {"text": "X($1)"}}],
+            }}),
+            json!({"maxRetries": 1, "fallback": {"adapter": "test", "model": "backup"}}),
+        )
+        .await?;
+        let msg = state.create_message(message("hi"), 3, 1).await?;
+        assert_eq!(msg.modified_content.as_deref(), Some("X(hi)"));
+
+        let outcomes = state.list_agent_outcomes(3, msg.id as _).await?;
+        assert_eq!(outcomes[0].status, AgentOutcomeStatus::Modified);
+        // every model call counts against the quota
+        let quota = state.get_agent_quota(3, proxy.id as _, 1).await?;
+        assert_eq!(quota.agent.requests_per_minute.used, 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn failing_agent_should_give_up_after_retries() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        add_agent_with_policy(
+            &state,
+            "proxy",
+            AgentType::Proxy,
+            "test",
+            mock_error_args(),
+            json!({"maxRetries": 1}),
+        )
+        .await?;
+        // the message is posted as it is
+        let msg = state.create_message(message("hi"), 3, 1).await?;
+        assert!(msg.modified_content.is_none());
+
+        let outcomes = state.list_agent_outcomes(3, msg.id as _).await?;
+        assert_eq!(outcomes[0].status, AgentOutcomeStatus::Failed);
+        let error = outcomes[0].error.as_deref().unwrap_or_default();
+        assert!(error.contains("after 2 attempts"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn slow_agent_should_time_out() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        add_agent_with_policy(
+            &state,
+            "proxy",
+            AgentType::Proxy,
+            "test",
+            json!({"mock": {"latency_ms": 5000}}),
+            json!({"timeoutSecs": 1, "maxRetries": 0}),
+        )
+        .await?;
+        let msg = state.create_message(message("hi"), 3, 1).await?;
+        assert!(msg.modified_content.is_none());
+
+        let outcomes = state.list_agent_outcomes(3, msg.id as _).await?;
+        assert_eq!(outcomes[0].status, AgentOutcomeStatus::Failed);
+        assert!(outcomes[0].latency_ms < 3000);
+        let error = outcomes[0].error.as_deref().unwrap_or_default();
+        assert!(error.contains("timed out"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn failing_agent_should_reject_message_by_policy() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        add_agent_with_policy(
+            &state,
+            "proxy",
+            AgentType::Proxy,
+            "test",
+            mock_error_args(),
+            json!({"maxRetries": 0, "onFailure": "reject"}),
+        )
+        .await?;
+        let ret = state.create_message(message("hi"), 3, 1).await;
+        assert!(matches!(ret, Err(AppError::CreateMessageError(_))));
+
+        let input = ListMessages {
+            last_id: None,
+            limit: 0,
+        };
+        assert!(state.list_messages(input, 3).await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn group_reply_agent_should_answer_commands_and_mentions_as_bot() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = CreateAgent::new(
+            "ask",
+            AgentType::Reply,
+            AdapterType::Test,
+            "test",
+            "",
+            mock_args("re: ${1}"),
+        );
+        let agent = state.create_agent(input, 4).await?;
+
+        // chat 4 is a group chat, plain messages are left to its members
+        let msg = state.create_message(message("hello all"), 4, 1).await?;
+        assert!(state.run_jobs().await?.is_empty());
+        assert!(state.list_agent_outcomes(4, msg.id as _).await?.is_empty());
+        assert_eq!(last_message(&state, 4).await?.id, msg.id);
+
+        state
+            .create_message(message("/ask what's new"), 4, 1)
+            .await?;
+        state.run_jobs().await?;
+        let reply = last_message(&state, 4).await?;
+        assert_eq!(reply.content, "re: what's new");
+        let bot = state
+            .find_user_by_id(reply.sender_id)
+            .await?
+            .expect("bot should exist");
+        assert_eq!(bot.fullname, "ask");
+        assert_eq!(bot.ws_id, 1);
+        assert_ne!(bot.email, format!("agent-{}@bot.org", agent.id));
+
+        state.create_message(message("hey @ask, hi"), 4, 3).await?;
+        state.run_jobs().await?;
+        let reply = last_message(&state, 4).await?;
+        assert_eq!(reply.content, "re: hey @ask, hi");
+        assert_eq!(reply.sender_id, bot.id);
+
+        // the bot follows the name of the agent
+        let input = UpdateAgent {
+            id: agent.id as _,
+            name: Some("helper".to_string()),
+            ..Default::default()
+        };
+        state.update_agent(input, 4).await?;
+        let bot = state
+            .find_user_by_id(bot.id)
+            .await?
+            .expect("bot should exist");
+        assert_eq!(bot.fullname, "helper");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reply_citations_should_be_stored_with_the_message() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let sources = [crate::Source {
+            path: "docs/setup.md".to_string(),
+            chunk: "Run make db".to_string(),
+        }];
+        let citations = crate::cited_sources("run make db [1]", &sources);
+        state
+            .post_reply(3, 2, "run make db [1]".to_string(), citations)
+            .await?;
+
+        let reply = last_message(&state, 3).await?;
+        assert_eq!(reply.content, "run make db [1]");
+        assert_eq!(reply.citations.len(), 1);
+        assert_eq!(reply.citations[0].source, "docs/setup.md");
+        let citation = json!({"index": 1, "source": "docs/setup.md", "excerpt": "Run make db"});
+        assert_eq!(serde_json::to_value(&reply.citations[0])?, citation);
+        Ok(())
+    }
+}
@@ -0,0 +1,434 @@
+use std::sync::{Arc, Mutex};
+
+use anyhow::anyhow;
+use chat_core::{AgentError, ChatAgent, TokenUsage};
+use chrono::{DateTime, Days, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgConnection};
+use tracing::warn;
+use utoipa::ToSchema;
+
+use crate::{AppError, AppState};
+
+/// Use of a limit, a limit of 0 is unlimited and has nothing remaining
+#[derive(Debug, Clone, Copy, ToSchema, Serialize, Deserialize, PartialEq)]
+pub struct QuotaUsage {
+    pub limit: u64,
+    pub used: u64,
+    pub remaining: Option<u64>,
+}
+
+/// Requests in the last minute and tokens of the day (UTC) against the limits
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct Quota {
+    #[serde(alias = "requestsPerMinute")]
+    pub requests_per_minute: QuotaUsage,
+    #[serde(alias = "tokensPerDay")]
+    pub tokens_per_day: QuotaUsage,
+    // when the tokens of the day start over
+    #[serde(alias = "resetsAt")]
+    pub resets_at: DateTime<Utc>,
+}
+
+/// An agent runs while both its own and the quota of its workspace last
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize, PartialEq)]
+pub struct AgentQuota {
+    pub agent: Quota,
+    pub workspace: Quota,
+}
+
+/// Reserves the model calls of an agent run against the quotas of its
+/// workspace. Every completion round is a call, an agent calling tools makes
+/// several in one run.
+#[derive(Clone)]
+pub(crate) struct CallMeter {
+    state: AppState,
+    agent: ChatAgent,
+    ws_id: i64,
+    // reserved before the run to skip an agent out of quota, taken by its first call
+    reserved: Arc<Mutex<Option<i64>>>,
+}
+
+#[derive(Debug, FromRow)]
+struct Spent {
+    requests: i64,
+    tokens: i64,
+}
+
+impl QuotaUsage {
+    fn new(limit: u64, used: i64) -> Self {
+        let used = used.max(0) as u64;
+        Self {
+            limit,
+            used,
+            remaining: (limit > 0).then(|| limit.saturating_sub(used)),
+        }
+    }
+
+    fn is_exhausted(&self) -> bool {
+        self.remaining == Some(0)
+    }
+}
+
+impl Quota {
+    /// why the quota doesn't allow another request, if it doesn't
+    fn exceeded(&self) -> Option<String> {
+        if self.requests_per_minute.is_exhausted() {
+            return Some(format!(
+                "{} requests per minute",
+                self.requests_per_minute.limit
+            ));
+        }
+        if self.tokens_per_day.is_exhausted() {
+            return Some(format!("{} tokens per day", self.tokens_per_day.limit));
+        }
+        None
+    }
+}
+
+impl AppState {
+    /// Quota of all agents of the workspace
+    pub async fn get_workspace_quota(&self, ws_id: u64) -> Result<Quota, AppError> {
+        let mut conn = self.pool.acquire().await?;
+        self.workspace_quota(&mut conn, ws_id).await
+    }
+
+    /// Quota of an agent of a chat and of its workspace
+    pub async fn get_agent_quota(
+        &self,
+        chat_id: u64,
+        agent_id: u64,
+        ws_id: u64,
+    ) -> Result<AgentQuota, AppError> {
+        let Some(agent) = self.get_agent_by_id(chat_id, agent_id).await? else {
+            return Err(AppError::NotFound(format!(
+                "Agent {} not found in chat {}",
+                agent_id, chat_id
+            )));
+        };
+        let mut conn = self.pool.acquire().await?;
+        Ok(AgentQuota {
+            agent: self.agent_quota(&mut conn, &agent, ws_id).await?,
+            workspace: self.workspace_quota(&mut conn, ws_id).await?,
+        })
+    }
+
+    /// Reserve a model call of an agent before it is made, it counts as a
+    /// request right away. Reservations of a workspace are made one at a
+    /// time, so concurrent calls can't all take the last request left.
+    /// Fails with `QuotaExceeded` if the agent or its workspace is out of quota.
+    pub(crate) async fn reserve_agent_call(
+        &self,
+        agent: &ChatAgent,
+        ws_id: i64,
+    ) -> Result<i64, AppError> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("SELECT id FROM workspaces WHERE id = $1 FOR NO KEY UPDATE")
+            .bind(ws_id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query(
+            "DELETE FROM agent_calls WHERE ws_id = $1 AND created_at < NOW() - INTERVAL '2 days'",
+        )
+        .bind(ws_id)
+        .execute(&mut *tx)
+        .await?;
+
+        let quota = self.agent_quota(&mut tx, agent, ws_id as _).await?;
+        if let Some(limit) = quota.exceeded() {
+            let reason = format!("agent {} reached {}", agent.name, limit);
+            return Err(AppError::QuotaExceeded(reason));
+        }
+        if let Some(limit) = self.workspace_quota(&mut tx, ws_id as _).await?.exceeded() {
+            return Err(AppError::QuotaExceeded(format!(
+                "workspace reached {}",
+                limit
+            )));
+        }
+        let id = sqlx::query_scalar(
+            "INSERT INTO agent_calls (ws_id, agent_id) VALUES ($1, $2) RETURNING id",
+        )
+        .bind(ws_id)
+        .bind(agent.id)
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(id)
+    }
+
+    /// Count the tokens a reserved call used against the tokens per day
+    pub(crate) async fn spend_agent_call(
+        &self,
+        id: i64,
+        usage: Option<TokenUsage>,
+    ) -> Result<(), AppError> {
+        let tokens = usage.map_or(0, |u| u.prompt_tokens as i64 + u.completion_tokens as i64);
+        sqlx::query("UPDATE agent_calls SET tokens = $2 WHERE id = $1")
+            .bind(id)
+            .bind(tokens)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn workspace_quota(
+        &self,
+        conn: &mut PgConnection,
+        ws_id: u64,
+    ) -> Result<Quota, AppError> {
+        let limits = &self.config.limits;
+        self.quota(
+            conn,
+            ws_id,
+            None,
+            limits.workspace_requests_per_minute,
+            limits.workspace_tokens_per_day,
+        )
+        .await
+    }
+
+    async fn agent_quota(
+        &self,
+        conn: &mut PgConnection,
+        agent: &ChatAgent,
+        ws_id: u64,
+    ) -> Result<Quota, AppError> {
+        let limits = &self.config.limits;
+        let policy = &agent.policy;
+        self.quota(
+            conn,
+            ws_id,
+            Some(agent.id),
+            policy
+                .requests_per_minute
+                .unwrap_or(limits.agent_requests_per_minute),
+            policy.tokens_per_day.unwrap_or(limits.agent_tokens_per_day),
+        )
+        .await
+    }
+
+    async fn quota(
+        &self,
+        conn: &mut PgConnection,
+        ws_id: u64,
+        agent_id: Option<i64>,
+        requests_per_minute: u32,
+        tokens_per_day: u64,
+    ) -> Result<Quota, AppError> {
+        let spent: Spent = sqlx::query_as(
+            r#"
+            WITH since AS (
+                SELECT date_trunc('day', NOW() AT TIME ZONE 'UTC') AT TIME ZONE 'UTC' AS day,
+                    NOW() - INTERVAL '1 minute' AS minute
+            )
+            SELECT COUNT(*) FILTER (WHERE c.created_at > since.minute) AS requests,
+                COALESCE(SUM(c.tokens) FILTER (WHERE c.created_at >= since.day), 0)::BIGINT AS tokens
+            FROM agent_calls c, since
+            WHERE c.ws_id = $1
+            AND ($2::BIGINT IS NULL OR c.agent_id = $2)
+            AND c.created_at >= LEAST(since.day, since.minute)
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(agent_id)
+        .fetch_one(conn)
+        .await?;
+
+        let tomorrow = Utc::now().date_naive() + Days::new(1);
+        Ok(Quota {
+            requests_per_minute: QuotaUsage::new(requests_per_minute as _, spent.requests),
+            tokens_per_day: QuotaUsage::new(tokens_per_day, spent.tokens),
+            resets_at: tomorrow.and_time(Default::default()).and_utc(),
+        })
+    }
+}
+
+impl CallMeter {
+    pub(crate) fn new(
+        state: AppState,
+        agent: ChatAgent,
+        ws_id: i64,
+        reserved: Option<i64>,
+    ) -> Self {
+        Self {
+            state,
+            agent,
+            ws_id,
+            reserved: Arc::new(Mutex::new(reserved)),
+        }
+    }
+
+    /// Reserve the next call, the run fails once the quota is spent
+    pub(crate) async fn reserve(&self) -> Result<i64, AgentError> {
+        let reserved = self.reserved.lock().expect("reserved call poisoned").take();
+        match reserved {
+            Some(id) => Ok(id),
+            None => Ok(self
+                .state
+                .reserve_agent_call(&self.agent, self.ws_id)
+                .await
+                .map_err(|e| anyhow!(e))?),
+        }
+    }
+
+    /// Count the tokens of a call, one without usage only counts as a request
+    pub(crate) async fn spend(&self, id: i64, usage: Option<TokenUsage>) {
+        if let Err(e) = self.state.spend_agent_call(id, usage).await {
+            warn!("Failed to count the tokens of call {}: {}", id, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ListMessages, PreviewAgent,
+        test_util::{add_agent_with_policy, message, mock_args, mock_error_args},
+    };
+    use anyhow::Result;
+    use chat_core::{AgentOutcomeStatus, AgentType};
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn agent_over_requests_per_minute_should_be_skipped() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let echo = add_agent_with_policy(
+            &state,
+            "echo",
+            AgentType::Reply,
+            "test",
+            mock_args("you said ${1}"),
+            json!({"requestsPerMinute": 1}),
+        )
+        .await?;
+
+        state.create_message(message("hello"), 3, 1).await?;
+        state.run_jobs().await?;
+        let msg = state.create_message(message("again"), 3, 1).await?;
+        state.run_jobs().await?;
+        let outcomes = state.list_agent_outcomes(3, msg.id as _).await?;
+        assert_eq!(outcomes[0].status, AgentOutcomeStatus::Skipped);
+        let error = outcomes[0].error.as_deref().unwrap_or_default();
+        assert!(error.contains("1 requests per minute"));
+
+        let input = ListMessages {
+            last_id: None,
+            limit: 0,
+        };
+        // two messages and a single reply
+        assert_eq!(state.list_messages(input, 3).await?.len(), 3);
+
+        let quota = state.get_agent_quota(3, echo.id as _, 1).await?;
+        assert_eq!(quota.agent.requests_per_minute.used, 1);
+        assert_eq!(quota.agent.requests_per_minute.remaining, Some(0));
+        assert!(quota.agent.tokens_per_day.used > 0);
+        assert_eq!(quota.workspace.tokens_per_day.remaining, None);
+
+        let preview = PreviewAgent {
+            content: "hey".to_string(),
+            history: None,
+        };
+        let ret = state.preview_agent(preview, 3, echo.id as _, 1).await;
+        assert!(matches!(ret, Err(AppError::QuotaExceeded(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn agent_over_tokens_per_day_should_be_skipped() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let echo = add_agent_with_policy(
+            &state,
+            "echo",
+            AgentType::Reply,
+            "test",
+            mock_args("you said ${1}"),
+            json!({"tokensPerDay": 3}),
+        )
+        .await?;
+
+        state.create_message(message("hello there"), 3, 1).await?;
+        state.run_jobs().await?;
+        let msg = state.create_message(message("again"), 3, 1).await?;
+        state.run_jobs().await?;
+        let outcomes = state.list_agent_outcomes(3, msg.id as _).await?;
+        assert_eq!(outcomes[0].status, AgentOutcomeStatus::Skipped);
+
+        let quota = state.get_agent_quota(3, echo.id as _, 1).await?;
+        assert_eq!(quota.agent.tokens_per_day.remaining, Some(0));
+        assert!(quota.agent.resets_at > Utc::now());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn every_model_call_should_be_reserved() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let policy = json!({"requestsPerMinute": 2, "maxRetries": 5});
+        let proxy = add_agent_with_policy(
+            &state,
+            "proxy",
+            AgentType::Proxy,
+            "test",
+            mock_error_args(),
+            policy,
+        )
+        .await?;
+
+        // the third attempt finds the quota spent by the first two
+        let msg = state.create_message(message("hi"), 3, 1).await?;
+        let outcomes = state.list_agent_outcomes(3, msg.id as _).await?;
+        assert_eq!(outcomes[0].status, AgentOutcomeStatus::Failed);
+        let error = outcomes[0].error.as_deref().unwrap_or_default();
+        assert!(error.contains("2 requests per minute"));
+        let quota = state.get_agent_quota(3, proxy.id as _, 1).await?;
+        assert_eq!(quota.agent.requests_per_minute.used, 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn every_tool_round_should_be_reserved() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let lookup =
+            json!({"tool_calls": [{"name": "lookup_user", "arguments": {"query": "Tyr"}}]});
+        let args = json!({
+            "tools": ["lookup_user"],
+            "mock": {"responses": [lookup, lookup], "default": {"text": "found him"}},
+        });
+        let policy = json!({"requestsPerMinute": 2});
+        let proxy =
+            add_agent_with_policy(&state, "proxy", AgentType::Proxy, "test", args, policy).await?;
+
+        // the third round finds the quota spent by the first two
+        let msg = state.create_message(message("who is Tyr?"), 3, 1).await?;
+        assert!(msg.modified_content.is_none());
+        let outcomes = state.list_agent_outcomes(3, msg.id as _).await?;
+        assert_eq!(outcomes[0].status, AgentOutcomeStatus::Failed);
+        let error = outcomes[0].error.as_deref().unwrap_or_default();
+        assert!(error.contains("2 requests per minute"));
+        let quota = state.get_agent_quota(3, proxy.id as _, 1).await?;
+        assert_eq!(quota.agent.requests_per_minute.used, 2);
+        assert!(quota.agent.tokens_per_day.used > 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn concurrent_calls_should_not_share_the_last_request() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let echo = add_agent_with_policy(
+            &state,
+            "echo",
+            AgentType::Reply,
+            "test",
+            mock_args("you said ${1}"),
+            json!({"requestsPerMinute": 1}),
+        )
+        .await?;
+        let (a, b) = tokio::join!(
+            state.reserve_agent_call(&echo, 1),
+            state.reserve_agent_call(&echo, 1)
+        );
+        assert_ne!(a.is_ok(), b.is_ok());
+        Ok(())
+    }
+}
@@ -0,0 +1,154 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::{AppError, AppState};
+
+#[derive(Debug, Clone, Default, IntoParams, ToSchema, Serialize, Deserialize)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct ListAgentUsage {
+    // invocations from, inclusive
+    #[serde(default)]
+    pub since: Option<DateTime<Utc>>,
+    // invocations until, exclusive
+    #[serde(default)]
+    pub until: Option<DateTime<Utc>>,
+}
+
+/// How often an agent of a chat ran in a period and what it cost
+#[derive(Debug, Clone, FromRow, ToSchema, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct AgentUsage {
+    #[serde(alias = "chatId")]
+    pub chat_id: i64,
+    #[serde(alias = "agentId")]
+    pub agent_id: i64,
+    // None once the agent is deleted
+    #[serde(alias = "agentName")]
+    pub agent_name: Option<String>,
+    pub invocations: i64,
+    pub failures: i64,
+    pub previews: i64,
+    #[serde(alias = "inputChars")]
+    pub input_chars: i64,
+    #[serde(alias = "outputChars")]
+    pub output_chars: i64,
+    #[serde(alias = "promptTokens")]
+    pub prompt_tokens: i64,
+    #[serde(alias = "completionTokens")]
+    pub completion_tokens: i64,
+    #[serde(alias = "avgLatencyMs")]
+    pub avg_latency_ms: i64,
+}
+
+impl AppState {
+    /// Usage of the agents of a workspace per chat and agent, or of a single chat
+    pub async fn list_agent_usage(
+        &self,
+        input: ListAgentUsage,
+        ws_id: u64,
+        chat_id: Option<u64>,
+    ) -> Result<Vec<AgentUsage>, AppError> {
+        let usage = sqlx::query_as(
+            r#"
+            SELECT i.chat_id, i.agent_id, a.name AS agent_name,
+                COUNT(*) AS invocations,
+                COUNT(*) FILTER (WHERE i.status = 'failed') AS failures,
+                COUNT(*) FILTER (WHERE i.source = 'preview') AS previews,
+                SUM(i.input_chars)::BIGINT AS input_chars,
+                SUM(i.output_chars)::BIGINT AS output_chars,
+                COALESCE(SUM(i.prompt_tokens), 0)::BIGINT AS prompt_tokens,
+                COALESCE(SUM(i.completion_tokens), 0)::BIGINT AS completion_tokens,
+                AVG(i.latency_ms)::BIGINT AS avg_latency_ms
+            FROM agent_invocations i
+            LEFT JOIN chat_agents a ON a.id = i.agent_id
+            WHERE i.ws_id = $1
+            AND ($2::BIGINT IS NULL OR i.chat_id = $2)
+            AND ($3::TIMESTAMPTZ IS NULL OR i.created_at >= $3)
+            AND ($4::TIMESTAMPTZ IS NULL OR i.created_at < $4)
+            GROUP BY i.chat_id, i.agent_id, a.name
+            ORDER BY i.chat_id, i.agent_id
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(chat_id.map(|id| id as i64))
+        .bind(input.since)
+        .bind(input.until)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(usage)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        PreviewAgent,
+        test_util::{add_agent, message, mock_args, mock_error_args},
+    };
+    use anyhow::Result;
+    use chat_core::AgentType;
+
+    #[tokio::test]
+    async fn agent_invocations_should_be_summarized() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let echo = add_agent(
+            &state,
+            "echo",
+            AgentType::Reply,
+            0,
+            mock_args("you said ${1}"),
+        )
+        .await?;
+        let broken = add_agent(&state, "broken", AgentType::Proxy, 0, mock_error_args()).await?;
+
+        state.create_message(message("hello"), 3, 1).await?;
+        state.run_jobs().await?;
+        state.create_message(message("hi"), 3, 1).await?;
+        state.run_jobs().await?;
+        let preview = PreviewAgent {
+            content: "hey".to_string(),
+            history: None,
+        };
+        state.preview_agent(preview, 3, echo.id as _, 1).await?;
+
+        let usage = state
+            .list_agent_usage(ListAgentUsage::default(), 1, Some(3))
+            .await?;
+        assert_eq!(usage.len(), 2);
+        let echo_usage = usage.iter().find(|u| u.agent_id == echo.id).unwrap();
+        assert_eq!(echo_usage.agent_name.as_deref(), Some("echo"));
+        assert_eq!(echo_usage.invocations, 3);
+        assert_eq!(echo_usage.failures, 0);
+        assert_eq!(echo_usage.previews, 1);
+        assert_eq!(echo_usage.input_chars, 10);
+        assert_eq!(echo_usage.output_chars, 37);
+        assert!(echo_usage.prompt_tokens > 0 && echo_usage.completion_tokens > 0);
+        let broken_usage = usage.iter().find(|u| u.agent_id == broken.id).unwrap();
+        assert_eq!(broken_usage.failures, 2);
+        assert_eq!(broken_usage.prompt_tokens, 0);
+
+        // the log outlives the agent
+        state.delete_agent(3, broken.id as _).await?;
+        let usage = state
+            .list_agent_usage(ListAgentUsage::default(), 1, None)
+            .await?;
+        assert_eq!(usage.len(), 2);
+        assert!(usage.iter().any(|u| u.agent_name.is_none()));
+
+        let input = ListAgentUsage {
+            since: Some(Utc::now()),
+            until: None,
+        };
+        assert!(state.list_agent_usage(input, 1, None).await?.is_empty());
+        assert!(
+            state
+                .list_agent_usage(ListAgentUsage::default(), 2, None)
+                .await?
+                .is_empty()
+        );
+        Ok(())
+    }
+}
@@ -8,6 +8,9 @@ use serde::{Deserialize, Serialize};
 use std::mem;
 use utoipa::ToSchema;
 
+// domain of the emails of the bots of agents
+pub(crate) const BOT_EMAIL_DOMAIN: &str = "@bot.org";
+
 #[derive(Debug, Clone, Serialize, ToSchema, Deserialize, PartialEq)]
 pub struct CreateUser {
     pub fullname: String,
@@ -47,6 +50,13 @@ impl AppState {
 
     //create a new user
     pub async fn create_user(&self, input: &CreateUser) -> Result<User, AppError> {
+        // bots are only created for agents, no one may sign in as one
+        if input.email.to_lowercase().ends_with(BOT_EMAIL_DOMAIN) {
+            return Err(AppError::CreateUserError(format!(
+                "Emails of {} are reserved for bots",
+                BOT_EMAIL_DOMAIN
+            )));
+        }
         // check if the user already exists
         let user = self.find_user_by_email(&input.email).await?;
         if user.is_some() {
@@ -60,12 +70,10 @@ impl AppState {
         };
 
         let password_hash = hash_password(&input.password)?;
-        // TODO: this is just a demo for now
-        let is_bot = input.email.ends_with("@bot.org");
         let mut user: User = sqlx::query_as(
             r#"
-            INSERT INTO users (ws_id, email, fullname, password_hash, is_bot)
-            VALUES ($1, $2, $3, $4, $5)
+            INSERT INTO users (ws_id, email, fullname, password_hash)
+            VALUES ($1, $2, $3, $4)
             RETURNING id, ws_id, fullname, email, created_at, is_bot
             "#,
         )
@@ -73,7 +81,6 @@ impl AppState {
         .bind(&input.email)
         .bind(&input.fullname)
         .bind(password_hash)
-        .bind(is_bot)
         .fetch_one(&self.pool)
         .await?;
 
@@ -213,6 +220,15 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn bot_email_should_be_reserved() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = CreateUser::new("acme", "Agent", "agent-1@Bot.org", "hunter42");
+        let ret = state.create_user(&input).await;
+        assert!(matches!(ret, Err(AppError::CreateUserError(_))));
+        Ok(())
+    }
+
     #[tokio::test]
     async fn create_user_and_verify_user_should_work() -> Result<()> {
         let (_tdb, state) = AppState::new_for_test().await?;
@@ -1,9 +1,17 @@
 use crate::handlers::*;
 use crate::{
-    AppState, CreateChat, CreateMessage, CreateUser, ErrorOutput, ListMessages, SigninUser,
+    AgentPreview, AgentQuota, AgentUsage, AppState, CreateChat, CreateEndpoint, CreateMessage,
+    CreateUser, ErrorOutput, ListAgentUsage, ListMessages, ListTapOutputs, PreviewAgent,
+    PreviewMessage, Quota, QuotaUsage, SigninUser, UpdateEndpoint, UpdateNotificationSettings,
+    UpdateWorkspaceNotification,
 };
 use axum::Router;
-use chat_core::{AgentType, Chat, ChatAgent, ChatType, ChatUser, Message, User, Workspace};
+use chat_core::{
+    AdapterType, AgentFallback, AgentOutcome, AgentOutcomeStatus, AgentPolicy, AgentType, Chat,
+    ChatAgent, ChatType, ChatUser, Citation, FailurePolicy, Message, ModelEndpoint, ModelOptions,
+    NotificationLevel, NotificationSettings, ResponseFormat, TapOutput, TokenUsage, User,
+    Workspace,
+};
 use utoipa::{
     Modify, OpenApi,
     openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme},
@@ -28,14 +36,35 @@ pub(crate) trait OpenApiRouter {
             send_message_handler,
             list_chat_users_handler,
             create_agent_handler,
+            get_agent_handler,
             update_agent_handler,
+            delete_agent_handler,
+            preview_agent_handler,
             list_agent_handler,
+            list_agent_outcome_handler,
+            list_tap_output_handler,
+            get_notification_handler,
+            update_notification_handler,
+            update_workspace_notification_handler,
+            chat_agent_usage_handler,
+            workspace_agent_usage_handler,
+            get_agent_quota_handler,
+            workspace_agent_quota_handler,
+            list_endpoint_handler,
+            create_endpoint_handler,
+            update_endpoint_handler,
+            delete_endpoint_handler,
 
         ),
         components(
             schemas(
                 User, Chat, ChatType, ChatAgent, AgentType, ChatUser, Message, Workspace,
-                SigninUser, CreateUser, CreateChat, CreateMessage, ListMessages, AuthOutput, ErrorOutput
+                SigninUser, CreateUser, CreateChat, CreateMessage, ListMessages, AuthOutput, ErrorOutput,
+                FailurePolicy, NotificationLevel, NotificationSettings, UpdateNotificationSettings, UpdateWorkspaceNotification,
+                AgentOutcome, AgentOutcomeStatus, TapOutput, ListTapOutputs,
+                PreviewAgent, PreviewMessage, AgentPreview, TokenUsage, AgentUsage, ListAgentUsage,
+                AgentPolicy, FailurePolicy, AgentFallback, AgentQuota, Quota, QuotaUsage, Citation,
+                ModelEndpoint, CreateEndpoint, UpdateEndpoint, AdapterType, ModelOptions, ResponseFormat
             ),
         ),
         modifiers(&SecurityAddon),
@@ -0,0 +1,164 @@
+use chat_core::{AgentArgs, AgentContext, AgentType};
+use minijinja::{Environment, context};
+
+use crate::TOOL_NAMES;
+
+/// Variables a prompt template can use
+pub const PROMPT_VARS: [&str; 4] = ["message", "sender", "chat", "args"];
+
+// postgres names are up to 63 bytes, `ws<id>_` takes up to 22 of them
+const MAX_COLLECTION_CHARS: usize = 40;
+// chunks a reply agent may get from its knowledge collection
+const MAX_TOP_K: u64 = 20;
+
+/// A rendered prompt. If the template placed the message itself, the prompt is
+/// sent as the user message instead of as the system prompt.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Prompt {
+    pub text: String,
+    pub has_message: bool,
+}
+
+/// Render the prompt template of an agent for a message, e.g.
+/// `Translate to {{ args.language }}: {{ message }}`
+pub fn render_prompt(
+    template: &str,
+    msg: &str,
+    ctx: &AgentContext,
+    args: &AgentArgs,
+) -> Result<Prompt, minijinja::Error> {
+    let env = Environment::new();
+    let tmpl = env.template_from_str(template)?;
+    let has_message = tmpl.undeclared_variables(false).contains("message");
+    let text = tmpl.render(context! {
+        message => msg,
+        sender => ctx.sender,
+        chat => ctx.chat,
+        args => args,
+    })?;
+    Ok(Prompt { text, has_message })
+}
+
+/// Check the prompt template and the args of an agent before it is saved
+pub fn validate_agent(
+    r#type: &AgentType,
+    prompt: &str,
+    args: &serde_json::Value,
+) -> Result<AgentArgs, String> {
+    let args = AgentArgs::parse(r#type, args).map_err(|e| format!("Invalid args: {}", e))?;
+    if let Some(tool) = args
+        .tools()
+        .iter()
+        .find(|t| !TOOL_NAMES.contains(&t.as_str()))
+    {
+        return Err(format!(
+            "Unknown tool {}, available are {}",
+            tool,
+            TOOL_NAMES.join(", ")
+        ));
+    }
+    if let Some(knowledge) = args.knowledge() {
+        // the collection names a table once prefixed with the workspace
+        let name = &knowledge.collection;
+        let valid = name.len() <= MAX_COLLECTION_CHARS
+            && name.starts_with(|c: char| c.is_ascii_lowercase() || c == '_')
+            && name
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+        if !valid {
+            return Err(format!(
+                "Invalid knowledge collection {}, use lowercase letters, digits and _",
+                name
+            ));
+        }
+        if !(1..=MAX_TOP_K).contains(&knowledge.top_k) {
+            return Err(format!("Knowledge top_k must be 1 to {}", MAX_TOP_K));
+        }
+    }
+    let env = Environment::new();
+    let tmpl = env
+        .template_from_str(prompt)
+        .map_err(|e| format!("Invalid prompt template: {}", e))?;
+    let mut unknown: Vec<_> = tmpl
+        .undeclared_variables(false)
+        .into_iter()
+        .filter(|v| !PROMPT_VARS.contains(&v.as_str()))
+        .collect();
+    if !unknown.is_empty() {
+        unknown.sort();
+        return Err(format!(
+            "Unknown prompt variables {}, available are {}",
+            unknown.join(", "),
+            PROMPT_VARS.join(", ")
+        ));
+    }
+    Ok(args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chat_core::{Chat, ChatType, ChatUser};
+    use chrono::Utc;
+    use serde_json::json;
+
+    fn context() -> AgentContext {
+        let chat = Chat {
+            id: 1,
+            ws_id: 1,
+            name: Some("general".to_string()),
+            r#type: ChatType::PublicChannel,
+            members: vec![1, 2],
+            agents: vec![],
+            created_at: Utc::now(),
+        };
+        let sender = ChatUser {
+            id: 1,
+            fullname: "Tyr Chen".to_string(),
+            email: "tchen@acme.org".to_string(),
+        };
+        AgentContext::new(chat, sender, vec![])
+    }
+
+    #[test]
+    fn render_prompt_should_fill_variables() -> anyhow::Result<()> {
+        let args = AgentArgs::parse(&AgentType::Proxy, &json!({"language": "French"}))?;
+        let prompt = render_prompt(
+            "{{ sender.fullname }} in {{ chat.name }}, translate to {{ args.language }}: {{ message }}",
+            "hello",
+            &context(),
+            &args,
+        )?;
+        assert_eq!(
+            prompt.text,
+            "Tyr Chen in general, translate to French: hello"
+        );
+        assert!(prompt.has_message);
+
+        let prompt = render_prompt("Be nice.", "hello", &context(), &args)?;
+        assert_eq!(prompt.text, "Be nice.");
+        assert!(!prompt.has_message);
+        Ok(())
+    }
+
+    #[test]
+    fn validate_agent_should_reject_bad_template_or_args() {
+        let ok = validate_agent(&AgentType::Tap, "Summarize {{ message }}", &json!({}));
+        assert!(ok.is_ok());
+        assert!(validate_agent(&AgentType::Proxy, "{{ message", &json!({})).is_err());
+        assert!(validate_agent(&AgentType::Proxy, "{{ msg }}", &json!({})).is_err());
+        assert!(validate_agent(&AgentType::Tap, "{{ message }}", &json!({"kind": 1})).is_err());
+        let tools = json!({"tools": ["lookup_user", "send_email"]});
+        assert!(validate_agent(&AgentType::Reply, "", &tools).is_err());
+
+        let knowledge = json!({"knowledge": {"collection": "swiftide_rag"}});
+        let args = validate_agent(&AgentType::Reply, "", &knowledge).expect("args should be valid");
+        assert_eq!(args.knowledge().map(|k| k.top_k), Some(5));
+        let knowledge = json!({"knowledge": {"collection": "docs; DROP TABLE users"}});
+        assert!(validate_agent(&AgentType::Reply, "", &knowledge).is_err());
+        let knowledge = json!({"knowledge": {"collection": "d".repeat(41)}});
+        assert!(validate_agent(&AgentType::Reply, "", &knowledge).is_err());
+        let knowledge = json!({"knowledge": {"collection": "docs", "top_k": 0}});
+        assert!(validate_agent(&AgentType::Reply, "", &knowledge).is_err());
+    }
+}
@@ -0,0 +1,292 @@
+use ai_sdk::{Tool, ToolCall};
+use anyhow::{anyhow, bail};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use sqlx::FromRow;
+
+use crate::AppState;
+
+/// Built-in tools an agent can be given in `args.tools`
+pub const TOOL_NAMES: [&str; 3] = ["search_messages", "lookup_user", "list_files"];
+
+const MAX_RESULTS: i64 = 20;
+
+/// The tools of an agent for one message. They run with the permissions of
+/// the sender: only the chat of the message and users of their workspace.
+#[derive(Clone)]
+pub struct ChatTools {
+    state: AppState,
+    chat_id: i64,
+    user_id: i64,
+    names: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SearchMessages {
+    query: String,
+    #[serde(default)]
+    limit: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct LookupUser {
+    query: String,
+}
+
+#[derive(Debug, Serialize, FromRow)]
+struct FoundMessage {
+    id: i64,
+    sender: String,
+    content: String,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, FromRow)]
+struct FoundUser {
+    id: i64,
+    fullname: String,
+    email: String,
+}
+
+#[derive(Debug, Serialize, FromRow)]
+struct FoundFile {
+    url: String,
+    message_id: i64,
+    sender: String,
+    created_at: DateTime<Utc>,
+}
+
+impl ChatTools {
+    pub fn new(state: AppState, chat_id: i64, user_id: i64, names: Vec<String>) -> Self {
+        Self {
+            state,
+            chat_id,
+            user_id,
+            names,
+        }
+    }
+
+    /// definitions of the tools sent to the model
+    pub fn definitions(&self) -> Vec<Tool> {
+        self.names.iter().filter_map(|n| definition(n)).collect()
+    }
+
+    /// Run a call the model asked for. Errors are the result of the call, so
+    /// the model can correct its arguments or answer without the tool.
+    pub async fn call(&self, call: &ToolCall) -> String {
+        match self.run(call).await {
+            Ok(ret) => ret.to_string(),
+            Err(e) => json!({ "error": e.to_string() }).to_string(),
+        }
+    }
+
+    async fn run(&self, call: &ToolCall) -> anyhow::Result<Value> {
+        if !self.names.contains(&call.name) {
+            bail!("tool {} is not available", call.name);
+        }
+        if !self
+            .state
+            .is_chat_member(self.chat_id as _, self.user_id as _)
+            .await?
+        {
+            bail!("user {} left the chat", self.user_id);
+        }
+        let args = call.arguments.clone();
+        let ret = match call.name.as_str() {
+            "search_messages" => json!(self.search_messages(serde_json::from_value(args)?).await?),
+            "lookup_user" => json!(self.lookup_user(serde_json::from_value(args)?).await?),
+            "list_files" => json!(self.list_files().await?),
+            name => return Err(anyhow!("unknown tool {}", name)),
+        };
+        Ok(ret)
+    }
+
+    async fn search_messages(&self, input: SearchMessages) -> anyhow::Result<Vec<FoundMessage>> {
+        let limit = input.limit.unwrap_or(10).clamp(1, MAX_RESULTS);
+        let messages = sqlx::query_as(
+            r#"
+            SELECT m.id, u.fullname AS sender, m.content, m.created_at
+            FROM messages m
+            JOIN users u ON u.id = m.sender_id
+            WHERE m.chat_id = $1 AND m.status = 'sent' AND m.content ILIKE $2 ESCAPE '\'
+            ORDER BY m.id DESC
+            LIMIT $3
+            "#,
+        )
+        .bind(self.chat_id)
+        .bind(contains(&input.query))
+        .bind(limit)
+        .fetch_all(&self.state.pool)
+        .await?;
+        Ok(messages)
+    }
+
+    async fn lookup_user(&self, input: LookupUser) -> anyhow::Result<Vec<FoundUser>> {
+        let users = sqlx::query_as(
+            r#"
+            SELECT u.id, u.fullname, u.email
+            FROM users u
+            JOIN users caller ON caller.ws_id = u.ws_id
+            WHERE caller.id = $1 AND (u.fullname ILIKE $2 ESCAPE '\' OR u.email ILIKE $2 ESCAPE '\')
+            ORDER BY u.id
+            LIMIT $3
+            "#,
+        )
+        .bind(self.user_id)
+        .bind(contains(&input.query))
+        .bind(MAX_RESULTS)
+        .fetch_all(&self.state.pool)
+        .await?;
+        Ok(users)
+    }
+
+    async fn list_files(&self) -> anyhow::Result<Vec<FoundFile>> {
+        let files = sqlx::query_as(
+            r#"
+            SELECT f.url, m.id AS message_id, u.fullname AS sender, m.created_at
+            FROM messages m
+            JOIN users u ON u.id = m.sender_id
+            CROSS JOIN LATERAL unnest(m.files) AS f(url)
+            WHERE m.chat_id = $1
+            ORDER BY m.id DESC
+            LIMIT $2
+            "#,
+        )
+        .bind(self.chat_id)
+        .bind(MAX_RESULTS)
+        .fetch_all(&self.state.pool)
+        .await?;
+        Ok(files)
+    }
+}
+
+// ILIKE pattern matching the text anywhere, the model may search for `%` or `_`
+fn contains(text: &str) -> String {
+    let text = text
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    format!("%{}%", text)
+}
+
+fn definition(name: &str) -> Option<Tool> {
+    let tool = match name {
+        "search_messages" => Tool::new(
+            name,
+            "Search earlier messages of this chat by text, newest first",
+            json!({
+                "type": "object",
+                "properties": {
+                    "query": {"type": "string", "description": "text the message contains"},
+                    "limit": {"type": "integer", "description": "at most 20, default 10"}
+                },
+                "required": ["query"]
+            }),
+        ),
+        "lookup_user" => Tool::new(
+            name,
+            "Find users of the workspace by name or email",
+            json!({
+                "type": "object",
+                "properties": {
+                    "query": {"type": "string", "description": "part of the name or email"}
+                },
+                "required": ["query"]
+            }),
+        ),
+        "list_files" => Tool::new(
+            name,
+            "List files shared in this chat, newest first",
+            json!({"type": "object", "properties": {}}),
+        ),
+        _ => return None,
+    };
+    Some(tool)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CreateUser, test_util::message};
+    use anyhow::Result;
+
+    fn call(name: &str, arguments: Value) -> ToolCall {
+        ToolCall {
+            id: "call_0".to_string(),
+            name: name.to_string(),
+            arguments,
+        }
+    }
+
+    fn tools(state: &AppState, chat_id: i64, user_id: i64) -> ChatTools {
+        let names = TOOL_NAMES.iter().map(|n| n.to_string()).collect();
+        ChatTools::new(state.clone(), chat_id, user_id, names)
+    }
+
+    #[tokio::test]
+    async fn tools_should_search_messages_and_users() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let tools = tools(&state, 1, 1);
+        assert_eq!(tools.definitions().len(), TOOL_NAMES.len());
+
+        let ret = tools
+            .call(&call(
+                "search_messages",
+                json!({"query": "fine", "limit": 5}),
+            ))
+            .await;
+        let ret: Vec<Value> = serde_json::from_str(&ret)?;
+        assert_eq!(ret.len(), 1);
+        assert_eq!(ret[0]["sender"], "Bob Jackson");
+
+        let input = CreateUser::new("other", "Tyr Other", "tyr@other.org", "123456");
+        state.create_user(&input).await?;
+        let ret = tools
+            .call(&call("lookup_user", json!({"query": "tyr"})))
+            .await;
+        let ret: Vec<Value> = serde_json::from_str(&ret)?;
+        // users of other workspaces are not visible
+        assert_eq!(ret.len(), 1);
+        assert_eq!(ret[0]["email"], "tchen@acme.org");
+
+        let ret = tools.call(&call("list_files", json!({}))).await;
+        assert_eq!(ret, "[]");
+
+        // wildcards are searched for as they are
+        state.create_message(message("50% off"), 1, 1).await?;
+        for (query, found) in [("50%", 1), ("5_%", 0), ("\\", 0)] {
+            let ret = tools
+                .call(&call("search_messages", json!({"query": query})))
+                .await;
+            let ret: Vec<Value> = serde_json::from_str(&ret)?;
+            assert_eq!(ret.len(), found, "{}", query);
+        }
+        let ret = tools
+            .call(&call("lookup_user", json!({"query": "_"})))
+            .await;
+        assert_eq!(ret, "[]");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tools_should_respect_permissions_and_report_errors() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        // user 5 is not a member of the private chat
+        let ret = tools(&state, 2, 5)
+            .call(&call("list_files", json!({})))
+            .await;
+        assert!(ret.contains("left the chat"));
+
+        let tools = ChatTools::new(state, 1, 1, vec!["search_messages".to_string()]);
+        let ret = tools
+            .call(&call("lookup_user", json!({"query": "tyr"})))
+            .await;
+        assert!(ret.contains("not available"));
+        let ret = tools
+            .call(&call("search_messages", json!({"text": "hi"})))
+            .await;
+        assert!(ret.contains("missing field `query`"));
+        Ok(())
+    }
+}
@@ -1,5 +1,6 @@
-use chat_core::{Chat, ChatAgent, ChatType, Message};
+use chat_core::{Chat, ChatAgent, ChatType, EventOutbox, Message};
 use futures::StreamExt;
+use notify_server::{PushMessage, PushStatus, PushSubscription, PushTransport};
 use reqwest::{
     StatusCode,
     multipart::{Form, Part},
@@ -7,12 +8,32 @@ use reqwest::{
 use reqwest_eventsource::{Event, EventSource};
 use serde::Deserialize;
 use serde_json::json;
-use std::{net::SocketAddr, time::Duration};
-use tokio::{net::TcpListener, time::sleep};
+use std::{
+    net::SocketAddr,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+use tokio::{
+    net::TcpListener,
+    sync::mpsc,
+    time::{sleep, timeout},
+};
 
 use anyhow::Result;
 
 const WILD_ADDR: &str = "0.0.0.0:0";
+// P-256 keys used by the notify server (vapid) and the fake browser (p256dh)
+const VAPID_PRIVATE_KEY: &str = "GMzYYhjhkVJpBKjv2iyvNhEQgBpkqnwG626nCVKyyT8";
+const VAPID_PUBLIC_KEY: &str =
+    "BMBRS939OkmvdIbndUgCbeNqquFGG3O2pmQIEjzQizNAP2TeqWlJ4c8tCKJ1cWxQi7O8ITQc-oM1Lu481sk7SQg";
+const BROWSER_PUBLIC_KEY: &str =
+    "BDp95IyMq3976EMwyRHhLRFKxW2_s8evcbzQvRLKPd-JgcHPoGzNUQaDRf32tEc3074Chi_SSP9MfigOnjkF6Qk";
+const BROWSER_AUTH: &str = "8eDyX_uCN0XRhSbY5hs7Hg";
+
+type PushRequest = (axum::http::HeaderMap, axum::body::Bytes);
 struct ChatServer {
     addr: SocketAddr,
     token: String,
@@ -24,7 +45,10 @@ struct AuthToken {
     token: String,
 }
 
-struct NotifyServer;
+struct NotifyServer {
+    addr: SocketAddr,
+    client: reqwest::Client,
+}
 
 #[tokio::test]
 #[ignore]
@@ -32,7 +56,8 @@ async fn chat_server_should_work() -> Result<()> {
     let (tdb, state) = chat_server::AppState::new_for_test().await?;
     let chat_server = ChatServer::new(state).await?;
     let db_url = tdb.url();
-    NotifyServer::new(&db_url, &chat_server.token).await?;
+    let notify_server = NotifyServer::new(&db_url, "notify-0").await?;
+    notify_server.listen(&chat_server.token);
     let chat = chat_server.create_chat().await?;
     let _agent = chat_server.create_agent(chat.id as u64).await?;
     let _message = chat_server.create_message(chat.id as u64).await?;
@@ -41,11 +66,485 @@ async fn chat_server_should_work() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+#[ignore]
+async fn mock_agents_should_rewrite_and_reply() -> Result<()> {
+    let (_tdb, state) = chat_server::AppState::new_for_test().await?;
+    let chat_server = ChatServer::new(state).await?;
+    let chat = chat_server.create_chat().await?;
+    let rules = json!([{"pattern": "^please (.*)$", "response": {"text": "$1"}}]);
+    let proxy = json!({
+        "name": "polite", "type": "proxy", "adapter": "test", "model": "test", "prompt": "",
+        "args": {"mock": {"rules": rules}}
+    });
+    chat_server.create_agent_with(chat.id as u64, proxy).await?;
+    let reply = json!({
+        "name": "helper", "type": "reply", "adapter": "test", "model": "test", "prompt": "",
+        "args": {"mock": {"default": {"text": "on it"}}}
+    });
+    chat_server.create_agent_with(chat.id as u64, reply).await?;
+
+    let message = chat_server
+        .send_message(chat.id as u64, "please @helper ship it")
+        .await?;
+    assert_eq!(message.modified_content.as_deref(), Some("@helper ship it"));
+    // the reply comes from the job workers after the message is sent
+    timeout(Duration::from_secs(5), async {
+        loop {
+            let messages = chat_server.list_messages(chat.id as u64).await?;
+            if messages.iter().any(|m| m.content == "on it") {
+                return Ok::<_, anyhow::Error>(());
+            }
+            sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await??;
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore]
+async fn notify_server_should_scale_horizontally() -> Result<()> {
+    let (tdb, state) = chat_server::AppState::new_for_test().await?;
+    let chat_server = ChatServer::new(state).await?;
+    let db_url = tdb.url();
+    // two instances share one database, each user is connected to a different one
+    let notify1 = NotifyServer::new(&db_url, "notify-1").await?;
+    let notify2 = NotifyServer::new(&db_url, "notify-2").await?;
+    let other_token = chat_server.signin_as("jdoe@acme.org").await?;
+    let (mut rx1, es1) = notify1.subscribe(&chat_server.token);
+    let (mut rx2, _es2) = notify2.subscribe(&other_token);
+    sleep(Duration::from_millis(500)).await;
+
+    let chat = chat_server.create_chat().await?;
+    for rx in [&mut rx1, &mut rx2] {
+        let (event, data) = rx.recv().await.expect("event should be delivered");
+        assert_eq!(event, "NewChat");
+        let received: Chat = serde_json::from_str(&data)?;
+        assert_eq!(received.id, chat.id);
+    }
+
+    let metrics = notify1.metrics().await?;
+    assert_eq!(metrics.instance_id, "notify-1");
+    assert_eq!(metrics.active_users, 1);
+    assert_eq!(metrics.active_connections, 1);
+    assert_eq!(metrics.events_delivered, 1);
+
+    // once the only connection of user 1 goes away its sender should be released
+    es1.abort();
+    let mut metrics = notify1.metrics().await?;
+    for _ in 0..10 {
+        if metrics.active_connections == 0 {
+            break;
+        }
+        sleep(Duration::from_millis(500)).await;
+        metrics = notify1.metrics().await?;
+    }
+    assert_eq!(metrics.active_connections, 0);
+    assert_eq!(metrics.active_users, 0);
+    assert_eq!(metrics.total_connections, 1);
+
+    let metrics = notify2.metrics().await?;
+    assert_eq!(metrics.active_users, 1);
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore]
+async fn outbox_should_be_pruned() -> Result<()> {
+    let (tdb, state) = chat_server::AppState::new_for_test().await?;
+    let chat_server = ChatServer::new(state).await?;
+    let db_url = tdb.url();
+    let pool = sqlx::PgPool::connect(&db_url).await?;
+    // the consumer of an instance which is gone for good
+    let gone = EventOutbox::new(pool.clone(), "notify_server:gone");
+    gone.register().await?;
+    sqlx::query(
+        "UPDATE event_consumers SET last_seen_at = NOW() - INTERVAL '2 days' WHERE name = $1",
+    )
+    .bind(gone.consumer())
+    .execute(&pool)
+    .await?;
+
+    let mut config = NotifyServer::config(&db_url, "notify-0")?;
+    config.outbox.prune_interval_secs = 1;
+    let _notify = NotifyServer::start(config).await?;
+    chat_server.create_chat().await?;
+
+    // the event is acknowledged by the only consumer left and deleted
+    timeout(Duration::from_secs(5), async {
+        loop {
+            let consumers: Vec<String> = sqlx::query_scalar("SELECT name FROM event_consumers")
+                .fetch_all(&pool)
+                .await?;
+            let events: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM events")
+                .fetch_one(&pool)
+                .await?;
+            if consumers == ["notify_server:notify-0"] && events == 0 {
+                return Ok::<_, anyhow::Error>(());
+            }
+            sleep(Duration::from_millis(200)).await;
+        }
+    })
+    .await??;
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore]
+async fn replayed_events_should_not_be_sent_again() -> Result<()> {
+    let (tdb, state) = chat_server::AppState::new_for_test().await?;
+    let chat_server = ChatServer::new(state).await?;
+    let db_url = tdb.url();
+    let pool = sqlx::PgPool::connect(&db_url).await?;
+    // the message is sent while the instance is down, it is replayed on start.
+    // Another consumer keeps the event from being pruned once replayed
+    for consumer in ["notify_server:notify-0", "archive"] {
+        EventOutbox::new(pool.clone(), consumer).register().await?;
+    }
+    chat_server.send_message(3, "while you were away").await?;
+    let id: i64 = sqlx::query_scalar("SELECT MAX(id) FROM events")
+        .fetch_one(&pool)
+        .await?;
+    let notify_server = NotifyServer::new(&db_url, "notify-0").await?;
+    let other_token = chat_server.signin_as("jdoe@acme.org").await?;
+    let (mut rx, _es) = notify_server.subscribe(&other_token);
+    sleep(Duration::from_millis(500)).await;
+
+    // the listener is notified of the replayed event once more
+    sqlx::query("SELECT pg_notify('chat_message_created', $1)")
+        .bind(id.to_string())
+        .execute(&pool)
+        .await?;
+    assert!(
+        timeout(Duration::from_millis(500), rx.recv())
+            .await
+            .is_err()
+    );
+
+    chat_server.send_message(3, "hello").await?;
+    let (event, data) = rx.recv().await.expect("event should be delivered");
+    assert_eq!(event, "NewMessage");
+    let received: notify_server::MessageEvent = serde_json::from_str(&data)?;
+    assert_eq!(received.message.content, "hello");
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore]
+async fn notification_settings_should_silence_messages() -> Result<()> {
+    let (tdb, state) = chat_server::AppState::new_for_test().await?;
+    let chat_server = ChatServer::new(state).await?;
+    let notify_server = NotifyServer::new(&tdb.url(), "notify-0").await?;
+    let other_token = chat_server.signin_as("jdoe@acme.org").await?;
+    // John Doe only wants to be alerted when mentioned in the single chat with Tyr
+    chat_server
+        .update_notification(3, &other_token, json!({"level": "mentions"}))
+        .await?;
+    let (mut rx, _es) = notify_server.subscribe(&other_token);
+    sleep(Duration::from_millis(500)).await;
+
+    for (content, silent) in [("hello", true), ("hello @John Doe", false)] {
+        chat_server.send_message(3, content).await?;
+        let (event, data) = rx.recv().await.expect("event should be delivered");
+        assert_eq!(event, "NewMessage");
+        let received: notify_server::MessageEvent = serde_json::from_str(&data)?;
+        assert_eq!(received.message.content, content);
+        assert_eq!(received.silent, silent);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore]
+async fn offline_users_should_get_web_push() -> Result<()> {
+    let (tdb, state) = chat_server::AppState::new_for_test().await?;
+    let chat_server = ChatServer::new(state).await?;
+    let notify_server = NotifyServer::with_push(&tdb.url(), "notify-0").await?;
+    let (endpoint, mut pushes) = start_push_service().await?;
+    assert_eq!(notify_server.vapid_key().await?, VAPID_PUBLIC_KEY);
+
+    let other_token = chat_server.signin_as("jdoe@acme.org").await?;
+    notify_server
+        .subscribe_push(&other_token, &endpoint)
+        .await?;
+
+    // John Doe isn't connected, the direct message should be pushed
+    chat_server.send_message(3, "hello").await?;
+    let (headers, body) = timeout(Duration::from_secs(5), pushes.recv())
+        .await?
+        .expect("push should be delivered");
+    let authorization = headers["authorization"].to_str()?;
+    assert!(authorization.starts_with("vapid t="));
+    assert!(authorization.ends_with(&format!("k={}", VAPID_PUBLIC_KEY)));
+    assert_eq!(headers["content-encoding"], "aes128gcm");
+    // salt, record size, key id and at least the padding delimiter and the auth tag
+    assert!(body.len() > 16 + 4 + 1 + 65 + 17);
+
+    // channel messages are only pushed when the user is mentioned
+    chat_server.send_message(2, "hello").await?;
+    chat_server.send_message(2, "hello @John Doe").await?;
+    let (_, body) = timeout(Duration::from_secs(5), pushes.recv())
+        .await?
+        .expect("push should be delivered");
+    assert!(!body.is_empty());
+    assert!(
+        timeout(Duration::from_millis(500), pushes.recv())
+            .await
+            .is_err()
+    );
+
+    // once connected, the user gets the messages from the event stream only
+    let (mut rx, _es) = notify_server.subscribe(&other_token);
+    sleep(Duration::from_millis(500)).await;
+    chat_server.send_message(3, "are you there?").await?;
+    let (event, _) = rx.recv().await.expect("event should be delivered");
+    assert_eq!(event, "NewMessage");
+    assert!(
+        timeout(Duration::from_millis(500), pushes.recv())
+            .await
+            .is_err()
+    );
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore]
+async fn failed_web_push_should_be_retried() -> Result<()> {
+    let (tdb, state) = chat_server::AppState::new_for_test().await?;
+    let chat_server = ChatServer::new(state).await?;
+    let notify_server = NotifyServer::with_push(&tdb.url(), "notify-0").await?;
+    let (endpoint, mut pushes) = start_flaky_push_service(1).await?;
+    let other_token = chat_server.signin_as("jdoe@acme.org").await?;
+    notify_server
+        .subscribe_push(&other_token, &endpoint)
+        .await?;
+
+    // the push service is down for the first try, the push goes out once it is back
+    chat_server.send_message(3, "hello").await?;
+    timeout(Duration::from_secs(5), pushes.recv())
+        .await?
+        .expect("push should be retried");
+    assert!(
+        timeout(Duration::from_millis(2500), pushes.recv())
+            .await
+            .is_err()
+    );
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore]
+async fn streamed_replies_should_be_pushed_once_final() -> Result<()> {
+    let (tdb, state) = chat_server::AppState::new_for_test().await?;
+    let chat_server = ChatServer::new(state).await?;
+    let (transport, mut pushes) = FakePushTransport::new();
+    let notify_server = NotifyServer::with_transport(&tdb.url(), "notify-0", transport).await?;
+    notify_server
+        .subscribe_push(&chat_server.token, "https://push.example.com/tyr")
+        .await?;
+    let rules = json!([{"pattern": "(?s)^(.*)$", "response": {"text": "you said ${1}"}}]);
+    let reply = json!({
+        "name": "helper", "type": "reply", "adapter": "test", "model": "test", "prompt": "",
+        "args": {"mock": {"rules": rules}}
+    });
+    chat_server.create_agent_with(3, reply).await?;
+
+    // the reply is streamed as John Doe, Tyr is pushed its final content only
+    chat_server.send_message(3, "hello").await?;
+    let (subscription, message) = timeout(Duration::from_secs(5), pushes.recv())
+        .await?
+        .expect("push should be delivered");
+    assert_eq!(subscription.user_id, 1);
+    assert_eq!(message.sender_id, 2);
+    assert_eq!(message.body, "you said hello");
+    assert!(
+        timeout(Duration::from_millis(500), pushes.recv())
+            .await
+            .is_err()
+    );
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore]
+async fn last_delta_should_carry_the_stored_citations() -> Result<()> {
+    let (tdb, state) = chat_server::AppState::new_for_test().await?;
+    let chat_server = ChatServer::new(state).await?;
+    let db_url = tdb.url();
+    let pool = sqlx::PgPool::connect(&db_url).await?;
+    let notify_server = NotifyServer::new(&db_url, "notify-0").await?;
+    let other_token = chat_server.signin_as("jdoe@acme.org").await?;
+    let (mut rx, _es) = notify_server.subscribe_with(&other_token, "events=MessageDelta");
+    sleep(Duration::from_millis(500)).await;
+
+    // far more sources than a notification could take
+    let message = chat_server.send_message(3, "see the docs [1]").await?;
+    let citations: Vec<_> = (1..=20)
+        .map(|i| json!({"index": i, "source": "docs/setup.md", "excerpt": "x".repeat(1000)}))
+        .collect();
+    sqlx::query("UPDATE messages SET citations = $2 WHERE id = $1")
+        .bind(message.id)
+        .bind(json!(citations))
+        .execute(&pool)
+        .await?;
+    let delta = json!({
+        "chatId": 3, "messageId": message.id, "seq": 0, "delta": "", "done": true,
+        "status": "sent", "members": [1, 2]
+    });
+    sqlx::query("SELECT pg_notify('chat_message_delta', $1)")
+        .bind(delta.to_string())
+        .execute(&pool)
+        .await?;
+
+    let (event, data) = timeout(Duration::from_secs(5), rx.recv())
+        .await?
+        .expect("delta should be delivered");
+    assert_eq!(event, "MessageDelta");
+    let received: chat_core::MessageDelta = serde_json::from_str(&data)?;
+    assert!(received.done);
+    assert_eq!(received.citations.len(), 20);
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore]
+async fn events_should_be_filtered_by_subscription() -> Result<()> {
+    let (tdb, state) = chat_server::AppState::new_for_test().await?;
+    let chat_server = ChatServer::new(state).await?;
+    let notify_server = NotifyServer::new(&tdb.url(), "notify-0").await?;
+    let other_token = chat_server.signin_as("jdoe@acme.org").await?;
+    let (mut rx, _es) = notify_server.subscribe_with(&other_token, "chats=3&events=NewMessage");
+    sleep(Duration::from_millis(500)).await;
+
+    chat_server.create_chat().await?;
+    chat_server.send_message(1, "hello general").await?;
+    chat_server.send_message(3, "hello john").await?;
+    let (event, data) = rx.recv().await.expect("event should be delivered");
+    assert_eq!(event, "NewMessage");
+    let received: notify_server::MessageEvent = serde_json::from_str(&data)?;
+    assert_eq!(received.message.chat_id, 3);
+    assert!(
+        timeout(Duration::from_millis(500), rx.recv())
+            .await
+            .is_err()
+    );
+
+    // invalid filters are rejected
+    let res = reqwest::get(format!(
+        "http://{}/events?token={}&events=Typing",
+        notify_server.addr, other_token
+    ))
+    .await?;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    Ok(())
+}
+
+/// delivers pushes in process, with the payload before encryption
+struct FakePushTransport(mpsc::UnboundedSender<(PushSubscription, PushMessage)>);
+
+impl FakePushTransport {
+    fn new() -> (
+        Self,
+        mpsc::UnboundedReceiver<(PushSubscription, PushMessage)>,
+    ) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        (Self(tx), rx)
+    }
+}
+
+impl PushTransport for FakePushTransport {
+    async fn send(&self, subscription: &PushSubscription, payload: &[u8]) -> Result<PushStatus> {
+        let message = serde_json::from_slice(payload)?;
+        let _ = self.0.send((subscription.clone(), message));
+        Ok(PushStatus::Delivered)
+    }
+}
+
+/// a push service accepting every message, received requests go to the returned channel
+async fn start_push_service() -> Result<(String, mpsc::UnboundedReceiver<PushRequest>)> {
+    start_flaky_push_service(0).await
+}
+
+/// a push service down for the first `failures` messages, then accepting every message
+async fn start_flaky_push_service(
+    failures: usize,
+) -> Result<(String, mpsc::UnboundedReceiver<PushRequest>)> {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let failures = Arc::new(AtomicUsize::new(failures));
+    let app = axum::Router::new()
+        .route(
+            "/push/{id}",
+            axum::routing::post(
+                |axum::extract::State((tx, failures)): axum::extract::State<(
+                    mpsc::UnboundedSender<PushRequest>,
+                    Arc<AtomicUsize>,
+                )>,
+                 headers: axum::http::HeaderMap,
+                 body: axum::body::Bytes| async move {
+                    let down = failures
+                        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                        .is_ok();
+                    if down {
+                        return StatusCode::SERVICE_UNAVAILABLE;
+                    }
+                    let _ = tx.send((headers, body));
+                    StatusCode::CREATED
+                },
+            ),
+        )
+        .with_state((tx, failures));
+    let listener = TcpListener::bind(WILD_ADDR).await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(async move {
+        axum::serve(listener, app.into_make_service())
+            .await
+            .unwrap();
+    });
+    Ok((format!("http://{}/push/jdoe", addr), rx))
+}
+
 impl NotifyServer {
-    async fn new(db_url: &str, token: &str) -> Result<()> {
+    async fn new(db_url: &str, instance_id: &str) -> Result<Self> {
+        Self::start(Self::config(db_url, instance_id)?).await
+    }
+
+    async fn with_push(db_url: &str, instance_id: &str) -> Result<Self> {
+        Self::start(Self::push_config(db_url, instance_id)?).await
+    }
+
+    /// pushes go to the transport instead of a push service
+    async fn with_transport(
+        db_url: &str,
+        instance_id: &str,
+        transport: impl PushTransport,
+    ) -> Result<Self> {
+        let config = Self::push_config(db_url, instance_id)?;
+        Self::serve(notify_server::get_router_with_push(config, transport).await?).await
+    }
+
+    fn push_config(db_url: &str, instance_id: &str) -> Result<notify_server::AppConfig> {
+        let mut config = Self::config(db_url, instance_id)?;
+        config.push = Some(notify_server::PushConfig {
+            vapid_private_key: VAPID_PRIVATE_KEY.to_string(),
+            subject: "mailto:admin@acme.org".to_string(),
+            retry_interval_secs: 1,
+        });
+        Ok(config)
+    }
+
+    fn config(db_url: &str, instance_id: &str) -> Result<notify_server::AppConfig> {
         let mut config = notify_server::AppConfig::load()?;
         config.server.db_url = db_url.to_string();
-        let app = notify_server::get_router(config).await?;
+        config.server.instance_id = instance_id.to_string();
+        Ok(config)
+    }
+
+    async fn start(config: notify_server::AppConfig) -> Result<Self> {
+        Self::serve(notify_server::get_router(config).await?).await
+    }
+
+    async fn serve(app: axum::Router) -> Result<Self> {
         let listener = TcpListener::bind(WILD_ADDR).await?;
         let addr = listener.local_addr()?;
         tokio::spawn(async move {
@@ -53,7 +552,89 @@ impl NotifyServer {
                 .await
                 .unwrap();
         });
-        let mut es = EventSource::get(format!("http://{}/events?token={}", addr, token));
+        Ok(Self {
+            addr,
+            client: reqwest::Client::new(),
+        })
+    }
+
+    /// forward every event received by the user to the returned channel
+    fn subscribe(
+        &self,
+        token: &str,
+    ) -> (
+        mpsc::UnboundedReceiver<(String, String)>,
+        tokio::task::JoinHandle<()>,
+    ) {
+        self.subscribe_with(token, "")
+    }
+
+    /// subscribe with extra query parameters, e.g. event filters
+    fn subscribe_with(
+        &self,
+        token: &str,
+        query: &str,
+    ) -> (
+        mpsc::UnboundedReceiver<(String, String)>,
+        tokio::task::JoinHandle<()>,
+    ) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let mut es = EventSource::get(format!(
+            "http://{}/events?token={}&{}",
+            self.addr, token, query
+        ));
+        let handle = tokio::spawn(async move {
+            while let Some(event) = es.next().await {
+                match event {
+                    Ok(Event::Open) => {}
+                    Ok(Event::Message(message)) => {
+                        let _ = tx.send((message.event, message.data));
+                    }
+                    Err(_) => es.close(),
+                }
+            }
+        });
+        (rx, handle)
+    }
+
+    async fn vapid_key(&self) -> Result<String> {
+        let res = self
+            .client
+            .get(format!("http://{}/push/vapid-key", self.addr))
+            .send()
+            .await?;
+        assert_eq!(res.status(), 200);
+        let ret: serde_json::Value = res.json().await?;
+        Ok(ret["publicKey"].as_str().unwrap_or_default().to_string())
+    }
+
+    async fn subscribe_push(&self, token: &str, endpoint: &str) -> Result<()> {
+        let res = self
+            .client
+            .post(format!("http://{}/push/subscriptions", self.addr))
+            .header("Authorization", format!("Bearer {}", token))
+            .json(&json!({
+                "endpoint": endpoint,
+                "keys": {"p256dh": BROWSER_PUBLIC_KEY, "auth": BROWSER_AUTH},
+            }))
+            .send()
+            .await?;
+        assert_eq!(res.status(), 201);
+        Ok(())
+    }
+
+    async fn metrics(&self) -> Result<notify_server::MetricsSnapshot> {
+        let res = self
+            .client
+            .get(format!("http://{}/metrics", self.addr))
+            .send()
+            .await?;
+        assert_eq!(res.status(), 200);
+        Ok(res.json().await?)
+    }
+
+    fn listen(&self, token: &str) {
+        let mut es = EventSource::get(format!("http://{}/events?token={}", self.addr, token));
         tokio::spawn(async move {
             while let Some(event) = es.next().await {
                 match event {
@@ -80,12 +661,12 @@ impl NotifyServer {
                 }
             }
         });
-        Ok(())
     }
 }
 
 impl ChatServer {
     async fn new(state: chat_server::AppState) -> Result<Self> {
+        state.spawn_job_workers();
         let app = chat_server::get_router(state).await?;
         let listener = TcpListener::bind(WILD_ADDR).await?;
         let addr = listener.local_addr()?;
@@ -106,11 +687,15 @@ impl ChatServer {
     }
 
     async fn signin(&self) -> Result<String> {
+        self.signin_as("tchen@acme.org").await
+    }
+
+    async fn signin_as(&self, email: &str) -> Result<String> {
         let res = self
             .client
             .post(format!("http://{}/api/signin", self.addr))
             .header("Content-Type", "application/json")
-            .body(r#"{"email": "tchen@acme.org", "password": "12345678"}"#)
+            .body(json!({"email": email, "password": "12345678"}).to_string())
             .send()
             .await?;
         assert_eq!(res.status(), 200);
@@ -147,6 +732,64 @@ impl ChatServer {
         Ok(agent)
     }
 
+    async fn create_agent_with(&self, chat_id: u64, body: serde_json::Value) -> Result<ChatAgent> {
+        let res = self
+            .client
+            .post(format!("http://{}/api/chats/{}/agents", self.addr, chat_id))
+            .header("Authorization", format!("Bearer {}", self.token))
+            .json(&body)
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        Ok(res.json().await?)
+    }
+
+    async fn list_messages(&self, chat_id: u64) -> Result<Vec<Message>> {
+        let res = self
+            .client
+            .get(format!(
+                "http://{}/api/chats/{}/messages?limit=10",
+                self.addr, chat_id
+            ))
+            .header("Authorization", format!("Bearer {}", self.token))
+            .send()
+            .await?;
+        assert_eq!(res.status(), 200);
+        Ok(res.json().await?)
+    }
+
+    async fn send_message(&self, chat_id: u64, content: &str) -> Result<Message> {
+        let res = self
+            .client
+            .post(format!("http://{}/api/chats/{}", self.addr, chat_id))
+            .header("Authorization", format!("Bearer {}", self.token))
+            .json(&json!({ "content": content }))
+            .send()
+            .await?;
+        assert_eq!(res.status(), 201);
+        Ok(res.json().await?)
+    }
+
+    async fn update_notification(
+        &self,
+        chat_id: u64,
+        token: &str,
+        body: serde_json::Value,
+    ) -> Result<()> {
+        let res = self
+            .client
+            .put(format!(
+                "http://{}/api/chats/{}/notification",
+                self.addr, chat_id
+            ))
+            .header("Authorization", format!("Bearer {}", token))
+            .json(&body)
+            .send()
+            .await?;
+        assert_eq!(res.status(), 200);
+        Ok(())
+    }
+
     async fn create_message(&self, chat_id: u64) -> Result<Message> {
         // upload file
         let data = include_bytes!("../Cargo.toml");
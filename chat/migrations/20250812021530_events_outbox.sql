@@ -0,0 +1,63 @@
+-- Add migration script here
+
+-- outbox of chat/message events, written in the same transaction as the change
+CREATE TABLE IF NOT EXISTS events (
+    id BIGSERIAL PRIMARY KEY,
+    channel VARCHAR(64) NOT NULL,
+    payload JSONB NOT NULL,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
+
+-- consumers of the outbox, events before start_id are never delivered to them
+CREATE TABLE IF NOT EXISTS event_consumers (
+    name VARCHAR(64) PRIMARY KEY,
+    start_id BIGINT NOT NULL,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
+
+-- events processed by each consumer
+CREATE TABLE IF NOT EXISTS event_acks (
+    consumer VARCHAR(64) NOT NULL REFERENCES event_consumers(name) ON DELETE CASCADE,
+    event_id BIGINT NOT NULL REFERENCES events(id) ON DELETE CASCADE,
+    acked_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (consumer, event_id)
+);
+
+-- if chat changed, store the chat data and notify with the event id
+CREATE OR REPLACE FUNCTION add_to_chat()
+RETURNS TRIGGER AS $$
+DECLARE
+    EVENT_ID BIGINT;
+BEGIN
+    RAISE NOTICE 'add_to_chat: %', NEW;
+    INSERT INTO events (channel, payload)
+    VALUES ('chat_updated', json_build_object(
+        'op', TG_OP,
+        'old', OLD,
+        'new', NEW
+    ))
+    RETURNING id INTO EVENT_ID;
+    PERFORM pg_notify('chat_updated', EVENT_ID::TEXT);
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+-- if new message added, store the message data and notify with the event id
+CREATE OR REPLACE FUNCTION add_to_message()
+RETURNS TRIGGER AS $$
+DECLARE
+    USERS BIGINT[];
+    EVENT_ID BIGINT;
+BEGIN
+    IF TG_OP = 'INSERT' THEN
+        RAISE NOTICE 'add_to_message: %', NEW;
+        -- select chat with chat_id in NEW
+        SELECT members INTO USERS FROM chats WHERE id = NEW.chat_id;
+        INSERT INTO events (channel, payload)
+        VALUES ('chat_message_created', json_build_object('message', NEW, 'members', USERS))
+        RETURNING id INTO EVENT_ID;
+        PERFORM pg_notify('chat_message_created', EVENT_ID::TEXT);
+    END IF;
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
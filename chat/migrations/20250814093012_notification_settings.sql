@@ -0,0 +1,22 @@
+-- Add migration script here
+
+-- how a user wants to be notified of new messages in a chat
+CREATE TYPE notification_level AS ENUM (
+    'all',
+    'mentions'
+);
+
+-- workspace wide default for chats without user settings
+ALTER TABLE workspaces
+    ADD COLUMN default_notification_level notification_level NOT NULL DEFAULT 'all';
+
+-- per user per chat notification settings
+CREATE TABLE IF NOT EXISTS chat_notification_settings (
+    chat_id BIGINT NOT NULL REFERENCES chats(id) ON DELETE CASCADE,
+    user_id BIGINT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    level notification_level NOT NULL DEFAULT 'all',
+    -- chat is silent until this time
+    muted_until TIMESTAMPTZ,
+    updated_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (chat_id, user_id)
+);
@@ -0,0 +1,23 @@
+-- Add migration script here
+
+-- web push subscriptions registered by the browsers of a user
+CREATE TABLE IF NOT EXISTS push_subscriptions (
+    id BIGSERIAL PRIMARY KEY,
+    user_id BIGINT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    endpoint TEXT NOT NULL UNIQUE,
+    -- base64url encoded client public key and auth secret
+    p256dh VARCHAR(128) NOT NULL,
+    auth VARCHAR(64) NOT NULL,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS push_subscriptions_user_id_index ON push_subscriptions (user_id);
+
+-- live SSE connections of users per notify_server instance
+CREATE TABLE IF NOT EXISTS user_connections (
+    user_id BIGINT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    instance_id VARCHAR(64) NOT NULL,
+    connections INT NOT NULL DEFAULT 0,
+    updated_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (user_id, instance_id)
+);
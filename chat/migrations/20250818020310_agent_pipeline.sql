@@ -0,0 +1,29 @@
+-- Add migration script here
+
+-- agents of a chat run in ascending priority, then by id
+ALTER TABLE chat_agents ADD COLUMN priority INT NOT NULL DEFAULT 0;
+
+-- what an agent did with a message
+CREATE TYPE agent_outcome_status AS ENUM (
+    'modified',
+    'replied',
+    'deleted',
+    'observed',
+    'skipped',
+    'failed'
+);
+
+CREATE TABLE IF NOT EXISTS agent_outcomes (
+    id BIGSERIAL PRIMARY KEY,
+    chat_id BIGINT NOT NULL REFERENCES chats(id) ON DELETE CASCADE,
+    -- null if the message was rejected by a proxy agent
+    message_id BIGINT REFERENCES messages(id) ON DELETE CASCADE,
+    agent_id BIGINT NOT NULL REFERENCES chat_agents(id) ON DELETE CASCADE,
+    status agent_outcome_status NOT NULL,
+    output TEXT,
+    error TEXT,
+    latency_ms INT NOT NULL DEFAULT 0,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS agent_outcomes_message_id_index ON agent_outcomes (message_id);
@@ -0,0 +1,40 @@
+-- Add migration script here
+
+CREATE TYPE job_status AS ENUM (
+    'pending',
+    'running',
+    'done',
+    'failed'
+);
+
+-- tap agent runs, claimed by chat_server workers with SKIP LOCKED
+CREATE TABLE IF NOT EXISTS agent_jobs (
+    id BIGSERIAL PRIMARY KEY,
+    agent_id BIGINT NOT NULL REFERENCES chat_agents(id) ON DELETE CASCADE,
+    chat_id BIGINT NOT NULL REFERENCES chats(id) ON DELETE CASCADE,
+    message_id BIGINT NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+    status job_status NOT NULL DEFAULT 'pending',
+    attempts INT NOT NULL DEFAULT 0,
+    last_error TEXT,
+    -- a failed attempt is retried after a backoff
+    run_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    locked_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS agent_jobs_run_at_index ON agent_jobs (run_at)
+    WHERE status IN ('pending', 'running');
+
+-- what tap agents extracted from messages: summaries, labels, tasks...
+CREATE TABLE IF NOT EXISTS tap_outputs (
+    id BIGSERIAL PRIMARY KEY,
+    agent_id BIGINT NOT NULL REFERENCES chat_agents(id) ON DELETE CASCADE,
+    chat_id BIGINT NOT NULL REFERENCES chats(id) ON DELETE CASCADE,
+    message_id BIGINT NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+    kind VARCHAR(64) NOT NULL,
+    output TEXT NOT NULL,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS tap_outputs_chat_id_kind_index ON tap_outputs (chat_id, kind);
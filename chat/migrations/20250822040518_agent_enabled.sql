@@ -0,0 +1,9 @@
+-- Add migration script here
+
+-- disabled agents are kept but skipped by the message pipeline
+ALTER TABLE chat_agents ADD COLUMN enabled BOOLEAN NOT NULL DEFAULT TRUE;
+
+-- chats.agents lists the ids of the agents of the chat, it was never filled
+UPDATE chats SET agents = ARRAY(
+    SELECT id FROM chat_agents WHERE chat_agents.chat_id = chats.id ORDER BY id
+);
@@ -0,0 +1,11 @@
+-- Add migration script here
+
+-- agent replies are inserted as streaming placeholders and finalized when the
+-- model is done, or marked failed
+CREATE TYPE message_status AS ENUM (
+    'sent',
+    'streaming',
+    'failed'
+);
+
+ALTER TABLE messages ADD COLUMN status message_status NOT NULL DEFAULT 'sent';
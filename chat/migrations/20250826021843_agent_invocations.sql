@@ -0,0 +1,32 @@
+-- Add migration script here
+
+-- what made an agent run
+CREATE TYPE agent_invocation_source AS ENUM (
+    'message',
+    'job',
+    'preview'
+);
+
+-- every run of an agent, for auditing and usage accounting. It is kept when
+-- the agent or the message is deleted, so costs stay accountable
+CREATE TABLE IF NOT EXISTS agent_invocations (
+    id BIGSERIAL PRIMARY KEY,
+    ws_id BIGINT NOT NULL REFERENCES workspaces(id) ON DELETE CASCADE,
+    chat_id BIGINT NOT NULL,
+    agent_id BIGINT NOT NULL,
+    -- null for previews and messages rejected by a proxy agent
+    message_id BIGINT,
+    source agent_invocation_source NOT NULL,
+    status agent_outcome_status NOT NULL,
+    input_chars INT NOT NULL DEFAULT 0,
+    output_chars INT NOT NULL DEFAULT 0,
+    -- null if the model didn't report its usage
+    prompt_tokens INT,
+    completion_tokens INT,
+    latency_ms INT NOT NULL DEFAULT 0,
+    error TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS agent_invocations_ws_id_index ON agent_invocations (ws_id, created_at);
+CREATE INDEX IF NOT EXISTS agent_invocations_chat_id_index ON agent_invocations (chat_id, created_at);
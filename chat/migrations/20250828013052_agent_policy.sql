@@ -0,0 +1,4 @@
+-- Add migration script here
+
+-- timeouts, retries, failure policy and fallback model of an agent, see `AgentPolicy`
+ALTER TABLE chat_agents ADD COLUMN policy JSONB NOT NULL DEFAULT '{}';
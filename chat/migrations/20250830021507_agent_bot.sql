@@ -0,0 +1,4 @@
+-- Add migration script here
+
+-- user an agent posts its replies as in group chats and channels
+ALTER TABLE chat_agents ADD COLUMN bot_id BIGINT REFERENCES users(id);
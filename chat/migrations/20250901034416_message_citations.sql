@@ -0,0 +1,4 @@
+-- Add migration script here
+
+-- sources an agent reply cites, see `Citation`
+ALTER TABLE messages ADD COLUMN citations JSONB NOT NULL DEFAULT '[]';
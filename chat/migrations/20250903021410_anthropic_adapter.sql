@@ -0,0 +1,4 @@
+-- Add migration script here
+
+-- agents of models served by the Messages API
+ALTER TYPE adapter_type ADD VALUE 'anthropic';
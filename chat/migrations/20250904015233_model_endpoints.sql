@@ -0,0 +1,24 @@
+-- Add migration script here
+
+-- provider APIs of a workspace, keys stay in the environment of the server
+CREATE TABLE IF NOT EXISTS model_endpoints (
+    id BIGSERIAL PRIMARY KEY,
+    ws_id BIGINT NOT NULL REFERENCES workspaces(id),
+    name VARCHAR(64) NOT NULL,
+    adapter adapter_type NOT NULL,
+    base_url VARCHAR(255) NOT NULL,
+    api_key_env VARCHAR(128),
+    api_key_header VARCHAR(64),
+    organization VARCHAR(128),
+    headers JSONB NOT NULL DEFAULT '{}',
+    is_default BOOLEAN NOT NULL DEFAULT FALSE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    UNIQUE (ws_id, name)
+);
+
+-- at most one default endpoint per adapter in a workspace
+CREATE UNIQUE INDEX IF NOT EXISTS model_endpoints_default_idx
+    ON model_endpoints(ws_id, adapter) WHERE is_default;
+
+ALTER TABLE chat_agents
+    ADD COLUMN endpoint_id BIGINT REFERENCES model_endpoints(id) ON DELETE SET NULL;
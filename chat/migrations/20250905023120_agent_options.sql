@@ -0,0 +1,5 @@
+-- Add migration script here
+
+-- generation parameters of the model of an agent
+ALTER TABLE chat_agents
+    ADD COLUMN options JSONB NOT NULL DEFAULT '{}';
@@ -0,0 +1,4 @@
+-- Add migration script here
+
+-- reply agents run from the job queue too, off the request path
+ALTER TABLE agent_jobs ADD COLUMN kind agent_type NOT NULL DEFAULT 'tap';
@@ -0,0 +1,7 @@
+-- Add migration script here
+
+-- consumers not seen for a while are pruned, so they don't keep events forever
+ALTER TABLE event_consumers
+    ADD COLUMN last_seen_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP;
+
+CREATE INDEX IF NOT EXISTS events_created_at_index ON events (created_at);
@@ -0,0 +1,22 @@
+-- Add migration script here
+
+-- model calls of agents, reserved before the call and counted against the
+-- quotas. Rows older than a day don't count anymore and are pruned
+CREATE TABLE IF NOT EXISTS agent_calls (
+    id BIGSERIAL PRIMARY KEY,
+    ws_id BIGINT NOT NULL REFERENCES workspaces(id) ON DELETE CASCADE,
+    -- kept when the agent is deleted, its calls still count for the workspace
+    agent_id BIGINT NOT NULL,
+    -- set once the call is done, 0 if the model didn't report its usage
+    tokens BIGINT NOT NULL DEFAULT 0,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS agent_calls_ws_id_index ON agent_calls (ws_id, created_at);
+CREATE INDEX IF NOT EXISTS agent_calls_agent_id_index ON agent_calls (agent_id, created_at);
+
+-- the quotas counted invocations until now
+INSERT INTO agent_calls (ws_id, agent_id, tokens, created_at)
+SELECT ws_id, agent_id, COALESCE(prompt_tokens, 0) + COALESCE(completion_tokens, 0), created_at
+FROM agent_invocations
+WHERE created_at >= NOW() - INTERVAL '1 day';
@@ -0,0 +1,10 @@
+-- Add migration script here
+
+-- subscriptions a message event was pushed to, so a push retried after a
+-- failure only goes to the ones which didn't get it. Pruned with the events
+CREATE TABLE IF NOT EXISTS push_deliveries (
+    event_id BIGINT NOT NULL REFERENCES events(id) ON DELETE CASCADE,
+    subscription_id BIGINT NOT NULL REFERENCES push_subscriptions(id) ON DELETE CASCADE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (event_id, subscription_id)
+);
@@ -0,0 +1,24 @@
+-- Add migration script here
+
+-- a streamed agent reply is created empty, once it is finalized its content is
+-- worth a web push
+CREATE OR REPLACE FUNCTION finalize_message()
+RETURNS TRIGGER AS $$
+DECLARE
+    USERS BIGINT[];
+    EVENT_ID BIGINT;
+BEGIN
+    SELECT members INTO USERS FROM chats WHERE id = NEW.chat_id;
+    INSERT INTO events (channel, payload)
+    VALUES ('chat_message_finalized', json_build_object('message', NEW, 'members', USERS))
+    RETURNING id INTO EVENT_ID;
+    PERFORM pg_notify('chat_message_finalized', EVENT_ID::TEXT);
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER finalize_message_trigger
+AFTER UPDATE OF status ON messages
+FOR EACH ROW
+WHEN (OLD.status = 'streaming' AND NEW.status != 'streaming')
+EXECUTE FUNCTION finalize_message();
@@ -6,12 +6,20 @@ use std::fs::File;
 pub struct AppConfig {
     pub server: ServerConfig,
     pub auth: AuthConfig,
+    // web push delivery is disabled if not configured
+    #[serde(default)]
+    pub push: Option<PushConfig>,
+    #[serde(default)]
+    pub outbox: OutboxConfig,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ServerConfig {
     pub port: u16,
     pub db_url: String,
+    // identifies this instance in metrics and as outbox consumer, it must stay
+    // the same across restarts to replay the events missed meanwhile
+    pub instance_id: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -19,6 +27,43 @@ pub struct AuthConfig {
     pub pk: String,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PushConfig {
+    // base64url encoded raw P-256 private key
+    pub vapid_private_key: String,
+    // contact of the application server, e.g. mailto:admin@example.com
+    pub subject: String,
+    // how often pushes which failed are sent again
+    #[serde(default = "default_push_retry_interval_secs")]
+    pub retry_interval_secs: u64,
+}
+
+/// Every instance prunes the outbox: events all consumers acknowledged, events
+/// older than the retention and consumers not seen for a while, e.g. of
+/// instances which were removed
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OutboxConfig {
+    pub retention_hours: u64,
+    pub consumer_ttl_hours: u64,
+    // also how often the consumers of the instance show they are alive
+    pub prune_interval_secs: u64,
+}
+
+impl Default for OutboxConfig {
+    fn default() -> Self {
+        Self {
+            retention_hours: 24 * 7,
+            consumer_ttl_hours: 24,
+            prune_interval_secs: 60,
+        }
+    }
+}
+
+fn default_push_retry_interval_secs() -> u64 {
+    30
+}
+
 impl AppConfig {
     pub fn load() -> Result<Self> {
         // read from ./app.yml, or /etc/config/app.yml, or from env CHAT_CONFIG
@@ -38,6 +83,10 @@ impl AppConfig {
             (_, _, Ok(path)) => serde_yaml::from_reader(File::open(path)?),
             _ => bail!("Config file not found"),
         };
-        Ok(ret?)
+        let config: AppConfig = ret?;
+        if config.server.instance_id.is_empty() {
+            bail!("server.instance_id must be set");
+        }
+        Ok(config)
     }
 }
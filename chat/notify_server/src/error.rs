@@ -14,6 +14,18 @@ pub enum AppError {
 
     #[error("io error: {0}")]
     IoError(#[from] std::io::Error),
+
+    #[error("sql error: {0}")]
+    SqlxError(#[from] sqlx::Error),
+
+    #[error("not found: {0}")]
+    NotFound(String),
+
+    #[error("invalid event filter: {0}")]
+    InvalidFilter(String),
+
+    #[error("web push is not configured")]
+    PushDisabled,
 }
 
 impl ErrorOutput {
@@ -29,6 +41,10 @@ impl IntoResponse for AppError {
         let status = match self {
             AppError::JwtError(_) => axum::http::StatusCode::FORBIDDEN,
             AppError::IoError(_) => axum::http::StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::SqlxError(_) => axum::http::StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::NotFound(_) => axum::http::StatusCode::NOT_FOUND,
+            AppError::InvalidFilter(_) => axum::http::StatusCode::BAD_REQUEST,
+            AppError::PushDisabled => axum::http::StatusCode::NOT_FOUND,
         };
         (status, Json(ErrorOutput::new(self.to_string()))).into_response()
     }
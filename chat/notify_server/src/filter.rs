@@ -0,0 +1,146 @@
+use std::collections::HashSet;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{AppError, AppEvent};
+
+/// Query parameters of `/events`, e.g. `?chats=1,2&events=NewMessage`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct EventFilterParams {
+    // comma separated chat ids
+    #[serde(default)]
+    pub chats: Option<String>,
+    // comma separated event names
+    #[serde(default)]
+    pub events: Option<String>,
+}
+
+/// Events a connection subscribed to, an empty filter lets everything through.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EventFilter {
+    chat_ids: Option<HashSet<i64>>,
+    events: Option<HashSet<&'static str>>,
+}
+
+impl TryFrom<EventFilterParams> for EventFilter {
+    type Error = AppError;
+
+    fn try_from(params: EventFilterParams) -> Result<Self, Self::Error> {
+        let chat_ids = params
+            .chats
+            .as_deref()
+            .map(|chats| {
+                split(chats)
+                    .map(|id| {
+                        id.parse()
+                            .map_err(|_| AppError::InvalidFilter(format!("invalid chat id {}", id)))
+                    })
+                    .collect::<Result<HashSet<_>, _>>()
+            })
+            .transpose()?;
+        let events = params
+            .events
+            .as_deref()
+            .map(|events| {
+                split(events)
+                    .map(|name| {
+                        AppEvent::NAMES
+                            .into_iter()
+                            .find(|v| *v == name)
+                            .ok_or_else(|| {
+                                AppError::InvalidFilter(format!("unknown event {}", name))
+                            })
+                    })
+                    .collect::<Result<HashSet<_>, _>>()
+            })
+            .transpose()?;
+        Ok(Self { chat_ids, events })
+    }
+}
+
+impl EventFilter {
+    pub fn matches(&self, event: &AppEvent) -> bool {
+        let chat_id_matched = self
+            .chat_ids
+            .as_ref()
+            .is_none_or(|ids| ids.contains(&event.chat_id()));
+        let event_matched = self
+            .events
+            .as_ref()
+            .is_none_or(|names| names.contains(event.name()));
+        chat_id_matched && event_matched
+    }
+}
+
+fn split(value: &str) -> impl Iterator<Item = &str> {
+    value.split(',').map(str::trim).filter(|v| !v.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MessageEvent;
+    use chat_core::{Chat, ChatType, Message, MessageStatus};
+    use chrono::Utc;
+
+    fn message_event(chat_id: i64) -> AppEvent {
+        AppEvent::NewMessage(MessageEvent {
+            message: Message {
+                id: 1,
+                chat_id,
+                sender_id: 1,
+                content: "hello".to_string(),
+                modified_content: None,
+                files: vec![],
+                status: MessageStatus::Sent,
+                citations: Default::default(),
+                created_at: Utc::now(),
+            },
+            silent: false,
+        })
+    }
+
+    fn chat_event(chat_id: i64) -> AppEvent {
+        AppEvent::NewChat(Chat {
+            id: chat_id,
+            ws_id: 1,
+            name: None,
+            r#type: ChatType::Single,
+            members: vec![1, 2],
+            agents: vec![],
+            created_at: Utc::now(),
+        })
+    }
+
+    fn filter(chats: Option<&str>, events: Option<&str>) -> Result<EventFilter, AppError> {
+        EventFilter::try_from(EventFilterParams {
+            chats: chats.map(Into::into),
+            events: events.map(Into::into),
+        })
+    }
+
+    #[test]
+    fn empty_filter_should_match_everything() -> Result<(), AppError> {
+        let filter = filter(None, None)?;
+        assert_eq!(filter, EventFilter::default());
+        assert!(filter.matches(&message_event(1)));
+        assert!(filter.matches(&chat_event(2)));
+        Ok(())
+    }
+
+    #[test]
+    fn filter_should_match_chats_and_events() -> Result<(), AppError> {
+        let filter = filter(Some("1, 3"), Some("NewMessage"))?;
+        assert!(filter.matches(&message_event(1)));
+        assert!(filter.matches(&message_event(3)));
+        assert!(!filter.matches(&message_event(2)));
+        assert!(!filter.matches(&chat_event(1)));
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_filter_should_fail() {
+        assert!(filter(Some("1,abc"), None).is_err());
+        assert!(filter(None, Some("NewMessage,Typing")).is_err());
+    }
+}
@@ -1,19 +1,38 @@
 mod config;
 mod error;
+mod filter;
+mod metrics;
 mod notif;
+mod presence;
+mod push;
 mod sse;
+use anyhow::Context;
 use axum::http::Method;
 pub use config::*;
 use dashmap::DashMap;
-pub use notif::AppEvent;
+pub use filter::{EventFilter, EventFilterParams};
+pub use metrics::{Metrics, MetricsSnapshot};
+pub use notif::{AppEvent, MessageEvent};
+pub use push::{
+    PushMessage, PushStatus, PushSubscription, PushTransport, PushWorker, VapidKey,
+    WebPushTransport,
+};
+use sqlx::PgPool;
 use std::ops::Deref;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::broadcast;
 use tower_http::cors::{Any, CorsLayer};
+use tracing::info;
 
+use axum::extract::State;
 use axum::middleware::from_fn_with_state;
 use axum::response::IntoResponse;
-use axum::{Router, response::Html, routing::get};
+use axum::{
+    Json, Router,
+    response::Html,
+    routing::{get, post},
+};
 use chat_core::{DecodingKey, TokenVerify, User, verify_token};
 use sse::sse_handler;
 
@@ -28,12 +47,48 @@ pub struct AppStateInner {
     pub config: AppConfig,
     users: UserMap,
     pub dk: DecodingKey,
+    pub metrics: Metrics,
+    pub pool: PgPool,
+    // present when web push is configured
+    pub vapid: Option<VapidKey>,
+    presence_lock: tokio::sync::Mutex<()>,
 }
 
 const INDEX_HTML: &str = include_str!("../index.html");
 pub async fn get_router(config: AppConfig) -> anyhow::Result<Router> {
-    let state = AppState::new(config);
-    notif::setup_pg_listener(state.clone()).await?;
+    let state = AppState::try_new(config).await?;
+    let transport = state.vapid.clone().map(WebPushTransport::new);
+    build_router(state, transport).await
+}
+
+/// Build the router with a custom web push transport, e.g. a fake push service in tests
+pub async fn get_router_with_push<T: PushTransport>(
+    config: AppConfig,
+    transport: T,
+) -> anyhow::Result<Router> {
+    build_router(AppState::try_new(config).await?, Some(transport)).await
+}
+
+async fn build_router<T: PushTransport>(
+    state: AppState,
+    transport: Option<T>,
+) -> anyhow::Result<Router> {
+    state.setup_presence().await?;
+    // outbox consumers of this instance, besides its own
+    let mut consumers = Vec::new();
+    let push = match transport {
+        Some(transport) => {
+            let mut worker = PushWorker::new(state.pool.clone(), transport);
+            if let Some(push) = &state.config.push {
+                let interval = Duration::from_secs(push.retry_interval_secs);
+                worker = worker.with_retry_interval(interval);
+            }
+            consumers.push(worker.outbox().clone());
+            Some(worker.spawn().await?)
+        }
+        None => None,
+    };
+    notif::setup_pg_listener(state.clone(), push, consumers).await?;
     let cors = CorsLayer::new()
         .allow_methods([
             Method::GET,
@@ -46,9 +101,15 @@ pub async fn get_router(config: AppConfig) -> anyhow::Result<Router> {
         .allow_headers(Any);
     let app = Router::new()
         .route("/events", get(sse_handler))
+        .route(
+            "/push/subscriptions",
+            post(push::subscribe_handler).delete(push::unsubscribe_handler),
+        )
         .layer(from_fn_with_state(state.clone(), verify_token::<AppState>))
         .layer(cors)
         .route("/", get(index_handler))
+        .route("/metrics", get(metrics_handler))
+        .route("/push/vapid-key", get(push::vapid_key_handler))
         .with_state(state);
     Ok(app)
 }
@@ -57,6 +118,10 @@ async fn index_handler() -> impl IntoResponse {
     Html(INDEX_HTML)
 }
 
+async fn metrics_handler(State(state): State<AppState>) -> impl IntoResponse {
+    Json(state.metrics_snapshot())
+}
+
 impl TokenVerify for AppState {
     type Error = AppError;
 
@@ -74,9 +139,42 @@ impl Deref for AppState {
 }
 
 impl AppState {
-    pub fn new(config: AppConfig) -> Self {
-        let dk = DecodingKey::load(&config.auth.pk).expect("Failed to load decoding key");
+    pub async fn try_new(config: AppConfig) -> anyhow::Result<Self> {
+        let dk = DecodingKey::load(&config.auth.pk).context("load pk failed")?;
         let users = Arc::new(DashMap::new());
-        Self(Arc::new(AppStateInner { config, dk, users }))
+        let pool = PgPool::connect(&config.server.db_url)
+            .await
+            .context("Failed to connect to the database")?;
+        let vapid = config
+            .push
+            .as_ref()
+            .map(VapidKey::try_new)
+            .transpose()
+            .context("Failed to load vapid key")?;
+        Ok(Self(Arc::new(AppStateInner {
+            config,
+            dk,
+            users,
+            metrics: Metrics::default(),
+            pool,
+            vapid,
+            presence_lock: tokio::sync::Mutex::new(()),
+        })))
+    }
+
+    pub fn metrics_snapshot(&self) -> MetricsSnapshot {
+        self.metrics
+            .snapshot(&self.config.server.instance_id, self.users.len())
+    }
+
+    /// drop the sender of a user once the last receiver is gone
+    pub(crate) fn release_user(&self, user_id: u64) {
+        if self
+            .users
+            .remove_if(&user_id, |_, tx| tx.receiver_count() == 0)
+            .is_some()
+        {
+            info!("User {} has no live connections, sender dropped", user_id);
+        }
     }
 }
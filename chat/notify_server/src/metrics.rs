@@ -0,0 +1,61 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde::{Deserialize, Serialize};
+
+/// Connection and delivery counters for a single notify_server instance.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    active_connections: AtomicU64,
+    total_connections: AtomicU64,
+    events_received: AtomicU64,
+    events_delivered: AtomicU64,
+    events_dropped: AtomicU64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct MetricsSnapshot {
+    pub instance_id: String,
+    // users with at least one live connection on this instance
+    pub active_users: u64,
+    pub active_connections: u64,
+    pub total_connections: u64,
+    pub events_received: u64,
+    pub events_delivered: u64,
+    pub events_dropped: u64,
+}
+
+impl Metrics {
+    pub fn connection_opened(&self) {
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+        self.total_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_closed(&self) {
+        self.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn event_received(&self) {
+        self.events_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn event_delivered(&self) {
+        self.events_delivered.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn event_dropped(&self) {
+        self.events_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self, instance_id: &str, active_users: usize) -> MetricsSnapshot {
+        MetricsSnapshot {
+            instance_id: instance_id.to_string(),
+            active_users: active_users as u64,
+            active_connections: self.active_connections.load(Ordering::Relaxed),
+            total_connections: self.total_connections.load(Ordering::Relaxed),
+            events_received: self.events_received.load(Ordering::Relaxed),
+            events_delivered: self.events_delivered.load(Ordering::Relaxed),
+            events_dropped: self.events_dropped.load(Ordering::Relaxed),
+        }
+    }
+}
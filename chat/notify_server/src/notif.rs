@@ -1,20 +1,67 @@
-use std::{collections::HashSet, sync::Arc};
+use std::{collections::HashSet, sync::Arc, time::Duration};
 
-use chat_core::{Chat, Message};
+use chat_core::{
+    Chat, Citation, EventOutbox, Message, MessageDelta, NotificationSettings, OutboxEvent,
+};
+use chrono::Utc;
 use futures::StreamExt;
 use serde::{Deserialize, Serialize};
-use sqlx::postgres::PgListener;
+use sqlx::{FromRow, PgPool, postgres::PgListener, types::Json};
+use tokio::sync::mpsc;
 use tracing::{info, warn};
 
 use crate::AppState;
 
+const PENDING_BATCH_SIZE: i64 = 100;
+
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "event")]
 pub enum AppEvent {
     NewChat(Chat),
     AddToChat(Chat),
     RemoveFromChat(Chat),
-    NewMessage(Message),
+    NewMessage(MessageEvent),
+    MessageDelta(MessageDelta),
+}
+
+impl AppEvent {
+    pub const NAMES: [&'static str; 5] = [
+        "NewChat",
+        "AddToChat",
+        "RemoveFromChat",
+        "NewMessage",
+        "MessageDelta",
+    ];
+
+    /// name of the SSE event
+    pub fn name(&self) -> &'static str {
+        match self {
+            AppEvent::NewChat(_) => "NewChat",
+            AppEvent::AddToChat(_) => "AddToChat",
+            AppEvent::RemoveFromChat(_) => "RemoveFromChat",
+            AppEvent::NewMessage(_) => "NewMessage",
+            AppEvent::MessageDelta(_) => "MessageDelta",
+        }
+    }
+
+    pub fn chat_id(&self) -> i64 {
+        match self {
+            AppEvent::NewChat(chat)
+            | AppEvent::AddToChat(chat)
+            | AppEvent::RemoveFromChat(chat) => chat.id,
+            AppEvent::NewMessage(msg) => msg.message.chat_id,
+            AppEvent::MessageDelta(delta) => delta.chat_id,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessageEvent {
+    #[serde(flatten)]
+    pub message: Message,
+    // the user muted the chat or only wants mentions, clients should not alert
+    #[serde(default)]
+    pub silent: bool,
 }
 
 #[derive(Debug)]
@@ -22,6 +69,15 @@ struct Notification {
     //users being impacted so we should send notification to them
     user_ids: HashSet<u64>,
     event: Arc<AppEvent>,
+    // users who get the event flagged as silent because of their notification settings
+    silent_user_ids: HashSet<u64>,
+}
+
+#[derive(Debug, FromRow)]
+struct MemberSettings {
+    #[sqlx(flatten)]
+    settings: NotificationSettings,
+    fullname: String,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -37,38 +93,222 @@ struct ChatMessageCreated {
     members: Vec<i64>,
 }
 
-pub async fn setup_pg_listener(state: AppState) -> anyhow::Result<()> {
-    let mut listener = PgListener::connect(&state.config.server.db_url).await?;
+#[derive(Debug, Serialize, Deserialize)]
+struct ChatMessageDelta {
+    #[serde(flatten)]
+    delta: MessageDelta,
+    members: Vec<i64>,
+}
+
+pub async fn setup_pg_listener(
+    state: AppState,
+    push: Option<mpsc::UnboundedSender<OutboxEvent>>,
+    mut consumers: Vec<EventOutbox>,
+) -> anyhow::Result<()> {
+    let db_url = &state.config.server.db_url;
+    let mut listener = PgListener::connect(db_url).await?;
     listener.listen("chat_updated").await?;
     listener.listen("chat_message_created").await?;
+    listener.listen("chat_message_delta").await?;
+    listener.listen("chat_message_finalized").await?;
+
+    let consumer = format!("notify_server:{}", state.config.server.instance_id);
+    let outbox = EventOutbox::new(state.pool.clone(), consumer);
+    outbox.register().await?;
+    consumers.push(outbox.clone());
+    spawn_outbox_maintenance(&state, consumers);
+    // events missed while this instance was down
+    replay(&state, &outbox, push.as_ref()).await?;
 
     let mut stream = listener.into_stream();
 
+    // one failing event or a lost connection must not end the listener, events
+    // left unacknowledged are replayed once it is back
     tokio::spawn(async move {
-        while let Some(Ok(notif)) = stream.next().await {
-            info!("Received notification: {:?}", notif);
-            let notification = Notification::load(notif.channel(), notif.payload())?;
-            let users = &state.users;
-            for user_id in notification.user_ids {
-                if let Some(tx) = users.get(&user_id) {
-                    info!("Sending notification to user {}", user_id);
-                    if let Err(e) = tx.send(notification.event.clone()) {
-                        warn!("Failed to send notification to user {}: {}", user_id, e);
+        while let Some(notif) = stream.next().await {
+            let notif = match notif {
+                Ok(notif) => notif,
+                Err(e) => {
+                    warn!("Listener failed, reconnecting: {}", e);
+                    if let Err(e) = replay(&state, &outbox, push.as_ref()).await {
+                        warn!("Failed to replay pending events: {}", e);
                     }
+                    continue;
+                }
+            };
+            info!("Received notification: {:?}", notif);
+            // streamed replies don't go through the outbox
+            if notif.channel() == "chat_message_delta" {
+                dispatch_delta(&state, notif.payload()).await;
+                continue;
+            }
+            let Ok(id) = notif.payload().parse::<i64>() else {
+                warn!("Invalid event id: {}", notif.payload());
+                continue;
+            };
+            let event = match outbox.fetch(id).await {
+                Ok(Some(event)) => event,
+                // replayed already, or pruned
+                Ok(None) => continue,
+                Err(e) => {
+                    warn!("Failed to fetch event {}: {}", id, e);
+                    continue;
+                }
+            };
+            dispatch(&state, &state.pool, &event).await;
+            if let Err(e) = outbox.ack(event.id).await {
+                warn!("Failed to acknowledge event {}: {}", event.id, e);
+            }
+            forward(push.as_ref(), event);
+        }
+    });
+
+    Ok(())
+}
+
+/// dispatch the events the consumer of this instance hasn't acknowledged yet
+async fn replay(
+    state: &AppState,
+    outbox: &EventOutbox,
+    push: Option<&mpsc::UnboundedSender<OutboxEvent>>,
+) -> anyhow::Result<()> {
+    loop {
+        let events = outbox.pending(PENDING_BATCH_SIZE).await?;
+        if events.is_empty() {
+            return Ok(());
+        }
+        for event in events {
+            dispatch(state, &state.pool, &event).await;
+            outbox.ack(event.id).await?;
+            forward(push, event);
+        }
+    }
+}
+
+/// keep the consumers of this instance alive and prune the outbox
+fn spawn_outbox_maintenance(state: &AppState, consumers: Vec<EventOutbox>) {
+    let config = state.config.outbox.clone();
+    let retention = Duration::from_secs(config.retention_hours * 3600);
+    let consumer_ttl = Duration::from_secs(config.consumer_ttl_hours * 3600);
+    let mut interval =
+        tokio::time::interval(Duration::from_secs(config.prune_interval_secs.max(1)));
+    tokio::spawn(async move {
+        loop {
+            interval.tick().await;
+            for consumer in &consumers {
+                if let Err(e) = consumer.heartbeat().await {
+                    warn!(
+                        "Failed to keep consumer {} alive: {}",
+                        consumer.consumer(),
+                        e
+                    );
                 }
             }
+            match consumers[0].prune(retention, consumer_ttl).await {
+                Ok(0) => {}
+                Ok(n) => info!("Pruned {} outbox events", n),
+                Err(e) => warn!("Failed to prune the outbox: {}", e),
+            }
         }
-        Ok::<_, anyhow::Error>(())
     });
+}
+
+async fn dispatch(state: &AppState, pool: &PgPool, event: &OutboxEvent) {
+    // clients got the finished reply with its last delta, only web push needs it
+    if event.channel == "chat_message_finalized" {
+        return;
+    }
+    let mut notification = match Notification::load(&event.channel, &event.payload) {
+        Ok(notification) => notification,
+        Err(e) => {
+            warn!("Failed to load event {}: {}", event.id, e);
+            return;
+        }
+    };
+    if let Err(e) = notification.apply_settings(pool).await {
+        warn!("Failed to load notification settings: {}", e);
+    }
+    state.metrics.event_received();
+    send(state, &notification.user_ids, &notification.event);
+    if let AppEvent::NewMessage(msg) = notification.event.as_ref()
+        && !notification.silent_user_ids.is_empty()
+    {
+        let event = Arc::new(AppEvent::NewMessage(MessageEvent {
+            message: msg.message.clone(),
+            silent: true,
+        }));
+        send(state, &notification.silent_user_ids, &event);
+    }
+}
+
+async fn dispatch_delta(state: &AppState, payload: &str) {
+    let mut payload: ChatMessageDelta = match serde_json::from_str(payload) {
+        Ok(payload) => payload,
+        Err(e) => {
+            warn!("Invalid message delta: {}", e);
+            return;
+        }
+    };
+    // the sources of a finished reply are too large for a notification
+    if payload.delta.done
+        && let Err(e) = load_citations(&state.pool, &mut payload.delta).await
+    {
+        warn!(
+            "Failed to load citations of message {}: {}",
+            payload.delta.message_id, e
+        );
+    }
+    state.metrics.event_received();
+    let user_ids = payload.members.iter().map(|v| *v as u64).collect();
+    send(
+        state,
+        &user_ids,
+        &Arc::new(AppEvent::MessageDelta(payload.delta)),
+    );
+}
 
+async fn load_citations(pool: &PgPool, delta: &mut MessageDelta) -> Result<(), sqlx::Error> {
+    let citations: Option<Json<Vec<Citation>>> =
+        sqlx::query_scalar("SELECT citations FROM messages WHERE id = $1")
+            .bind(delta.message_id)
+            .fetch_optional(pool)
+            .await?;
+    delta.citations = citations.map(|c| c.0).unwrap_or_default();
     Ok(())
 }
 
+/// hand events over to the web push worker, which pushes new messages to
+/// members who aren't connected and acknowledges the rest
+fn forward(push: Option<&mpsc::UnboundedSender<OutboxEvent>>, event: OutboxEvent) {
+    if let Some(push) = push
+        && push.send(event).is_err()
+    {
+        warn!("Web push worker is gone");
+    }
+}
+
+fn send(state: &AppState, user_ids: &HashSet<u64>, event: &Arc<AppEvent>) {
+    for user_id in user_ids {
+        // users connected to other instances are served by their own listener
+        let Some(tx) = state.users.get(user_id).map(|tx| tx.clone()) else {
+            continue;
+        };
+        info!("Sending notification to user {}", user_id);
+        if let Err(e) = tx.send(event.clone()) {
+            warn!("Failed to send notification to user {}: {}", user_id, e);
+            state.metrics.event_dropped();
+            state.release_user(*user_id);
+        } else {
+            state.metrics.event_delivered();
+        }
+    }
+}
+
 impl Notification {
-    fn load(r#type: &str, payload: &str) -> anyhow::Result<Self> {
+    fn load(r#type: &str, payload: &serde_json::Value) -> anyhow::Result<Self> {
         match r#type {
             "chat_updated" => {
-                let payload: ChatUpdated = serde_json::from_str(payload)?;
+                let payload = ChatUpdated::deserialize(payload)?;
                 info!("ChatUpdated: {:?}", payload);
                 let user_ids =
                     get_affected_chat_user_ids(payload.old.as_ref(), payload.new.as_ref());
@@ -81,19 +321,59 @@ impl Notification {
                 Ok(Self {
                     user_ids,
                     event: Arc::new(event),
+                    silent_user_ids: HashSet::new(),
                 })
             }
             "chat_message_created" => {
-                let payload: ChatMessageCreated = serde_json::from_str(payload)?;
+                let payload = ChatMessageCreated::deserialize(payload)?;
                 let user_ids = payload.members.iter().map(|v| *v as u64).collect();
                 Ok(Self {
                     user_ids,
-                    event: Arc::new(AppEvent::NewMessage(payload.message)),
+                    event: Arc::new(AppEvent::NewMessage(MessageEvent {
+                        message: payload.message,
+                        silent: false,
+                    })),
+                    silent_user_ids: HashSet::new(),
                 })
             }
             _ => Err(anyhow::anyhow!("Invalid notification type")),
         }
     }
+
+    /// move users who muted the chat, or only want mentions and aren't mentioned, to silent
+    async fn apply_settings(&mut self, pool: &PgPool) -> anyhow::Result<()> {
+        let AppEvent::NewMessage(msg) = self.event.as_ref() else {
+            return Ok(());
+        };
+        let members: Vec<MemberSettings> = sqlx::query_as(
+            r#"
+            SELECT c.id AS chat_id, u.id AS user_id, u.fullname,
+                COALESCE(s.level, w.default_notification_level) AS level, s.muted_until
+            FROM chats c
+            JOIN workspaces w ON w.id = c.ws_id
+            JOIN users u ON u.id = ANY(c.members)
+            LEFT JOIN chat_notification_settings s ON s.chat_id = c.id AND s.user_id = u.id
+            WHERE c.id = $1
+            "#,
+        )
+        .bind(msg.message.chat_id)
+        .fetch_all(pool)
+        .await?;
+
+        let now = Utc::now();
+        for member in members {
+            let user_id = member.settings.user_id as u64;
+            if member.settings.user_id != msg.message.sender_id
+                && member
+                    .settings
+                    .is_silent(&msg.message.content, &member.fullname, now)
+                && self.user_ids.remove(&user_id)
+            {
+                self.silent_user_ids.insert(user_id);
+            }
+        }
+        Ok(())
+    }
 }
 
 fn get_affected_chat_user_ids(old: Option<&Chat>, new: Option<&Chat>) -> HashSet<u64> {
@@ -0,0 +1,80 @@
+use std::time::Duration;
+
+use tracing::warn;
+
+use crate::AppState;
+
+// rows not refreshed for a while belong to an instance which went away
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+
+impl AppState {
+    /// Forget the connections a previous run of this instance left behind and keep
+    /// the rows of live connections fresh.
+    pub(crate) async fn setup_presence(&self) -> anyhow::Result<()> {
+        sqlx::query("DELETE FROM user_connections WHERE instance_id = $1")
+            .bind(&self.config.server.instance_id)
+            .execute(&self.pool)
+            .await?;
+
+        let state = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(HEARTBEAT_INTERVAL);
+            loop {
+                interval.tick().await;
+                let ret = sqlx::query(
+                    "UPDATE user_connections SET updated_at = NOW() WHERE instance_id = $1",
+                )
+                .bind(&state.config.server.instance_id)
+                .execute(&state.pool)
+                .await;
+                if let Err(e) = ret {
+                    warn!("Failed to refresh user connections: {}", e);
+                }
+            }
+        });
+        Ok(())
+    }
+
+    /// Record the live connections of a user on this instance. Writes are
+    /// serialized and read the current count, so the last one always wins with
+    /// the right value.
+    pub(crate) fn sync_presence(&self, user_id: u64) {
+        let state = self.clone();
+        tokio::spawn(async move {
+            let _guard = state.presence_lock.lock().await;
+            let connections = state
+                .users
+                .get(&user_id)
+                .map(|tx| tx.receiver_count())
+                .unwrap_or_default();
+            if let Err(e) = state.write_presence(user_id, connections as i32).await {
+                warn!("Failed to update connections of user {}: {}", user_id, e);
+            }
+        });
+    }
+
+    async fn write_presence(&self, user_id: u64, connections: i32) -> Result<(), sqlx::Error> {
+        if connections > 0 {
+            sqlx::query(
+                r#"
+                INSERT INTO user_connections (user_id, instance_id, connections)
+                VALUES ($1, $2, $3)
+                ON CONFLICT (user_id, instance_id)
+                DO UPDATE SET connections = $3, updated_at = NOW()
+                "#,
+            )
+            .bind(user_id as i64)
+            .bind(&self.config.server.instance_id)
+            .bind(connections)
+            .execute(&self.pool)
+            .await?;
+        } else {
+            sqlx::query("DELETE FROM user_connections WHERE user_id = $1 AND instance_id = $2")
+                .bind(user_id as i64)
+                .bind(&self.config.server.instance_id)
+                .execute(&self.pool)
+                .await?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,85 @@
+use axum::{Extension, Json, extract::State, http::StatusCode, response::IntoResponse};
+use chat_core::User;
+use serde::{Deserialize, Serialize};
+
+use super::PushSubscription;
+use crate::{AppError, AppState};
+
+/// Subscription as returned by `PushSubscription.toJSON()` in the browser
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreatePushSubscription {
+    pub endpoint: String,
+    pub keys: PushSubscriptionKeys,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PushSubscriptionKeys {
+    pub p256dh: String,
+    pub auth: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeletePushSubscription {
+    pub endpoint: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VapidPublicKey {
+    pub public_key: String,
+}
+
+pub(crate) async fn vapid_key_handler(
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let vapid = state.vapid.as_ref().ok_or(AppError::PushDisabled)?;
+    Ok(Json(VapidPublicKey {
+        public_key: vapid.public_key().to_string(),
+    }))
+}
+
+pub(crate) async fn subscribe_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<CreatePushSubscription>,
+) -> Result<impl IntoResponse, AppError> {
+    if state.vapid.is_none() {
+        return Err(AppError::PushDisabled);
+    }
+    // an endpoint belongs to a single browser, re-subscribing may hand it over to another user
+    let subscription: PushSubscription = sqlx::query_as(
+        r#"
+        INSERT INTO push_subscriptions (user_id, endpoint, p256dh, auth)
+        VALUES ($1, $2, $3, $4)
+        ON CONFLICT (endpoint)
+        DO UPDATE SET user_id = $1, p256dh = $3, auth = $4
+        RETURNING id, user_id, endpoint, p256dh, auth
+        "#,
+    )
+    .bind(user.id)
+    .bind(&input.endpoint)
+    .bind(&input.keys.p256dh)
+    .bind(&input.keys.auth)
+    .fetch_one(&state.pool)
+    .await?;
+    Ok((StatusCode::CREATED, Json(subscription)))
+}
+
+pub(crate) async fn unsubscribe_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<DeletePushSubscription>,
+) -> Result<impl IntoResponse, AppError> {
+    let ret = sqlx::query("DELETE FROM push_subscriptions WHERE user_id = $1 AND endpoint = $2")
+        .bind(user.id)
+        .bind(&input.endpoint)
+        .execute(&state.pool)
+        .await?;
+    if ret.rows_affected() == 0 {
+        return Err(AppError::NotFound(format!(
+            "push subscription {}",
+            input.endpoint
+        )));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,321 @@
+mod handlers;
+mod webpush;
+
+use std::{future::Future, time::Duration as StdDuration};
+
+use chat_core::{
+    ChatType, EventOutbox, Message, MessageStatus, NotificationSettings, OutboxEvent, is_mentioned,
+};
+use chrono::{Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgPool};
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+pub(crate) use handlers::*;
+pub use webpush::{VapidKey, WebPushTransport};
+
+// all notify_server instances share the consumer so each message is pushed once
+const PUSH_CONSUMER: &str = "web_push";
+// a notification for an older message is no longer useful
+const MAX_MESSAGE_AGE_MINUTES: i64 = 60;
+const MAX_BODY_CHARS: usize = 120;
+const RETRY_LIMIT: i64 = 100;
+const DEFAULT_RETRY_INTERVAL_SECS: u64 = 30;
+
+/// A push subscription registered by a browser of the user.
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct PushSubscription {
+    pub id: i64,
+    pub user_id: i64,
+    pub endpoint: String,
+    pub p256dh: String,
+    pub auth: String,
+}
+
+/// The payload the service worker of the client receives.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct PushMessage {
+    pub chat_id: i64,
+    pub message_id: i64,
+    pub sender_id: i64,
+    pub title: String,
+    pub body: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PushStatus {
+    Delivered,
+    // the subscription expired or was revoked, it should be removed
+    Gone,
+    // the push service refused the message, sending it again won't help
+    Rejected,
+}
+
+/// Delivers an encrypted payload to a push subscription. The default transport
+/// talks to the push service over HTTP, tests can plug in their own. Errors
+/// are taken as transient, the message is sent again later.
+pub trait PushTransport: Send + Sync + 'static {
+    fn send(
+        &self,
+        subscription: &PushSubscription,
+        payload: &[u8],
+    ) -> impl Future<Output = anyhow::Result<PushStatus>> + Send;
+}
+
+#[derive(Debug, FromRow)]
+struct Recipient {
+    #[sqlx(flatten)]
+    subscription: PushSubscription,
+    #[sqlx(flatten)]
+    settings: NotificationSettings,
+    fullname: String,
+    chat_type: ChatType,
+}
+
+// payload of the `chat_message_created` and `chat_message_finalized` events
+#[derive(Debug, Deserialize)]
+struct ChatMessageEvent {
+    message: Message,
+}
+
+/// Sends web push notifications of new messages to members without a live SSE
+/// connection. Streamed agent replies are pushed when they are finalized.
+/// Events whose push failed are released and retried with the other pending
+/// events until their message is too old to be worth a notification.
+pub struct PushWorker<T> {
+    pool: PgPool,
+    outbox: EventOutbox,
+    transport: T,
+    retry_interval: StdDuration,
+}
+
+impl<T: PushTransport> PushWorker<T> {
+    pub fn new(pool: PgPool, transport: T) -> Self {
+        let outbox = EventOutbox::new(pool.clone(), PUSH_CONSUMER);
+        Self {
+            pool,
+            outbox,
+            transport,
+            retry_interval: StdDuration::from_secs(DEFAULT_RETRY_INTERVAL_SECS),
+        }
+    }
+
+    pub fn with_retry_interval(mut self, retry_interval: StdDuration) -> Self {
+        self.retry_interval = retry_interval;
+        self
+    }
+
+    pub fn outbox(&self) -> &EventOutbox {
+        &self.outbox
+    }
+
+    /// Process message events in a background task, returns the sender to feed them.
+    /// The pending events, e.g. released after a failed push or missed while
+    /// no instance was running, are processed every retry interval.
+    pub async fn spawn(self) -> anyhow::Result<mpsc::UnboundedSender<OutboxEvent>> {
+        self.outbox.register().await?;
+        let (tx, mut rx) = mpsc::unbounded_channel::<OutboxEvent>();
+        tokio::spawn(async move {
+            let mut retry = tokio::time::interval(self.retry_interval);
+            loop {
+                tokio::select! {
+                    event = rx.recv() => match event {
+                        Some(event) => self.handle(&event).await,
+                        None => break,
+                    },
+                    _ = retry.tick() => match self.outbox.pending(RETRY_LIMIT).await {
+                        Ok(events) => {
+                            for event in events {
+                                self.handle(&event).await;
+                            }
+                        }
+                        Err(e) => warn!("Failed to fetch pending push events: {}", e),
+                    },
+                }
+            }
+        });
+        Ok(tx)
+    }
+
+    async fn handle(&self, event: &OutboxEvent) {
+        if let Err(e) = self.process(event).await {
+            warn!("Failed to push event {}: {}", event.id, e);
+        }
+    }
+
+    /// Push the event to the subscriptions it is for. Another instance may
+    /// have claimed it already. Skipped events are claimed too, an
+    /// unacknowledged event is never pruned. A failed push releases the
+    /// event, the subscriptions which got it aren't pushed again.
+    pub async fn process(&self, event: &OutboxEvent) -> anyhow::Result<()> {
+        if !self.outbox.claim(event.id).await? {
+            return Ok(());
+        }
+        let ret = self.push(event).await;
+        if !matches!(ret, Ok(true)) {
+            self.outbox.release(event.id).await?;
+        }
+        ret.map(|_| ())
+    }
+
+    /// whether every push of the event went out
+    async fn push(&self, event: &OutboxEvent) -> anyhow::Result<bool> {
+        if !matches!(
+            event.channel.as_str(),
+            "chat_message_created" | "chat_message_finalized"
+        ) {
+            return Ok(true);
+        }
+        if Utc::now() - event.created_at > Duration::minutes(MAX_MESSAGE_AGE_MINUTES) {
+            return Ok(true);
+        }
+        let ChatMessageEvent { message } = ChatMessageEvent::deserialize(&event.payload.0)?;
+        // a streamed agent reply has no content yet, it is pushed once it is
+        // finalized, unless it failed
+        if message.status != MessageStatus::Sent {
+            return Ok(true);
+        }
+        let sender: String = sqlx::query_scalar("SELECT fullname FROM users WHERE id = $1")
+            .bind(message.sender_id)
+            .fetch_one(&self.pool)
+            .await?;
+        let payload = serde_json::to_vec(&PushMessage::new(&message, sender))?;
+
+        let now = Utc::now();
+        let mut sent = true;
+        for recipient in self.recipients(event.id, &message).await? {
+            // only direct messages and mentions are worth a push notification
+            let mentioned = is_mentioned(&message.content, &recipient.fullname);
+            if !(recipient.chat_type == ChatType::Single || mentioned)
+                || recipient
+                    .settings
+                    .is_silent(&message.content, &recipient.fullname, now)
+            {
+                continue;
+            }
+            let subscription = &recipient.subscription;
+            match self.transport.send(subscription, &payload).await {
+                Ok(PushStatus::Delivered) => {
+                    info!(
+                        "Pushed message {} to user {}",
+                        message.id, subscription.user_id
+                    );
+                    self.delivered(event.id, subscription.id).await?;
+                }
+                Ok(PushStatus::Gone) => self.remove(subscription.id).await?,
+                // not sent again either
+                Ok(PushStatus::Rejected) => self.delivered(event.id, subscription.id).await?,
+                Err(e) => {
+                    warn!("Failed to push to subscription {}: {}", subscription.id, e);
+                    sent = false;
+                }
+            }
+        }
+        Ok(sent)
+    }
+
+    /// subscriptions of members, other than the sender, who aren't connected to
+    /// any instance and didn't get the event yet
+    async fn recipients(
+        &self,
+        event_id: i64,
+        message: &Message,
+    ) -> Result<Vec<Recipient>, sqlx::Error> {
+        sqlx::query_as(
+            r#"
+            SELECT p.id, p.user_id, p.endpoint, p.p256dh, p.auth, c.id AS chat_id,
+                COALESCE(s.level, w.default_notification_level) AS level, s.muted_until,
+                u.fullname, c.type AS chat_type
+            FROM chats c
+            JOIN workspaces w ON w.id = c.ws_id
+            JOIN users u ON u.id = ANY(c.members)
+            JOIN push_subscriptions p ON p.user_id = u.id
+            LEFT JOIN chat_notification_settings s ON s.chat_id = c.id AND s.user_id = u.id
+            WHERE c.id = $1 AND u.id != $2
+            AND NOT EXISTS (
+                SELECT 1 FROM user_connections uc
+                WHERE uc.user_id = u.id AND uc.updated_at > NOW() - INTERVAL '2 minutes'
+            )
+            AND NOT EXISTS (
+                SELECT 1 FROM push_deliveries d WHERE d.event_id = $3 AND d.subscription_id = p.id
+            )
+            "#,
+        )
+        .bind(message.chat_id)
+        .bind(message.sender_id)
+        .bind(event_id)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn delivered(&self, event_id: i64, subscription_id: i64) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"
+            INSERT INTO push_deliveries (event_id, subscription_id)
+            VALUES ($1, $2)
+            ON CONFLICT DO NOTHING
+            "#,
+        )
+        .bind(event_id)
+        .bind(subscription_id)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn remove(&self, id: i64) -> Result<(), sqlx::Error> {
+        info!("Push subscription {} is gone, removing it", id);
+        sqlx::query("DELETE FROM push_subscriptions WHERE id = $1")
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+}
+
+impl PushMessage {
+    pub fn new(message: &Message, sender: String) -> Self {
+        let mut body: String = message.content.chars().take(MAX_BODY_CHARS).collect();
+        if body.len() < message.content.len() {
+            body.push('…');
+        }
+        Self {
+            chat_id: message.chat_id,
+            message_id: message.id,
+            sender_id: message.sender_id,
+            title: sender,
+            body,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn push_message_should_truncate_long_content() {
+        let mut message = Message {
+            id: 1,
+            chat_id: 3,
+            sender_id: 1,
+            content: "hello".to_string(),
+            modified_content: None,
+            files: vec![],
+            status: MessageStatus::Sent,
+            citations: Default::default(),
+            created_at: Utc::now(),
+        };
+        let msg = PushMessage::new(&message, "Tyr Chen".to_string());
+        assert_eq!(msg.title, "Tyr Chen");
+        assert_eq!(msg.body, "hello");
+
+        message.content = "a".repeat(500);
+        let msg = PushMessage::new(&message, "Tyr Chen".to_string());
+        assert_eq!(msg.body.chars().count(), MAX_BODY_CHARS + 1);
+        assert!(msg.body.ends_with('…'));
+    }
+}
@@ -0,0 +1,234 @@
+use aes_gcm::{Aes128Gcm, KeyInit, Nonce, aead::Aead};
+use anyhow::{Context, anyhow};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use hkdf::Hkdf;
+use jwt_simple::prelude::*;
+use p256::{PublicKey, SecretKey, ecdh::EphemeralSecret, elliptic_curve::sec1::ToEncodedPoint};
+use rand::{RngCore, rngs::OsRng};
+use reqwest::{Client, StatusCode, Url};
+use sha2::Sha256;
+use std::sync::Arc;
+use tracing::warn;
+
+use super::{PushStatus, PushSubscription, PushTransport};
+use crate::PushConfig;
+
+// single record, large enough for any push message payload
+const RECORD_SIZE: u32 = 4096;
+const PUSH_TTL: &str = "86400";
+const VAPID_VALID_HOURS: u64 = 12;
+
+/// Application server key used to sign VAPID (RFC 8292) tokens.
+#[derive(Clone)]
+pub struct VapidKey {
+    key_pair: Arc<ES256KeyPair>,
+    public_key: String,
+    subject: String,
+}
+
+/// Sends push messages to the push service of each subscription.
+pub struct WebPushTransport {
+    vapid: VapidKey,
+    client: Client,
+}
+
+impl VapidKey {
+    pub fn try_new(config: &PushConfig) -> anyhow::Result<Self> {
+        let raw = URL_SAFE_NO_PAD
+            .decode(config.vapid_private_key.trim())
+            .context("vapid private key should be base64url encoded")?;
+        let secret = SecretKey::from_slice(&raw).map_err(|_| anyhow!("invalid vapid key"))?;
+        let public_key = URL_SAFE_NO_PAD.encode(secret.public_key().to_encoded_point(false));
+        let key_pair = Arc::new(ES256KeyPair::from_bytes(&raw)?);
+        Ok(Self {
+            key_pair,
+            public_key,
+            subject: config.subject.clone(),
+        })
+    }
+
+    /// base64url encoded public key, used by clients as `applicationServerKey`
+    pub fn public_key(&self) -> &str {
+        &self.public_key
+    }
+
+    /// `Authorization` header for a push service endpoint
+    pub fn authorization(&self, endpoint: &str) -> anyhow::Result<String> {
+        let url = Url::parse(endpoint)?;
+        let audience = url.origin().ascii_serialization();
+        let claims = Claims::create(Duration::from_hours(VAPID_VALID_HOURS))
+            .with_audience(audience)
+            .with_subject(&self.subject);
+        let token = self.key_pair.sign(claims)?;
+        Ok(format!("vapid t={}, k={}", token, self.public_key))
+    }
+}
+
+impl WebPushTransport {
+    pub fn new(vapid: VapidKey) -> Self {
+        Self {
+            vapid,
+            client: Client::new(),
+        }
+    }
+}
+
+impl PushTransport for WebPushTransport {
+    async fn send(
+        &self,
+        subscription: &PushSubscription,
+        payload: &[u8],
+    ) -> anyhow::Result<PushStatus> {
+        let body = encrypt(&subscription.p256dh, &subscription.auth, payload)?;
+        let authorization = self.vapid.authorization(&subscription.endpoint)?;
+        let res = self
+            .client
+            .post(&subscription.endpoint)
+            .header("Authorization", authorization)
+            .header("Content-Encoding", "aes128gcm")
+            .header("Content-Type", "application/octet-stream")
+            .header("TTL", PUSH_TTL)
+            .body(body)
+            .send()
+            .await?;
+        match res.status() {
+            StatusCode::NOT_FOUND | StatusCode::GONE => Ok(PushStatus::Gone),
+            status if status.is_success() => Ok(PushStatus::Delivered),
+            // the push service is busy or down, worth another try
+            status if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS => Err(
+                anyhow!("push service returned {}: {}", status, res.text().await?),
+            ),
+            status => {
+                warn!(
+                    "Push service refused the message with {}: {}",
+                    status,
+                    res.text().await?
+                );
+                Ok(PushStatus::Rejected)
+            }
+        }
+    }
+}
+
+/// Encrypt the payload for a subscription with `aes128gcm` content encoding (RFC 8291)
+pub fn encrypt(p256dh: &str, auth: &str, payload: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let ua_public = URL_SAFE_NO_PAD.decode(p256dh)?;
+    let auth_secret = URL_SAFE_NO_PAD.decode(auth)?;
+    let ua_key =
+        PublicKey::from_sec1_bytes(&ua_public).map_err(|_| anyhow!("invalid p256dh key"))?;
+
+    let as_secret = EphemeralSecret::random(&mut OsRng);
+    let as_public = as_secret.public_key().to_encoded_point(false);
+    let shared = as_secret.diffie_hellman(&ua_key);
+
+    // IKM = HKDF(auth_secret, ecdh_secret, "WebPush: info" || 0x00 || ua_public || as_public)
+    let mut key_info = b"WebPush: info\0".to_vec();
+    key_info.extend_from_slice(&ua_public);
+    key_info.extend_from_slice(as_public.as_bytes());
+    let mut ikm = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(&auth_secret), shared.raw_secret_bytes())
+        .expand(&key_info, &mut ikm)
+        .map_err(|_| anyhow!("failed to derive ikm"))?;
+
+    let mut salt = [0u8; 16];
+    OsRng.fill_bytes(&mut salt);
+    let hk = Hkdf::<Sha256>::new(Some(&salt), &ikm);
+    let mut cek = [0u8; 16];
+    let mut nonce = [0u8; 12];
+    hk.expand(b"Content-Encoding: aes128gcm\0", &mut cek)
+        .and_then(|_| hk.expand(b"Content-Encoding: nonce\0", &mut nonce))
+        .map_err(|_| anyhow!("failed to derive content encryption key"))?;
+
+    // a single record, terminated by the last record delimiter
+    let mut plaintext = payload.to_vec();
+    plaintext.push(2);
+    let cipher = Aes128Gcm::new_from_slice(&cek).map_err(|_| anyhow!("invalid cek"))?;
+    let ciphertext = cipher
+        .encrypt(Nonce::from_slice(&nonce), plaintext.as_ref())
+        .map_err(|_| anyhow!("failed to encrypt push payload"))?;
+
+    // header: salt || record size || key id length || key id (as_public)
+    let mut body = Vec::with_capacity(86 + ciphertext.len());
+    body.extend_from_slice(&salt);
+    body.extend_from_slice(&RECORD_SIZE.to_be_bytes());
+    body.push(as_public.as_bytes().len() as u8);
+    body.extend_from_slice(as_public.as_bytes());
+    body.extend_from_slice(&ciphertext);
+    Ok(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use p256::ecdh::diffie_hellman;
+
+    // decrypt as the user agent would do
+    fn decrypt(ua_secret: &SecretKey, auth: &[u8], body: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let salt = &body[..16];
+        let id_len = body[20] as usize;
+        let as_public = &body[21..21 + id_len];
+        let ciphertext = &body[21 + id_len..];
+
+        let as_key = PublicKey::from_sec1_bytes(as_public)?;
+        let shared = diffie_hellman(ua_secret.to_nonzero_scalar(), as_key.as_affine());
+        let ua_public = ua_secret.public_key().to_encoded_point(false);
+        let mut key_info = b"WebPush: info\0".to_vec();
+        key_info.extend_from_slice(ua_public.as_bytes());
+        key_info.extend_from_slice(as_public);
+        let mut ikm = [0u8; 32];
+        Hkdf::<Sha256>::new(Some(auth), shared.raw_secret_bytes())
+            .expand(&key_info, &mut ikm)
+            .unwrap();
+        let hk = Hkdf::<Sha256>::new(Some(salt), &ikm);
+        let mut cek = [0u8; 16];
+        let mut nonce = [0u8; 12];
+        hk.expand(b"Content-Encoding: aes128gcm\0", &mut cek)
+            .unwrap();
+        hk.expand(b"Content-Encoding: nonce\0", &mut nonce).unwrap();
+        let mut plaintext = Aes128Gcm::new_from_slice(&cek)?
+            .decrypt(Nonce::from_slice(&nonce), ciphertext)
+            .map_err(|_| anyhow!("decrypt failed"))?;
+        assert_eq!(plaintext.pop(), Some(2));
+        Ok(plaintext)
+    }
+
+    #[test]
+    fn encrypt_should_be_decryptable_by_user_agent() -> anyhow::Result<()> {
+        let ua_secret = SecretKey::random(&mut OsRng);
+        let p256dh = URL_SAFE_NO_PAD.encode(ua_secret.public_key().to_encoded_point(false));
+        let auth = [7u8; 16];
+
+        let body = encrypt(&p256dh, &URL_SAFE_NO_PAD.encode(auth), b"hello world")?;
+        assert_eq!(&body[16..20], &RECORD_SIZE.to_be_bytes());
+        assert_eq!(body[20], 65);
+        assert_eq!(decrypt(&ua_secret, &auth, &body)?, b"hello world");
+        Ok(())
+    }
+
+    #[test]
+    fn vapid_authorization_should_be_verifiable() -> anyhow::Result<()> {
+        let secret = SecretKey::random(&mut OsRng);
+        let config = PushConfig {
+            vapid_private_key: URL_SAFE_NO_PAD.encode(secret.to_bytes()),
+            subject: "mailto:admin@acme.org".to_string(),
+            retry_interval_secs: 30,
+        };
+        let vapid = VapidKey::try_new(&config)?;
+        let header = vapid.authorization("https://push.example.com/send/abc")?;
+        let (token, key) = header
+            .strip_prefix("vapid t=")
+            .and_then(|v| v.split_once(", k="))
+            .expect("header should have token and key");
+        assert_eq!(key, vapid.public_key());
+
+        let pk = ES256PublicKey::from_bytes(&URL_SAFE_NO_PAD.decode(key)?)?;
+        let claims = pk.verify_token::<NoCustomClaims>(token, None)?;
+        assert_eq!(claims.subject.as_deref(), Some("mailto:admin@acme.org"));
+        let audience = claims
+            .audiences
+            .expect("audience should be set")
+            .into_string()?;
+        assert_eq!(audience, "https://push.example.com");
+        Ok(())
+    }
+}
@@ -1,49 +1,86 @@
-use crate::AppEvent;
+use crate::{AppError, AppState, EventFilter, EventFilterParams};
 use axum::{
     Extension,
-    extract::State,
+    extract::{Query, State},
     response::sse::{Event, Sse},
 };
 use chat_core::User;
 use futures::stream::Stream;
-use std::{convert::Infallible, time::Duration};
+use std::{
+    convert::Infallible,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
 use tokio::sync::broadcast;
 use tokio_stream::{StreamExt, wrappers::BroadcastStream};
 use tracing::info;
 
 const CHANNEL_CAPACITY: usize = 256;
 
-use crate::AppState;
+/// Event stream of a single connection. When the client goes away the stream is
+/// dropped: the receiver goes first, then the guard releases the user's sender
+/// if it was the last receiver.
+struct ConnectionStream<S> {
+    inner: S,
+    _guard: ConnectionGuard,
+}
+
+struct ConnectionGuard {
+    user_id: u64,
+    state: AppState,
+}
+
 pub(crate) async fn sse_handler(
     Extension(user): Extension<User>,
     State(state): State<AppState>,
-) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    Query(params): Query<EventFilterParams>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, AppError> {
     let user_id = user.id as u64;
-    let users = &state.users;
-
-    let rx = if let Some(tx) = users.get(&user_id) {
-        tx.subscribe()
-    } else {
-        let (tx, rx) = broadcast::channel(CHANNEL_CAPACITY);
-        state.users.insert(user_id, tx);
-        rx
-    };
+    let filter = EventFilter::try_from(params)?;
+
+    let rx = state
+        .users
+        .entry(user_id)
+        .or_insert_with(|| broadcast::channel(CHANNEL_CAPACITY).0)
+        .subscribe();
+    state.metrics.connection_opened();
+    state.sync_presence(user_id);
     info!("User {} subscribed", user_id);
 
-    let stream = BroadcastStream::new(rx).filter_map(|v| v.ok()).map(|v| {
-        let name = match v.as_ref() {
-            AppEvent::NewChat(_) => "NewChat",
-            AppEvent::AddToChat(_) => "AddToChat",
-            AppEvent::RemoveFromChat(_) => "RemoveFromChat",
-            AppEvent::NewMessage(_) => "NewMessage",
-        };
-        let v = serde_json::to_string(&v).expect("Failed to serialize event");
-        Ok(Event::default().data(v).event(name))
-    });
-
-    Sse::new(stream).keep_alive(
+    // drop unwanted events before paying for their serialization
+    let stream = BroadcastStream::new(rx)
+        .filter_map(|v| v.ok())
+        .filter(move |v| filter.matches(v))
+        .map(|v| {
+            let data = serde_json::to_string(&v).expect("Failed to serialize event");
+            Ok(Event::default().data(data).event(v.name()))
+        });
+    let stream = ConnectionStream {
+        inner: stream,
+        _guard: ConnectionGuard { user_id, state },
+    };
+
+    Ok(Sse::new(stream).keep_alive(
         axum::response::sse::KeepAlive::new()
             .interval(Duration::from_secs(1))
             .text("keep-alive-text"),
-    )
+    ))
+}
+
+impl<S: Stream + Unpin> Stream for ConnectionStream<S> {
+    type Item = S::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.inner).poll_next(cx)
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        info!("User {} unsubscribed", self.user_id);
+        self.state.metrics.connection_closed();
+        self.state.release_user(self.user_id);
+        self.state.sync_presence(self.user_id);
+    }
 }
@@ -5,6 +5,7 @@ use pgvector::Vector;
 use sqlx::{prelude::FromRow, types::Uuid};
 use swiftide_core::{
     Retrieve,
+    indexing::Metadata,
     querying::{Document, Query, search_strategies::SimilaritySingleEmbedding, states},
 };
 use tracing::info;
@@ -13,11 +14,10 @@ use tracing::info;
 #[derive(Debug, Clone, FromRow)]
 struct RetrievalResult {
     id: Uuid,
+    path: String,
     chunk: String,
 }
 
-const DEFAULT_LIMIT: usize = 5;
-
 #[async_trait]
 impl Retrieve<SimilaritySingleEmbedding<String>> for PgVector {
     #[tracing::instrument]
@@ -34,17 +34,21 @@ impl Retrieve<SimilaritySingleEmbedding<String>> for PgVector {
         let pool = self.get_pool();
 
         let sql = format!(
-            "SELECT id, chunk FROM {} ORDER BY embedding <=> $1 LIMIT $2",
+            "SELECT id, path, chunk FROM {} ORDER BY embedding <=> $1 LIMIT $2",
             self.table_name
         );
         info!("Running retrieve with SQL: {}", sql);
         let data: Vec<RetrievalResult> = sqlx::query_as(&sql)
             .bind(embedding)
-            .bind(DEFAULT_LIMIT as i32)
+            .bind(search_strategy.top_k() as i64)
             .fetch_all(pool)
             .await?;
 
-        let docs = data.into_iter().map(|r| Document::from(r.chunk)).collect();
+        // the path of a chunk tells where it comes from
+        let docs = data
+            .into_iter()
+            .map(|r| Document::new(r.chunk, Some(Metadata::from(("path", r.path)))))
+            .collect();
 
         Ok(query.retrieved_documents(docs))
     }